@@ -0,0 +1,179 @@
+//! POSIX access ACLs, stored as GNU tar and star do, in a `SCHILY.acl.access` pax
+//! record of the ACL's text form with numeric ids, e.g.
+//! `user::rw-,user:1000:r--,group::r--,mask::r--,other::---`.
+//!
+//! Linux keeps them in the `system.posix_acl_access` extended attribute, whose binary
+//! form is converted to and from the text form here. Only regular files are archived,
+//! so directories' default ACLs aren't kept.
+
+use anyhow::{bail, ensure, Context};
+use crate::Result;
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+use xattr::FileExt;
+
+const ACCESS_XATTR: &str = "system.posix_acl_access";
+const DEFAULT_XATTR: &str = "system.posix_acl_default";
+const PAX_KEY: &str = "SCHILY.acl.access";
+
+/// Version of the binary format.
+const XATTR_VERSION: u32 = 2;
+/// Id of entries that don't name a user or group.
+const UNDEFINED_ID: u32 = u32::MAX;
+
+// Entry tags of the binary format, in the order entries are sorted in.
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+/// Whether the extended attribute `name` holds an ACL, which `--xattrs` leaves to
+/// `--acls`.
+pub fn is_acl_xattr(name: &[u8]) -> bool {
+    name == ACCESS_XATTR.as_bytes() || name == DEFAULT_XATTR.as_bytes()
+}
+
+/// Read the access ACL of `file` as a pax record, if it has one beyond its mode bits.
+pub fn record(file: &File) -> Result<Option<(String, Vec<u8>)>> {
+    let value = match file.get_xattr(ACCESS_XATTR) {
+        Ok(value) => value,
+        Err(err) if err.kind() == io::ErrorKind::Unsupported
+                    || err.raw_os_error() == Some(libc::ENOTSUP) => None,
+        Err(err) => return Err(err.into()),
+    };
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    Ok(Some((PAX_KEY.to_string(), to_text(&value)?.into_bytes())))
+}
+
+/// Set the access ACL in `entry`'s pax records, if any, on the file extracted from it at
+/// `path`.
+pub fn apply<R: Read>(entry: &mut tar::Entry<R>, path: &Path) -> Result<()> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(());
+    };
+    for extension in extensions.filter_map(|e| e.ok()) {
+        if extension.key_bytes() != PAX_KEY.as_bytes() {
+            continue;
+        }
+        let text = extension.value().context("ACL record isn't UTF-8")?;
+        let value = from_text(text)
+            .with_context(|| format!("parsing ACL '{text}' of '{}'", path.display()))?;
+        xattr::set(path, ACCESS_XATTR, &value)?;
+    }
+    Ok(())
+}
+
+/// Convert an ACL from the binary form of its extended attribute to text.
+fn to_text(value: &[u8]) -> Result<String> {
+    ensure!(value.len() >= 4 && (value.len() - 4).is_multiple_of(8),
+            "ACL attribute of {} bytes is malformed", value.len());
+    let version = u32::from_le_bytes(value[0..4].try_into().expect("4 bytes"));
+    ensure!(version == XATTR_VERSION, "Unknown ACL attribute version {version}");
+
+    let mut entries = Vec::new();
+    for chunk in value[4..].chunks_exact(8) {
+        let tag = u16::from_le_bytes(chunk[0..2].try_into().expect("2 bytes"));
+        let perm = u16::from_le_bytes(chunk[2..4].try_into().expect("2 bytes"));
+        let id = u32::from_le_bytes(chunk[4..8].try_into().expect("4 bytes"));
+        let (name, qualifier) = match tag {
+            USER_OBJ => ("user", String::new()),
+            USER => ("user", id.to_string()),
+            GROUP_OBJ => ("group", String::new()),
+            GROUP => ("group", id.to_string()),
+            MASK => ("mask", String::new()),
+            OTHER => ("other", String::new()),
+            _ => bail!("Unknown ACL entry tag {tag:#x}"),
+        };
+        let perms = [(4, 'r'), (2, 'w'), (1, 'x')]
+            .map(|(bit, c)| if perm & bit != 0 { c } else { '-' })
+            .iter()
+            .collect::<String>();
+        entries.push(format!("{name}:{qualifier}:{perms}"));
+    }
+    Ok(entries.join(","))
+}
+
+/// Convert an ACL from text to the binary form of its extended attribute.
+///
+/// Accepts entries separated by commas or newlines, with short or long tag names, and
+/// star's `<tag>:<name>:<perms>:<id>` form, but not user or group names without ids.
+fn from_text(text: &str) -> Result<Vec<u8>> {
+    let mut entries = Vec::new();
+    for entry in text.split([',', '\n']) {
+        // Comments, as `getfacl` writes.
+        let entry = entry.split('#').next().unwrap_or("").trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let fields = entry.split(':').collect::<Vec<&str>>();
+        ensure!(matches!(fields.len(), 3 | 4), "Malformed ACL entry '{entry}'");
+        let (qualifier, perms) = (fields[1], fields[2]);
+        let id = match fields.get(3) {
+            Some(id) => Some(*id),
+            None if qualifier.is_empty() => None,
+            None => Some(qualifier),
+        };
+        let id = id.map(|id| id.parse::<u32>()
+                               .with_context(|| format!("ACL entry '{entry}' has no \
+                                                         numeric id")))
+                   .transpose()?;
+        let tag = match (fields[0], id) {
+            ("user" | "u", None) => USER_OBJ,
+            ("user" | "u", Some(_)) => USER,
+            ("group" | "g", None) => GROUP_OBJ,
+            ("group" | "g", Some(_)) => GROUP,
+            ("mask" | "m", None) => MASK,
+            ("other" | "o", None) => OTHER,
+            _ => bail!("Malformed ACL entry '{entry}'"),
+        };
+        let mut perm = 0_u16;
+        for c in perms.chars() {
+            perm |= match c {
+                'r' => 4,
+                'w' => 2,
+                'x' => 1,
+                '-' => 0,
+                _ => bail!("Malformed permissions in ACL entry '{entry}'"),
+            };
+        }
+        entries.push((tag, id.unwrap_or(UNDEFINED_ID), perm));
+    }
+    // The kernel requires entries in this order.
+    entries.sort();
+
+    let mut value = XATTR_VERSION.to_le_bytes().to_vec();
+    for (tag, id, perm) in entries {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&perm.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let text = "user::rw-,user:1000:r--,group::r--,group:50:rwx,mask::rwx,other::---";
+        let value = from_text(text).unwrap();
+        assert_eq!(value.len(), 4 + 6 * 8);
+        assert_eq!(to_text(&value).unwrap(), text);
+
+        // Unsorted, short tags, newlines, comments and star's trailing ids.
+        let other = "u:1000:r--\ng::r--\nu::rw-  # owner\nm::rwx,o::,group:staff:rwx:50";
+        assert_eq!(from_text(other).unwrap(), value);
+
+        assert!(from_text("user:alice:r--").is_err());
+        assert!(from_text("mask:1:r--").is_err());
+        assert!(to_text(&value[..7]).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! `--level auto`: adjusting each shard's zstd level while it compresses, as
+//! `zstd --adapt` does, by where the shard's writer thread spends its time.
+//!
+//! Writes of the tar stream into the encoder block while it compresses, or waits for
+//! its output to be written, and writes of the compressed output block on the
+//! destination, which are timed by the `ProgressWriter` counting them. The rest of the
+//! time goes on reading files. Every `INTERVAL`:
+//!
+//! * If compressing took most of it, the shard is CPU-bound, so the level is lowered.
+//! * If writing the output took most of it, or compressing took little of it, the shard
+//!   is I/O-bound and the compressor has time to spare, so the level is raised.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Level each shard starts at, zstd's default.
+pub const START_LEVEL: i32 = 3;
+pub const MIN_LEVEL: i32 = 1;
+/// Higher levels use much more memory for little gain.
+pub const MAX_LEVEL: i32 = 19;
+const INTERVAL: Duration = Duration::from_millis(500);
+
+/// The current level of one shard's encoder, and its time spent since the last change.
+#[derive(Debug)]
+pub struct AdaptiveLevel {
+    level: i32,
+    interval_start: Instant,
+    /// Time blocked writing into the encoder this interval.
+    encoder_busy: Duration,
+    /// Nanoseconds blocked writing the compressed output, in total, and at the start of
+    /// this interval.
+    output_busy: Arc<AtomicU64>,
+    interval_output_busy: u64,
+}
+
+impl AdaptiveLevel {
+    /// Start at `level`, with `output_busy` counting the nanoseconds the encoder's output
+    /// writer is busy.
+    pub fn new(level: i32, output_busy: Arc<AtomicU64>) -> AdaptiveLevel {
+        AdaptiveLevel {
+            level,
+            interval_start: Instant::now(),
+            encoder_busy: Duration::ZERO,
+            interval_output_busy: output_busy.load(Ordering::Relaxed),
+            output_busy,
+        }
+    }
+
+    /// Record a write into the encoder that took `elapsed`. Returns the level to change
+    /// to, at the end of an interval that calls for it.
+    pub fn record(&mut self, elapsed: Duration) -> Option<i32> {
+        self.encoder_busy += elapsed;
+        let interval = self.interval_start.elapsed();
+        if interval < INTERVAL {
+            return None;
+        }
+
+        let output_busy = self.output_busy.load(Ordering::Relaxed);
+        let output = Duration::from_nanos(output_busy - self.interval_output_busy);
+        let compress = self.encoder_busy.saturating_sub(output);
+        let next = next_level(self.level,
+                              compress.as_secs_f64() / interval.as_secs_f64(),
+                              output.as_secs_f64() / interval.as_secs_f64());
+        tracing::trace!(level = self.level, next, compress_ms = compress.as_millis(),
+                        output_ms = output.as_millis(), interval_ms = interval.as_millis(),
+                        "Adaptive level interval");
+
+        self.interval_start = Instant::now();
+        self.encoder_busy = Duration::ZERO;
+        self.interval_output_busy = output_busy;
+        if next == self.level {
+            return None;
+        }
+        self.level = next;
+        Some(next)
+    }
+}
+
+/// The level after an interval at `level` spending the fractions `compress` of its time
+/// compressing and `output` writing the output.
+fn next_level(level: i32, compress: f64, output: f64) -> i32 {
+    let next = if compress > 0.6 {
+        level - 1
+    } else if output > 0.5 || compress < 0.2 {
+        level + 1
+    } else {
+        level
+    };
+    next.clamp(MIN_LEVEL, MAX_LEVEL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_levels() {
+        // CPU-bound.
+        assert_eq!(next_level(3, 0.9, 0.05), 2);
+        assert_eq!(next_level(MIN_LEVEL, 0.9, 0.0), MIN_LEVEL);
+        // Waiting on reads, or on the output.
+        assert_eq!(next_level(3, 0.1, 0.0), 4);
+        assert_eq!(next_level(3, 0.3, 0.6), 4);
+        assert_eq!(next_level(MAX_LEVEL, 0.0, 0.9), MAX_LEVEL);
+        // Balanced.
+        assert_eq!(next_level(3, 0.4, 0.1), 3);
+    }
+}
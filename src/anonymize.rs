@@ -0,0 +1,93 @@
+//! Removing identifying metadata from archived files, so archive sets can be shared
+//! externally without leaking usernames or the layout of the source tree.
+
+use crate::{Result, manifest};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Replaces each path component with a keyed hash of it, and records the original paths
+/// in a mapping file.
+///
+/// The key is random for each run, so hashes of common names can't be looked up without
+/// the mapping file.
+pub struct PathHasher {
+    key: [u8; blake3::KEY_LEN],
+    mapping: Mutex<BufWriter<File>>,
+}
+
+#[derive(serde::Serialize)]
+struct MappingLine<'a> {
+    anonymized: &'a Path,
+    original: &'a Path,
+}
+
+/// Hex digits of each hashed path component.
+const COMPONENT_LEN: usize = 16;
+
+/// Zero the owner and modification time of a tar header.
+pub fn header(header: &mut tar::Header) -> Result<()> {
+    header.set_uid(0);
+    header.set_gid(0);
+    // v7 headers have no owner names.
+    if header.as_ustar().is_some() || header.as_gnu().is_some() {
+        header.set_username("")?;
+        header.set_groupname("")?;
+    }
+    header.set_mtime(0);
+    Ok(())
+}
+
+/// Zero the owner and modification time of a manifest entry.
+pub fn entry(entry: &mut manifest::Entry) {
+    entry.uid = None;
+    entry.gid = None;
+    entry.uname = None;
+    entry.gname = None;
+    entry.mtime = 0;
+    entry.mtime_nsec = 0;
+}
+
+impl PathHasher {
+    /// Create the mapping file at `mapping_path`, which must not exist yet.
+    pub fn create(mapping_path: &Path) -> Result<PathHasher> {
+        let mut key = [0_u8; blake3::KEY_LEN];
+        File::open("/dev/urandom")?.read_exact(&mut key)?;
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(mapping_path)?;
+        Ok(PathHasher {
+            key,
+            mapping: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Returns `path` with each component hashed, and records it in the mapping file.
+    pub fn anonymize(&self, path: &Path) -> Result<PathBuf> {
+        let anonymized = path.iter()
+            .map(|component| {
+                let hash = blake3::keyed_hash(&self.key, component.as_encoded_bytes());
+                hash.to_hex()[..COMPONENT_LEN].to_string()
+            })
+            .collect::<PathBuf>();
+
+        let mut mapping = self.mapping.lock().expect("mapping lock");
+        serde_json::to_writer(&mut *mapping, &MappingLine {
+            anonymized: &anonymized,
+            original: path,
+        })?;
+        mapping.write_all(b"\n")?;
+        Ok(anonymized)
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let file = self.mapping.into_inner().expect("mapping lock")
+                       .into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
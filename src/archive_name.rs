@@ -0,0 +1,258 @@
+//! Archive file names, from `--name-template` and `--prefix`, so runs from several hosts
+//! or dates can write their archives to shared storage without their names colliding.
+//!
+//! A template is a file name with placeholders: `{prefix}`, `{bucket}` for the
+//! `--bucket-by` bucket, `{num}` for the archive's number, or `{num:08}` for it padded
+//! with zeros to 8 digits, and `{ext}` for the codec's extension, e.g. `tar.zstd`.
+//! Encrypted archives get `.age` after the name. Readers match file names against the
+//! template as a pattern, so they only read the archives of the runs they're given.
+
+use anyhow::{bail, ensure};
+use crate::{Result, codec::Codec, shard_encryption};
+use regex::Regex;
+use valuable::Valuable;
+
+/// Options naming archives, shared by compress and the commands reading its archives.
+#[derive(clap::Args, Clone, Debug, Default, Valuable)]
+pub struct NameArgs {
+    /// Name archives from this template, e.g. `{prefix}-{num:08}.{ext}`, with the
+    /// placeholders `{prefix}`, `{bucket}`, `{num}`, `{num:08}` and `{ext}`. Defaults to
+    /// `{num:08}.{ext}`, after `{bucket}.` with `--bucket-by` and `{prefix}.` with
+    /// `--prefix`. When reading, only archives named by it are read.
+    #[arg(long, value_name = "TEMPLATE")]
+    pub(crate) name_template: Option<String>,
+
+    /// The `{prefix}` in archive names, e.g. the host name and date, so several runs
+    /// can share storage. When reading, only archives with this prefix are read, and
+    /// with `--in-url` the manifest is the one uploaded with this prefix.
+    #[arg(long)]
+    pub(crate) prefix: Option<String>,
+}
+
+/// A parsed `--name-template`, with its `--prefix`.
+#[derive(Clone, Debug)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+    prefix: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    Literal(String),
+    Prefix,
+    Bucket,
+    /// The archive number, padded with zeros to `width` digits.
+    Num { width: usize },
+    Ext,
+}
+
+/// Pattern of `--bucket-by` buckets, e.g. `2023` or `2023-04`.
+const BUCKET_PATTERN: &str = "[0-9]{4}(-[0-9]{2})?";
+
+impl NameArgs {
+    /// Whether either option is given.
+    pub fn is_set(&self) -> bool {
+        self.name_template.is_some() || self.prefix.is_some()
+    }
+
+    /// The template compress names archives by, checking it names each archive
+    /// distinctly. `bucket` is whether `--bucket-by` is given.
+    pub fn template(&self, bucket: bool) -> Result<NameTemplate> {
+        let prefix = self.prefix()?;
+        let template = match self.name_template {
+            Some(ref template) => NameTemplate::parse(template, prefix.clone())?,
+            None => {
+                let mut template = "{num:08}.{ext}".to_string();
+                if bucket {
+                    template.insert_str(0, "{bucket}.");
+                }
+                if prefix.is_some() {
+                    template.insert_str(0, "{prefix}.");
+                }
+                NameTemplate::parse(&template, prefix.clone())?
+            },
+        };
+        let has = |part: &dyn Fn(&Part) -> bool| template.parts.iter().any(part);
+        ensure!(has(&|p| matches!(p, Part::Num { .. })),
+                "--name-template must include {{num}}, so each archive's name differs");
+        match (bucket, has(&|p| *p == Part::Bucket)) {
+            (true, false) => bail!("--name-template must include {{bucket}} with --bucket-by"),
+            (false, true) => bail!("--name-template uses {{bucket}}; pass --bucket-by"),
+            _ => {},
+        }
+        match (prefix.is_some(), has(&|p| *p == Part::Prefix)) {
+            (true, false) => bail!("--name-template must include {{prefix}} with --prefix"),
+            (false, true) => bail!("--name-template uses {{prefix}}; pass --prefix"),
+            _ => {},
+        }
+        Ok(template)
+    }
+
+    /// Pattern matching the names of archives named by these options, or by compress's
+    /// default names if neither is given.
+    pub fn pattern(&self) -> Result<Regex> {
+        let prefix = self.prefix()?;
+        let pattern = match self.name_template {
+            Some(ref template) => NameTemplate::parse(template, prefix)?.pattern(),
+            None => {
+                let prefix = prefix.map(|p| format!("{}\\.", regex::escape(&p)))
+                                   .unwrap_or_default();
+                format!("^{prefix}({BUCKET_PATTERN}\\.)?[0-9]{{8}}\\.{ext}{age}$",
+                        ext = ext_pattern(), age = age_pattern())
+            },
+        };
+        Ok(Regex::new(&pattern)?)
+    }
+
+    /// The name of the metadata file `name`, e.g. the manifest, in remote storage shared
+    /// by several runs.
+    pub fn metadata_name(&self, name: &str) -> String {
+        match self.prefix {
+            Some(ref prefix) => format!("{prefix}.{name}"),
+            None => name.to_string(),
+        }
+    }
+
+    fn prefix(&self) -> Result<Option<String>> {
+        if let Some(ref prefix) = self.prefix {
+            ensure!(!prefix.is_empty() && !prefix.contains('/'),
+                    "--prefix must be non-empty, without '/'");
+        }
+        Ok(self.prefix.clone())
+    }
+}
+
+impl NameTemplate {
+    fn parse(template: &str, prefix: Option<String>) -> Result<NameTemplate> {
+        ensure!(!template.contains('/'), "--name-template can't contain '/'");
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}').filter(|_| rest[start..].starts_with('{'))
+            else {
+                bail!("Unmatched brace in --name-template '{template}'");
+            };
+            let placeholder = &rest[start + 1..start + end];
+            parts.push(match placeholder {
+                "prefix" => Part::Prefix,
+                "bucket" => Part::Bucket,
+                "ext" => Part::Ext,
+                "num" => Part::Num { width: 0 },
+                _ => match placeholder.strip_prefix("num:0").map(str::parse) {
+                    Some(Ok(width)) => Part::Num { width },
+                    _ => bail!("Unknown placeholder '{{{placeholder}}}' in --name-template \
+                                '{template}'; expected {{prefix}}, {{bucket}}, {{num}}, \
+                                {{num:0N}} or {{ext}}"),
+                },
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(NameTemplate { parts, prefix })
+    }
+
+    /// The name of archive `num`, in `bucket` if given, with the extension `ext`.
+    pub fn render(&self, bucket: Option<&str>, num: u64, ext: &str) -> String {
+        self.parts.iter().map(|part| match part {
+            Part::Literal(s) => s.clone(),
+            Part::Prefix => self.prefix.clone().unwrap_or_default(),
+            Part::Bucket => bucket.unwrap_or_default().to_string(),
+            Part::Num { width } => format!("{num:0width$}"),
+            Part::Ext => ext.to_string(),
+        }).collect()
+    }
+
+    /// Regex matching every name this template renders. Without a prefix, `{prefix}`
+    /// matches any.
+    fn pattern(&self) -> String {
+        let body = self.parts.iter().map(|part| match part {
+            Part::Literal(s) => regex::escape(s),
+            Part::Prefix => match self.prefix {
+                Some(ref prefix) => regex::escape(prefix),
+                None => ".+".to_string(),
+            },
+            Part::Bucket => BUCKET_PATTERN.to_string(),
+            Part::Num { width } => format!("[0-9]{{{min},}}", min = (*width).max(1)),
+            Part::Ext => ext_pattern(),
+        }).collect::<String>();
+        format!("^{body}{age}$", age = age_pattern())
+    }
+}
+
+/// Pattern of every codec's extension.
+fn ext_pattern() -> String {
+    let exts = <Codec as clap::ValueEnum>::value_variants().iter()
+        .map(|codec| regex::escape(codec.extension()))
+        .collect::<Vec<_>>();
+    format!("({})", exts.join("|"))
+}
+
+/// Pattern of the extension encrypted archives have after their name.
+fn age_pattern() -> String {
+    format!("(\\.{})?", regex::escape(shard_encryption::EXTENSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(template: Option<&str>, prefix: Option<&str>) -> NameArgs {
+        NameArgs {
+            name_template: template.map(str::to_string),
+            prefix: prefix.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn templates() {
+        let name = |args: NameArgs, bucket: Option<&str>| {
+            args.template(bucket.is_some()).unwrap().render(bucket, 3, "tar.zstd")
+        };
+        assert_eq!(name(args(None, None), None), "00000003.tar.zstd");
+        assert_eq!(name(args(None, None), Some("2023-04")), "2023-04.00000003.tar.zstd");
+        assert_eq!(name(args(None, Some("host-2024")), None), "host-2024.00000003.tar.zstd");
+        assert_eq!(name(args(Some("{prefix}-{num:04}.{ext}"), Some("h")), None),
+                   "h-0003.tar.zstd");
+        assert_eq!(name(args(Some("a{num}b.tar.zst"), None), None), "a3b.tar.zst");
+
+        for (template, prefix, bucket) in [
+            ("{prefix}.tar", Some("h"), false),
+            ("{num}{bucket}", None, false),
+            ("{num}", None, true),
+            ("{num}", Some("h"), false),
+            ("{prefix}{num}", None, false),
+            ("{num}{other}", None, false),
+            ("{num", None, false),
+            ("num}", None, false),
+            ("d/{num}", None, false),
+        ] {
+            assert!(args(Some(template), prefix).template(bucket).is_err(), "{template}");
+        }
+        assert!(args(None, Some("a/b")).template(false).is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        let matches = |args: NameArgs, name: &str| args.pattern().unwrap().is_match(name);
+        assert!(matches(args(None, None), "00000001.tar.zstd"));
+        assert!(matches(args(None, None), "2023-04.00000001.tar.gz.age"));
+        assert!(!matches(args(None, None), "h.00000001.tar.zstd"));
+        assert!(!matches(args(None, None), "00000001.tar.zstd.partial"));
+        assert!(matches(args(None, Some("h")), "h.00000001.tar.zstd"));
+        assert!(matches(args(None, Some("h")), "h.2023.00000001.tar"));
+        assert!(!matches(args(None, Some("h")), "00000001.tar.zstd"));
+        assert!(!matches(args(None, Some("h")), "g.00000001.tar.zstd"));
+
+        let template = Some("{prefix}-{num:04}.{ext}");
+        assert!(matches(args(template, Some("h.1")), "h.1-0001.tar.xz"));
+        assert!(matches(args(template, Some("h.1")), "h.1-12345.tar.xz"));
+        assert!(!matches(args(template, Some("h.1")), "h.1-001.tar.xz"));
+        assert!(!matches(args(template, Some("h.1")), "hx1-0001.tar.xz"));
+        assert!(matches(args(template, None), "any-0001.tar.xz"));
+    }
+}
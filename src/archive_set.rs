@@ -0,0 +1,154 @@
+//! Finding and opening the archives in an archive set directory.
+
+use anyhow::{bail, ensure, Context};
+use crate::{
+    ProgressReader, Result, ThreadOffloadReader,
+    codec::{self, Codec, Dictionary},
+    manifest,
+    remote::StoredFile,
+    shard_encryption::{self, DecryptionKeys},
+};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::AtomicU64},
+};
+
+/// An archive opened for reading its uncompressed tar stream.
+pub struct OpenArchive {
+    pub codec: Codec,
+    /// Compressed bytes read so far.
+    pub compressed_bytes: Arc<AtomicU64>,
+    /// The uncompressed tar stream.
+    pub reader: ThreadOffloadReader,
+    /// Uncompressed bytes read so far.
+    pub uncompressed_bytes: Arc<AtomicU64>,
+}
+
+impl OpenArchive {
+    /// Skip the tar stream to `offset`, e.g. a manifest entry's, so the next tar entry
+    /// read is the one starting there. The bytes before it are still decompressed, but
+    /// not parsed.
+    pub fn skip_to(&mut self, offset: u64) -> Result<()> {
+        let skipped = io::copy(&mut (&mut self.reader).take(offset), &mut io::sink())?;
+        ensure!(skipped == offset, "Archive ends before offset {offset}");
+        Ok(())
+    }
+}
+
+/// Suffix of an archive still being written. compress renames it to drop the suffix
+/// once the archive is complete and synced.
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+/// List the regular files in `dir`, sorted by name, except incomplete archives.
+///
+/// Archives are recognised by their magic bytes when they're opened, so this includes
+/// other files in the directory, which `open()` skips.
+pub fn candidate_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    list_files(dir, |name| !name.ends_with(PARTIAL_SUFFIX))
+}
+
+/// List the incomplete archives in `dir`, left there by a crashed compress, sorted by
+/// name.
+pub fn partial_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    list_files(dir, |name| name.ends_with(PARTIAL_SUFFIX))
+}
+
+/// Like `candidate_paths()`, with each file's size and modification time.
+pub fn candidate_files(dir: &Path) -> Result<Vec<StoredFile>> {
+    let mut files = Vec::new();
+    for path in candidate_paths(dir)? {
+        let meta = fs::metadata(&*path)?;
+        files.push(StoredFile {
+            name: path.file_name().expect("has a file name").to_string_lossy().into_owned(),
+            size: meta.len(),
+            modified: meta.modified()?,
+        });
+    }
+    Ok(files)
+}
+
+fn list_files(dir: &Path, include: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() || !include(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        paths.push(entry.path());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Open the archive at `path`. Returns None if it's not a recognised archive.
+///
+/// Archives encrypted with `compress --encrypt` or `--passphrase` are decrypted with
+/// `keys`, and are an error without them. Likewise archives compressed with `--dict`
+/// are decompressed with `dictionary`.
+pub fn open(path: &Path, keys: Option<&DecryptionKeys>, dictionary: Option<&Dictionary>)
+-> Result<Option<OpenArchive>>
+{
+    open_stream(File::open(path)?, keys, dictionary)
+}
+
+/// Open the archive at `path` positioned at manifest `entry`, so the next tar entry read
+/// is its file. Starts from the entry's zstd frame with `compress --seekable`, and
+/// otherwise from the start, skipping to its offset if the manifest has it.
+pub fn open_at_entry(path: &Path, entry: &manifest::Entry, keys: Option<&DecryptionKeys>,
+                     dictionary: Option<&Dictionary>)
+-> Result<Option<OpenArchive>>
+{
+    let (Some(offset), Some(frame_offset), Some(frame_tar_offset)) =
+        (entry.offset, entry.frame_offset, entry.frame_tar_offset) else {
+        let Some(mut archive) = open(path, keys, dictionary)? else {
+            return Ok(None);
+        };
+        if let Some(offset) = entry.offset {
+            archive.skip_to(offset)?;
+        }
+        return Ok(Some(archive));
+    };
+
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(frame_offset))?;
+    let mut archive = match open_stream(file, None, dictionary)? {
+        Some(archive) if archive.codec == Codec::Zstd => archive,
+        _ => bail!("No zstd frame at offset {frame_offset} of '{}', where the manifest \
+                    says '{}' is", path.display(), entry.path.display()),
+    };
+    archive.skip_to(offset.checked_sub(frame_tar_offset)
+                          .context("Manifest entry's offset is before its frame")?)?;
+    Ok(Some(archive))
+}
+
+/// Open an archive from a stream of its compressed, and possibly encrypted, bytes.
+/// Returns None if it's not a recognised archive.
+pub fn open_stream<R: Read + Send + 'static>(source: R, keys: Option<&DecryptionKeys>,
+                                             dictionary: Option<&Dictionary>)
+-> Result<Option<OpenArchive>>
+{
+    let (source_prog_read, compressed_bytes) = ProgressReader::new(source);
+
+    let (encrypted, source) = shard_encryption::sniff(source_prog_read)?;
+    let decoded = if encrypted {
+        let keys = keys.context("Archive is encrypted; pass --identity or --passphrase to \
+                                 decrypt it")?;
+        codec::decoder(keys.decrypt(source)?, dictionary)?
+    } else {
+        codec::decoder(source, dictionary)?
+    };
+    let Some((codec, decoder)) = decoded else {
+        return Ok(None);
+    };
+
+    let (uncompressed_prog_read, uncompressed_bytes) = ProgressReader::new(decoder);
+
+    Ok(Some(OpenArchive {
+        codec,
+        compressed_bytes,
+        reader: ThreadOffloadReader::new(uncompressed_prog_read),
+        uncompressed_bytes,
+    }))
+}
@@ -0,0 +1,79 @@
+//! Cancelling a running compress or decompress from another thread, or on SIGINT and
+//! SIGTERM.
+
+use crate::Result;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// A flag shared with a running compress or decompress, which stops it soon after
+/// `cancel()` is called. The run then returns an error.
+///
+/// Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+/// The error a run returns when it was cancelled.
+#[derive(Debug)]
+pub struct Cancelled;
+
+/// Exit code after `Cancelled`, as after a SIGINT.
+pub const EXIT_CODE: i32 = 130;
+
+/// What compress does with the archives it's writing when cancelled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CancelPolicy {
+    /// Finish each archive after the file being appended, so the archives and manifest
+    /// are consistent but only cover some of the input.
+    #[default]
+    Finish,
+    /// Delete the unfinished archives and their checksums files. Archives already
+    /// finished, e.g. at `--max-shard-size`, are kept.
+    Discard,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Cancel on SIGINT or SIGTERM. A second signal exits immediately with `EXIT_CODE`.
+    pub fn cancel_on_signals(&self) -> Result<()> {
+        for signal in [SIGINT, SIGTERM] {
+            // Registered first, so it only sees the flag set by an earlier signal.
+            signal_hook::flag::register_conditional_shutdown(signal, EXIT_CODE,
+                                                             self.0.clone())?;
+            signal_hook::flag::register(signal, self.0.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Return an error if `token` has been cancelled.
+pub fn check(token: Option<&CancellationToken>) -> Result<()> {
+    if token.is_some_and(|token| token.is_cancelled()) {
+        return Err(Cancelled.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Finding paths that differ only in case, which collide when restored onto a case
+//! insensitive filesystem, e.g. on macOS or Windows, so one silently overwrites the
+//! other.
+//!
+//! Only hashes of the paths seen are kept, so checking costs a few dozen bytes per
+//! file and directory.
+
+use std::{
+    collections::HashMap,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Tracks the paths walked so far, to find later ones that collide with them.
+#[derive(Debug, Default)]
+pub struct CaseCollisions {
+    count: AtomicU64,
+    examples: Mutex<Vec<PathBuf>>,
+    /// The hash of the first path seen with each case folded path hash.
+    seen: Mutex<HashMap<u128, u128>>,
+}
+
+/// Number of colliding paths kept as examples.
+const MAX_EXAMPLES: usize = 10;
+
+impl CaseCollisions {
+    /// Record `path` and its parent directories. If one of them differs only in case from
+    /// one recorded earlier, returns the shortest that does, e.g. `Dir` for `Dir/a` after
+    /// `dir/b`.
+    pub fn check(&self, path: &Path) -> Option<PathBuf> {
+        let mut collision = None;
+        let mut prefix = PathBuf::new();
+        {
+            let mut seen = self.seen.lock().expect("seen lock");
+            for name in path.iter() {
+                prefix.push(name);
+                let exact = hash(prefix.as_os_str().as_bytes());
+                let folded = hash(prefix.to_string_lossy().to_lowercase().as_bytes());
+                // Keep recording deeper prefixes after a collision, for later paths.
+                if *seen.entry(folded).or_insert(exact) != exact && collision.is_none() {
+                    collision = Some(prefix.clone());
+                }
+            }
+        }
+        if collision.is_some() {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            let mut examples = self.examples.lock().expect("examples lock");
+            if examples.len() < MAX_EXAMPLES {
+                examples.push(path.to_path_buf());
+            }
+        }
+        collision
+    }
+
+    /// Number of paths that collided with an earlier one.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The first colliding paths found, sorted.
+    pub fn examples(&self) -> Vec<PathBuf> {
+        let mut examples = self.examples.lock().expect("examples lock").clone();
+        examples.sort();
+        examples
+    }
+}
+
+fn hash(bytes: &[u8]) -> u128 {
+    let hash = blake3::hash(bytes);
+    u128::from_le_bytes(hash.as_bytes()[..16].try_into().expect("16 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collisions() {
+        let c = CaseCollisions::default();
+        assert_eq!(c.check(Path::new("dir/a")), None);
+        assert_eq!(c.check(Path::new("dir/b")), None);
+        assert_eq!(c.check(Path::new("dir/A")), Some(PathBuf::from("dir/A")));
+        assert_eq!(c.check(Path::new("Dir/c")), Some(PathBuf::from("Dir")));
+        // `Dir/c` was recorded, so `Dir/C` collides with it too.
+        assert_eq!(c.check(Path::new("Dir/C")), Some(PathBuf::from("Dir")));
+        assert_eq!(c.check(Path::new("ÄRGER")), None);
+        assert_eq!(c.check(Path::new("ärger")), Some(PathBuf::from("ärger")));
+        assert_eq!(c.count(), 4);
+        assert_eq!(c.examples()[0], PathBuf::from("Dir/C"));
+    }
+}
@@ -0,0 +1,176 @@
+//! `ptar cat`: write one archived file's contents to stdout, for a quick look at it
+//! without extracting the whole set.
+//!
+//! The manifest says which archive holds the file, and where in it, so only that
+//! archive is read, and its entries before the file aren't parsed; with
+//! `compress --seekable`, reading starts at the file's zstd frame. Without a manifest,
+//! each archive is scanned in turn.
+//!
+//! A file split by `compress --chunk-size` is written a chunk at a time, reading each
+//! from where the manifest says it is.
+
+use anyhow::{bail, ensure};
+use crate::{
+    Result, archive_set, file_chunk, manifest, stream_writer,
+    entry_encryption::{DataKey, DecryptingReader, MasterKey},
+    shard_encryption::{self, DecryptionKeys},
+};
+use std::{
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Path of the file to write, relative to the archived directory, as listed in the
+    /// manifest, e.g. `docs/notes.txt`.
+    #[arg(long)]
+    path: PathBuf,
+
+    /// Decrypt a file compressed with `--entry-key-file`, using the same master key file.
+    #[arg(long, value_name = "KEY_FILE")]
+    entry_key_file: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--encrypt`, using the age identities in this
+    /// file.
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    identity: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--passphrase`. The passphrase is read from the
+    /// `PTAR_PASSPHRASE` environment variable if set, or else prompted for.
+    #[arg(long, visible_alias = "passphrase-prompt")]
+    passphrase: bool,
+}
+
+pub fn main(cmd_args: Args, _args: crate::Args) -> Result<()> {
+    // Match the manifest's form of the path, e.g. `a/b` for `./a/b/`.
+    let path = cmd_args.path.components()
+                            .filter(|c| matches!(c, Component::Normal(_)))
+                            .collect::<PathBuf>();
+    ensure!(!path.as_os_str().is_empty(), "--path must name a file");
+
+    let passphrase = cmd_args.passphrase.then(|| shard_encryption::read_passphrase(false))
+                                        .transpose()?;
+    let keys = DecryptionKeys::new(cmd_args.identity.as_deref(), passphrase)?;
+
+    // Each archive to look in, and the file's manifest entry if known, or each of its
+    // chunks' in order.
+    let (archives, data_key) = match manifest::read(&cmd_args.in_dir) {
+        Ok(entries) => {
+            let mut entries = entries.into_iter()
+                                     .filter(|e| e.path == path)
+                                     .collect::<Vec<_>>();
+            ensure!(!entries.is_empty(), "'{}' isn't in the manifest", path.display());
+            if entries[0].chunk_offset.is_some() {
+                entries.sort_by_key(|e| e.chunk_offset);
+            } else {
+                entries.truncate(1);
+            }
+            let entry = &entries[0];
+            let data_key = match (&entry.wrapped_key, &cmd_args.entry_key_file) {
+                (Some(wrapped), Some(key_file)) => {
+                    Some(MasterKey::load(key_file)?.unwrap(wrapped, &path)?)
+                },
+                (Some(_), None) => bail!("'{}' is encrypted; pass --entry-key-file",
+                                         path.display()),
+                (None, _) => None,
+            };
+            let archives = entries.into_iter()
+                                  .map(|e| (cmd_args.in_dir.join(&e.archive), Some(e)))
+                                  .collect::<Vec<_>>();
+            (archives, data_key)
+        },
+        Err(err) => {
+            ensure!(cmd_args.entry_key_file.is_none(),
+                    "--entry-key-file requires the manifest, which has the data keys: {err}");
+            tracing::info!(%err, "No manifest read, so scanning every archive");
+            let paths = archive_set::candidate_paths(&cmd_args.in_dir)?;
+            (paths.into_iter().map(|path| (path, None)).collect(), None)
+        },
+    };
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let res = (|| -> Result<bool> {
+        let chunked = archives.first()
+                              .is_some_and(|(_, e)| e.as_ref()
+                                                     .is_some_and(|e| e.chunk_offset.is_some()));
+        for (archive_path, entry) in archives {
+            let found = cat_from_archive(&archive_path, entry.as_ref(), &path, keys.as_ref(),
+                                         data_key.as_ref(), &mut out)?;
+            if chunked {
+                ensure!(found, "A chunk of '{}' isn't in '{}'", path.display(),
+                        archive_path.display());
+            } else if found {
+                out.flush()?;
+                return Ok(true);
+            }
+        }
+        out.flush()?;
+        Ok(chunked)
+    })();
+    match res {
+        Ok(true) => Ok(()),
+        Ok(false) => bail!("'{}' isn't in the archive set", path.display()),
+        // The reader stopped early, e.g. `ptar cat ... | head`.
+        Err(err) if err.downcast_ref::<io::Error>()
+                       .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Write the file at `path` from the archive at `archive_path` to `out`, starting from
+/// its manifest `entry`'s position if known, and returning whether it was found.
+fn cat_from_archive(archive_path: &Path, entry: Option<&manifest::Entry>, path: &Path,
+                    keys: Option<&DecryptionKeys>, data_key: Option<&DataKey>,
+                    out: &mut impl Write)
+-> Result<bool>
+{
+    let scanning = entry.is_none();
+    let archive = match entry {
+        Some(entry) => archive_set::open_at_entry(archive_path, entry, keys, None)?,
+        None => archive_set::open(archive_path, keys, None)?,
+    };
+    let Some(archive) = archive else {
+        return Ok(false);
+    };
+    let mut tar = tar::Archive::new(archive.reader);
+    let mut found = false;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if *entry.path()? != *path {
+            if found {
+                break;
+            }
+            continue;
+        }
+        if found {
+            // Later parts of a stream written by `StreamWriter` directly follow the first.
+            if !stream_writer::is_part(&mut entry)? {
+                break;
+            }
+            io::copy(&mut entry, out)?;
+            continue;
+        }
+
+        let entry_type = entry.header().entry_type();
+        ensure!(entry_type.is_file(), "'{}' isn't a regular file, it's a {entry_type:?}",
+                path.display());
+        // Without the manifest, the other chunks can't be found.
+        ensure!(!scanning || file_chunk::read(&mut entry)?.is_none(),
+                "'{}' is split into chunks, which cat finds with the manifest",
+                path.display());
+        match data_key {
+            Some(data_key) => {
+                let size = entry.size();
+                io::copy(&mut DecryptingReader::new(&mut entry, data_key, size), out)?
+            },
+            None => io::copy(&mut entry, out)?,
+        };
+        found = true;
+    }
+    Ok(found)
+}
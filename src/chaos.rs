@@ -0,0 +1,204 @@
+//! `ptar chaos`: copy an archive set and damage the copy, to rehearse recovering from
+//! bit rot and crashes with `ptar verify` and `ptar fsck --repair`.
+//!
+//! Hidden from `--help`, as it's only useful for testing.
+
+use anyhow::{Context, ensure};
+use crate::{Result, archive_set, archive_name::NameArgs};
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Directory containing the archive set to copy. It isn't modified.
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Directory to copy the archive set to and damage. Must not exist yet.
+    #[arg(long)]
+    out_dir: PathBuf,
+
+    /// Number of bits to flip, each at a random offset in a random archive.
+    #[arg(long, default_value_t = 0)]
+    flip_bits: usize,
+
+    /// Number of archives to truncate at a random length, as after a crash mid-write.
+    #[arg(long, default_value_t = 0)]
+    truncate_archives: usize,
+
+    /// Seed for choosing the damage. The same seed damages the same set the same way.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// Damage to apply to a file in an archive set.
+#[derive(Clone, Copy, Debug)]
+pub enum Corruption {
+    /// Keep only the first `keep` bytes, as after a crash mid-write.
+    Truncate { keep: u64 },
+    /// Invert the byte at `offset`, as after bit rot.
+    FlipByte { offset: u64 },
+    /// Invert bit `bit` (0 is the least significant) of the byte at `offset`.
+    FlipBit { offset: u64, bit: u8 },
+    /// Append bytes that aren't part of the format.
+    AppendGarbage { len: usize },
+    /// Remove the file.
+    Delete,
+}
+
+/// Deterministic pseudo-random numbers from blake3's extendable output.
+pub(crate) struct Rng(blake3::OutputReader);
+
+pub fn main(cmd_args: Args, _args: crate::Args) -> Result<()> {
+    let damaged = damage_copy(&cmd_args)?;
+    tracing::info!(out_dir = %cmd_args.out_dir.display(), damaged,
+                   "Damaged copy of archive set; check it with `ptar verify` and repair \
+                    it with `ptar fsck --repair`");
+    Ok(())
+}
+
+/// Copy the archive set and damage the copy. Returns the number of corruptions applied.
+fn damage_copy(cmd_args: &Args) -> Result<usize> {
+    ensure!(!cmd_args.out_dir.exists(),
+            "--out-dir '{}' already exists; chaos only damages a new copy",
+            cmd_args.out_dir.display());
+    fs::create_dir_all(&cmd_args.out_dir)?;
+
+    let mut archives = Vec::new();
+    let archive_pattern = NameArgs::default().pattern()?;
+    for src in archive_set::candidate_paths(&cmd_args.in_dir)? {
+        let name = src.file_name().expect("candidate path has a file name");
+        let dest = cmd_args.out_dir.join(name);
+        fs::copy(&src, &dest)
+            .with_context(|| format!("copying '{}' to '{}'", src.display(), dest.display()))?;
+        if archive_pattern.is_match(&name.to_string_lossy()) {
+            archives.push(dest);
+        }
+    }
+    ensure!(!archives.is_empty() || cmd_args.flip_bits + cmd_args.truncate_archives == 0,
+            "No archives found in '{}'", cmd_args.in_dir.display());
+    ensure!(cmd_args.truncate_archives <= archives.len(),
+            "Can't truncate {truncate} archives, the set only has {count}",
+            truncate = cmd_args.truncate_archives, count = archives.len());
+
+    let mut rng = Rng::new(cmd_args.seed);
+    let mut damaged = 0;
+
+    // Flip bits first, so they land within the archives' original lengths.
+    for _ in 0..cmd_args.flip_bits {
+        let path = &archives[rng.below(archives.len() as u64) as usize];
+        let len = fs::metadata(path)?.len();
+        if len == 0 {
+            continue;
+        }
+        let corruption = Corruption::FlipBit {
+            offset: rng.below(len),
+            bit: rng.below(8) as u8,
+        };
+        apply_logged(corruption, path)?;
+        damaged += 1;
+    }
+
+    // Choose distinct archives to truncate by partially shuffling the list.
+    for i in 0..cmd_args.truncate_archives {
+        let j = i + rng.below((archives.len() - i) as u64) as usize;
+        archives.swap(i, j);
+        let path = &archives[i];
+        let keep = rng.below(fs::metadata(path)?.len());
+        apply_logged(Corruption::Truncate { keep }, path)?;
+        damaged += 1;
+    }
+
+    Ok(damaged)
+}
+
+fn apply_logged(corruption: Corruption, path: &Path) -> Result<()> {
+    tracing::info!(path = %path.display(), ?corruption, "Damaging archive");
+    corruption.apply(path)
+}
+
+impl Corruption {
+    pub fn apply(self, path: &Path) -> Result<()> {
+        match self {
+            Corruption::Truncate { keep } => {
+                File::options().write(true).open(path)?.set_len(keep)?;
+            },
+            Corruption::FlipByte { offset } => flip(path, offset, 0xff)?,
+            Corruption::FlipBit { offset, bit } => {
+                ensure!(bit < 8, "Bit index {bit} is not in 0..8");
+                flip(path, offset, 1 << bit)?;
+            },
+            Corruption::AppendGarbage { len } => {
+                let garbage = (0..len).map(|i| (i * 31 + 7) as u8).collect::<Vec<u8>>();
+                File::options().append(true).open(path)?.write_all(&garbage)?;
+            },
+            Corruption::Delete => fs::remove_file(path)?,
+        }
+        Ok(())
+    }
+}
+
+/// XOR the byte at `offset` in the file at `path` with `mask`.
+fn flip(path: &Path, offset: u64, mask: u8) -> Result<()> {
+    let mut file = File::options().read(true).write(true).open(path)?;
+    let mut byte = [0_u8];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut byte)
+        .with_context(|| format!("'{}' is shorter than offset {offset}", path.display()))?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&[byte[0] ^ mask])?;
+    Ok(())
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&seed.to_le_bytes());
+        Rng(hasher.finalize_xof())
+    }
+
+    pub(crate) fn fill(&mut self, buf: &mut [u8]) {
+        self.0.fill(buf);
+    }
+
+    /// A number in `0..n`, or 0 if `n` is 0.
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        let mut bytes = [0_u8; 8];
+        self.fill(&mut bytes);
+        u64::from_le_bytes(bytes).checked_rem(n).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::{Fixture, TempDir, TreeSpec, diff_trees};
+
+    #[test]
+    fn damages_only_the_copy() {
+        let spec = TreeSpec { files: 30, max_file_size: 20_000, ..TreeSpec::default() };
+        let fixture = Fixture::build(&spec, |options| {
+            options.threads(2).max_shard_size(16_000)
+        }).unwrap();
+        let parent = TempDir::new("ptar-test").unwrap();
+        let args = Args {
+            in_dir: fixture.archives.path().to_path_buf(),
+            out_dir: parent.path().join("damaged"),
+            flip_bits: 3,
+            truncate_archives: 1,
+            seed: 0,
+        };
+        assert_eq!(damage_copy(&args).unwrap(), 4);
+
+        // The original still extracts, and the copy differs from it.
+        fixture.extract().unwrap();
+        assert!(!diff_trees(fixture.archives.path(), &args.out_dir).unwrap().is_empty());
+
+        // Never overwrites an existing directory.
+        assert!(damage_copy(&args).is_err());
+    }
+}
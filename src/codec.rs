@@ -0,0 +1,568 @@
+//! Archive compression formats: encoding them, and detecting and decoding them by
+//! their magic bytes, or for v7 tar archives, which have none, their header checksum.
+
+use anyhow::{bail, ensure, Context};
+use crate::{Result, adaptive_level::AdaptiveLevel};
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    num::NonZeroU32,
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+use valuable::Valuable;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+    Xz,
+    Lz4,
+    /// An uncompressed tar archive.
+    #[value(name = "none")]
+    Tar,
+}
+
+/// A compressing writer for any `Codec`.
+pub enum Encoder<W: Write> {
+    /// The encoder, which is only `None` after `end_frame` failed, its parameters, and
+    /// with `--level auto` what adjusts the level.
+    Zstd(Option<zstd::stream::write::Encoder<'static, W>>, ZstdParams, Option<AdaptiveLevel>),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    Tar(W),
+}
+
+/// Parameters a zstd encoder is created with, again for each frame.
+#[derive(Clone, Debug)]
+pub struct ZstdParams {
+    level: i32,
+    /// Some with `--dict`.
+    dictionary: Option<Dictionary>,
+    /// Log2 of the long-distance matching window, with `--long`.
+    long_window_log: Option<u32>,
+    /// Compression worker threads, when the zstd library supports multithreading.
+    workers: u32,
+    /// Whether each frame ends with a checksum of its contents, which decoding verifies.
+    checksum: bool,
+}
+
+/// A zstd dictionary, e.g. from `ptar train-dict`, that each zstd frame is compressed
+/// with, and must be decompressed with.
+#[derive(Clone)]
+pub struct Dictionary {
+    bytes: Arc<[u8]>,
+    /// Recorded in each frame's header, to check the same dictionary decompresses it.
+    id: NonZeroU32,
+}
+
+impl Dictionary {
+    /// Load the dictionary in the file at `path`.
+    pub fn load(path: &Path) -> Result<Dictionary> {
+        let bytes = fs::read(path)
+            .with_context(|| format!("reading dictionary '{}'", path.display()))?;
+        Dictionary::from_bytes(bytes)
+            .with_context(|| format!("loading dictionary '{}'", path.display()))
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Dictionary> {
+        let id = zstd::zstd_safe::get_dict_id_from_dict(&bytes)
+            .context("Not a zstd dictionary with an ID, as `ptar train-dict` writes")?;
+        Ok(Dictionary { bytes: bytes.into(), id })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id.get()
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+         .field("id", &self.id)
+         .field("len", &self.bytes.len())
+         .finish()
+    }
+}
+
+/// The error decoding a zstd archive compressed with a dictionary without that
+/// dictionary.
+#[derive(Debug)]
+pub struct WrongDictionary {
+    archive: u32,
+    given: Option<u32>,
+}
+
+impl fmt::Display for WrongDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let archive = self.archive;
+        match self.given {
+            None => write!(f, "The archive was compressed with zstd dictionary {archive}, \
+                               which is needed to decompress it, e.g. with --dict"),
+            Some(given) => write!(f, "The archive was compressed with zstd dictionary \
+                                      {archive}, not the given dictionary {given}"),
+        }
+    }
+}
+
+impl std::error::Error for WrongDictionary {}
+
+/// Bytes read from the start of a file to detect its codec.
+///
+/// A tar archive's magic is at offset 257 in its first header block, so this covers
+/// the whole first block.
+const SNIFF_LEN: usize = 512;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+/// "ustar" followed by NUL (POSIX) or a space (GNU).
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+/// Offset and length of a tar header's checksum field.
+const TAR_CKSUM_OFFSET: usize = 148;
+const TAR_CKSUM_LEN: usize = 8;
+
+const ZSTD_DEFAULT_COMPRESSION_LEVEL: i32 = 0;
+const GZIP_DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+const XZ_DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/// The largest `--long` window, 2 GiB, which is zstd's limit on 64-bit platforms.
+pub const MAX_WINDOW_LOG: u32 = 31;
+
+impl Codec {
+    /// Detect the codec of a file from its first bytes.
+    pub fn detect(header: &[u8]) -> Option<Codec> {
+        if header.starts_with(ZSTD_MAGIC) {
+            Some(Codec::Zstd)
+        } else if header.starts_with(GZIP_MAGIC) {
+            Some(Codec::Gzip)
+        } else if header.starts_with(XZ_MAGIC) {
+            Some(Codec::Xz)
+        } else if header.starts_with(LZ4_MAGIC) {
+            Some(Codec::Lz4)
+        } else if header.get(TAR_MAGIC_OFFSET..(TAR_MAGIC_OFFSET + TAR_MAGIC.len()))
+                        == Some(TAR_MAGIC) || is_tar_header(header) {
+            Some(Codec::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// File name extension of archives in this codec, without a leading `.`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Zstd => "tar.zstd",
+            Codec::Gzip => "tar.gz",
+            Codec::Xz => "tar.xz",
+            Codec::Lz4 => "tar.lz4",
+            Codec::Tar => "tar",
+        }
+    }
+
+    /// The codec of an archive named `name`, from its extension, e.g. `.tar.gz` or `.tgz`.
+    /// Returns None if the extension isn't an archive's.
+    pub fn from_file_name(name: &str) -> Option<Codec> {
+        let (_, ext) = name.rsplit_once('.')?;
+        Some(match ext {
+            "zst" | "zstd" | "tzst" => Codec::Zstd,
+            "gz" | "tgz" => Codec::Gzip,
+            "xz" | "txz" => Codec::Xz,
+            "lz4" => Codec::Lz4,
+            "tar" => Codec::Tar,
+            _ => return None,
+        })
+    }
+
+    /// Wrap `inner` in this codec's encoder, at compression `level`, or the codec's
+    /// default level if None. lz4 and uncompressed tar have no levels. Only zstd uses
+    /// `dictionary`.
+    pub fn encoder<W: Write>(self, inner: W, level: Option<i32>,
+                             dictionary: Option<&Dictionary>)
+    -> Result<Encoder<W>>
+    {
+        let unsigned_level = |default: u32| -> Result<u32> {
+            match level {
+                None => Ok(default),
+                Some(level @ 0..=9) => Ok(level as u32),
+                Some(level) => bail!("Invalid {self:?} compression level {level}"),
+            }
+        };
+        Ok(match self {
+            Codec::Zstd => {
+                let params = ZstdParams {
+                    level: level.unwrap_or(ZSTD_DEFAULT_COMPRESSION_LEVEL),
+                    dictionary: dictionary.cloned(),
+                    long_window_log: None,
+                    workers: 1,
+                    checksum: true,
+                };
+                Encoder::Zstd(Some(zstd_encoder(inner, &params)?), params, None)
+            },
+            Codec::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::new(unsigned_level(GZIP_DEFAULT_COMPRESSION_LEVEL)?))),
+            Codec::Xz => Encoder::Xz(xz2::write::XzEncoder::new(
+                inner, unsigned_level(XZ_DEFAULT_COMPRESSION_LEVEL)?)),
+            Codec::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(inner)),
+            Codec::Tar => Encoder::Tar(inner),
+        })
+    }
+}
+
+fn zstd_encoder<W: Write>(inner: W, params: &ZstdParams)
+-> io::Result<zstd::stream::write::Encoder<'static, W>>
+{
+    let mut zstdw = match params.dictionary {
+        Some(ref dictionary) => zstd::stream::write::Encoder::with_dictionary(
+            inner, params.level, &dictionary.bytes)?,
+        None => zstd::stream::write::Encoder::new(inner, params.level)?,
+    };
+    zstdw.include_checksum(params.checksum)?;
+    if let Some(window_log) = params.long_window_log {
+        zstdw.long_distance_matching(true)?;
+        zstdw.window_log(window_log)?;
+    }
+    // Compression will be done in separate threads, to detach I/O and compression, if
+    // this zstd build supports it.
+    if zstd_multithread_supported() {
+        zstdw.multithread(params.workers)?;
+    }
+    Ok(zstdw)
+}
+
+/// The zstd encoder in `Encoder::Zstd`, or an error if a failed `end_frame` lost it.
+fn zstd_active<'a, W: Write>(zstdw: &'a mut Option<zstd::stream::write::Encoder<'static, W>>)
+-> io::Result<&'a mut zstd::stream::write::Encoder<'static, W>>
+{
+    zstdw.as_mut().ok_or_else(lost_zstd_encoder)
+}
+
+fn lost_zstd_encoder() -> io::Error {
+    io::Error::other("The zstd encoder was lost when ending a frame failed")
+}
+
+/// Whether the linked zstd library was built with multithreading support. Without it,
+/// zstd compresses on the calling thread.
+pub fn zstd_multithread_supported() -> bool {
+    static SUPPORTED: once_cell::sync::OnceCell<bool> = once_cell::sync::OnceCell::new();
+    *SUPPORTED.get_or_init(|| {
+        zstd::stream::write::Encoder::new(io::sink(), ZSTD_DEFAULT_COMPRESSION_LEVEL)
+            .and_then(|mut zstdw| zstdw.multithread(1))
+            .is_ok()
+    })
+}
+
+impl<W: Write> Encoder<W> {
+    /// End the current zstd frame, writing all of it to the inner writer. The next write
+    /// starts a new frame, which can be decoded without the ones before.
+    ///
+    /// Other codecs continue their stream.
+    pub fn end_frame(&mut self) -> io::Result<()> {
+        let Encoder::Zstd(zstdw, params, _) = self else {
+            return Ok(());
+        };
+        // A zstd encoder only finishes once, so finish it and start another on the same
+        // writer.
+        let inner = zstdw.take().ok_or_else(lost_zstd_encoder)?.finish()?;
+        *zstdw = Some(zstd_encoder(inner, params)?);
+        Ok(())
+    }
+
+    /// Adjust a zstd encoder's level with `adaptive` as it compresses, from the level it
+    /// was created with. Other codecs keep theirs.
+    pub fn with_adaptive_level(mut self, adaptive: AdaptiveLevel) -> Self {
+        if let Encoder::Zstd(_, _, ref mut adapt) = self {
+            *adapt = Some(adaptive);
+        }
+        self
+    }
+
+    /// Enable a zstd encoder's long-distance matching, which finds matches up to
+    /// `2^window_log` bytes back rather than within zstd's usual window of at most a few
+    /// MiB, at the cost of that much memory to compress and decompress. Other codecs
+    /// have no such mode.
+    pub fn with_long(mut self, window_log: u32) -> Result<Self> {
+        if let Encoder::Zstd(ref mut zstdw, ref mut params, _) = self {
+            params.long_window_log = Some(window_log);
+            let zstdw = zstd_active(zstdw)?;
+            zstdw.long_distance_matching(true)?;
+            zstdw.window_log(window_log)
+                 .with_context(|| format!("Invalid zstd window log {window_log}"))?;
+        }
+        Ok(self)
+    }
+
+    /// Compress with `workers` zstd worker threads rather than 1, which compress
+    /// separate jobs of the stream in parallel. An error if the zstd library doesn't
+    /// support multithreading. Other codecs compress on the calling thread.
+    pub fn with_zstd_workers(mut self, workers: u32) -> Result<Self> {
+        if let Encoder::Zstd(ref mut zstdw, ref mut params, _) = self {
+            ensure!(zstd_multithread_supported(),
+                    "The zstd library doesn't support multithreading, so can't use \
+                     {workers} workers");
+            params.workers = workers;
+            zstd_active(zstdw)?.multithread(workers)?;
+        }
+        Ok(self)
+    }
+
+    /// Whether to end each zstd frame with a checksum of its contents, as by default.
+    /// Other codecs keep their own checks: gzip and xz always have checksums, and lz4
+    /// and uncompressed tar have none.
+    pub fn with_zstd_checksum(mut self, checksum: bool) -> Result<Self> {
+        if let Encoder::Zstd(ref mut zstdw, ref mut params, _) = self {
+            params.checksum = checksum;
+            zstd_active(zstdw)?.include_checksum(checksum)?;
+        }
+        Ok(self)
+    }
+
+    /// The current level of a zstd encoder.
+    pub fn level(&self) -> Option<i32> {
+        match self {
+            Encoder::Zstd(_, params, _) => Some(params.level),
+            _ => None,
+        }
+    }
+
+    /// Change a zstd encoder's level. With multithreading it applies from zstd's next
+    /// job; otherwise only at a frame start, so the current frame is ended.
+    fn set_zstd_level(&mut self, new_level: i32) -> io::Result<()> {
+        let Encoder::Zstd(zstdw, params, _) = self else {
+            return Ok(());
+        };
+        params.level = new_level;
+        if zstd_multithread_supported() {
+            zstd_active(zstdw)?.set_parameter(
+                zstd::stream::raw::CParameter::CompressionLevel(new_level))?;
+            return Ok(());
+        }
+        self.end_frame()
+    }
+
+    /// Finish the compressed stream and return the inner writer.
+    pub fn finish(self) -> Result<W> {
+        Ok(match self {
+            Encoder::Zstd(w, ..) => w.ok_or_else(lost_zstd_encoder)?.finish()?,
+            Encoder::Gzip(w) => w.finish()?,
+            Encoder::Xz(w) => w.finish()?,
+            Encoder::Lz4(w) => w.finish()?,
+            Encoder::Tar(w) => w,
+        })
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zstd(w, _, Some(adapt)) => {
+                let start = Instant::now();
+                let count = zstd_active(w)?.write(buf)?;
+                if let Some(level) = adapt.record(start.elapsed()) {
+                    tracing::debug!(level, "Changing zstd level");
+                    self.set_zstd_level(level)?;
+                }
+                Ok(count)
+            },
+            Encoder::Zstd(w, ..) => zstd_active(w)?.write(buf),
+            Encoder::Gzip(w) => w.write(buf),
+            Encoder::Xz(w) => w.write(buf),
+            Encoder::Lz4(w) => w.write(buf),
+            Encoder::Tar(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zstd(w, ..) => zstd_active(w)?.flush(),
+            Encoder::Gzip(w) => w.flush(),
+            Encoder::Xz(w) => w.flush(),
+            Encoder::Lz4(w) => w.flush(),
+            Encoder::Tar(w) => w.flush(),
+        }
+    }
+}
+
+/// A stream read by `sniff()`: the bytes it read, then the rest of the stream.
+pub type Sniffed<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Read the first bytes of `inner` and return its detected codec with a reader that
+/// still yields the whole stream, including the bytes read to detect the codec.
+pub fn sniff<R: Read>(mut inner: R) -> io::Result<(Option<Codec>, Sniffed<R>)> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    (&mut inner).take(SNIFF_LEN as u64).read_to_end(&mut header)?;
+    let codec = Codec::detect(&header);
+    Ok((codec, io::Cursor::new(header).chain(inner)))
+}
+
+/// Whether `header` starts with a tar header block: one whose checksum field is the sum
+/// of its bytes, counting the field itself as spaces. v7 tar archives, which have no
+/// magic, are detected by this.
+pub(crate) fn is_tar_header(header: &[u8]) -> bool {
+    let Some(block) = header.get(..SNIFF_LEN) else {
+        return false;
+    };
+    let field = &block[TAR_CKSUM_OFFSET..(TAR_CKSUM_OFFSET + TAR_CKSUM_LEN)];
+    let Some(cksum) = std::str::from_utf8(field).ok()
+        .map(|s| s.trim_matches([' ', '\0']))
+        .filter(|s| !s.is_empty())
+        .and_then(|s| u32::from_str_radix(s, 8).ok()) else {
+        return false;
+    };
+    let sum = block.iter().enumerate()
+        .map(|(i, &b)| if (TAR_CKSUM_OFFSET..(TAR_CKSUM_OFFSET + TAR_CKSUM_LEN)).contains(&i) {
+            u32::from(b' ')
+        } else {
+            u32::from(b)
+        })
+        .sum::<u32>();
+    sum == cksum
+}
+
+/// Detect the codec of `inner` and wrap it in the matching decoder, returning a reader
+/// of the uncompressed tar stream, or `None` if the codec isn't recognised.
+///
+/// zstd archives compressed with a dictionary need `dictionary` to be the same one.
+/// Other archives ignore it.
+pub fn decoder<R: Read + Send + 'static>(inner: R, dictionary: Option<&Dictionary>)
+-> Result<Option<(Codec, Box<dyn Read + Send>)>>
+{
+    let (codec, inner) = sniff(inner)?;
+    let Some(codec) = codec else {
+        return Ok(None);
+    };
+
+    let decoded: Box<dyn Read + Send> = match codec {
+        Codec::Zstd => {
+            let header = inner.get_ref().0.get_ref();
+            // Frames compressed without a dictionary have no ID, and decode without one.
+            let dictionary = match zstd::zstd_safe::get_dict_id_from_frame(header) {
+                None => None,
+                Some(id) if dictionary.is_some_and(|d| d.id == id) => dictionary,
+                Some(id) => return Err(WrongDictionary {
+                    archive: id.get(),
+                    given: dictionary.map(Dictionary::id),
+                }.into()),
+            };
+            let mut zstdr = zstd::stream::read::Decoder::with_dictionary(
+                io::BufReader::with_capacity(zstd::zstd_safe::DCtx::in_size(), inner),
+                dictionary.map_or(&[], |dictionary| &dictionary.bytes))?;
+            // Accept archives compressed with any `--long` window, rather than only up
+            // to zstd's default limit of 128 MiB. Only as much memory as an archive's
+            // window is allocated.
+            zstdr.window_log_max(MAX_WINDOW_LOG)?;
+            // Frames' checksums, from `compress --zstd-checksum`, are verified as each
+            // frame ends, failing the read if they don't match.
+            Box::new(zstdr)
+        },
+        // Multi-member gzip files, e.g. from `pigz` or concatenation, decode to their
+        // concatenated contents.
+        Codec::Gzip => Box::new(flate2::read::MultiGzDecoder::new(inner)),
+        Codec::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(inner)),
+        Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(inner)),
+        Codec::Tar => Box::new(inner),
+    };
+
+    Ok(Some((codec, decoded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tar_bytes() -> Vec<u8> {
+        let mut tarb = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_size(3);
+        header.set_cksum();
+        tarb.append_data(&mut header, "a.txt", &b"abc"[..]).unwrap();
+        tarb.into_inner().unwrap()
+    }
+
+    fn round_trip(encoded: Vec<u8>, expected_codec: Codec) {
+        let (codec, mut decoded) = decoder(io::Cursor::new(encoded), None).unwrap().unwrap();
+        assert_eq!(codec, expected_codec);
+        let mut out = Vec::new();
+        decoded.read_to_end(&mut out).unwrap();
+        assert_eq!(out, tar_bytes());
+    }
+
+    #[test]
+    fn detects_and_decodes_each_codec() {
+        round_trip(zstd::encode_all(&*tar_bytes(), 0).unwrap(), Codec::Zstd);
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(&tar_bytes()).unwrap();
+        round_trip(gz.finish().unwrap(), Codec::Gzip);
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 1);
+        xz.write_all(&tar_bytes()).unwrap();
+        round_trip(xz.finish().unwrap(), Codec::Xz);
+
+        round_trip(tar_bytes(), Codec::Tar);
+    }
+
+    #[test]
+    fn encoders_round_trip() {
+        for codec in [Codec::Zstd, Codec::Gzip, Codec::Xz, Codec::Lz4, Codec::Tar] {
+            let mut w = codec.encoder(Vec::new(), None, None).unwrap();
+            w.write_all(&tar_bytes()).unwrap();
+            round_trip(w.finish().unwrap(), codec);
+        }
+    }
+
+    #[test]
+    fn dictionaries() {
+        let train = |name: &str| {
+            let samples = (0..1000)
+                .map(|i| format!("{{\"id\": {i}, \"{name}\": \"{name}-{}\"}}\n", i * 7919))
+                .collect::<Vec<_>>();
+            Dictionary::from_bytes(zstd::dict::from_samples(&samples, 4096).unwrap()).unwrap()
+        };
+        let dictionary = train("name");
+        let other = train("email");
+        assert_ne!(dictionary.id(), other.id());
+
+        let mut w = Codec::Zstd.encoder(Vec::new(), None, Some(&dictionary)).unwrap();
+        w.write_all(&tar_bytes()).unwrap();
+        let encoded = w.finish().unwrap();
+
+        let (_, mut decoded) = decoder(io::Cursor::new(encoded.clone()), Some(&dictionary))
+            .unwrap().unwrap();
+        let mut buf = Vec::new();
+        decoded.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, tar_bytes());
+
+        for wrong in [None, Some(&other)] {
+            let err = decoder(io::Cursor::new(encoded.clone()), wrong).err().unwrap();
+            assert!(err.is::<WrongDictionary>(), "{err}");
+        }
+    }
+
+    #[test]
+    fn unknown_and_short_inputs() {
+        assert_eq!(Codec::detect(b""), None);
+        assert_eq!(Codec::detect(b"{\"run_id\": \"1\"}"), None);
+        assert!(decoder(io::Cursor::new(b"hello".to_vec()), None).unwrap().is_none());
+        assert_eq!(Codec::detect(&[0; SNIFF_LEN]), None);
+    }
+
+    #[test]
+    fn detects_v7_tar() {
+        let mut tarb = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_old();
+        header.set_size(3);
+        header.set_cksum();
+        tarb.append_data(&mut header, "a.txt", &b"abc"[..]).unwrap();
+        let bytes = tarb.into_inner().unwrap();
+        assert_eq!(Codec::detect(&bytes), Some(Codec::Tar));
+
+        let mut corrupt = bytes.clone();
+        corrupt[0] ^= 1;
+        assert_eq!(Codec::detect(&corrupt), None);
+    }
+}
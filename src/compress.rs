@@ -1,207 +1,3266 @@
-use anyhow::ensure;
-use crate::Result;
-use ignore::{DirEntry, WalkBuilder, WalkState};
+use age::secrecy::SecretString;
+use anyhow::{anyhow, bail, ensure, Context};
+use crate::{
+    ProgressReader, ProgressWriter, Result,
+    acls,
+    adaptive_level::{self, AdaptiveLevel},
+    anonymize::{self, PathHasher},
+    archive_name::{NameArgs, NameTemplate},
+    archive_set,
+    cancel::{self, CancelPolicy, CancellationToken},
+    error_report::{self, ErrorReport},
+    case_collision::CaseCollisions,
+    content_type::ContentTypeFilter,
+    entry_encryption::{self, DataKey, EncryptingReader, MasterKey},
+    file_chunk::{self, Chunk},
+    fsck,
+    codec::{self, Codec, Dictionary},
+    device_limit::DeviceLimiter,
+    latency_guard::{LatencyGuard, LatencyReader},
+    hasher::{self, HasherThread},
+    incremental::{self, Snapshot},
+    io_retry::{RetryArgs, RetryReader, RetryWriter},
+    long_path::{LongPaths, PathLimits},
+    manifest, nodump, size, status,
+    manifest_signature::{self, ManifestSigningKey},
+    owners,
+    remote::{self, RemoteArgs, Store, Upload},
+    run_lock::{self, RunLock},
+    progress::{self, ProgressCallback, ProgressEvent},
+    rate_limit::{LimitedReader, LimitedWriter, RateArgs, RateLimiter},
+    shard_encryption::{self, EncryptingWriter, Encryption},
+    status_socket::StatusServer,
+    special_files,
+    tar_format::{self, TarFormat},
+    transform::Transform,
+    xattrs,
+};
+use ignore::{
+    DirEntry, ParallelVisitor, ParallelVisitorBuilder, WalkBuilder, WalkState,
+    overrides::OverrideBuilder,
+};
+use regex::Regex;
 use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
     fs::{self, File},
-    io::{BufWriter, Write},
-    path::PathBuf,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::OsStringExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Component, Path, PathBuf},
     result::Result as StdResult,
     sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
     },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 use valuable::Valuable;
 
 #[derive(clap::Args, Clone, Debug, Valuable)]
 pub struct Args {
+    /// File or directory to archive. May be repeated, e.g. `--in-path /etc
+    /// --in-path /var/lib/foo`, to archive each path relative to the deepest directory
+    /// holding them all, e.g. as `etc/...` and `var/lib/foo/...`.
+    #[arg(long, required = true)]
+    in_path: Vec<PathBuf>,
+    /// Directory for the archives, manifest and other metadata. Optional with `--out -`,
+    /// where it only holds the manifest. If it's inside `--in-path`, it's left out of
+    /// the archive.
+    #[arg(long, required_unless_present = "out")]
+    out_dir: Option<PathBuf>,
+
+    /// Write a single archive to stdout instead, with `--out -`, for pipelines such as
+    /// `ptar compress ... --out - | ssh host 'cat > backup.tar.zstd'`.
+    ///
+    /// One writer archives every file, while the walk and compression still use
+    /// `--walk-threads` and `--zstd-workers`. The manifest, if `--out-dir` is given,
+    /// lists the archive as `00000000.<EXT>`. Extract with `decompress`, or
+    /// `tar --zstd -x`.
+    #[arg(long, value_name = "-", value_parser = parse_out,
+          conflicts_with_all = ["out_url", "max_shard_size", "bucket_by", "level_policy",
+                                "pre_scan", "compress_threads"])]
+    out: Option<String>,
+
+    #[command(flatten)]
+    names: NameArgs,
+
+    /// Upload the archives to this URL, e.g. `s3://bucket/prefix/` or
+    /// `sftp://user@host/path`, as they're written, instead of writing them to
+    /// `--out-dir`. The manifest and other metadata are still written to `--out-dir`, and
+    /// uploaded once the archives are.
+    #[arg(long, value_name = "URL", conflicts_with = "sign_key")]
+    out_url: Option<String>,
+
+    #[command(flatten)]
+    remote: RemoteArgs,
+
+    /// Start a new archive once the current one reaches this size, e.g. `5GiB`.
+    ///
+    /// A single file larger than this still goes into one archive. In compressed mode
+    /// the size is checked after each file, so archives may exceed it slightly.
+    ///
+    /// With `--pre-scan` or `--bucket-by`, archives are made about equal in size rather
+    /// than leaving a small last archive in each shard or bucket.
+    #[arg(long, value_parser = size::parse)]
+    max_shard_size: Option<u64>,
+
+    /// Whether `--max-shard-size` limits the compressed or uncompressed archive size.
+    #[arg(long, value_enum, default_value_t = ShardSizeMode::Compressed)]
+    shard_size_mode: ShardSizeMode,
+
+    /// Compression format of the archives. decompress detects it automatically.
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    codec: Codec,
+
+    /// Fail if the zstd library doesn't support multithreading, rather than warning and
+    /// compressing on each shard's own thread, which is slower as I/O then waits on
+    /// compression.
+    #[arg(long)]
+    require_zstd_mt: bool,
+
+    /// zstd worker threads compressing each shard. More than 1 speeds up compression
+    /// when there are fewer shards than cores, e.g. for a single huge file, or with
+    /// `--out`. Requires a zstd library with multithreading.
+    #[arg(long, value_name = "N", default_value_t = 1,
+          value_parser = clap::value_parser!(u32).range(1..=200))]
+    zstd_workers: u32,
+
+    /// End each zstd frame with a checksum of its contents, which decompress verifies,
+    /// so corrupted archives are detected even without `--checksums`. The default.
+    #[arg(long, overrides_with = "no_zstd_checksum")]
+    zstd_checksum: bool,
+
+    /// Leave out zstd frame checksums, saving 4 bytes per frame and a little time.
+    #[arg(long, overrides_with = "zstd_checksum")]
+    no_zstd_checksum: bool,
+
+    /// Tar header format. Use `ustar` for old or minimal tar implementations, e.g.
+    /// BusyBox, `v7` for the oldest, or `gnu` for GNU tar's own format.
+    #[arg(long, visible_alias = "format", value_enum, default_value_t = TarFormat::Pax)]
+    tar_format: TarFormat,
+
+    /// Rewrite each file's archived path, relative to `--in-path`, with this sed
+    /// substitution, as GNU tar's `--transform` does, e.g. `s,^,project/,` to archive
+    /// files under `project/`. May be repeated, to apply each in turn.
+    ///
+    /// Expressions are `s/REGEX/REPLACEMENT/FLAGS`, with any delimiter in place of `/`.
+    /// REGEX uses the `regex` crate's syntax, like `sed -E`, so groups are `(...)`, and
+    /// REPLACEMENT may refer to the whole match with `&` and to groups with `\1`. FLAGS
+    /// are `g` to replace every match and `i` to ignore case.
+    ///
+    /// Path length limits, the manifest and `--since-manifest` see the rewritten paths,
+    /// so use the same transforms for each increment.
+    #[arg(long, visible_alias = "xform", value_name = "EXPR")]
+    transform: Vec<String>,
+
+    /// Treat paths longer than this many bytes, relative to `--in-path`, as too long,
+    /// e.g. for a destination filesystem with a path limit.
+    #[arg(long, value_name = "BYTES")]
+    max_path_len: Option<usize>,
+
+    /// What to do with files whose paths are too long for `--tar-format ustar` or `v7`,
+    /// or `--max-path-len`. They're found while walking, before they're archived, and
+    /// counted with examples in a warning at the end.
+    #[arg(long, value_enum, default_value_t = LongPathPolicy::Abort)]
+    long_path_policy: LongPathPolicy,
+
+    /// Record a checksum of each file in the manifest, and in a checksums file per
+    /// archive named `<archive>.<algorithm>`.
+    ///
+    /// Hashing runs on a separate thread per shard, fed by the same buffers that are
+    /// compressed.
+    #[arg(long, requires = "out_dir")]
+    checksums: bool,
+
+    /// Hash algorithm for `--checksums`.
+    #[arg(long, value_enum, default_value_t = hasher::Algorithm::Blake3)]
+    hash: hasher::Algorithm,
+
+    /// Only archive files matching this gitignore-style glob. May be repeated.
+    ///
+    /// Directories are still walked, so `--include '*.rs'` finds `.rs` files at any depth.
     #[arg(long)]
-    in_path: PathBuf,
+    include: Vec<String>,
+
+    /// Skip files and directories matching this gitignore-style glob, e.g. `target/` or
+    /// `*.o`. May be repeated, and takes precedence over `--include`.
     #[arg(long)]
-    out_dir: PathBuf,
+    exclude: Vec<String>,
+
+    /// Skip version control metadata: `.git`, `.hg`, `.svn`, `.bzr` and `CVS`
+    /// directories, and `.git` files of worktrees and submodules.
+    #[arg(long)]
+    exclude_vcs: bool,
+
+    /// Skip files whose type, detected from their first bytes, matches one of these
+    /// comma separated MIME type globs, e.g. `video/*,image/*`. May be repeated.
+    ///
+    /// Files of unrecognised types, including most text, are never skipped.
+    #[arg(long, value_delimiter = ',', value_name = "MIME_TYPES")]
+    exclude_content_type: Vec<String>,
+
+    /// Only archive files modified at or after this time: an RFC 3339 time, e.g.
+    /// `2024-05-01T00:00:00Z`, or a duration before the run with an `s`, `m`, `h`, `d`
+    /// or `w` suffix, e.g. `7d`.
+    #[arg(long, value_parser = parse_mtime_cutoff, value_name = "TIME|DURATION")]
+    newer_mtime: Option<MtimeCutoff>,
+
+    /// Only archive regular files of at least this size, e.g. `1KiB`.
+    #[arg(long, value_parser = size::parse, value_name = "SIZE")]
+    min_size: Option<u64>,
+
+    /// Only archive regular files of at most this size, e.g. `100GB`.
+    #[arg(long, value_parser = size::parse, value_name = "SIZE")]
+    max_size: Option<u64>,
+
+    /// Skip files ignored by `.gitignore`, `.ignore`, `.git/info/exclude` and the global
+    /// git excludes file, including those in parent directories of `--in-path`.
+    #[arg(long)]
+    respect_gitignore: bool,
+
+    /// Skip hidden files and directories, i.e. those whose names start with `.`.
+    #[arg(long, visible_alias = "hidden")]
+    skip_hidden: bool,
+
+    /// Skip directories holding a valid `CACHEDIR.TAG` file, which marks them as caches
+    /// that can be regenerated, e.g. build outputs.
+    #[arg(long)]
+    exclude_caches: bool,
+
+    /// Skip directories holding a file with this name, e.g. `.nobackup`. May be
+    /// repeated.
+    #[arg(long, value_name = "FILE_NAME")]
+    exclude_if_present: Vec<String>,
+
+    /// Skip files and directories with the `nodump` attribute, set with `chattr +d`, as
+    /// `dump` and other backup tools do.
+    #[arg(long)]
+    honor_nodump: bool,
+
+    /// Don't descend into directories on other filesystems than `--in-path`'s, e.g.
+    /// `/proc`, network mounts or bind mounts when archiving `/`.
+    #[arg(long)]
+    one_file_system: bool,
+
+    /// Only archive files at most this many levels below each `--in-path`: 1 archives
+    /// only the files directly in it, 2 also those in its subdirectories, and so on.
+    #[arg(long, value_name = "DEPTH",
+          value_parser = clap::value_parser!(u32).range(1..))]
+    max_depth: Option<u32>,
+
+    /// Archive the files listed in this file, or `-` for stdin, one per line, rather than
+    /// walking `--in-path`, e.g. a list from `find` or a database query.
+    ///
+    /// `--in-path` must then be one directory: relative paths in the list are relative
+    /// to it, and every file is archived relative to it. Listed directories aren't
+    /// descended into.
+    #[arg(long, value_name = "FILE",
+          conflicts_with_all = ["include", "exclude", "respect_gitignore", "skip_hidden",
+                                "exclude_vcs", "exclude_caches", "exclude_if_present",
+                                "honor_nodump", "one_file_system", "max_depth"])]
+    files_from: Option<PathBuf>,
+
+    /// Paths in `--files-from` are separated by NUL bytes rather than newlines, as
+    /// `find -print0` writes them.
+    #[arg(short = '0', long = "null", requires = "files_from")]
+    null: bool,
+
+    /// Format of the manifest of archived files written to `--out-dir`.
+    #[arg(long, value_enum, default_value_t = manifest::Format::Jsonl)]
+    manifest_format: manifest::Format,
+
+    /// What to do when a file can't be read, e.g. permission denied or deleted since it
+    /// was walked.
+    ///
+    /// With `keep-going` such files are logged and skipped, and compress still exits with
+    /// an error once it has archived everything else.
+    #[arg(long, value_enum, default_value_t = ErrorPolicy::FailFast)]
+    error_policy: ErrorPolicy,
+
+    /// Write each error with a file, e.g. one skipped by `--error-policy keep-going`, to
+    /// this file once the run ends, with its path, the operation that failed, its OS
+    /// error number and its message. Written as JSON, or as CSV if the name ends in
+    /// `.csv`.
+    #[arg(long, value_name = "PATH")]
+    error_report: Option<PathBuf>,
+
+    /// Record this octal permission mode for every file instead of its own, e.g. `0644`.
+    ///
+    /// Given as `<MODE>/<EXEC_MODE>`, e.g. `0644/0755`, files with any execute bit set get
+    /// `EXEC_MODE`.
+    #[arg(long, value_parser = parse_mode_override)]
+    mode_override: Option<ModeOverride>,
+
+    /// Clear the setuid, setgid and sticky bits from recorded file modes.
+    #[arg(long)]
+    clear_setuid: bool,
+
+    /// Record each file's extended attributes, e.g. `user.*`, `security.selinux` and
+    /// file capabilities, in pax records. Requires `--tar-format pax`.
+    #[arg(long, overrides_with = "no_xattrs", conflicts_with = "anonymize")]
+    xattrs: bool,
+
+    /// Don't record extended attributes; the default, and overrides an earlier
+    /// `--xattrs`.
+    #[arg(long, overrides_with = "xattrs")]
+    no_xattrs: bool,
+
+    /// Record each file's POSIX access ACL, in a `SCHILY.acl.access` pax record as GNU
+    /// tar does. Requires `--tar-format pax`.
+    #[arg(long, overrides_with = "no_acls", conflicts_with = "anonymize")]
+    acls: bool,
+
+    /// Don't record ACLs; the default, and overrides an earlier `--acls`.
+    #[arg(long, overrides_with = "acls")]
+    no_acls: bool,
+
+    /// Also archive character and block devices and FIFOs, which aren't listed in the
+    /// manifest. Otherwise they're skipped, and counted in a warning. Sockets are always
+    /// skipped, as tar can't store them.
+    #[arg(long)]
+    special_files: bool,
+
+    /// Show a progress bar on stderr, or log progress if stderr isn't a terminal.
+    ///
+    /// Totals grow as the input is walked, so the percentage and ETA are only reliable
+    /// once walking has finished.
+    #[arg(long)]
+    progress: bool,
+
+    /// Record every file as owned by uid and gid 0, with modification time 0, in both
+    /// the archives and the manifest.
+    #[arg(long)]
+    anonymize: bool,
+
+    /// With `--anonymize`, also replace each path component with a hash, and write the
+    /// original paths to this JSON lines file. Keep it outside `--out-dir` so it isn't
+    /// shared with the archives.
+    #[arg(long, requires = "anonymize", value_name = "MAPPING_FILE")]
+    anonymize_paths: Option<PathBuf>,
+
+    /// Serve progress and recent events as JSON to each client connecting to the
+    /// abstract Unix socket with this name, e.g. `socat - ABSTRACT-CONNECT:<NAME>`.
+    #[arg(long, value_name = "NAME")]
+    status_socket: Option<String>,
+
+    /// Tune for spinning disks: walk the input on one thread in file name order, and
+    /// read at most READS files at a time from each device (`st_dev`).
+    ///
+    /// Compression still runs on `--compress-threads` shards.
+    #[arg(long, value_name = "READS", num_args = 0..=1, default_missing_value = "1")]
+    hdd_mode: Option<usize>,
+
+    /// Read fewer files at once while the 99th percentile latency of reads from the
+    /// source is over MS milliseconds, so the backup doesn't slow down other workloads on
+    /// the same filesystem. Ramps back up to a file per shard once latency recovers.
+    #[arg(long, value_name = "MS")]
+    latency_guard: Option<u64>,
+
+    #[command(flatten)]
+    io_retry: RetryArgs,
+
+    #[command(flatten)]
+    rate: RateArgs,
+
+    /// Threads walking the input directory and dispatching files to shards. Defaults
+    /// to `--threads`.
+    #[arg(long, value_name = "N")]
+    walk_threads: Option<usize>,
+
+    /// Number of shards, which compress in parallel, each on its own thread reading the
+    /// files it archives and writing its own archives. Defaults to `--threads`.
+    ///
+    /// The walker threads feed the shards through queues, so e.g. `--shards 4` with
+    /// `--walk-threads 32` suits a slow network filesystem with a few local cores.
+    #[arg(long, visible_alias = "shards", value_name = "N")]
+    compress_threads: Option<usize>,
+
+    /// Compression level, e.g. 19 for smaller zstd archives, or 1 for faster ones.
+    /// Defaults to zstd's level 3, or 6 for gzip and xz.
+    ///
+    /// `auto` adjusts each shard's zstd level between 1 and 19 while compressing, as
+    /// `zstd --adapt` does: down while compressing is the bottleneck, and up while
+    /// reading files or writing archives is.
+    #[arg(long, value_parser = parse_level, value_name = "LEVEL",
+          conflicts_with = "level_policy")]
+    level: Option<Level>,
+
+    /// Compress files smaller than a threshold at one zstd level and larger files at
+    /// another, e.g. `small=12,large=3,threshold=64MiB`.
+    ///
+    /// Small files compress well at a high level for little time, while a fast level for
+    /// large files keeps their shards from lagging. Half the shards take each class, so
+    /// each archive has a single level.
+    #[arg(long, value_parser = parse_level_policy, value_name = "POLICY")]
+    level_policy: Option<LevelPolicy>,
+
+    /// Enable zstd's long-distance matching, finding repeats up to `2^WINDOW_LOG` bytes
+    /// apart, e.g. copies of the same files in different directories. Defaults to 27, a
+    /// 128 MiB window, as `zstd --long` does, up to 31.
+    ///
+    /// Compressing and decompressing each archive use up to the window's size in memory.
+    /// Decompressing needs no flag.
+    #[arg(long, value_name = "WINDOW_LOG", num_args = 0..=1, default_missing_value = "27",
+          value_parser = clap::value_parser!(u32).range(10..=codec::MAX_WINDOW_LOG as i64))]
+    long: Option<u32>,
+
+    /// Compress with this zstd dictionary, e.g. from `ptar train-dict`, which helps most
+    /// with many small, similar files and small `--seekable` frames. decompress then
+    /// needs the same dictionary, with `--dict`.
+    #[arg(long, value_name = "DICT_FILE")]
+    dict: Option<PathBuf>,
+
+    /// Encrypt each file's contents with its own random key, and record that key in the
+    /// manifest wrapped by the 256-bit master key in this file, written as 64 hex digits.
+    ///
+    /// Deleting a manifest entry's `wrapped_key` crypto-shreds that file. Keep the key
+    /// file safe: without it nothing can be extracted.
+    #[arg(long, value_name = "KEY_FILE")]
+    entry_key_file: Option<PathBuf>,
+
+    /// Encrypt each archive to this recipient, as `age:<RECIPIENT>` with an age public
+    /// key. May be repeated; any one of the recipients' identities can decrypt.
+    ///
+    /// Archives are compressed, then encrypted, and named with a `.age` suffix. The
+    /// manifest isn't encrypted, so paths, sizes and checksums stay readable.
+    #[arg(long, value_name = "SPEC")]
+    encrypt: Vec<String>,
+
+    /// Encrypt each archive with a passphrase, for when there are no keys to manage.
+    /// It's read from the `PTAR_PASSPHRASE` environment variable if set, or else
+    /// prompted for on the terminal.
+    ///
+    /// age derives each archive's key from the passphrase with scrypt, which takes
+    /// about a second per archive, both to compress and to decompress.
+    #[arg(long, visible_alias = "passphrase-prompt", conflicts_with = "encrypt")]
+    passphrase: bool,
+
+    /// Sign the archive set with the ed25519 secret key in this file, written as 64 hex
+    /// digits, e.g. by `head -c 32 /dev/urandom | xxd -p -c 32`.
+    ///
+    /// Writes `manifest.sig`, listing the hashes of the manifest and each archive, and a
+    /// signature of them. The public key is logged; check the set with
+    /// `verify --pubkey`.
+    #[arg(long, value_name = "KEY_FILE", requires = "out_dir")]
+    sign_key: Option<PathBuf>,
+
+    /// On SIGINT or SIGTERM, delete the archives still being written instead of
+    /// finishing them after the current file. Either way the exit code is 130.
+    #[arg(long)]
+    clean_partial: bool,
+
+    /// Fail before writing anything if `--out-dir` already holds files.
+    #[arg(long, requires = "out_dir", conflicts_with = "force")]
+    require_empty: bool,
+
+    /// Delete the files an earlier run left in `--out-dir` before writing anything: its
+    /// archives, complete or partial, their checksums files, manifest, signature,
+    /// deletions file and temporary files. Other files are left alone.
+    #[arg(long, requires = "out_dir")]
+    force: bool,
+
+    /// Only archive files that are new or changed since the run that wrote this manifest,
+    /// given as its `--out-dir` or manifest file. Files are changed if their size or
+    /// mtime differ.
+    ///
+    /// May be repeated to compare against a full backup and each increment since, oldest
+    /// first. The new manifest lists only the files archived in this run, and
+    /// `deletions.jsonl` lists the files deleted or now excluded since.
+    /// `ptar restore` extracts a full backup and its increments in order.
+    #[arg(long, value_name = "PATH", requires = "out_dir")]
+    since_manifest: Vec<PathBuf>,
+
+    /// With `--since-manifest`, also hash files whose size and mtime are unchanged, and
+    /// archive them if the checksum differs from the manifest's.
+    #[arg(long, requires = "since_manifest")]
+    compare_checksums: bool,
+
+    /// Group files into archives by their modification time, so an archive set synced
+    /// elsewhere, e.g. with rsync, only changes in the buckets with changed files.
+    ///
+    /// Archives are named `<BUCKET>.<NNNNNNNN>.<EXT>`, e.g. `2023-04.00000000.tar.zstd`,
+    /// or by `{bucket}` in `--name-template`, and each bucket's files are archived in
+    /// path order, so unchanged buckets give identical archives. The whole input is
+    /// walked before archiving starts.
+    #[arg(long, value_enum, value_name = "BUCKETS")]
+    bucket_by: Option<BucketBy>,
+
+    /// Walk the whole input before archiving starts, so its total size is known. Files
+    /// are then spread evenly over the shards, and with `--max-shard-size` each shard's
+    /// archives are about equal in size, e.g. 3 archives of 7GiB rather than 10GiB,
+    /// 10GiB and 1GiB, which suits object stores with a cost per object.
+    ///
+    /// Holds the metadata of every file in memory until the walk finishes.
+    #[arg(long)]
+    pre_scan: bool,
+
+    /// Start a new zstd frame at the first file after each FRAME_SIZE of tar stream, and
+    /// record in the manifest where each file's frame starts, so `cat` and
+    /// `extract-one` can seek to it instead of decompressing the archive from the
+    /// start. Smaller frames compress a little worse.
+    #[arg(long, value_name = "FRAME_SIZE", value_parser = size::parse, num_args = 0..=1,
+          default_missing_value = "4MiB", conflicts_with_all = ["encrypt", "passphrase"])]
+    seekable: Option<u64>,
+
+    /// Split files larger than CHUNK_SIZE into chunks of that size, each archived as its
+    /// own tar entry and sent to a shard like a file, so one huge file is compressed by
+    /// several shards in parallel. `decompress` and `extract-one` reassemble the file;
+    /// other tar tools extract each chunk over the one before. Progress counts each
+    /// chunk as a file.
+    #[arg(long, value_name = "CHUNK_SIZE", value_parser = size::parse,
+          conflicts_with_all = ["checksums", "entry_key_file"])]
+    chunk_size: Option<u64>,
+
+    /// Delete each archived regular file once the run has finished without errors, so
+    /// its archives and manifest are synced to disk, or uploaded, e.g. to drain a spool
+    /// directory. Files changed since they were archived are kept, as are directories
+    /// and special files.
+    #[arg(long, conflicts_with_all = ["out", "chunk_size"])]
+    remove_source_files: bool,
+
+    /// With `--remove-source-files`, first read each archive back and check it holds
+    /// every file at its archived size, keeping the files if not.
+    #[arg(long, requires = "remove_source_files",
+          conflicts_with_all = ["out_url", "encrypt", "passphrase"])]
+    verify_before_remove: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum BucketBy {
+    /// UTC calendar month of the modification time, e.g. `2023-04`.
+    MtimeMonth,
+    /// UTC calendar year of the modification time, e.g. `2023`.
+    MtimeYear,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum Level {
+    Fixed(i32),
+    /// Adjusted while compressing, with `--level auto`.
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub struct LevelPolicy {
+    small: i32,
+    large: i32,
+    /// Files of at least this many bytes are large.
+    threshold: u64,
+}
+
+/// A `--newer-mtime` time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum MtimeCutoff {
+    /// Seconds since the Unix epoch.
+    At(i64),
+    /// Seconds before the run starts.
+    Ago(u64),
+}
+
+/// Filters on each file's metadata: `--newer-mtime`, `--min-size` and `--max-size`.
+#[derive(Clone, Copy, Debug, Default)]
+struct MetadataFilter {
+    min_mtime: Option<i64>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub struct ModeOverride {
+    mode: u32,
+    exec_mode: u32,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum ErrorPolicy {
+    /// Stop at the first error.
+    FailFast,
+    /// Log and skip what fails, e.g. files that can't be read, and continue.
+    KeepGoing,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum LongPathPolicy {
+    /// Stop walking at the first long path, and exit with an error once the files
+    /// already walked are archived.
+    Abort,
+    /// Skip files with long paths and continue.
+    Skip,
+    /// Archive files under a shortened path, their truncated name followed by a hash
+    /// of the original path, and record the original path in the manifest.
+    Shorten,
 }
 
-struct PVB {
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum ShardSizeMode {
+    Compressed,
+    Uncompressed,
+}
+
+/// Options for compressing with ptar as a library. Defaults match the command line's.
+///
+/// ```no_run
+/// let report = ptar::CompressOptions::new("/data", "/backup")
+///     .threads(8)
+///     .checksums(true)
+///     .run()?;
+/// println!("Wrote {} archives", report.archives.len());
+/// # Ok::<(), ptar::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct CompressOptions {
+    args: Args,
+    callback: Option<progress::Callback>,
+    cancel: Option<CancellationToken>,
+    cancel_policy: CancelPolicy,
+    /// Set with `--passphrase`. Kept out of `args` so it's never logged.
+    passphrase: Option<SecretString>,
+    threads: usize,
+}
+
+/// Summary of a successful compress run.
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// File names of the archives written, relative to the output directory, sorted.
+    pub archives: Vec<String>,
+    pub file_count: u64,
+    /// Total size of the files archived.
+    pub uncompressed_bytes: u64,
+    /// Total size of the archives written.
+    pub compressed_bytes: u64,
+    /// Files skipped because they're unchanged since `--since-manifest`.
+    pub unchanged_count: u64,
+    /// Files whose paths were too long, skipped or shortened by the long path policy.
+    pub long_path_count: u64,
+    /// Files whose paths differ only in case from a file walked before them, and would
+    /// overwrite it on a case insensitive filesystem.
+    pub case_collision_count: u64,
+    /// Devices, FIFOs and sockets skipped, as they're only archived with
+    /// `--special-files`, and sockets never are.
+    pub skipped_special_count: u64,
+    pub duration: Duration,
+}
+
+/// Builds a `Visitor` for each `ignore` walker thread.
+struct VisitorBuilder {
+    cancel: Option<CancellationToken>,
+    /// Some with `--exclude-content-type`.
+    content_type_filter: Option<Arc<ContentTypeFilter>>,
+    dispatcher: Arc<Dispatcher>,
     error_count: Arc<AtomicUsize>,
-    #[allow(dead_code)] // Not used yet.
-    in_path: PathBuf,
+    error_report: Arc<ErrorReport>,
+    error_policy: ErrorPolicy,
+    /// None with `--anonymize-paths`, as hashed names don't collide.
+    case_collisions: Option<Arc<CaseCollisions>>,
     in_prefix: PathBuf,
-    next_archive_num: u64,
-    out_dir: PathBuf,
+    long_path_policy: LongPathPolicy,
+    long_paths: Arc<LongPaths>,
+    path_limits: PathLimits,
+    /// Some with `--since-manifest`.
+    snapshot: Option<Arc<Snapshot>>,
+    skipped_special_count: Arc<AtomicU64>,
+    special_files: bool,
+    metadata_filter: MetadataFilter,
+    transform: Arc<Transform>,
+    unchanged_count: Arc<AtomicU64>,
 }
 
-struct PV {
-    archive_num: u64,
+/// Walks files on one `ignore` thread and hands them to the `Dispatcher`.
+struct Visitor {
+    cancel: Option<CancellationToken>,
+    content_type_filter: Option<Arc<ContentTypeFilter>>,
+    dispatcher: Arc<Dispatcher>,
     error_count: Arc<AtomicUsize>,
+    error_report: Arc<ErrorReport>,
+    error_policy: ErrorPolicy,
+    /// None with `--anonymize-paths`, as hashed names don't collide.
+    case_collisions: Option<Arc<CaseCollisions>>,
     in_prefix: PathBuf,
-    out_path: PathBuf,
+    long_path_policy: LongPathPolicy,
+    long_paths: Arc<LongPaths>,
+    path_limits: PathLimits,
+    /// Some with `--since-manifest`.
+    snapshot: Option<Arc<Snapshot>>,
+    skipped_special_count: Arc<AtomicU64>,
+    special_files: bool,
+    metadata_filter: MetadataFilter,
+    transform: Arc<Transform>,
+    unchanged_count: Arc<AtomicU64>,
+}
 
-    /// tarb is None when PV is constructed,
-    /// then on first use it's initialised to Some(value),
-    /// then during drop() its value is taken and tarb is None again.
-    ///
-    /// The lazy initialisation is so that the first thread / ParallelVisitor that `ignore`
-    /// starts, which visits no files, doesn't create an unnecessary empty archive.
-    tarb: Option<tar::Builder<zstd::stream::write::Encoder<'static, BufWriter<File>>>>,
+/// Assigns each file to the shard with the fewest bytes assigned so far,
+/// so shard sizes stay roughly equal regardless of walk order.
+///
+/// With `--level-policy`, files only go to shards of their size class. With
+/// `--chunk-size`, each chunk of a large file is assigned on its own.
+struct Dispatcher {
+    /// With `--chunk-size`.
+    chunk_size: Option<u64>,
+    /// Some with `--bucket-by` or `--pre-scan`, holding the files walked until they're
+    /// all dispatched at once.
+    collected: Option<Mutex<Vec<FileJob>>>,
+    /// Some with `--level-policy`.
+    large_threshold: Option<u64>,
+    progress: Arc<progress::Counters>,
+    shards: Vec<ShardQueue>,
+}
+
+struct ShardQueue {
+    /// Sum of the uncompressed sizes of the files sent to this shard.
+    assigned_bytes: AtomicU64,
+    /// Whether this shard takes large files under `--level-policy`.
+    large: bool,
+    tx: crossbeam_channel::Sender<FileJob>,
+}
+
+/// A file, or with `--chunk-size` a chunk of one, to append to a shard.
+#[derive(Clone)]
+struct FileJob {
+    /// With `--bucket-by`, the bucket of the archive to append it to.
+    bucket: Option<String>,
+    /// The prefix of `rel_path` differing only in case from an earlier file's path.
+    case_collision: Option<PathBuf>,
+    /// With `--chunk-size`, the part of the file to append.
+    chunk: Option<Chunk>,
+    meta: fs::Metadata,
+    /// With `--long-path-policy shorten`, the path relative to the input before it was
+    /// shortened to `rel_path`.
+    original_path: Option<PathBuf>,
+    path: PathBuf,
+    rel_path: PathBuf,
+    /// With `--bucket-by` or `--pre-scan`, the estimated tar size of this file and the
+    /// ones after it that go in the same shard and bucket.
+    remaining_bytes: Option<u64>,
+}
+
+/// Owns one output shard, appending the files it receives on its own thread.
+///
+/// With `--max-shard-size` a shard is split into several archives, each taking the next
+/// free archive number.
+struct ShardWriter {
+    anonymize: bool,
+    /// Number of the current archive.
+    archive_num: u64,
+    /// With `--bucket-by`, the current archive's bucket and number within the bucket,
+    /// which name it instead of `archive_num`.
+    bucket: Option<(String, u64)>,
+    /// Names archives, from `--name-template` and `--prefix`.
+    name_template: Arc<NameTemplate>,
+    /// File names of the archives finished so far.
+    archives: Vec<String>,
+    /// Total compressed bytes of the archives finished so far.
+    compressed_bytes: u64,
+    cancel: Option<CancellationToken>,
+    cancel_policy: CancelPolicy,
+    /// Some with `--hdd-mode`.
+    device_limiter: Option<Arc<DeviceLimiter>>,
+    /// Some with `--latency-guard`.
+    latency_guard: Option<Arc<LatencyGuard>>,
+    /// Retries of reads from the source and writes to archives, with `--io-retries`.
+    io_retry: RetryArgs,
+    /// Some with `--limit-read-rate`, shared by all shards.
+    read_limiter: Option<Arc<RateLimiter>>,
+    /// Some with `--limit-write-rate`, shared by all shards.
+    write_limiter: Option<Arc<RateLimiter>>,
+    error_count: Arc<AtomicUsize>,
+    /// The errors counted in `error_count`, for `--error-report`.
+    error_report: Arc<ErrorReport>,
+    clear_setuid: bool,
+    xattrs: bool,
+    acls: bool,
+    codec: Codec,
+    error_policy: ErrorPolicy,
+    /// Some with `--checksums`, except during shutdown.
+    hasher: Option<HasherThread>,
+    /// Compression level, or None for the codec's default. With `--level auto`, the
+    /// level the last archive ended at, which the next starts at.
+    level: Option<i32>,
+    /// With `--level auto`.
+    adaptive_level: bool,
+    /// Some with `--long`.
+    long_window_log: Option<u32>,
+    /// Some with `--dict`.
+    dictionary: Option<Dictionary>,
+    zstd_workers: u32,
+    zstd_checksum: bool,
+    /// Some with `--entry-key-file`.
+    master_key: Option<Arc<MasterKey>>,
+    /// Some unless `--out -` is used without `--out-dir`.
+    manifest_tx: Option<crossbeam_channel::Sender<manifest::Entry>>,
+    max_shard_size: Option<u64>,
+    mode_override: Option<ModeOverride>,
+    next_archive_num: Arc<AtomicU64>,
+    /// Unused with `--out -`, when it may be empty.
+    out_dir: PathBuf,
+    /// Some with `--seekable`.
+    frame_size: Option<u64>,
+    /// Some with `--anonymize-paths`.
+    path_hasher: Option<Arc<PathHasher>>,
+    /// Manifest entries of the current archive, held back until it's synced to disk.
+    /// Unused with `--checksums`, where the `HasherThread` holds them.
+    pending_entries: Vec<manifest::Entry>,
+    progress: Arc<progress::Counters>,
+    /// Some with `--encrypt` or `--passphrase`.
+    encryption: Option<Arc<Encryption>>,
+    /// Some with `--out-url`.
+    store: Option<Arc<dyn Store>>,
+    /// With `--out -`.
+    stdout: bool,
+
+    /// shard is None until the first file is received, so that shards that
+    /// receive no files don't create an unnecessary empty archive.
+    /// It's also None after rolling over until the next file is received.
+    shard: Option<OpenShard>,
+    shard_size_mode: ShardSizeMode,
+    tar_format: TarFormat,
+    owner_names: owners::Names,
+    /// With `--remove-source-files`, the files appended to the current archive.
+    archived_sources: Vec<ArchivedSource>,
+    /// With `--remove-source-files`, the files in the archives finished, deleted once
+    /// the manifest listing them is durable too.
+    finished_sources: Vec<ArchivedSource>,
+    remove_source_files: bool,
+    verify_before_remove: bool,
+}
+
+/// A file appended to an archive with `--remove-source-files`.
+struct ArchivedSource {
+    path: PathBuf,
+    /// Its path and size in the archive.
+    tar_path: PathBuf,
+    tar_size: u64,
+    /// Its metadata when it was archived, to tell if it's changed since.
+    meta: fs::Metadata,
+}
+
+/// An archive being written, encrypted with `--encrypt` or `--passphrase`.
+type ArchiveFile = EncryptingWriter<LimitedWriter<ArchiveOutput>>;
+
+/// Where an archive's bytes go.
+enum ArchiveOutput {
+    File(BufWriter<RetryWriter<File>>),
+    /// With `--out-url`.
+    Upload(Box<dyn Upload>),
+    /// With `--out -`.
+    Stdout(BufWriter<io::Stdout>),
+}
+
+/// The archive a `ShardWriter` is currently writing.
+struct OpenShard {
+    tarb: tar::Builder<ProgressWriter<codec::Encoder<ProgressWriter<ArchiveFile>>>>,
+    compressed_bytes: Arc<AtomicU64>,
+    uncompressed_bytes: Arc<AtomicU64>,
+    /// With `--seekable`, where the current zstd frame starts in the archive file and in
+    /// the tar stream.
+    frame_start: (u64, u64),
 }
 
-const ZSTD_DEFAULT_COMPRESSION_LEVEL: i32 = 0;
+/// Capacity of each shard's queue of pending files.
+const SHARD_QUEUE_LEN: usize = 64;
+
+/// Names of version control metadata skipped with `--exclude-vcs`.
+const VCS_NAMES: &[&str] = &[".git", ".hg", ".svn", ".bzr", "CVS"];
+
+/// The start of a valid `CACHEDIR.TAG` file, from the Cache Directory Tagging
+/// Specification.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
 
 pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
-    let in_meta = cmd_args.in_path.metadata()?;
-    let (in_prefix, in_path) = if in_meta.is_dir() {
-        (cmd_args.in_path.clone(), cmd_args.in_path.clone())
+    let cancel_policy = if cmd_args.clean_partial {
+        CancelPolicy::Discard
     } else {
-        match cmd_args.in_path.parent() {
-            Some(parent) => (parent.to_path_buf(), cmd_args.in_path.clone()),
-            None => (PathBuf::from("./"), PathBuf::from("./").join(&*cmd_args.in_path)),
-        }
+        CancelPolicy::Finish
     };
+    let cancel = CancellationToken::new();
+    cancel.cancel_on_signals()?;
+    let passphrase = cmd_args.passphrase.then(|| shard_encryption::read_passphrase(true))
+                                        .transpose()?;
+    let mut options = CompressOptions::from_args(cmd_args, args.threads())
+        .cancellation(cancel, cancel_policy);
+    if let Some(passphrase) = passphrase {
+        options = options.passphrase(passphrase);
+    }
+    options.run().map(|_report| ())
+}
 
-    fs::create_dir_all(&*cmd_args.out_dir)?;
+fn compress(options: CompressOptions) -> Result<Report> {
+    let CompressOptions {
+        args: cmd_args, callback, cancel, cancel_policy, passphrase, threads,
+    } = options;
+    let start = Instant::now();
 
-    let walker =
-        WalkBuilder::new(&*in_path)
-                    .threads(args.threads)
-                    .standard_filters(false)
-                    .build_parallel();
+    let (in_prefix, in_paths) = walk_roots(&cmd_args.in_path)?;
+    if cmd_args.files_from.is_some() {
+        ensure!(in_paths.len() == 1 && in_prefix == in_paths[0],
+                "--files-from requires one --in-path directory");
+    }
+    let transform = Arc::new(Transform::new(&cmd_args.transform)?);
+    match cmd_args.level {
+        Some(Level::Auto) =>
+            ensure!(cmd_args.codec == Codec::Zstd, "--level auto requires --codec zstd"),
+        // Check the level before creating the output directory.
+        Some(Level::Fixed(level)) =>
+            drop(cmd_args.codec.encoder(io::sink(), Some(level), None)?),
+        None => (),
+    }
+    let dictionary = cmd_args.dict.as_deref().map(Dictionary::load).transpose()?;
+    let name_template = Arc::new(cmd_args.names.template(cmd_args.bucket_by.is_some())?);
+
+    if let Some(ref out_dir) = cmd_args.out_dir {
+        fs::create_dir_all(out_dir)?;
+    }
+    // Held until the run ends, so another can't write archives alongside these.
+    let lock = cmd_args.out_dir.as_deref().map(RunLock::exclusive).transpose()?;
+    if let Some(ref out_dir) = cmd_args.out_dir {
+        let archive_pattern = cmd_args.names.pattern()?;
+        prepare_out_dir(out_dir, cmd_args.require_empty, cmd_args.force, &archive_pattern)?;
+        if cmd_args.force || lock.as_ref().is_some_and(RunLock::follows_crash) {
+            remove_orphans(out_dir, &archive_pattern)?;
+        }
+    }
+    // Listed files are archived even from the output directory.
+    let walked_out_dirs = match cmd_args.out_dir {
+        Some(ref out_dir) if cmd_args.files_from.is_none() =>
+            walked_out_dirs(&in_paths, out_dir)?,
+        _ => Vec::new(),
+    };
 
     let error_count = Arc::new(AtomicUsize::new(0));
+    let error_report = Arc::new(ErrorReport::default());
+    let progress = Arc::new(progress::Counters::with_callback(callback));
+    let reporter = if cmd_args.progress {
+        Some(progress::Reporter::spawn(progress.clone(), "files")?)
+    } else {
+        None
+    };
+    let status_server = match cmd_args.status_socket {
+        Some(ref name) => Some(StatusServer::spawn(name, "compress", progress.clone())?),
+        None => None,
+    };
 
-    walker.visit(&mut PVB {
-        error_count: error_count.clone(),
-        in_path,
-        in_prefix,
-        next_archive_num: 0,
-        out_dir: cmd_args.out_dir,
-    });
+    let manifest_writer = cmd_args.out_dir.as_deref()
+        .map(|out_dir| manifest::Writer::create(out_dir, cmd_args.manifest_format)
+                           .with_context(|| format!("creating the manifest in '{}'; pass \
+                                                     --force to replace an earlier run's",
+                                                    out_dir.display())))
+        .transpose()?;
 
-    let final_error_count = error_count.load(Ordering::SeqCst);
-    ensure!(final_error_count == 0, "Errors in compress() count={final_error_count}");
+    let path_hasher = match cmd_args.anonymize_paths {
+        Some(ref mapping_path) => Some(Arc::new(PathHasher::create(mapping_path)?)),
+        None => None,
+    };
 
-    Ok(())
-}
+    let master_key = match cmd_args.entry_key_file {
+        Some(ref path) => Some(Arc::new(MasterKey::load(path)?)),
+        None => None,
+    };
 
-impl ignore::ParallelVisitorBuilder<'static> for PVB {
-    /// Build a visitor for an ignore thread.
-    fn build(&mut self) -> Box<dyn ignore::ParallelVisitor + 'static> {
-        let archive_num = self.next_archive_num;
-        self.next_archive_num += 1;
-        let out_file_path = self.out_dir.join(format!("{archive_num:08}.tar.zstd"));
+    let sign_key = cmd_args.sign_key.as_deref().map(ManifestSigningKey::load).transpose()?;
+    let store = cmd_args.out_url.as_deref()
+                                .map(|url| remote::open(url, &cmd_args.remote))
+                                .transpose()?;
 
-        Box::new(PV {
-            archive_num,
-            error_count: self.error_count.clone(),
-            in_prefix: self.in_prefix.clone(),
-            out_path: out_file_path.to_path_buf(),
-            tarb: None,
-        })
+    ensure!(cmd_args.encrypt.is_empty() || passphrase.is_none(),
+            "--encrypt can't be used with a passphrase");
+    let encryption = match passphrase {
+        Some(passphrase) => Some(Arc::new(Encryption::Passphrase(passphrase))),
+        None if !cmd_args.encrypt.is_empty() => {
+            Some(Arc::new(Encryption::parse_recipients(&cmd_args.encrypt)?))
+        },
+        None => None,
+    };
+
+    let device_limiter = cmd_args.hdd_mode.map(|reads| Arc::new(DeviceLimiter::new(reads)));
+
+    if cmd_args.level_policy.is_some() {
+        ensure!(cmd_args.codec == Codec::Zstd, "--level-policy requires --codec zstd");
+    }
+    if cmd_args.long.is_some() {
+        ensure!(cmd_args.codec == Codec::Zstd, "--long requires --codec zstd");
+    }
+    if cmd_args.dict.is_some() {
+        ensure!(cmd_args.codec == Codec::Zstd, "--dict requires --codec zstd");
+    }
+    if cmd_args.seekable.is_some() {
+        ensure!(cmd_args.codec == Codec::Zstd, "--seekable requires --codec zstd");
+    }
+    if cmd_args.xattrs {
+        ensure!(cmd_args.tar_format == TarFormat::Pax, "--xattrs requires --tar-format pax");
+    }
+    if cmd_args.acls {
+        ensure!(cmd_args.tar_format == TarFormat::Pax, "--acls requires --tar-format pax");
+    }
+    if cmd_args.special_files {
+        ensure!(cmd_args.tar_format != TarFormat::V7,
+                "--special-files requires a --tar-format with device and FIFO entries, \
+                 which v7 lacks");
     }
-}
 
-impl PV {
-    fn tarb(&mut self) -> Result<&mut tar::Builder<impl Write>> {
-        if let Some(ref mut tarb) = self.tarb {
-            return Ok(tarb);
-        }
+    if cmd_args.zstd_workers > 1 {
+        ensure!(cmd_args.codec == Codec::Zstd, "--zstd-workers requires --codec zstd");
+        ensure!(codec::zstd_multithread_supported(),
+                "The zstd library doesn't support multithreading, which --zstd-workers \
+                 requires");
+    }
+    if cmd_args.codec == Codec::Zstd && !codec::zstd_multithread_supported() {
+        ensure!(!cmd_args.require_zstd_mt,
+                "The zstd library doesn't support multithreading, which --require-zstd-mt \
+                 requires");
+        tracing::warn!("The zstd library doesn't support multithreading, so each shard \
+                        compresses on its own thread; this may be slower");
+    }
 
-        let file = fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&*self.out_path)?;
-        let bufw = BufWriter::with_capacity(128 * 1024, file);
-        let mut zstdw = zstd::stream::write::Encoder::new(bufw,
-                                                          ZSTD_DEFAULT_COMPRESSION_LEVEL)?;
-        // Compression will be done in a separate thread, to detach I/O and compression.
-        zstdw.multithread(1)?;
-        let tarb = tar::Builder::new(zstdw);
+    if let Some(chunk_size) = cmd_args.chunk_size {
+        ensure!(chunk_size > 0, "--chunk-size must be more than 0");
+        ensure!(cmd_args.tar_format == TarFormat::Pax,
+                "--chunk-size requires --tar-format pax");
+    }
 
-        Ok(self.tarb.insert(tarb))
+    if let (Some(min_size), Some(max_size)) = (cmd_args.min_size, cmd_args.max_size) {
+        ensure!(min_size <= max_size, "--min-size is more than --max-size");
     }
 
-    fn incr_errors(&self) {
-        let _ = self.error_count.fetch_add(1, Ordering::SeqCst);
+    if cmd_args.bucket_by.is_some() {
+        ensure!(cmd_args.level_policy.is_none(),
+                "--bucket-by can't be used with --level-policy");
     }
-}
 
-impl ignore::ParallelVisitor for PV {
-    fn visit(&mut self, entry: StdResult<DirEntry, ignore::Error>) -> WalkState {
-        let entry = match entry {
-            Err(err) => {
-                tracing::warn!(%err, "Error given to PV.visit");
-                self.incr_errors();
-                return WalkState::Continue;
+    // Anonymized manifests have no real mtimes to compare.
+    ensure!(cmd_args.since_manifest.is_empty() || !cmd_args.anonymize,
+            "--since-manifest can't be used with --anonymize");
+    let snapshot = if cmd_args.since_manifest.is_empty() {
+        None
+    } else {
+        Some(Arc::new(Snapshot::load(&cmd_args.since_manifest, cmd_args.compare_checksums)?))
+    };
+    let unchanged_count = Arc::new(AtomicU64::new(0));
+    let skipped_special_count = Arc::new(AtomicU64::new(0));
+    let long_paths = Arc::new(LongPaths::default());
+    let case_collisions = Arc::new(CaseCollisions::default());
+
+    // With a level policy there must be a shard for each size class.
+    let shard_count = if cmd_args.out.is_some() {
+        1
+    } else {
+        cmd_args.compress_threads.unwrap_or(threads)
+                .max(if cmd_args.level_policy.is_some() { 2 } else { 1 })
+    };
+    let small_shard_count = shard_count.div_ceil(2);
+    let latency_guard = cmd_args.latency_guard.map(|ms| {
+        Arc::new(LatencyGuard::new(Duration::from_millis(ms), shard_count))
+    });
+    let next_archive_num = Arc::new(AtomicU64::new(shard_count as u64));
+    let (read_limiter, write_limiter) = (cmd_args.rate.read_limiter(),
+                                         cmd_args.rate.write_limiter());
+    let mut shard_queues = Vec::with_capacity(shard_count);
+    let mut shard_threads = Vec::with_capacity(shard_count);
+    for archive_num in 0..(shard_count as u64) {
+        let (tx, rx) = crossbeam_channel::bounded::<FileJob>(SHARD_QUEUE_LEN);
+        let large = cmd_args.level_policy.is_some()
+                    && archive_num >= small_shard_count as u64;
+        shard_queues.push(ShardQueue {
+            assigned_bytes: AtomicU64::new(0),
+            large,
+            tx,
+        });
+
+        let writer = ShardWriter {
+            anonymize: cmd_args.anonymize,
+            archive_num,
+            archives: Vec::new(),
+            bucket: None,
+            name_template: name_template.clone(),
+            compressed_bytes: 0,
+            cancel: cancel.clone(),
+            cancel_policy,
+            device_limiter: device_limiter.clone(),
+            latency_guard: latency_guard.clone(),
+            io_retry: cmd_args.io_retry,
+            read_limiter: read_limiter.clone(),
+            write_limiter: write_limiter.clone(),
+            error_count: error_count.clone(),
+            error_report: error_report.clone(),
+            clear_setuid: cmd_args.clear_setuid,
+            xattrs: cmd_args.xattrs,
+            acls: cmd_args.acls,
+            codec: cmd_args.codec,
+            error_policy: cmd_args.error_policy,
+            hasher: if cmd_args.checksums {
+                Some(HasherThread::spawn(format!("hasher-{archive_num}"),
+                                         cmd_args.hash,
+                                         cmd_args.out_dir.clone()
+                                                 .expect("--checksums requires --out-dir"))?)
+            } else {
+                None
             },
-            Ok(v) => v,
-        };
-        let Some(file_type) = entry.file_type() else {
-            return WalkState::Continue;
-        };
-        if !file_type.is_file() {
-            return WalkState::Continue;
-        }
-        // It's a file.
-        let path = entry.path();
-        let rel_path = match path.strip_prefix(&*self.in_prefix) {
-            Ok(p) => p,
-            Err(err) => {
-                tracing::error!(path = %path.display(),
-                                prefix = %self.in_prefix.display(),
-                                %err,
-                                "Error stripping path prefix");
-                self.incr_errors();
-                return WalkState::Quit;
-            }
+            level: match (cmd_args.level, cmd_args.level_policy) {
+                (Some(Level::Fixed(level)), _) => Some(level),
+                (Some(Level::Auto), _) => Some(adaptive_level::START_LEVEL),
+                (None, Some(policy)) => Some(if large { policy.large } else { policy.small }),
+                (None, None) => None,
+            },
+            adaptive_level: cmd_args.level == Some(Level::Auto),
+            long_window_log: cmd_args.long,
+            dictionary: dictionary.clone(),
+            zstd_workers: cmd_args.zstd_workers,
+            zstd_checksum: !cmd_args.no_zstd_checksum,
+            manifest_tx: manifest_writer.as_ref().map(|writer| writer.sender()),
+            master_key: master_key.clone(),
+            max_shard_size: cmd_args.max_shard_size,
+            mode_override: cmd_args.mode_override,
+            next_archive_num: next_archive_num.clone(),
+            out_dir: cmd_args.out_dir.clone().unwrap_or_default(),
+            frame_size: cmd_args.seekable,
+            path_hasher: path_hasher.clone(),
+            pending_entries: Vec::new(),
+            progress: progress.clone(),
+            encryption: encryption.clone(),
+            store: store.clone(),
+            stdout: cmd_args.out.is_some(),
+            shard: None,
+            shard_size_mode: cmd_args.shard_size_mode,
+            tar_format: cmd_args.tar_format,
+            owner_names: owners::Names::default(),
+            archived_sources: Vec::new(),
+            finished_sources: Vec::new(),
+            remove_source_files: cmd_args.remove_source_files,
+            verify_before_remove: cmd_args.verify_before_remove,
         };
+        shard_threads.push(
+            thread::Builder::new()
+                .name(format!("shard-{archive_num}"))
+                .spawn(move || writer.main(rx))?);
+    }
 
-        let tarb = match self.tarb() {
-            Ok(tarb) => tarb,
-            Err(err) => {
-                tracing::error!(path = %path.display(), %err, "Error creating tarb");
-                self.incr_errors();
-                return WalkState::Quit;
-            }
-        };
+    let mut visitor_builder = VisitorBuilder {
+        cancel: cancel.clone(),
+        content_type_filter: if cmd_args.exclude_content_type.is_empty() {
+            None
+        } else {
+            Some(Arc::new(ContentTypeFilter::new(&cmd_args.exclude_content_type)?))
+        },
+        dispatcher: Arc::new(Dispatcher {
+            chunk_size: cmd_args.chunk_size,
+            collected: (cmd_args.bucket_by.is_some() || cmd_args.pre_scan)
+                       .then(|| Mutex::new(Vec::new())),
+            large_threshold: cmd_args.level_policy.map(|policy| policy.threshold),
+            progress: progress.clone(),
+            shards: shard_queues,
+        }),
+        error_count: error_count.clone(),
+        error_report: error_report.clone(),
+        error_policy: cmd_args.error_policy,
+        case_collisions: if cmd_args.anonymize_paths.is_none() {
+            Some(case_collisions.clone())
+        } else {
+            None
+        },
+        in_prefix: in_prefix.clone(),
+        long_path_policy: cmd_args.long_path_policy,
+        long_paths: long_paths.clone(),
+        path_limits: PathLimits {
+            tar_format: cmd_args.tar_format,
+            max_len: cmd_args.max_path_len,
+        },
+        snapshot: snapshot.clone(),
+        skipped_special_count: skipped_special_count.clone(),
+        special_files: cmd_args.special_files,
+        metadata_filter: MetadataFilter {
+            min_mtime: cmd_args.newer_mtime.map(|cutoff| match cutoff {
+                MtimeCutoff::At(secs) => secs,
+                MtimeCutoff::Ago(secs) => {
+                    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+                                               .map_or(0, |d| d.as_secs());
+                    now.saturating_sub(secs) as i64
+                },
+            }),
+            min_size: cmd_args.min_size,
+            max_size: cmd_args.max_size,
+        },
+        transform,
+        unchanged_count: unchanged_count.clone(),
+    };
 
-        if let Err(err) = tarb.append_path_with_name(path, rel_path) {
-            tracing::error!(path = %path.display(), %err, "Error appending file");
-            self.incr_errors();
-            return WalkState::Quit;
+    if let Some(ref list) = cmd_args.files_from {
+        let mut visitor = visitor_builder.visitor();
+        visit_files_from(&mut visitor, list, cmd_args.null, &in_prefix)?;
+    } else if cmd_args.hdd_mode.is_some() {
+        // Walk sequentially in sorted order, so directories are read in one pass and
+        // files are dispatched, and mostly read, in order.
+        let mut walker = build_walker(&cmd_args, &in_prefix, &in_paths, walked_out_dirs)?;
+        let mut visitor = visitor_builder.visitor();
+        for entry in walker.sort_by_file_name(|a, b| a.cmp(b)).build() {
+            if visitor.visit(entry) == WalkState::Quit {
+                break;
+            }
         }
+    } else {
+        build_walker(&cmd_args, &in_prefix, &in_paths, walked_out_dirs)?
+            .threads(cmd_args.walk_threads.unwrap_or(threads))
+            .build_parallel()
+            .visit(&mut visitor_builder);
+    }
+    if visitor_builder.dispatcher.collected.is_some()
+       && cancel::check(cancel.as_ref()).is_ok() {
+        visitor_builder.dispatcher.dispatch_collected(cmd_args.bucket_by);
+    }
+    drop(visitor_builder);
+    // The VisitorBuilder and all Visitors are dropped by now, so each shard's
+    // sender is dropped and the shard writers finish their archives.
 
-        WalkState::Continue
+    let mut compressed_bytes = 0;
+    let mut archives = Vec::new();
+    let mut archived_sources = Vec::new();
+    for thread in shard_threads {
+        let (shard_compressed_bytes, shard_archives, shard_sources) =
+            thread.join().expect("joining shard writer thread");
+        compressed_bytes += shard_compressed_bytes;
+        archives.extend(shard_archives);
+        archived_sources.extend(shard_sources);
     }
-}
+    archives.sort();
 
-impl Drop for PV {
-    fn drop(&mut self) {
-        tracing::debug!(archive_num = self.archive_num,
-                        "PV::drop start");
+    if let Some(manifest_writer) = manifest_writer {
+        manifest_writer.finish()?;
+    }
 
-        // Closure to catch errors with `?`.
-        let res = (|| -> Result<()> {
-            let Some(tarb) = self.tarb.take() else {
-                return Ok(());
-            };
+    if let Some(path_hasher) = path_hasher {
+        Arc::into_inner(path_hasher).expect("shard writers have stopped").finish()?;
+    }
 
-            // tarb.into_inner() finishes writing the tar archive.
-            let zstdw: zstd::stream::write::Encoder<_> =
-                tarb.into_inner()?;
-            let bufw = zstdw.finish()?;
-            let file = bufw.into_inner()
-                           .map_err(|err| err.into_error())?;
-            file.sync_all()?;
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+    if let Some(status_server) = status_server {
+        status_server.finish();
+    }
 
-            Ok(())
-        })();
+    // Written even if the run was cancelled, to list the errors before it.
+    if let Some(ref report_path) = cmd_args.error_report {
+        error_report.write(report_path)?;
+    }
+    cancel::check(cancel.as_ref())?;
 
-        tracing::debug!(archive_num = self.archive_num,
-                        "PV::drop complete");
+    let long_path_count = long_paths.count();
+    if long_path_count > 0 {
+        let examples = long_paths.examples();
+        tracing::warn!(long_path_count, ?examples, policy = ?cmd_args.long_path_policy,
+                       "Found paths too long to archive as they are");
+        ensure!(cmd_args.long_path_policy != LongPathPolicy::Abort,
+                "Path '{}' is too long to archive; use --long-path-policy to skip or \
+                 shorten such paths", examples[0].display());
+    }
 
-        if let Err(err) = res {
-            tracing::error!(%err, out_path = %self.out_path.display(),
-                            "Error while closing archive in PV::drop()");
-            let _ = self.error_count.fetch_add(1, Ordering::SeqCst);
-        }
+    let case_collision_count = case_collisions.count();
+    if case_collision_count > 0 {
+        tracing::warn!(case_collision_count, examples = ?case_collisions.examples(),
+                       "Found paths differing only in case from others, which would \
+                        overwrite them on a case insensitive filesystem; they're marked \
+                        in the manifest's case_collision field");
+    }
+
+    let skipped_special_count = skipped_special_count.load(Ordering::SeqCst);
+    if skipped_special_count > 0 {
+        tracing::warn!(skipped_special_count,
+                       "Skipped special files: devices and FIFOs are only archived with \
+                        --special-files, and sockets never are");
+    }
+
+    let final_error_count = error_count.load(Ordering::SeqCst);
+    ensure!(final_error_count == 0, "Errors in compress() count={final_error_count}{listed}",
+            listed = error_report::listed_in(cmd_args.error_report.as_deref()));
+
+    let unchanged_count = unchanged_count.load(Ordering::SeqCst);
+    if let Some(ref snapshot) = snapshot {
+        // Only now the walk finished without errors is every file still present seen.
+        let deleted = snapshot.deleted_paths();
+        let out_dir = cmd_args.out_dir.as_deref().expect("--since-manifest requires --out-dir");
+        incremental::write_deletions(out_dir, &deleted)?;
+        tracing::info!(unchanged_count, deleted_count = deleted.len(),
+                       "Compared with the previous manifests");
+    }
+
+    if let Some(sign_key) = sign_key {
+        sign_key.sign(cmd_args.out_dir.as_deref().expect("--sign-key requires --out-dir"),
+                      &archives)?;
+        tracing::info!(public_key = sign_key.public_key_hex(), "Signed the archive set");
+    }
+
+    if let Some(ref store) = store {
+        // Only now every archive is uploaded can the manifest refer to them. The metadata
+        // is named with `--prefix`, so runs sharing the store keep their own.
+        let mut names = vec![cmd_args.manifest_format.file_name().to_string(),
+                             incremental::DELETIONS_FILE_NAME.to_string()];
+        if cmd_args.checksums {
+            names.extend(archives.iter().map(|archive| {
+                format!("{archive}.{alg}", alg = cmd_args.hash.name())
+            }));
+        }
+        for name in names {
+            let path = cmd_args.out_dir.as_deref().expect("--out-dir is required").join(&name);
+            if path.exists() {
+                remote::upload_file(&**store, &cmd_args.names.metadata_name(&name), &path)?;
+            }
+        }
+        tracing::info!(archive_count = archives.len(), "Uploaded the archive set");
+    }
+
+    // Only now the manifest is durable, and uploaded with `--out-url`, can the files it
+    // lists be deleted.
+    if cmd_args.remove_source_files {
+        let removal_error_count = remove_sources(archived_sources, &error_report);
+        if removal_error_count > 0 {
+            if let Some(ref report_path) = cmd_args.error_report {
+                error_report.write(report_path)?;
+            }
+            bail!("Errors removing archived files count={removal_error_count}{listed}",
+                  listed = error_report::listed_in(cmd_args.error_report.as_deref()));
+        }
+    }
+
+    Ok(Report {
+        archives,
+        file_count: progress.done_files.load(Ordering::SeqCst),
+        uncompressed_bytes: progress.done_bytes.load(Ordering::SeqCst),
+        compressed_bytes,
+        unchanged_count,
+        long_path_count,
+        case_collision_count,
+        skipped_special_count,
+        duration: start.elapsed(),
+    })
+}
+
+/// Delete the archived files `sources`, with `--remove-source-files`, unless they've
+/// changed since they were archived. Returns the number of errors, recorded in
+/// `error_report`.
+fn remove_sources(sources: Vec<ArchivedSource>, error_report: &ErrorReport) -> usize {
+    let (mut removed_count, mut error_count) = (0, 0);
+    for source in sources {
+        let path = &*source.path;
+        let res = fs::symlink_metadata(path).and_then(|meta| {
+            let unchanged = (meta.dev(), meta.ino(), meta.len(), meta.mtime(),
+                             meta.mtime_nsec())
+                            == (source.meta.dev(), source.meta.ino(), source.meta.len(),
+                                source.meta.mtime(), source.meta.mtime_nsec());
+            if unchanged {
+                fs::remove_file(path)?;
+            }
+            Ok(unchanged)
+        });
+        match res {
+            Ok(true) => removed_count += 1,
+            Ok(false) => tracing::warn!(path = %path.display(),
+                                        "File changed since it was archived, so it's kept"),
+            Err(err) => {
+                tracing::warn!(path = %path.display(), %err, "Error removing archived file");
+                error_count += 1;
+                error_report.record(path, "remove", &err.into());
+            },
+        }
+    }
+    tracing::info!(removed_count, "Removed archived files");
+    error_count
+}
+
+/// Remove the temporary files and incomplete archives a crashed run left in `out_dir`:
+/// ptar's own temporary files, and partial archives named by `archive_pattern`, with
+/// their checksums files. Only run under the lock, once it's confirmed the run that
+/// held it crashed, or with `--force`.
+fn remove_orphans(out_dir: &Path, archive_pattern: &Regex) -> Result<()> {
+    let mut removed_count = 0;
+    for name in [manifest::MANIFEST_FILE_NAME, manifest_signature::SIGNATURE_FILE_NAME,
+                 incremental::DELETIONS_FILE_NAME, status::STATUS_FILE_NAME] {
+        let path = out_dir.join(format!("{name}.tmp"));
+        match fs::remove_file(&path) {
+            Ok(()) => removed_count += 1,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err)
+                .with_context(|| format!("removing '{}'", path.display())),
+        }
+    }
+    for path in archive_set::partial_paths(out_dir)? {
+        let name = path.file_name().expect("partial path has a file name").to_string_lossy();
+        let archive = name.strip_suffix(archive_set::PARTIAL_SUFFIX)
+                          .expect("partial path has the suffix");
+        if !archive_pattern.is_match(archive) {
+            continue;
+        }
+        fs::remove_file(&path).with_context(|| format!("removing '{}'", path.display()))?;
+        fsck::remove_checksums_files(out_dir, archive)?;
+        removed_count += 1;
+    }
+    if removed_count > 0 {
+        tracing::warn!(out_dir = %out_dir.display(), removed_count,
+                       "Removed temporary files and incomplete archives left by a crashed run");
+    }
+    Ok(())
+}
+
+/// Check `out_dir` is empty with `--require-empty`, or delete an earlier run's files from
+/// it with `--force`.
+fn prepare_out_dir(out_dir: &Path, require_empty: bool, force: bool, archive_pattern: &Regex)
+-> Result<()>
+{
+    if !(require_empty || force) {
+        return Ok(());
+    }
+    let mut removed_count = 0;
+    for entry in fs::read_dir(out_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == run_lock::LOCK_FILE_NAME {
+            continue;
+        }
+        ensure!(!require_empty, "--out-dir '{}' isn't empty: it holds '{name}'",
+                out_dir.display());
+        if entry.file_type()?.is_file() && is_run_output(&name, archive_pattern) {
+            fs::remove_file(entry.path())
+                .with_context(|| format!("removing '{}'", entry.path().display()))?;
+            removed_count += 1;
+        }
+    }
+    if removed_count > 0 {
+        tracing::warn!(out_dir = %out_dir.display(), removed_count,
+                       "Removed an earlier run's files with --force");
+    }
+    Ok(())
+}
+
+/// Whether a file named `name` in `--out-dir` is one compress writes: an archive matching
+/// `archive_pattern`, e.g. `00000001.tar.zstd`, possibly encrypted, partial or its
+/// checksums file, or a manifest, signature or deletions file.
+fn is_run_output(name: &str, archive_pattern: &Regex) -> bool {
+    if [manifest::MANIFEST_FILE_NAME, manifest::PARQUET_MANIFEST_FILE_NAME,
+        manifest_signature::SIGNATURE_FILE_NAME, incremental::DELETIONS_FILE_NAME]
+        .contains(&name)
+    {
+        return true;
+    }
+    // Partial and checksums files are named after their archive.
+    archive_pattern.is_match(name)
+    || name.rsplit_once('.').is_some_and(|(stem, _)| archive_pattern.is_match(stem))
+}
+
+/// The prefix stripped from walked paths to get their archived paths, and the paths
+/// to walk.
+///
+/// A single directory's files are archived relative to it, and a single file by its
+/// name. Several paths are walked as absolute paths, and archived relative to the
+/// deepest directory holding them all, so each keeps a distinct prefix.
+fn walk_roots(in_paths: &[PathBuf]) -> Result<(PathBuf, Vec<PathBuf>)> {
+    if let [in_path] = in_paths {
+        return Ok(if in_path.metadata()?.is_dir() {
+            (in_path.clone(), vec![in_path.clone()])
+        } else {
+            match in_path.parent() {
+                Some(parent) => (parent.to_path_buf(), vec![in_path.clone()]),
+                None => (PathBuf::from("./"), vec![PathBuf::from("./").join(in_path)]),
+            }
+        });
+    }
+
+    let mut roots = Vec::with_capacity(in_paths.len());
+    for in_path in in_paths {
+        in_path.metadata().with_context(|| format!("reading '{}'", in_path.display()))?;
+        let root = std::path::absolute(in_path)?
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .collect::<PathBuf>();
+        if let Some(i) = roots.iter().position(|other: &PathBuf| {
+            root.starts_with(other) || other.starts_with(&root)
+        }) {
+            bail!("--in-path '{}' overlaps --in-path '{}'; each file must be archived once",
+                  in_path.display(), in_paths[i].display());
+        }
+        roots.push(root);
+    }
+    let mut prefix = roots[0].parent().unwrap_or(&roots[0]).to_path_buf();
+    while !roots.iter().all(|root| root.starts_with(&prefix)) {
+        prefix.pop();
+    }
+    Ok((prefix, roots))
+}
+
+/// The output directory's path as the walk of `in_paths` would find it, if it's inside
+/// one of them, so the archives being written aren't archived too. An error if it's
+/// the same directory as one.
+fn walked_out_dirs(in_paths: &[PathBuf], out_dir: &Path) -> Result<Vec<PathBuf>> {
+    let canonical_out = fs::canonicalize(out_dir)?;
+    let mut walked = Vec::new();
+    for in_path in in_paths {
+        let canonical_in = fs::canonicalize(in_path)?;
+        ensure!(canonical_in != canonical_out,
+                "--out-dir '{}' is the same directory as --in-path '{}'", out_dir.display(),
+                in_path.display());
+        let Ok(rel_path) = canonical_out.strip_prefix(&canonical_in) else {
+            continue;
+        };
+        tracing::warn!(out_dir = %out_dir.display(), in_path = %in_path.display(),
+                       "--out-dir is inside --in-path, so it's excluded from the archive");
+        walked.push(in_path.join(rel_path));
+    }
+    Ok(walked)
+}
+
+/// Visit the files listed in `list`, in order, rather than walking `in_dir`. Listed
+/// paths are relative to `in_dir`, or absolute paths inside it.
+fn visit_files_from(visitor: &mut Visitor, list: &Path, null: bool, in_dir: &Path)
+-> Result<()>
+{
+    let reader: Box<dyn BufRead> = if list == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(list).with_context(|| {
+            format!("opening --files-from '{}'", list.display())
+        })?))
+    };
+    let absolute_in_dir = std::path::absolute(in_dir)?;
+    for line in reader.split(if null { b'\0' } else { b'\n' }) {
+        let line = line.with_context(|| format!("reading --files-from '{}'", list.display()))?;
+        if line.is_empty() {
+            continue;
+        }
+        let listed = PathBuf::from(OsString::from_vec(line));
+        let rel_path = match listed.strip_prefix(&absolute_in_dir) {
+            Ok(rel_path) => rel_path,
+            Err(_) if listed.is_absolute() => Path::new(".."),
+            Err(_) => &listed,
+        };
+        if !rel_path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            tracing::error!(path = %listed.display(), in_path = %in_dir.display(),
+                            "Listed file isn't inside --in-path");
+            let err = anyhow!("Listed file isn't inside --in-path");
+            if visitor.incr_errors(&listed, "list", err) == WalkState::Quit {
+                break;
+            }
+            continue;
+        }
+        let path = in_dir.join(rel_path.components().collect::<PathBuf>());
+        let state = match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => {
+                tracing::debug!(path = %path.display(),
+                                "Skipping listed directory, as directories aren't archived");
+                WalkState::Continue
+            },
+            Ok(meta) => visitor.visit_file(&path, meta.file_type(), || Ok(meta)),
+            Err(err) => {
+                tracing::warn!(path = %path.display(), %err, "Error reading file metadata");
+                visitor.incr_errors(&path, "metadata", err.into())
+            },
+        };
+        if state == WalkState::Quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn build_walker(cmd_args: &Args, in_prefix: &Path, in_paths: &[PathBuf],
+                walked_out_dirs: Vec<PathBuf>)
+-> Result<WalkBuilder>
+{
+    let mut overrides = OverrideBuilder::new(in_prefix);
+    for glob in cmd_args.include.iter() {
+        overrides.add(glob)?;
+    }
+    for glob in cmd_args.exclude.iter() {
+        overrides.add(&format!("!{glob}"))?;
+    }
+    if cmd_args.exclude_vcs {
+        for name in VCS_NAMES {
+            overrides.add(&format!("!{name}"))?;
+        }
+    }
+
+    let respect_gitignore = cmd_args.respect_gitignore;
+    let mut walker = WalkBuilder::new(&in_paths[0]);
+    for in_path in &in_paths[1..] {
+        walker.add(in_path);
+    }
+    walker.standard_filters(false)
+          .git_ignore(respect_gitignore)
+          .git_global(respect_gitignore)
+          .git_exclude(respect_gitignore)
+          .ignore(respect_gitignore)
+          .parents(respect_gitignore)
+          .hidden(cmd_args.skip_hidden)
+          .same_file_system(cmd_args.one_file_system)
+          .max_depth(cmd_args.max_depth.map(|depth| depth as usize))
+          .overrides(overrides.build()?);
+    let exclude_caches = cmd_args.exclude_caches;
+    let exclude_if_present = cmd_args.exclude_if_present.clone();
+    let honor_nodump = cmd_args.honor_nodump;
+    if !walked_out_dirs.is_empty() || exclude_caches || !exclude_if_present.is_empty()
+       || honor_nodump
+    {
+        walker.filter_entry(move |entry| {
+            let path = entry.path();
+            if walked_out_dirs.iter().any(|d| path == d) {
+                return false;
+            }
+            let Some(file_type) = entry.file_type() else {
+                return true;
+            };
+            if honor_nodump {
+                match nodump::is_nodump(path, file_type) {
+                    Ok(false) => (),
+                    Ok(true) => {
+                        tracing::debug!(path = %path.display(), "Skipping nodump file");
+                        return false;
+                    },
+                    // Archived, so reading it fails later if it's unreadable.
+                    Err(err) => tracing::warn!(path = %path.display(), %err,
+                                               "Error reading file attributes"),
+                }
+            }
+            if !file_type.is_dir() {
+                return true;
+            }
+            if exclude_caches && is_cache_dir(path) {
+                tracing::debug!(path = %path.display(), "Skipping directory with CACHEDIR.TAG");
+                return false;
+            }
+            if let Some(name) = exclude_if_present.iter().find(|name| path.join(name).exists()) {
+                tracing::debug!(path = %path.display(), marker = name,
+                                "Skipping directory with --exclude-if-present file");
+                return false;
+            }
+            true
+        });
+    }
+    Ok(walker)
+}
+
+/// Whether `dir` holds a `CACHEDIR.TAG` file starting with the tag's signature.
+fn is_cache_dir(dir: &Path) -> bool {
+    let Ok(file) = File::open(dir.join("CACHEDIR.TAG")) else {
+        return false;
+    };
+    let mut start = Vec::with_capacity(CACHEDIR_TAG_SIGNATURE.len());
+    match file.take(CACHEDIR_TAG_SIGNATURE.len() as u64).read_to_end(&mut start) {
+        Ok(_) => start == CACHEDIR_TAG_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+impl ParallelVisitorBuilder<'static> for VisitorBuilder {
+    /// Build a visitor for an ignore thread.
+    fn build(&mut self) -> Box<dyn ignore::ParallelVisitor + 'static> {
+        Box::new(self.visitor())
+    }
+}
+
+impl VisitorBuilder {
+    fn visitor(&self) -> Visitor {
+        Visitor {
+            cancel: self.cancel.clone(),
+            content_type_filter: self.content_type_filter.clone(),
+            dispatcher: self.dispatcher.clone(),
+            error_count: self.error_count.clone(),
+            error_report: self.error_report.clone(),
+            error_policy: self.error_policy,
+            case_collisions: self.case_collisions.clone(),
+            in_prefix: self.in_prefix.clone(),
+            long_path_policy: self.long_path_policy,
+            long_paths: self.long_paths.clone(),
+            path_limits: self.path_limits,
+            snapshot: self.snapshot.clone(),
+            skipped_special_count: self.skipped_special_count.clone(),
+            special_files: self.special_files,
+            metadata_filter: self.metadata_filter,
+            transform: self.transform.clone(),
+            unchanged_count: self.unchanged_count.clone(),
+        }
+    }
+}
+
+impl Visitor {
+    /// Count an error from `operation` on the file at `path`, and return how to continue
+    /// the walk under the error policy.
+    fn incr_errors(&self, path: &Path, operation: &'static str, err: anyhow::Error)
+    -> WalkState
+    {
+        let _ = self.error_count.fetch_add(1, Ordering::SeqCst);
+        self.error_report.record(path, operation, &err);
+        match self.error_policy {
+            ErrorPolicy::FailFast => WalkState::Quit,
+            ErrorPolicy::KeepGoing => WalkState::Continue,
+        }
+    }
+}
+
+impl ParallelVisitor for Visitor {
+    fn visit(&mut self, entry: StdResult<DirEntry, ignore::Error>) -> WalkState {
+        if cancel::check(self.cancel.as_ref()).is_err() {
+            return WalkState::Quit;
+        }
+        let entry = match entry {
+            Err(err) => {
+                tracing::warn!(%err, "Error given to Visitor.visit");
+                return self.incr_errors(Path::new(""), "walk", err.into());
+            },
+            Ok(v) => v,
+        };
+        let Some(file_type) = entry.file_type() else {
+            return WalkState::Continue;
+        };
+        self.visit_file(entry.path(), file_type, || Ok(entry.metadata()?))
+    }
+}
+
+impl Visitor {
+    /// Archive the file at `path`, walked or listed, if it passes the filters.
+    fn visit_file(&mut self, path: &Path, file_type: fs::FileType,
+                  metadata: impl FnOnce() -> Result<fs::Metadata>)
+    -> WalkState
+    {
+        let special = special_files::is_special(file_type);
+        if (special && !self.special_files) || file_type.is_socket() {
+            tracing::warn!(path = %path.display(), ?file_type, "Skipping special file");
+            self.skipped_special_count.fetch_add(1, Ordering::Relaxed);
+            return WalkState::Continue;
+        }
+        if !file_type.is_file() && !special {
+            return WalkState::Continue;
+        }
+        // It's a file, or a device or FIFO with `--special-files`.
+        let rel_path = match path.strip_prefix(&*self.in_prefix) {
+            Ok(p) => p,
+            Err(err) => {
+                tracing::error!(path = %path.display(),
+                                prefix = %self.in_prefix.display(),
+                                %err,
+                                "Error stripping path prefix");
+                return self.incr_errors(path, "strip_prefix", err.into());
+            }
+        };
+        let transformed = self.transform.apply(rel_path);
+        let rel_path = transformed.as_path();
+        if rel_path.as_os_str().is_empty()
+           || !rel_path.components().all(|c| matches!(c, Component::Normal(_))) {
+            tracing::error!(path = %path.display(), transformed = %rel_path.display(),
+                            "--transform made the path empty, absolute or contain `.` or `..`");
+            let err = anyhow!("--transform made the path '{}' empty, absolute or contain \
+                               `.` or `..`", rel_path.display());
+            return self.incr_errors(path, "transform", err);
+        }
+
+        let (rel_path, original_path) = if self.path_limits.fits(rel_path) {
+            (rel_path.to_path_buf(), None)
+        } else {
+            self.long_paths.record(rel_path);
+            match self.long_path_policy {
+                LongPathPolicy::Abort => {
+                    tracing::error!(path = %path.display(), "Path is too long to archive");
+                    return WalkState::Quit;
+                },
+                LongPathPolicy::Skip => {
+                    tracing::warn!(path = %path.display(),
+                                   "Skipping file whose path is too long to archive");
+                    return WalkState::Continue;
+                },
+                LongPathPolicy::Shorten => match self.path_limits.shorten(rel_path) {
+                    Some(short) => {
+                        tracing::warn!(path = %path.display(), short_path = %short.display(),
+                                       "Shortened path too long to archive");
+                        (short, Some(rel_path.to_path_buf()))
+                    },
+                    None => {
+                        tracing::warn!(path = %path.display(),
+                                       "Path is too long to archive, even shortened");
+                        let err = anyhow!("Path is too long to archive, even shortened");
+                        return self.incr_errors(path, "shorten_path", err);
+                    },
+                },
+            }
+        };
+
+        // Checked before skipping unchanged files, as those are still restored.
+        let case_collision = self.case_collisions.as_ref()
+                                                 .and_then(|c| c.check(&rel_path));
+        if let Some(ref collision) = case_collision {
+            tracing::warn!(path = %path.display(), collision = %collision.display(),
+                           "Path differs only in case from an earlier one");
+        }
+
+        let meta = match metadata() {
+            Ok(meta) => meta,
+            Err(err) => {
+                tracing::warn!(path = %path.display(), %err, "Error reading file metadata");
+                return self.incr_errors(path, "metadata", err);
+            }
+        };
+
+        // Shortened paths are deterministic, so they match the previous manifest's.
+        if let Some(ref snapshot) = self.snapshot {
+            match snapshot.is_unchanged(&rel_path, &meta, path) {
+                Ok(false) => (),
+                Ok(true) => {
+                    self.unchanged_count.fetch_add(1, Ordering::Relaxed);
+                    return WalkState::Continue;
+                },
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), %err,
+                                   "Error comparing file with the previous manifest");
+                    return self.incr_errors(path, "compare", err);
+                },
+            }
+        }
+
+        if !self.metadata_filter.matches(&meta) {
+            tracing::debug!(path = %path.display(), "Skipping file by mtime or size");
+            return WalkState::Continue;
+        }
+
+        // Reading a FIFO would block, so special files have no content type.
+        if let (Some(filter), false) = (&self.content_type_filter, special) {
+            match filter.is_match_file(path) {
+                Ok(false) => (),
+                Ok(true) => {
+                    tracing::debug!(path = %path.display(), "Skipping file by content type");
+                    return WalkState::Continue;
+                },
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), %err,
+                                   "Error reading file to detect its type");
+                    return self.incr_errors(path, "detect_content_type", err.into());
+                },
+            }
+        }
+
+        let job = FileJob {
+            bucket: None,
+            case_collision,
+            chunk: None,
+            meta,
+            original_path,
+            path: path.to_path_buf(),
+            rel_path,
+            remaining_bytes: None,
+        };
+        if self.dispatcher.dispatch(job).is_err() {
+            // The shard writer has stopped after an error, which it has already logged
+            // and counted.
+            return WalkState::Quit;
+        }
+
+        WalkState::Continue
+    }
+}
+
+impl MetadataFilter {
+    /// Whether to archive a file with metadata `meta`. Sizes only apply to regular
+    /// files.
+    fn matches(&self, meta: &fs::Metadata) -> bool {
+        if self.min_mtime.is_some_and(|min| meta.mtime() < min) {
+            return false;
+        }
+        if !meta.is_file() {
+            return true;
+        }
+        !(self.min_size.is_some_and(|min| meta.len() < min)
+          || self.max_size.is_some_and(|max| meta.len() > max))
+    }
+}
+
+impl Dispatcher {
+    /// Send `job` to the least-loaded shard. Returns `Err(())` if that shard's writer
+    /// has stopped.
+    fn dispatch(&self, job: FileJob) -> StdResult<(), ()> {
+        match self.chunk_size {
+            Some(chunk_size) if job.meta.is_file() && job.meta.len() > chunk_size => {
+                for chunk in file_chunk::split(job.meta.len(), chunk_size) {
+                    self.dispatch_one(FileJob { chunk: Some(chunk), ..job.clone() })?;
+                }
+                Ok(())
+            },
+            _ => self.dispatch_one(job),
+        }
+    }
+
+    /// `dispatch()` a single file or chunk.
+    fn dispatch_one(&self, job: FileJob) -> StdResult<(), ()> {
+        if let Some(ref collected) = self.collected {
+            collected.lock().expect("collected lock").push(job);
+            return Ok(());
+        }
+        // Concurrent dispatches may both pick the same shard; this only makes the
+        // balance slightly less even, so no locking is needed.
+        let shard = &self.shards[self.least_loaded(self.is_large(&job))];
+        shard.assigned_bytes.fetch_add(job.len(), Ordering::Relaxed);
+        self.progress.total_files.fetch_add(1, Ordering::Relaxed);
+        self.progress.total_bytes.fetch_add(job.len(), Ordering::Relaxed);
+        shard.tx.send(job).map_err(|_| ())
+    }
+
+    /// Whether `job` goes to the large file shards under `--level-policy`.
+    fn is_large(&self, job: &FileJob) -> bool {
+        self.large_threshold.is_some_and(|threshold| job.meta.len() >= threshold)
+    }
+
+    /// Index of the shard of the size class `large` with the fewest bytes assigned.
+    fn least_loaded(&self, large: bool) -> usize {
+        (0..self.shards.len())
+            .filter(|&i| self.shards[i].large == large)
+            .min_by_key(|&i| self.shards[i].assigned_bytes.load(Ordering::Relaxed))
+            .expect("Dispatcher has at least 1 shard")
+    }
+}
+
+impl Dispatcher {
+    /// Send the files collected during the walk, now their total size is known.
+    ///
+    /// With `bucket_by`, group them into buckets, and send each bucket's files in path
+    /// order to a single shard, spreading the buckets over the shards. Otherwise spread
+    /// the files themselves over the shards, and send each shard's in path order.
+    fn dispatch_collected(&self, bucket_by: Option<BucketBy>) {
+        let collected = self.collected.as_ref().expect("collecting the walk");
+        let jobs = std::mem::take(&mut *collected.lock().expect("collected lock"));
+        // Files to send to one shard together.
+        let mut groups = match bucket_by {
+            Some(bucket_by) => {
+                let mut buckets = BTreeMap::<String, Vec<FileJob>>::new();
+                for mut job in jobs {
+                    let bucket = bucket_by.bucket(&job.meta);
+                    job.bucket = Some(bucket.clone());
+                    buckets.entry(bucket).or_default().push(job);
+                }
+                buckets.into_values().collect::<Vec<_>>()
+            },
+            None => jobs.into_iter().map(|job| vec![job]).collect(),
+        };
+
+        // Assign the largest groups first, each to the shard with the fewest bytes.
+        groups.sort_by_key(|jobs| std::cmp::Reverse(bucket_bytes(jobs)));
+        let mut shard_buckets = (0..self.shards.len()).map(|_| Vec::new()).collect::<Vec<_>>();
+        for mut jobs in groups {
+            jobs.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+            let i = self.least_loaded(self.is_large(&jobs[0]));
+            let shard = &self.shards[i];
+            shard.assigned_bytes.fetch_add(bucket_bytes(&jobs), Ordering::Relaxed);
+            self.progress.total_files.fetch_add(jobs.len() as u64, Ordering::Relaxed);
+            self.progress.total_bytes.fetch_add(bucket_bytes(&jobs), Ordering::Relaxed);
+            shard_buckets[i].push(jobs);
+        }
+        if bucket_by.is_none() {
+            // Without buckets all of a shard's files are in one run of archives.
+            for buckets in shard_buckets.iter_mut() {
+                let mut jobs = buckets.drain(..).flatten().collect::<Vec<_>>();
+                jobs.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+                buckets.push(jobs);
+            }
+        }
+
+        // Tell the shard writers how much is left of each run, to even out archive sizes.
+        for jobs in shard_buckets.iter_mut().flatten() {
+            let mut remaining = 0;
+            for job in jobs.iter_mut().rev() {
+                remaining += tar_entry_size_estimate(job.len());
+                job.remaining_bytes = Some(remaining);
+            }
+        }
+
+        // Send to each shard on its own thread, so a full queue doesn't hold up the
+        // others.
+        thread::scope(|scope| {
+            for (shard, buckets) in self.shards.iter().zip(shard_buckets) {
+                scope.spawn(move || {
+                    for job in buckets.into_iter().flatten() {
+                        if shard.tx.send(job).is_err() {
+                            // The shard writer has stopped after an error, which it has
+                            // already logged and counted.
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+fn bucket_bytes(jobs: &[FileJob]) -> u64 {
+    jobs.iter().map(FileJob::len).sum()
+}
+
+impl FileJob {
+    /// Uncompressed bytes to append: the file's size, or its chunk's.
+    fn len(&self) -> u64 {
+        self.chunk.map_or(self.meta.len(), |chunk| chunk.len)
+    }
+}
+
+impl BucketBy {
+    /// The bucket name of a file with metadata `meta`.
+    fn bucket(self, meta: &fs::Metadata) -> String {
+        let (mtime, _) = manifest::unix_time(meta.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+        self.bucket_of(mtime)
+    }
+
+    fn bucket_of(self, mtime: i64) -> String {
+        let date = time::OffsetDateTime::from_unix_timestamp(mtime)
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH)
+            .date();
+        match self {
+            BucketBy::MtimeMonth => format!("{:04}-{:02}", date.year(), date.month() as u8),
+            BucketBy::MtimeYear => format!("{:04}", date.year()),
+        }
+    }
+}
+
+impl ShardWriter {
+    /// Returns the compressed bytes written, the file names of the archives finished,
+    /// and with `--remove-source-files`, the files archived in them.
+    #[tracing::instrument(target = "ShardWriter::main", level = "debug",
+                          skip(self, rx), fields(first_archive_num = self.archive_num))]
+    fn main(mut self, rx: crossbeam_channel::Receiver<FileJob>)
+    -> (u64, Vec<String>, Vec<ArchivedSource>)
+    {
+        // The file and operation an error below is from, if it's from one.
+        let mut failed: Option<(PathBuf, &'static str)> = None;
+        // Closure to catch errors with `?`.
+        let res = (|| -> Result<()> {
+            for job in rx.iter() {
+                if cancel::check(self.cancel.as_ref()).is_err() {
+                    break;
+                }
+                let job_len = job.len();
+
+                if let Some(ref bucket) = job.bucket {
+                    if self.bucket.as_ref().is_none_or(|(current, _)| current != bucket) {
+                        self.start_bucket(bucket.clone())?;
+                    }
+                }
+
+                if self.shard_size_mode == ShardSizeMode::Uncompressed {
+                    if let (Some(max), Some(shard)) = (self.max_shard_size, &self.shard) {
+                        let size = shard.uncompressed_bytes.load(Ordering::SeqCst);
+                        let max = balanced_max(max, size, job.remaining_bytes);
+                        if size > 0 && size + tar_entry_size_estimate(job_len) > max {
+                            self.roll_over()?;
+                        }
+                    }
+                }
+
+                // Held until the file has been read.
+                let device_limiter = self.device_limiter.clone();
+                let _permit = device_limiter.as_ref()
+                                            .map(|limiter| limiter.acquire(job.meta.dev()));
+                let latency_guard = self.latency_guard.clone();
+                let _latency_permit = latency_guard.as_ref().map(|guard| guard.acquire());
+
+                // Devices and FIFOs, with `--special-files`, have no contents to read, and
+                // opening them could block or have side effects.
+                let file = if job.meta.is_file() {
+                    let open_start = Instant::now();
+                    let opened = self.io_retry.run(&job.path, || File::open(&*job.path));
+                    if let Some(ref guard) = latency_guard {
+                        guard.record(open_start.elapsed());
+                    }
+                    match opened {
+                        Ok(file) => Some(file),
+                        Err(err) if self.error_policy == ErrorPolicy::KeepGoing => {
+                            // Nothing has been written for this file yet, so it can be
+                            // skipped cleanly.
+                            tracing::warn!(path = %job.path.display(), %err,
+                                           "Error opening file, skipping it");
+                            let _ = self.error_count.fetch_add(1, Ordering::SeqCst);
+                            self.progress.remove_file(job_len);
+                            self.progress.event(format!("Skipped '{}': {err}",
+                                                        job.path.display()));
+                            self.error_report.record(&job.path, "open", &err.into());
+                            continue;
+                        },
+                        Err(err) => {
+                            failed = Some((job.path.clone(), "open"));
+                            return Err(err)
+                                .with_context(|| format!("opening '{}'", job.path.display()));
+                        },
+                    }
+                } else {
+                    None
+                };
+
+                self.progress.notify(|| ProgressEvent::FileStarted {
+                    path: job.rel_path.clone(),
+                });
+                let appended = match file {
+                    Some(file) => self.append(&job, file),
+                    None => self.append_special(&job),
+                };
+                if let Err(err) = appended {
+                    tracing::error!(%err, "Error appending file");
+                    failed = Some((job.path.clone(), "archive"));
+                    return Err(err);
+                }
+                let files_done = self.progress.done_files.fetch_add(1, Ordering::Relaxed) + 1;
+                self.progress.notify(|| ProgressEvent::FileDone {
+                    path: job.rel_path,
+                    files_done,
+                    bytes_done: self.progress.done_bytes.load(Ordering::Relaxed),
+                });
+
+                if self.shard_size_mode == ShardSizeMode::Compressed {
+                    if let (Some(max), Some(shard)) = (self.max_shard_size, &self.shard) {
+                        let compressed = shard.compressed_bytes.load(Ordering::SeqCst);
+                        // Estimate what's left will compress as well as this archive has,
+                        // once the encoder has written some of it.
+                        let uncompressed = shard.uncompressed_bytes.load(Ordering::SeqCst);
+                        let remaining = job.remaining_bytes.filter(|_| compressed > 0)
+                                                           .map(|remaining| {
+                            let after = remaining - tar_entry_size_estimate(job_len);
+                            (after as u128 * compressed as u128
+                             / uncompressed.max(1) as u128) as u64
+                        });
+                        if compressed >= balanced_max(max, compressed, remaining) {
+                            self.roll_over()?;
+                        }
+                    }
+                }
+            }
+            if cancel::check(self.cancel.as_ref()).is_err()
+               && self.cancel_policy == CancelPolicy::Discard {
+                self.discard()?;
+            }
+            self.finish()?;
+            if let Some(hasher) = self.hasher.take() {
+                hasher.finish()?;
+            }
+            Ok(())
+        })();
+
+        if let Err(err) = res {
+            tracing::error!(%err, out_path = %self.out_path().display(),
+                            "Error in ShardWriter");
+            let _ = self.error_count.fetch_add(1, Ordering::SeqCst);
+            let (path, operation) = failed.unwrap_or_else(|| (self.out_path(), "write_archive"));
+            self.error_report.record(&path, operation, &err);
+        }
+
+        (self.compressed_bytes, self.archives, self.finished_sources)
+    }
+
+    /// The tar header of a file with metadata `meta`, and its path in the archive.
+    fn header(&self, job: &FileJob, meta: &fs::Metadata) -> Result<(tar::Header, PathBuf)> {
+        let mut header = self.tar_format.new_header();
+        header.set_metadata(meta);
+        if let Some(mode) = self.mode_override {
+            header.set_mode(mode.apply(header.mode()?));
+        }
+        if self.clear_setuid {
+            header.set_mode(header.mode()? & !0o7000);
+        }
+        if self.anonymize {
+            anonymize::header(&mut header)?;
+        } else if self.tar_format != TarFormat::V7 {
+            // v7 headers have no owner names.
+            self.owner_names.set_header(&mut header)?;
+        }
+        let rel_path = match self.path_hasher {
+            Some(ref path_hasher) => path_hasher.anonymize(&job.rel_path)?,
+            None => job.rel_path.clone(),
+        };
+        Ok((header, rel_path))
+    }
+
+    /// With `--tar-format pax`, the pax record of the mtime in `meta` with its fractional
+    /// part, which the header's whole seconds lose.
+    fn mtime_record(&self, meta: &fs::Metadata) -> Option<(String, Vec<u8>)> {
+        (self.tar_format == TarFormat::Pax && !self.anonymize && meta.mtime_nsec() != 0)
+            .then(|| ("mtime".to_string(),
+                      tar_format::pax_time(meta.mtime(), meta.mtime_nsec() as u32).into_bytes()))
+    }
+
+    /// Append a device or FIFO, which has only metadata. It's not listed in the manifest.
+    fn append_special(&mut self, job: &FileJob) -> Result<()> {
+        let (mut header, rel_path) = self.header(job, &job.meta)?;
+        special_files::set_header(&mut header, &job.meta)?;
+        let records = self.mtime_record(&job.meta);
+        let records = records.iter()
+                             .map(|(key, value)| (key.as_str(), value.as_slice()))
+                             .collect::<Vec<_>>();
+        self.shard()?;
+        let shard = self.shard.as_mut().expect("shard opened above");
+        self.tar_format.append_with_records(&mut shard.tarb, &mut header, &rel_path,
+                                            io::empty(), &records)?;
+        Ok(())
+    }
+
+    fn append(&mut self, job: &FileJob, mut file: File) -> Result<()> {
+        // Use the metadata of the open file, in case the file changed since it was walked.
+        let meta = file.metadata()?;
+        let (mut header, rel_path) = self.header(job, &meta)?;
+        // Pax records of the file's extended attributes, ACL and precise mtime.
+        let mut extra_records = if self.xattrs { xattrs::records(&file)? } else { Vec::new() };
+        if self.acls {
+            extra_records.extend(acls::record(&file)?);
+        }
+        extra_records.extend(self.mtime_record(&meta));
+
+        // Its chunks were split by its size when it was walked, so can't follow a change.
+        let data_len = match job.chunk {
+            Some(chunk) => {
+                ensure!(meta.len() == chunk.file_size,
+                        "'{}' changed size from {} to {} bytes while being archived in \
+                         chunks", job.path.display(), chunk.file_size, meta.len());
+                file.seek(SeekFrom::Start(chunk.offset))?;
+                header.set_size(chunk.len);
+                extra_records.extend(chunk.records());
+                chunk.len
+            },
+            None => meta.len(),
+        };
+
+        let file = RetryReader::new(file, self.io_retry, &job.path);
+        let file = LimitedReader::new(file, self.read_limiter.clone());
+        let file = ProgressReader::with_counter(file.take(data_len),
+                                                self.progress.done_bytes.clone());
+        let latency_guard = self.latency_guard.clone();
+        let file = LatencyReader::new(file, latency_guard.as_deref());
+
+        let archive = self.archive_file_name();
+        let mut entry = manifest::Entry::new(rel_path.clone(), &meta, archive);
+        entry.uname = owners::header_name(header.username());
+        entry.gname = owners::header_name(header.groupname());
+        if self.anonymize {
+            anonymize::entry(&mut entry);
+        }
+        // Anonymized paths mustn't reveal the original.
+        if self.path_hasher.is_none() {
+            entry.original_path = job.original_path.clone();
+        }
+        entry.case_collision = job.case_collision.clone();
+        if let Some(chunk) = job.chunk {
+            entry.size = chunk.len;
+            entry.chunk_offset = Some(chunk.offset);
+        }
+
+        let data_key = match self.master_key {
+            Some(ref master_key) => {
+                let data_key = DataKey::generate()?;
+                entry.wrapped_key = Some(master_key.wrap(&data_key, &rel_path)?);
+                header.set_size(entry_encryption::encrypted_len(meta.len()));
+                Some(data_key)
+            },
+            None => None,
+        };
+
+        // Borrow self.shard and self.hasher separately.
+        self.shard()?;
+        let shard = self.shard.as_mut().expect("shard opened above");
+        // The tar builder doesn't buffer, so this is where the entry's first header starts.
+        let offset = shard.uncompressed_bytes.load(Ordering::SeqCst);
+        entry.offset = Some(offset);
+        if let Some(frame_size) = self.frame_size {
+            if offset - shard.frame_start.1 >= frame_size {
+                shard.tarb.get_mut().get_mut().end_frame()?;
+                shard.frame_start = (shard.compressed_bytes.load(Ordering::SeqCst), offset);
+            }
+            (entry.frame_offset, entry.frame_tar_offset) =
+                (Some(shard.frame_start.0), Some(shard.frame_start.1));
+        }
+        let tarb = &mut shard.tarb;
+
+        // Checksums are of the plaintext, so they match the extracted file.
+        let data: Box<dyn Read + '_> = match self.hasher {
+            Some(ref hasher) => Box::new(hasher.reader(file)),
+            None => Box::new(file),
+        };
+        let data: Box<dyn Read + '_> = match data_key {
+            Some(ref data_key) => Box::new(EncryptingReader::new(data, data_key, meta.len())),
+            None => data,
+        };
+        let records = extra_records.iter()
+                                   .map(|(key, value)| (key.as_str(), value.as_slice()))
+                                   .collect::<Vec<_>>();
+        self.tar_format.append_with_records(tarb, &mut header, &rel_path, data, &records)?;
+        if self.remove_source_files {
+            self.archived_sources.push(ArchivedSource {
+                path: job.path.clone(),
+                tar_size: header.entry_size()?,
+                tar_path: rel_path,
+                meta,
+            });
+        }
+
+        match self.hasher {
+            Some(ref hasher) => hasher.end_file(entry)?,
+            None => self.pending_entries.push(entry),
+        }
+
+        Ok(())
+    }
+
+    fn archive_file_name(&self) -> String {
+        let name = match self.bucket {
+            Some((ref bucket, num)) =>
+                self.name_template.render(Some(bucket), num, self.codec.extension()),
+            None => self.name_template.render(None, self.archive_num, self.codec.extension()),
+        };
+        match self.encryption {
+            Some(_) => format!("{name}.{ext}", ext = shard_encryption::EXTENSION),
+            None => name,
+        }
+    }
+
+    fn out_path(&self) -> PathBuf {
+        self.out_dir.join(self.archive_file_name())
+    }
+
+    /// Where the current archive is written until it's complete and synced.
+    fn partial_path(&self) -> PathBuf {
+        self.out_dir.join(format!("{name}{suffix}", name = self.archive_file_name(),
+                                  suffix = archive_set::PARTIAL_SUFFIX))
+    }
+
+    fn shard(&mut self) -> Result<&mut OpenShard> {
+        if let Some(ref mut shard) = self.shard {
+            return Ok(shard);
+        }
+
+        let output = match self.store {
+            Some(ref store) => ArchiveOutput::Upload(store.create(&self.archive_file_name())?),
+            None if self.stdout => {
+                ArchiveOutput::Stdout(BufWriter::with_capacity(128 * 1024, io::stdout()))
+            },
+            None => {
+                let path = self.partial_path();
+                let file = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&*path)?;
+                let file = RetryWriter::new(file, self.io_retry, &path);
+                ArchiveOutput::File(BufWriter::with_capacity(128 * 1024, file))
+            },
+        };
+        let output = LimitedWriter::new(output, self.write_limiter.clone());
+        let encw = match self.encryption {
+            Some(ref encryption) => encryption.writer(output)?,
+            None => EncryptingWriter::Plain(output),
+        };
+        let (mut compressed_progw, compressed_bytes) = ProgressWriter::new(encw);
+        let output_busy = self.adaptive_level.then(|| compressed_progw.time_writes());
+        let mut encoder = self.codec.encoder(compressed_progw, self.level,
+                                             self.dictionary.as_ref())?;
+        if let (Some(output_busy), Some(level)) = (output_busy, self.level) {
+            encoder = encoder.with_adaptive_level(AdaptiveLevel::new(level, output_busy));
+        }
+        if let Some(window_log) = self.long_window_log {
+            encoder = encoder.with_long(window_log)?;
+        }
+        if self.zstd_workers > 1 {
+            encoder = encoder.with_zstd_workers(self.zstd_workers)?;
+        }
+        encoder = encoder.with_zstd_checksum(self.zstd_checksum)?;
+        let (uncompressed_progw, uncompressed_bytes) = ProgressWriter::new(encoder);
+        let tarb = tar::Builder::new(uncompressed_progw);
+
+        Ok(self.shard.insert(OpenShard {
+            tarb,
+            compressed_bytes,
+            uncompressed_bytes,
+            frame_start: (0, 0),
+        }))
+    }
+
+    /// Finish the current archive and continue with a new archive number.
+    fn roll_over(&mut self) -> Result<()> {
+        self.finish()?;
+        if let Some((_, ref mut num)) = self.bucket {
+            *num += 1;
+            return Ok(());
+        }
+        let prev_archive_num = self.archive_num;
+        self.archive_num = self.next_archive_num.fetch_add(1, Ordering::SeqCst);
+        tracing::debug!(prev_archive_num, archive_num = self.archive_num,
+                        "ShardWriter rolled over to a new archive");
+        Ok(())
+    }
+
+    /// Finish the current archive and continue with the first archive of `bucket`.
+    fn start_bucket(&mut self, bucket: String) -> Result<()> {
+        self.finish()?;
+        tracing::debug!(bucket, "ShardWriter started bucket");
+        self.bucket = Some((bucket, 0));
+        Ok(())
+    }
+
+    /// Delete the current archive, if one was started, leaving it out of the manifest.
+    fn discard(&mut self) -> Result<()> {
+        let Some(shard) = self.shard.take() else {
+            return Ok(());
+        };
+        if self.store.is_some() {
+            // Finish the stream to get at the upload and abort it, so no parts are left
+            // stored.
+            if let ArchiveOutput::Upload(upload) = shard.finish()? {
+                upload.abort()?;
+            }
+        } else if self.stdout {
+            // What's written can't be taken back; leave the stream unterminated, so
+            // readers see it's incomplete.
+            drop(shard);
+        } else {
+            drop(shard);
+            fs::remove_file(self.partial_path())?;
+        }
+        match self.hasher {
+            Some(ref hasher) => hasher.discard_archive()?,
+            None => self.pending_entries.clear(),
+        }
+        self.archived_sources.clear();
+
+        tracing::debug!(archive_num = self.archive_num, "ShardWriter discarded archive");
+        self.progress.event(format!("Discarded archive {name}",
+                                    name = self.archive_file_name()));
+
+        Ok(())
+    }
+
+    /// Finish writing the current archive, if one was started.
+    fn finish(&mut self) -> Result<()> {
+        let Some(mut shard) = self.shard.take() else {
+            return Ok(());
+        };
+
+        let compressed_bytes = shard.compressed_bytes.load(Ordering::SeqCst);
+        let level = shard.tarb.get_mut().get_mut().level();
+        if self.adaptive_level {
+            self.level = level;
+        }
+        match shard.finish()? {
+            ArchiveOutput::File(bufw) => {
+                let file = bufw.into_inner()
+                               .map_err(|err| err.into_error())?
+                               .into_inner();
+                file.sync_all()?;
+                drop(file);
+                fs::rename(self.partial_path(), self.out_path())?;
+                // Sync the directory so the rename is durable too.
+                File::open(&*self.out_dir)?.sync_all()?;
+            },
+            ArchiveOutput::Upload(upload) => upload.finish()
+                .with_context(|| format!("uploading '{}'", self.archive_file_name()))?,
+            ArchiveOutput::Stdout(bufw) => {
+                bufw.into_inner().map_err(|err| err.into_error())?.flush()?;
+            },
+        }
+
+        // Only now the archive is durable can the manifest refer to it.
+        let entries = match self.hasher {
+            Some(ref hasher) => hasher.end_archive()?,
+            None => std::mem::take(&mut self.pending_entries),
+        };
+        if let Some(ref manifest_tx) = self.manifest_tx {
+            for entry in entries {
+                if manifest_tx.send(entry).is_err() {
+                    bail!("Manifest writer stopped");
+                }
+            }
+        }
+
+        self.compressed_bytes += compressed_bytes;
+        self.archives.push(self.archive_file_name());
+        if self.remove_source_files {
+            self.check_sources()?;
+        }
+
+        tracing::debug!(archive_num = self.archive_num, compressed_bytes, level,
+                        "ShardWriter finished archive");
+        self.progress.event(format!("Finished archive {name}",
+                                    name = self.archive_file_name()));
+        self.progress.notify(|| ProgressEvent::ArchiveFinished {
+            name: self.archive_file_name(),
+            compressed_bytes,
+        });
+
+        Ok(())
+    }
+
+    /// Keep the files of the archive just finished, with `--remove-source-files`, to
+    /// delete at the end of the run, after checking it holds them with
+    /// `--verify-before-remove`.
+    fn check_sources(&mut self) -> Result<()> {
+        let sources = std::mem::take(&mut self.archived_sources);
+        if self.verify_before_remove {
+            self.verify_archive(&sources).with_context(|| {
+                format!("verifying '{}' before removing its files", self.archive_file_name())
+            })?;
+        }
+        self.finished_sources.extend(sources);
+        Ok(())
+    }
+
+    /// Read the finished archive back, checking it decodes and holds each of `sources`
+    /// at its archived size.
+    fn verify_archive(&self, sources: &[ArchivedSource]) -> Result<()> {
+        let mut expected = sources.iter()
+                                  .map(|source| (source.tar_path.as_path(), source.tar_size))
+                                  .collect::<HashMap<_, _>>();
+        let archive = archive_set::open(&self.out_path(), None, self.dictionary.as_ref())?
+            .context("Not an archive")?;
+        let mut tar = tar::Archive::new(archive.reader);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let size = entry.size();
+            let read = io::copy(&mut entry, &mut io::sink())?;
+            if read == size && expected.get(&*path) == Some(&size) {
+                expected.remove(&*path);
+            }
+        }
+        if let Some(path) = expected.keys().next() {
+            bail!("The archive is missing '{}', or it's the wrong size, and {} other files",
+                  path.display(), expected.len() - 1);
+        }
+        Ok(())
+    }
+}
+
+impl CompressOptions {
+    pub fn new(in_path: impl Into<PathBuf>, out_dir: impl Into<PathBuf>) -> CompressOptions {
+        CompressOptions::from_args(
+            crate::default_cmd_args(&[("in-path", in_path.into().into_os_string()),
+                                      ("out-dir", out_dir.into().into_os_string())]),
+            crate::default_threads())
+    }
+
+    fn from_args(args: Args, threads: usize) -> CompressOptions {
+        CompressOptions {
+            args,
+            callback: None,
+            cancel: None,
+            cancel_policy: CancelPolicy::default(),
+            passphrase: None,
+            threads,
+        }
+    }
+
+    /// Compress, writing the archives, manifest and status file to the output directory.
+    pub fn run(self) -> Result<Report> {
+        let run = status::Run::start("compress");
+        let out_dir = self.args.out_dir.clone();
+        let res = compress(self);
+        if let Some(out_dir) = out_dir {
+            run.finish(&out_dir, &res,
+                       res.as_ref().map_or(0, |report| report.compressed_bytes));
+        }
+        res
+    }
+
+    /// Stop walking and archiving when `token` is cancelled, treating the archives being
+    /// written according to `policy`. The run then returns an error.
+    pub fn cancellation(mut self, token: CancellationToken, policy: CancelPolicy) -> Self {
+        self.cancel = Some(token);
+        self.cancel_policy = policy;
+        self
+    }
+
+    /// Call `callback` with progress as files are archived. It's called from the worker
+    /// threads, so should return quickly.
+    pub fn progress_callback(mut self, callback: ProgressCallback) -> Self {
+        self.callback = Some(callback.into());
+        self
+    }
+
+    /// Number of walker threads, which is also the number of shards. Defaults to the
+    /// number of CPUs.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Number of walker threads, overriding `threads()`, like `--walk-threads`.
+    pub fn walk_threads(mut self, threads: usize) -> Self {
+        self.args.walk_threads = Some(threads);
+        self
+    }
+
+    /// Number of shards, overriding `threads()`, like `--shards`.
+    pub fn compress_threads(mut self, threads: usize) -> Self {
+        self.args.compress_threads = Some(threads);
+        self
+    }
+
+    pub fn max_shard_size(mut self, bytes: u64) -> Self {
+        self.args.max_shard_size = Some(bytes);
+        self
+    }
+
+    pub fn shard_size_mode(mut self, mode: ShardSizeMode) -> Self {
+        self.args.shard_size_mode = mode;
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.args.codec = codec;
+        self
+    }
+
+    /// Fail if zstd can't compress on its own thread, like `--require-zstd-mt`.
+    pub fn require_zstd_mt(mut self, require: bool) -> Self {
+        self.args.require_zstd_mt = require;
+        self
+    }
+
+    /// zstd worker threads compressing each shard, like `--zstd-workers`.
+    pub fn zstd_workers(mut self, workers: u32) -> Self {
+        self.args.zstd_workers = workers;
+        self
+    }
+
+    /// Whether to end each zstd frame with a checksum, like `--zstd-checksum`, the
+    /// default, and `--no-zstd-checksum`.
+    pub fn zstd_checksum(mut self, checksum: bool) -> Self {
+        self.args.zstd_checksum = checksum;
+        self.args.no_zstd_checksum = !checksum;
+        self
+    }
+
+    pub fn tar_format(mut self, tar_format: TarFormat) -> Self {
+        self.args.tar_format = tar_format;
+        self
+    }
+
+    /// Record extended attributes, like `--xattrs`.
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.args.xattrs = xattrs;
+        self
+    }
+
+    /// Record POSIX ACLs, like `--acls`.
+    pub fn acls(mut self, acls: bool) -> Self {
+        self.args.acls = acls;
+        self
+    }
+
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.args.checksums = checksums;
+        self
+    }
+
+    pub fn hash(mut self, algorithm: hasher::Algorithm) -> Self {
+        self.args.hash = algorithm;
+        self
+    }
+
+    /// Rewrite archived paths with a sed substitution, like `--transform`. May be called
+    /// again, to apply each in turn.
+    pub fn transform(mut self, expr: impl Into<String>) -> Self {
+        self.args.transform.push(expr.into());
+        self
+    }
+
+    /// Add a gitignore-style glob of files to include.
+    pub fn include(mut self, glob: impl Into<String>) -> Self {
+        self.args.include.push(glob.into());
+        self
+    }
+
+    /// Add a gitignore-style glob of files and directories to exclude.
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.args.exclude.push(glob.into());
+        self
+    }
+
+    /// Like `--exclude-vcs`: skip version control metadata, e.g. `.git`.
+    pub fn exclude_vcs(mut self, exclude_vcs: bool) -> Self {
+        self.args.exclude_vcs = exclude_vcs;
+        self
+    }
+
+    /// Add a MIME type glob of files to skip, e.g. `video/*`.
+    pub fn exclude_content_type(mut self, mime_type: impl Into<String>) -> Self {
+        self.args.exclude_content_type.push(mime_type.into());
+        self
+    }
+
+    /// Like `--newer-mtime`: only archive files modified at or after `time`.
+    pub fn newer_mtime(mut self, time: SystemTime) -> Self {
+        self.args.newer_mtime = Some(MtimeCutoff::At(time::OffsetDateTime::from(time)
+                                                         .unix_timestamp()));
+        self
+    }
+
+    /// Like `--min-size` and `--max-size`: only archive regular files of sizes in this
+    /// range.
+    pub fn size_range(mut self, min_size: Option<u64>, max_size: Option<u64>) -> Self {
+        self.args.min_size = min_size;
+        self.args.max_size = max_size;
+        self
+    }
+
+    pub fn respect_gitignore(mut self, respect_gitignore: bool) -> Self {
+        self.args.respect_gitignore = respect_gitignore;
+        self
+    }
+
+    pub fn skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.args.skip_hidden = skip_hidden;
+        self
+    }
+
+    /// Like `--exclude-caches`: skip directories holding a valid `CACHEDIR.TAG`.
+    pub fn exclude_caches(mut self, exclude_caches: bool) -> Self {
+        self.args.exclude_caches = exclude_caches;
+        self
+    }
+
+    /// Like `--honor-nodump`: skip files and directories with the `nodump` attribute.
+    pub fn honor_nodump(mut self, honor_nodump: bool) -> Self {
+        self.args.honor_nodump = honor_nodump;
+        self
+    }
+
+    /// Skip directories holding a file named `name`, like `--exclude-if-present`.
+    pub fn exclude_if_present(mut self, name: impl Into<String>) -> Self {
+        self.args.exclude_if_present.push(name.into());
+        self
+    }
+
+    /// Archive `in_path` too, like repeating `--in-path`.
+    pub fn add_in_path(mut self, in_path: impl Into<PathBuf>) -> Self {
+        self.args.in_path.push(in_path.into());
+        self
+    }
+
+    /// Like `--one-file-system`: don't descend into directories on other filesystems.
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.args.one_file_system = one_file_system;
+        self
+    }
+
+    /// Like `--max-depth`: only archive files at most `depth` levels below the input.
+    pub fn max_depth(mut self, depth: u32) -> Self {
+        self.args.max_depth = Some(depth);
+        self
+    }
+
+    /// Like `--files-from` and `--null`: archive the files listed in `list` rather than
+    /// walking the input directory.
+    pub fn files_from(mut self, list: impl Into<PathBuf>, null: bool) -> Self {
+        self.args.files_from = Some(list.into());
+        self.args.null = null;
+        self
+    }
+
+    /// Like `--require-empty`: fail before writing anything if the output directory
+    /// holds files.
+    pub fn require_empty(mut self, require_empty: bool) -> Self {
+        self.args.require_empty = require_empty;
+        self
+    }
+
+    /// Like `--force`: delete an earlier run's files from the output directory first.
+    pub fn force(mut self, force: bool) -> Self {
+        self.args.force = force;
+        self
+    }
+
+    pub fn manifest_format(mut self, format: manifest::Format) -> Self {
+        self.args.manifest_format = format;
+        self
+    }
+
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.args.error_policy = error_policy;
+        self
+    }
+
+    /// Write the errors with files to `path` once the run ends, like `--error-report`.
+    pub fn error_report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.error_report = Some(path.into());
+        self
+    }
+
+    /// Compress at `level`, like `--level`.
+    pub fn level(mut self, level: Level) -> Self {
+        self.args.level = Some(level);
+        self
+    }
+
+    /// Compress files smaller than `threshold` bytes at zstd level `small` and larger
+    /// files at level `large`, like `--level-policy`.
+    pub fn level_policy(mut self, small: i32, large: i32, threshold: u64) -> Self {
+        self.args.level_policy = Some(LevelPolicy { small, large, threshold });
+        self
+    }
+
+    /// Enable zstd's long-distance matching with a `2^window_log` byte window, like
+    /// `--long`.
+    pub fn long(mut self, window_log: u32) -> Self {
+        self.args.long = Some(window_log);
+        self
+    }
+
+    /// Compress with the zstd dictionary in the file at `path`, like `--dict`.
+    pub fn dict(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.dict = Some(path.into());
+        self
+    }
+
+    /// Only archive files new or changed since the manifest at `path`, like
+    /// `--since-manifest`. Call again for each later increment, oldest first.
+    pub fn since_manifest(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.since_manifest.push(path.into());
+        self
+    }
+
+    pub fn compare_checksums(mut self, compare_checksums: bool) -> Self {
+        self.args.compare_checksums = compare_checksums;
+        self
+    }
+
+    /// Treat paths longer than `bytes` as too long, like `--max-path-len`.
+    pub fn max_path_len(mut self, bytes: usize) -> Self {
+        self.args.max_path_len = Some(bytes);
+        self
+    }
+
+    pub fn long_path_policy(mut self, policy: LongPathPolicy) -> Self {
+        self.args.long_path_policy = policy;
+        self
+    }
+
+    /// Walk the whole input before archiving, like `--pre-scan`.
+    pub fn pre_scan(mut self, pre_scan: bool) -> Self {
+        self.args.pre_scan = pre_scan;
+        self
+    }
+
+    /// Group files into archives by modification time, like `--bucket-by`.
+    pub fn bucket_by(mut self, bucket_by: BucketBy) -> Self {
+        self.args.bucket_by = Some(bucket_by);
+        self
+    }
+
+    /// Name archives from `template`, like `--name-template`.
+    pub fn name_template(mut self, template: impl Into<String>) -> Self {
+        self.args.names.name_template = Some(template.into());
+        self
+    }
+
+    /// Prefix archive names, like `--prefix`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.args.names.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Encrypt archives to `spec`, an `age:<RECIPIENT>` like `--encrypt`. Call again to
+    /// add more recipients.
+    pub fn encrypt(mut self, spec: impl Into<String>) -> Self {
+        self.args.encrypt.push(spec.into());
+        self
+    }
+
+    /// Encrypt archives with `passphrase`, like `--passphrase`.
+    pub fn passphrase(mut self, passphrase: SecretString) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    /// Sign the archive set with the key in `path`, like `--sign-key`.
+    pub fn sign_key(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.sign_key = Some(path.into());
+        self
+    }
+
+    /// Like `--hdd-mode`: walk sequentially and read at most `reads_per_device` files at
+    /// a time from each device.
+    pub fn hdd_mode(mut self, reads_per_device: usize) -> Self {
+        self.args.hdd_mode = Some(reads_per_device);
+        self
+    }
+
+    /// Like `--seekable`: start a new zstd frame about every `frame_size` bytes of tar
+    /// stream, recording each file's frame in the manifest.
+    pub fn seekable(mut self, frame_size: u64) -> Self {
+        self.args.seekable = Some(frame_size);
+        self
+    }
+
+    /// Split files larger than `chunk_size` into chunks compressed in parallel, like
+    /// `--chunk-size`.
+    pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+        self.args.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Like `--remove-source-files` and `--verify-before-remove`: delete each archived
+    /// file once the run's archives and manifest are durable, optionally after reading
+    /// each archive back.
+    pub fn remove_source_files(mut self, remove: bool, verify: bool) -> Self {
+        self.args.remove_source_files = remove;
+        self.args.verify_before_remove = remove && verify;
+        self
+    }
+
+    /// Like `--latency-guard`: read fewer files at once while the 99th percentile read
+    /// latency is over `threshold`.
+    pub fn latency_guard(mut self, threshold: Duration) -> Self {
+        self.args.latency_guard =
+            Some(u64::try_from(threshold.as_millis()).unwrap_or(u64::MAX));
+        self
+    }
+
+    /// Retry I/O that fails with transient errors `retries` times, first after `delay`,
+    /// like `--io-retries` and `--io-retry-delay`.
+    pub fn io_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.args.io_retry.io_retries = retries;
+        self.args.io_retry.io_retry_delay =
+            u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+        self
+    }
+
+    /// Read at most `rate` bytes per second, like `--limit-read-rate`.
+    pub fn limit_read_rate(mut self, rate: u64) -> Self {
+        self.args.rate.limit_read_rate = Some(rate);
+        self
+    }
+
+    /// Write at most `rate` bytes per second, like `--limit-write-rate`.
+    pub fn limit_write_rate(mut self, rate: u64) -> Self {
+        self.args.rate.limit_write_rate = Some(rate);
+        self
+    }
+
+    /// Like `--out -`: write a single archive to stdout. The output directory then only
+    /// holds the manifest and status file.
+    pub fn stdout(mut self) -> Self {
+        self.args.out = Some("-".to_string());
+        self
+    }
+}
+
+impl OpenShard {
+    /// Finish writing the archive's streams, returning where it was written.
+    fn finish(self) -> Result<ArchiveOutput> {
+        // tarb.into_inner() finishes writing the tar archive.
+        let encoder = self.tarb.into_inner()?.into_inner();
+        Ok(encoder.finish()?.into_inner().finish()?.into_inner())
+    }
+}
+
+impl Write for ArchiveOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveOutput::File(w) => w.write(buf),
+            ArchiveOutput::Upload(w) => w.write(buf),
+            ArchiveOutput::Stdout(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveOutput::File(w) => w.flush(),
+            ArchiveOutput::Upload(w) => w.flush(),
+            ArchiveOutput::Stdout(w) => w.flush(),
+        }
+    }
+}
+
+impl ModeOverride {
+    fn apply(self, mode: u32) -> u32 {
+        if mode & 0o111 != 0 { self.exec_mode } else { self.mode }
+    }
+}
+
+/// Parse `--out`, which only supports stdout for now.
+fn parse_out(s: &str) -> Result<String> {
+    ensure!(s == "-", "Only '-', for stdout, is supported; use --out-dir to write files");
+    Ok(s.to_string())
+}
+
+/// Parse an RFC 3339 time, or a duration with a unit suffix, e.g. `7d`.
+fn parse_mtime_cutoff(s: &str) -> Result<MtimeCutoff> {
+    let unit = match s.as_bytes().last() {
+        Some(b's') => 1,
+        Some(b'm') => 60,
+        Some(b'h') => 60 * 60,
+        Some(b'd') => 24 * 60 * 60,
+        Some(b'w') => 7 * 24 * 60 * 60,
+        _ => 0,
+    };
+    if let Ok(n) = s[..s.len().saturating_sub(1)].parse::<u64>() {
+        if unit > 0 {
+            return Ok(MtimeCutoff::Ago(n.checked_mul(unit)
+                .with_context(|| format!("Duration '{s}' is too long"))?));
+        }
+    }
+    let t = time::OffsetDateTime::parse(s, &time::format_description::well_known::Rfc3339)
+        .with_context(|| format!("'{s}' is neither an RFC 3339 time nor a duration such \
+                                  as '7d'"))?;
+    Ok(MtimeCutoff::At(t.unix_timestamp()))
+}
+
+/// Parse `<MODE>` or `<MODE>/<EXEC_MODE>`, both in octal.
+fn parse_mode_override(s: &str) -> Result<ModeOverride> {
+    let parse = |m: &str| -> Result<u32> {
+        let mode = u32::from_str_radix(m, 8)
+            .with_context(|| format!("Mode '{m}' is not octal"))?;
+        ensure!(mode <= 0o7777, "Mode '{m}' is larger than 07777");
+        Ok(mode)
+    };
+    let (mode, exec_mode) = match s.split_once('/') {
+        Some((mode, exec_mode)) => (parse(mode)?, parse(exec_mode)?),
+        None => (parse(s)?, parse(s)?),
+    };
+    Ok(ModeOverride { mode, exec_mode })
+}
+
+/// Parse a zstd level, or `auto`.
+fn parse_level(s: &str) -> Result<Level> {
+    if s == "auto" {
+        return Ok(Level::Auto);
+    }
+    Ok(Level::Fixed(s.parse().with_context(|| format!("Invalid level '{s}'"))?))
+}
+
+/// Parse `small=<LEVEL>,large=<LEVEL>,threshold=<SIZE>`, in any order.
+fn parse_level_policy(s: &str) -> Result<LevelPolicy> {
+    let (mut small, mut large, mut threshold) = (None, None, None);
+    for part in s.split(',') {
+        let (key, value) = part.split_once('=')
+            .with_context(|| format!("Expected KEY=VALUE, got '{part}'"))?;
+        let parse_level = || value.trim().parse::<i32>()
+            .with_context(|| format!("Invalid level '{value}'"));
+        match key.trim() {
+            "small" => small = Some(parse_level()?),
+            "large" => large = Some(parse_level()?),
+            "threshold" => threshold = Some(size::parse(value)?),
+            _ => bail!("Unknown level policy key '{key}'"),
+        }
+    }
+    Ok(LevelPolicy {
+        small: small.context("Level policy is missing 'small'")?,
+        large: large.context("Level policy is missing 'large'")?,
+        threshold: threshold.context("Level policy is missing 'threshold'")?,
+    })
+}
+
+/// Upper bound on the bytes a file of length `len` adds to an uncompressed tar archive:
+/// its header, a possible long name header, and its data padded to a 512 byte block.
+fn tar_entry_size_estimate(len: u64) -> u64 {
+    3 * 512 + len.div_ceil(512) * 512
+}
+
+/// The archive size limit that splits the `current` bytes in the open archive and the
+/// `remaining` bytes still to come in the same run into as few archives under `max` as
+/// possible, of about equal size, rather than filling each to `max` and leaving a small
+/// last archive.
+fn balanced_max(max: u64, current: u64, remaining: Option<u64>) -> u64 {
+    let Some(remaining) = remaining else {
+        return max;
+    };
+    let total = current + remaining;
+    let count = total.div_ceil(max).max(1);
+    total.div_ceil(count).min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::{Fixture, TempDir, TreeSpec, ensure_trees_equal};
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn run_outputs() {
+        let pattern = NameArgs::default().pattern().unwrap();
+        let is_run_output = |name| is_run_output(name, &pattern);
+        for name in ["00000001.tar.zstd", "00000001.tar.zstd.partial", "00000012.tar.age",
+                     "00000001.tar.gz.blake3", "2023-04.00000000.tar.xz", "manifest.jsonl"] {
+            assert!(is_run_output(name), "{name}");
+        }
+        for name in ["notes.txt", "0001.tar.zstd", "00000001.zip", "2023-04.tar.zstd",
+                     "a.b.00000000.tar", "00000001.tarball", "ptar-status.json"] {
+            assert!(!is_run_output(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn removes_orphans() {
+        let input = TempDir::new("ptar-test").unwrap();
+        fs::write(input.path().join("a"), "a").unwrap();
+        let out = TempDir::new("ptar-test").unwrap();
+        let orphans = ["manifest.jsonl.tmp", "ptar-status.json.tmp",
+                       "00000000.tar.zstd.partial", "00000000.tar.zstd.blake3"];
+        let others = ["notes.txt", "notes.tmp", "download.partial"];
+        for name in orphans.iter().chain(&others) {
+            fs::write(out.path().join(name), "crashed").unwrap();
+        }
+        let compress = || CompressOptions::new(input.path(), out.path()).run();
+
+        // The run that held the lock can't be confirmed to have crashed.
+        let lock_path = out.path().join(run_lock::LOCK_FILE_NAME);
+        fs::write(&lock_path, "1 elsewhere\n").unwrap();
+        assert!(compress().is_err());
+        assert!(out.path().join("manifest.jsonl.tmp").exists());
+        fs::remove_file(out.path().join("manifest.jsonl")).unwrap();
+
+        // It ran here, and has exited.
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let crashed = || fs::write(&lock_path, format!("{} {}\n", child.id(),
+                                                     run_lock::host_name())).unwrap();
+        crashed();
+        assert!(CompressOptions::new(input.path(), out.path()).require_empty(true)
+                    .run().is_err());
+        assert!(out.path().join("manifest.jsonl.tmp").exists());
+
+        crashed();
+        compress().unwrap();
+        for name in orphans {
+            assert!(!out.path().join(name).exists(), "{name}");
+        }
+        for name in others {
+            assert!(out.path().join(name).exists(), "{name}");
+        }
+        assert!(out.path().join("00000000.tar.zstd").exists());
+    }
+
+    #[test]
+    fn files_from() {
+        let input = TempDir::new("ptar-test").unwrap();
+        fs::create_dir(input.path().join("dir")).unwrap();
+        for name in ["a.txt", "dir/b.txt", "c.txt", "new\nline.txt", "unlisted.txt"] {
+            fs::write(input.path().join(name), name).unwrap();
+        }
+        let lists = TempDir::new("ptar-test").unwrap();
+        let archived = |list: &[u8], null| {
+            let list_path = lists.path().join(if null { "list0" } else { "list" });
+            fs::write(&list_path, list).unwrap();
+            let out = TempDir::new("ptar-test").unwrap();
+            CompressOptions::new(input.path(), out.path())
+                .files_from(list_path, null)
+                .run()
+                .unwrap();
+            let mut paths = manifest::read(out.path()).unwrap().into_iter()
+                .map(|entry| entry.path)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        // Absolute paths inside the input directory are archived at their relative paths.
+        let mut list = b"a.txt\ndir/b.txt\n\n".to_vec();
+        list.extend(input.path().join("c.txt").as_os_str().as_bytes());
+        list.push(b'\n');
+        assert_eq!(archived(&list, false), ["a.txt", "c.txt", "dir/b.txt"].map(PathBuf::from));
+
+        // Listed directories aren't archived, nor the files in them.
+        assert_eq!(archived(b"new\nline.txt\0a.txt\0dir\0", true),
+                   ["a.txt", "new\nline.txt"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn removes_source_files() {
+        let spec = TreeSpec { files: 30, max_file_size: 4096, ..TreeSpec::default() };
+        for verify in [false, true] {
+            let fixture = Fixture::build(&spec, |options| {
+                options.threads(2).max_shard_size(16_000).remove_source_files(true, verify)
+            }).unwrap();
+            for path in fixture.files.iter() {
+                assert!(!fixture.input.path().join(path).exists(), "{}", path.display());
+            }
+            assert_eq!(manifest::read(fixture.archives.path()).unwrap().len(),
+                       fixture.files.len());
+
+            let expected = TempDir::new("ptar-test").unwrap();
+            spec.generate(expected.path()).unwrap();
+            ensure_trees_equal(expected.path(), fixture.extract().unwrap().path()).unwrap();
+        }
+    }
+
+    #[test]
+    fn keeps_changed_source_files() {
+        let dir = TempDir::new("ptar-test").unwrap();
+        let sources = ["same", "changed"].map(|name| {
+            let path = dir.path().join(name);
+            fs::write(&path, "archived").unwrap();
+            ArchivedSource {
+                meta: fs::symlink_metadata(&path).unwrap(),
+                tar_path: PathBuf::from(name),
+                tar_size: 8,
+                path,
+            }
+        });
+        fs::write(dir.path().join("changed"), "changed since").unwrap();
+
+        let error_report = ErrorReport::default();
+        assert_eq!(remove_sources(sources.into(), &error_report), 0);
+        assert!(!dir.path().join("same").exists());
+        assert!(dir.path().join("changed").exists());
+    }
+
+    #[test]
+    fn mtime_cutoffs() {
+        assert_eq!(parse_mtime_cutoff("7d").unwrap(), MtimeCutoff::Ago(7 * 24 * 60 * 60));
+        assert_eq!(parse_mtime_cutoff("90s").unwrap(), MtimeCutoff::Ago(90));
+        assert_eq!(parse_mtime_cutoff("2023-05-01T00:00:00Z").unwrap(),
+                   MtimeCutoff::At(1682899200));
+        assert!(parse_mtime_cutoff("7").is_err());
+        assert!(parse_mtime_cutoff("d").is_err());
+        assert!(parse_mtime_cutoff("").is_err());
+    }
+
+    #[test]
+    fn mode_override() {
+        let m = parse_mode_override("0644/0755").unwrap();
+        assert_eq!(m.apply(0o4755), 0o755);
+        assert_eq!(m.apply(0o600), 0o644);
+        assert_eq!(m.apply(0o100), 0o755);
+
+        let m = parse_mode_override("600").unwrap();
+        assert_eq!(m.apply(0o755), 0o600);
+
+        assert!(parse_mode_override("0648").is_err());
+        assert!(parse_mode_override("17777").is_err());
+        assert!(parse_mode_override("0644/").is_err());
+    }
+
+    #[test]
+    fn multiple_walk_roots() {
+        let (prefix, roots) = walk_roots(&["/usr/bin".into(), "/usr/lib".into()]).unwrap();
+        assert_eq!(prefix, Path::new("/usr"));
+        assert_eq!(roots, ["/usr/bin", "/usr/lib"].map(PathBuf::from));
+        let (prefix, _) = walk_roots(&["/etc".into(), "/usr/./lib".into()]).unwrap();
+        assert_eq!(prefix, Path::new("/"));
+        assert!(walk_roots(&["/usr".into(), "/usr/lib".into()]).is_err());
+        assert!(walk_roots(&["/etc".into(), "/etc".into()]).is_err());
+    }
+
+    #[test]
+    fn buckets() {
+        // 2023-04-30T23:59:59Z and a second later.
+        assert_eq!(BucketBy::MtimeMonth.bucket_of(1682899199), "2023-04");
+        assert_eq!(BucketBy::MtimeMonth.bucket_of(1682899200), "2023-05");
+        assert_eq!(BucketBy::MtimeYear.bucket_of(1682899200), "2023");
+        assert_eq!(BucketBy::MtimeMonth.bucket_of(-1), "1969-12");
+    }
+
+    #[test]
+    fn balanced_sizes() {
+        assert_eq!(balanced_max(10, 0, None), 10);
+        assert_eq!(balanced_max(10, 0, Some(21)), 7);
+        assert_eq!(balanced_max(10, 7, Some(14)), 7);
+        assert_eq!(balanced_max(10, 0, Some(20)), 10);
+        assert_eq!(balanced_max(10, 3, Some(2)), 5);
+        assert_eq!(balanced_max(10, 0, Some(0)), 0);
+    }
+
+    #[test]
+    fn level_policy() {
+        assert_eq!(parse_level_policy("small=12,large=3,threshold=64MiB").unwrap(),
+                   LevelPolicy { small: 12, large: 3, threshold: 64 << 20 });
+        assert_eq!(parse_level_policy("threshold=1000, large=-1, small=19").unwrap(),
+                   LevelPolicy { small: 19, large: -1, threshold: 1000 });
+
+        assert!(parse_level_policy("small=12,large=3").is_err());
+        assert!(parse_level_policy("small=12,large=3,threshold=1,medium=6").is_err());
+        assert!(parse_level_policy("small=high,large=3,threshold=1").is_err());
     }
 }
@@ -0,0 +1,59 @@
+//! Selecting files by the MIME type detected from their first bytes, for inputs whose
+//! file names don't say what they contain.
+
+use crate::Result;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+/// Matches files whose detected MIME type matches any of a set of globs, e.g. `video/*`.
+///
+/// Files whose type isn't recognised never match.
+pub struct ContentTypeFilter {
+    globs: GlobSet,
+}
+
+/// Bytes read from the start of each file to detect its type. Enough for every
+/// signature `infer` knows except a few container formats, which are then unknown.
+const SNIFF_LEN: u64 = 8192;
+
+impl ContentTypeFilter {
+    pub fn new(patterns: &[String]) -> Result<ContentTypeFilter> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern)?);
+        }
+        Ok(ContentTypeFilter {
+            globs: builder.build()?,
+        })
+    }
+
+    pub fn is_match_type(&self, mime_type: &str) -> bool {
+        self.globs.is_match(mime_type)
+    }
+
+    /// Read the start of the file at `path` and match its type.
+    pub fn is_match_file(&self, path: &Path) -> io::Result<bool> {
+        let mut buf = Vec::with_capacity(SNIFF_LEN as usize);
+        File::open(path)?.take(SNIFF_LEN).read_to_end(&mut buf)?;
+        Ok(infer::get(&buf).is_some_and(|t| self.is_match_type(t.mime_type())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContentTypeFilter;
+
+    #[test]
+    fn mime_globs() {
+        let filter = ContentTypeFilter::new(&["video/*".to_string(),
+                                              "application/zip".to_string()]).unwrap();
+        assert!(filter.is_match_type("video/mp4"));
+        assert!(filter.is_match_type("application/zip"));
+        assert!(!filter.is_match_type("application/gzip"));
+        assert!(!filter.is_match_type("image/png"));
+    }
+}
@@ -1,78 +1,1427 @@
-use crate::{ProgressReader, Result, ThreadOffloadReader};
+use age::secrecy::SecretString;
+use anyhow::{anyhow, bail, ensure, Context};
+use crate::{
+    Result, acls, archive_set, manifest,
+    archive_name::NameArgs,
+    cancel::{self, CancellationToken, Cancelled},
+    codec::Dictionary,
+    compress::ErrorPolicy,
+    entry_encryption::{DecryptingReader, MasterKey},
+    error_report::{self, ErrorReport},
+    extract_path::{self, ExtractPaths},
+    file_chunk, owners,
+    io_retry::{RetryArgs, RetryWriter},
+    path_filter::PathFilter,
+    progress::{self, ProgressCallback, ProgressEvent},
+    shard_encryption::{self, DecryptionKeys},
+    status_socket::StatusServer,
+    stream_writer,
+    tar_format,
+    quota::{self, QuotaCheck},
+    rate_limit::{LimitedReader, RateArgs},
+    remote::{self, RemoteArgs, Store, StoredFile},
+    run_lock::RunLock,
+    special_files,
+    xattrs,
+};
 use rayon::prelude::*;
 use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+    fmt,
     fs::{self, File},
-    // io::BufReader,
-    path::PathBuf,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::{self, ffi::OsStrExt, fs::{OpenOptionsExt, PermissionsExt}},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use valuable::Valuable;
 
 #[derive(clap::Args, Clone, Debug, Valuable)]
 pub struct Args {
-    #[arg(long)]
-    in_dir: PathBuf,
+    #[arg(long, required_unless_present_any = ["in_url", "in_stream"])]
+    in_dir: Option<PathBuf>,
+
+    /// Read a single archive from stdin instead, with `--in -`, e.g. at the receiving end
+    /// of `compress --out -`. Archives concatenated into one stream are all extracted.
+    ///
+    /// There's no manifest, so `--entry-key-file` and `--quota-check` can't be used.
+    #[arg(long = "in", value_name = "-", value_parser = parse_in,
+          conflicts_with_all = ["in_dir", "in_url", "resume", "entry_key_file",
+                                "name_template", "prefix"])]
+    in_stream: Option<String>,
+
+    /// Read the archive set from this URL, e.g. `s3://bucket/prefix/` or
+    /// `sftp://user@host/path`, as written by
+    /// `compress --out-url`, instead of from `--in-dir`.
+    #[arg(long, value_name = "URL", conflicts_with = "in_dir")]
+    in_url: Option<String>,
+
+    #[command(flatten)]
+    remote: RemoteArgs,
+
+    #[command(flatten)]
+    names: NameArgs,
+
     #[arg(long)]
     out_dir: PathBuf,
+
+    /// Only extract entries matching this glob, or under a directory matching it.
+    /// May be repeated.
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Skip entries matching this glob, or under a directory matching it.
+    /// May be repeated, and takes precedence over `--include`.
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    #[command(flatten)]
+    unpack: UnpackArgs,
+
+    /// Create character and block devices and FIFOs archived with
+    /// `compress --special-files`. Only root can create devices, so otherwise they're
+    /// skipped, and counted in a warning, as all special files are without this.
+    #[arg(long)]
+    special_files: bool,
+
+    /// What to do when an entry can't be extracted, e.g. it's corrupt, or its file can't
+    /// be written.
+    ///
+    /// With `keep-going` such entries are logged and skipped, and decompress still exits
+    /// with an error once it has extracted everything else. A corrupt tar header ends
+    /// its archive, as the entries after it can't be found.
+    #[arg(long, value_enum, default_value_t = ErrorPolicy::FailFast)]
+    error_policy: ErrorPolicy,
+
+    /// Short for `--error-policy keep-going`.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Write each entry that failed to extract to this file once the run ends, with its
+    /// path, the operation that failed, its OS error number and its message. Written as
+    /// JSON, or as CSV if the name ends in `.csv`.
+    #[arg(long, value_name = "PATH")]
+    error_report: Option<PathBuf>,
+
+    /// With `--same-owner`, check each user's disk quota on the destination can hold
+    /// their files, using the manifest, before extracting anything.
+    #[arg(long, value_enum, default_value_t = QuotaCheck::Warn)]
+    quota_check: QuotaCheck,
+
+    /// Archives extracted concurrently. Defaults to `--threads`.
+    #[arg(long, value_name = "N")]
+    extract_threads: Option<usize>,
+
+    #[command(flatten)]
+    rate: RateArgs,
+
+    /// Show a progress bar on stderr, or log progress if stderr isn't a terminal.
+    /// Progress is measured in compressed bytes read out of the total archive sizes.
+    #[arg(long)]
+    progress: bool,
+
+    /// Serve progress and recent events as JSON to each client connecting to the
+    /// abstract Unix socket with this name, e.g. `socat - ABSTRACT-CONNECT:<NAME>`.
+    #[arg(long, value_name = "NAME")]
+    status_socket: Option<String>,
+
+    /// Decrypt files compressed with `--entry-key-file`, using the same master key file.
+    /// Files whose wrapped key was deleted from the manifest are skipped.
+    #[arg(long, value_name = "KEY_FILE")]
+    entry_key_file: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--encrypt` using the age identities (secret
+    /// keys) in this file, e.g. one written by `age-keygen`.
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    identity: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--passphrase`. The passphrase is read from the
+    /// `PTAR_PASSPHRASE` environment variable if set, or else prompted for.
+    #[arg(long, visible_alias = "passphrase-prompt")]
+    passphrase: bool,
+
+    /// Decompress archives compressed with `--dict`, using the same zstd dictionary.
+    #[arg(long, value_name = "DICT_FILE")]
+    dict: Option<PathBuf>,
+
+    /// File recording which archives have been fully extracted, saved after each one
+    /// and deleted once every archive is. Defaults to `ptar-decompress-state.json` in
+    /// `--out-dir`.
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Skip archives the state file records as extracted, if they're unchanged since,
+    /// to continue a run that crashed or was interrupted.
+    #[arg(long)]
+    resume: bool,
+}
+
+/// How extracted files' paths, owners, permissions and other metadata are restored, for
+/// each command that extracts files.
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct UnpackArgs {
+    /// Remove this many leading components from each entry's path, e.g. 1 to extract
+    /// `project-1.2/src/main.rs` to `src/main.rs`. Entries with no more components than
+    /// this are skipped. `--include` and `--exclude` match the unstripped paths.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub(crate) strip_components: usize,
+
+    /// Rewrite each entry's path with this sed substitution, e.g. `s,^old/,new/,`, before
+    /// `--strip-components`. May be repeated, to apply each in turn. The regex uses the
+    /// `regex` crate's syntax, and the replacement may refer to groups with `\1`; see
+    /// `compress --help` for details. Entries whose paths become empty are skipped.
+    #[arg(long, visible_alias = "xform", value_name = "EXPR")]
+    pub(crate) transform: Vec<String>,
+
+    /// Extract entries with absolute paths at those paths, e.g. from archives made with
+    /// `tar --absolute-names`, rather than rejecting them. Only for trusted archives, as
+    /// they can then write anywhere.
+    #[arg(long)]
+    pub(crate) allow_absolute_paths: bool,
+
+    /// Restore file owners from the archive: the users and groups with the archived
+    /// names if they exist on this system, or else the archived ids. Also restores
+    /// setuid, setgid and sticky bits. Usually requires running as root.
+    #[arg(long, overrides_with = "no_same_owner")]
+    pub(crate) same_owner: bool,
+
+    /// Leave extracted files owned by the user running ptar; the default, and overrides an
+    /// earlier `--same-owner`.
+    #[arg(long, overrides_with = "same_owner")]
+    pub(crate) no_same_owner: bool,
+
+    /// With `--same-owner`, restore the archived numeric user and group ids, ignoring the
+    /// owner names recorded with them.
+    #[arg(long)]
+    pub(crate) numeric_owner: bool,
+
+    /// Apply the umask to archived permissions, as for newly created files, rather than
+    /// restoring them as archived.
+    #[arg(long)]
+    pub(crate) no_permissions: bool,
+
+    /// Restore modification times from the archive, to the nanosecond from pax archives;
+    /// the default, and overrides an earlier `--touch`.
+    #[arg(long, overrides_with = "touch")]
+    pub(crate) preserve_mtime: bool,
+
+    /// Don't restore modification times, so extracted files are modified at the time of
+    /// extraction, e.g. so build systems see them as newer than earlier build outputs.
+    #[arg(long, overrides_with = "preserve_mtime")]
+    pub(crate) touch: bool,
+
+    /// Restore extended attributes recorded by `compress --xattrs`. Setting `security.*`
+    /// attributes, e.g. file capabilities, usually requires running as root.
+    #[arg(long, overrides_with = "no_xattrs")]
+    pub(crate) xattrs: bool,
+
+    /// Don't restore extended attributes; the default, and overrides an earlier
+    /// `--xattrs`.
+    #[arg(long, overrides_with = "xattrs")]
+    pub(crate) no_xattrs: bool,
+
+    /// Restore POSIX ACLs recorded by `compress --acls`. The destination filesystem must
+    /// support them.
+    #[arg(long, overrides_with = "no_acls")]
+    pub(crate) acls: bool,
+
+    /// Don't restore ACLs; the default, and overrides an earlier `--acls`.
+    #[arg(long, overrides_with = "acls")]
+    pub(crate) no_acls: bool,
+
+    #[command(flatten)]
+    pub(crate) io_retry: RetryArgs,
+}
+
+/// Decompress progress, saved after each archive.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+struct State {
+    /// The run's `--include` and `--exclude` globs. Resuming with different ones would
+    /// leave out entries of the archives already extracted.
+    include: Vec<String>,
+    exclude: Vec<String>,
+    /// Keyed by archive file name.
+    archives: BTreeMap<String, ExtractedArchive>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct ExtractedArchive {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: u32,
+}
+
+pub const DEFAULT_STATE_FILE_NAME: &str = "ptar-decompress-state.json";
+
+/// Where the archive set is read from.
+enum Source<'a> {
+    Dir(&'a Path),
+    /// With `--in-url`.
+    Store(Arc<dyn Store>),
+    /// With `--in -`, or `DecompressOptions::reader`: a single archive, named `-`.
+    Stdin,
+}
+
+/// Data keys for an archive set compressed with `--entry-key-file`.
+pub(crate) struct EntryKeys {
+    pub(crate) master_key: MasterKey,
+    /// Wrapped data key of each entry path, from the manifest.
+    pub(crate) wrapped: HashMap<PathBuf, String>,
+}
+
+/// Where extracted entries go, and how their owners, permissions and other metadata are
+/// restored.
+pub(crate) struct UnpackOptions {
+    pub(crate) paths: ExtractPaths,
+    /// With `--same-owner`.
+    owners: Option<owners::Ids>,
+    /// With `--no-permissions`, the umask, cleared from archived modes.
+    mask: Option<u32>,
+    /// Unless `--touch`.
+    mtime: bool,
+    pub(crate) xattrs: bool,
+    pub(crate) acls: bool,
+    /// Retries of writes to extracted files, with `--io-retries`.
+    io_retry: RetryArgs,
+    /// Files reassembled from chunks whose modes lack owner write permission, which is
+    /// kept until every chunk is written, with their modes to set then.
+    read_only_chunked: Mutex<Vec<(PathBuf, u32)>>,
+}
+
+/// Options for decompressing with ptar as a library. Defaults match the command line's.
+///
+/// ```no_run
+/// let report = ptar::DecompressOptions::new("/backup", "/restore")
+///     .include("home/alice")
+///     .run()?;
+/// println!("Extracted {} archives", report.archive_count);
+/// # Ok::<(), ptar::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct DecompressOptions {
+    args: Args,
+    callback: Option<progress::Callback>,
+    cancel: Option<CancellationToken>,
+    /// Set with `--passphrase`. Kept out of `args` so it's never logged.
+    passphrase: Option<SecretString>,
+    /// Read instead of stdin, when set with `reader()`.
+    reader: Option<StreamReader>,
+    threads: usize,
+}
+
+/// The archive stream passed to `DecompressOptions::reader`, shared by clones of the
+/// options. Only one run can read it.
+#[derive(Clone)]
+struct StreamReader(Arc<Mutex<Option<Box<dyn Read + Send>>>>);
+
+/// Summary of a successful decompress run.
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub archive_count: u64,
+    /// Total size of the archives read.
+    pub compressed_bytes: u64,
+    /// Total size of the tar streams read from the archives.
+    pub uncompressed_bytes: u64,
+    /// Devices and FIFOs not created, as `--special-files` wasn't given, or for devices,
+    /// this process isn't root.
+    pub skipped_special_count: u64,
+    /// Entries not extracted as their paths were unsafe, e.g. absolute, containing `..`,
+    /// or through a symlink out of the output directory.
+    pub rejected_count: u64,
+    pub duration: Duration,
 }
 
 pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
-    let mut archive_paths = Vec::<PathBuf>::with_capacity(args.threads + 1);
+    let cancel = CancellationToken::new();
+    cancel.cancel_on_signals()?;
+    let passphrase = cmd_args.passphrase.then(|| shard_encryption::read_passphrase(false))
+                                        .transpose()?;
+    let mut options = DecompressOptions::from_args(cmd_args, args.threads())
+        .cancellation(cancel);
+    if let Some(passphrase) = passphrase {
+        options = options.passphrase(passphrase);
+    }
+    options.run().map(|_report| ())
+}
+
+fn decompress(options: DecompressOptions) -> Result<Report> {
+    let DecompressOptions { args: cmd_args, callback, cancel, passphrase, reader, threads } =
+        options;
+    let start = Instant::now();
+    let filter = PathFilter::new(&cmd_args.include, &cmd_args.exclude)?;
 
-    for entry in fs::read_dir(&*cmd_args.in_dir)? {
-        let entry = entry?;
-        if !entry.file_type()?.is_file() {
-            continue;
+    fs::create_dir_all(&*cmd_args.out_dir)?;
+    let unpack = UnpackOptions::new(&cmd_args.out_dir, &cmd_args.unpack)?;
+
+    let state_path = cmd_args.state_file.clone()
+        .unwrap_or_else(|| cmd_args.out_dir.join(DEFAULT_STATE_FILE_NAME));
+    let state = if cmd_args.resume && state_path.exists() {
+        let json = fs::read_to_string(&*state_path)?;
+        let state: State = serde_json::from_str(&json)
+            .with_context(|| format!("parsing state file '{}'", state_path.display()))?;
+        ensure!(state.include == cmd_args.include && state.exclude == cmd_args.exclude,
+                "Can't resume with different --include or --exclude globs from the \
+                 interrupted run's: include={include:?} exclude={exclude:?}",
+                include = state.include, exclude = state.exclude);
+        state
+    } else {
+        State {
+            include: cmd_args.include.clone(),
+            exclude: cmd_args.exclude.clone(),
+            archives: BTreeMap::new(),
         }
-        if !lazy_regex!(".tar.zstd$").is_match(&*entry.file_name().to_string_lossy()) {
-            continue;
+    };
+
+    let source = match (&cmd_args.in_url, &cmd_args.in_stream, &cmd_args.in_dir) {
+        (Some(url), _, _) => Source::Store(remote::open(url, &cmd_args.remote)?),
+        (None, Some(_), _) => Source::Stdin,
+        (None, None, Some(in_dir)) => Source::Dir(in_dir),
+        (None, None, None) => bail!("Pass --in-dir, --in-url or --in -"),
+    };
+    // Held until the run ends, so a compress can't write to the set while it's read.
+    let _lock = match source {
+        Source::Dir(in_dir) => RunLock::shared(in_dir)?,
+        _ => None,
+    };
+    let mut candidates = match source {
+        Source::Dir(in_dir) => archive_set::candidate_files(in_dir)?,
+        Source::Store(ref store) => store.list()?,
+        Source::Stdin => vec![StoredFile {
+            name: "-".to_string(),
+            size: 0,
+            modified: SystemTime::now(),
+        }],
+    };
+    // Other runs' archives may share the directory or store.
+    if cmd_args.names.is_set() {
+        let pattern = cmd_args.names.pattern()?;
+        candidates.retain(|file| pattern.is_match(&file.name));
+    }
+
+    let mut archive_files = Vec::new();
+    let mut skipped_count = 0_usize;
+    for file in candidates {
+        if cmd_args.resume {
+            let (mtime, mtime_nsec) = manifest::unix_time(file.modified);
+            if let Some(e) = state.archives.get(&file.name) {
+                if e.size == file.size && e.mtime == mtime && e.mtime_nsec == mtime_nsec {
+                    tracing::debug!(name = file.name, "Skipping archive already extracted");
+                    skipped_count += 1;
+                    continue;
+                }
+            }
         }
-        archive_paths.push(entry.path());
+        archive_files.push(file);
     }
+    let state = Mutex::new(state);
 
-    archive_paths.sort();
+    let manifest_entries = match source {
+        Source::Dir(in_dir) => manifest::read(in_dir),
+        Source::Store(ref store) => remote::read_manifest(&**store, &cmd_args.names),
+        Source::Stdin => Err(anyhow!("There's no manifest reading from stdin")),
+    };
 
-    tracing::debug!(len = archive_paths.len(), ?archive_paths, "Enumerated archive paths");
+    if cmd_args.unpack.same_owner && cmd_args.quota_check != QuotaCheck::Off {
+        match manifest_entries {
+            Ok(ref entries) => {
+                let ids = owners::Ids::new(cmd_args.unpack.numeric_owner);
+                quota::check(cmd_args.quota_check, &cmd_args.out_dir,
+                             &quota::bytes_by_uid(entries, &filter, &ids))?
+            },
+            Err(ref err) => tracing::warn!(%err, "Error reading manifest, skipping quota check"),
+        }
+    }
+
+    // Streams written in parts by `StreamWriter` need each part appended. Without a
+    // manifest to tell, check every entry.
+    let has_parts = matches!(source, Source::Stdin)
+        || manifest_entries.as_ref()
+                           .is_ok_and(|entries| entries.iter().any(|e| e.parts.is_some()));
+    // Likewise files split into chunks by `compress --chunk-size`.
+    let has_chunks = matches!(source, Source::Stdin)
+        || manifest_entries.as_ref()
+                           .is_ok_and(|entries| entries.iter().any(|e| e.chunk_offset.is_some()));
+
+    let entry_keys = match cmd_args.entry_key_file {
+        Some(ref key_file) => {
+            let entries = manifest_entries
+                .context("--entry-key-file requires the manifest, which has the data keys")?;
+            Some(EntryKeys {
+                master_key: MasterKey::load(key_file)?,
+                wrapped: entries.into_iter()
+                                .filter_map(|e| Some((e.path, e.wrapped_key?)))
+                                .collect(),
+            })
+        },
+        None => {
+            let is_encrypted = manifest_entries.as_ref()
+                .is_ok_and(|entries| entries.iter().any(|e| e.wrapped_key.is_some()));
+            ensure!(!is_encrypted, "The archive set is encrypted; pass --entry-key-file");
+            None
+        },
+    };
+
+    let keys = DecryptionKeys::new(cmd_args.identity.as_deref(), passphrase)?;
+    let dictionary = cmd_args.dict.as_deref().map(Dictionary::load).transpose()?;
+
+    tracing::debug!(len = archive_files.len(),
+                    archive_names = ?archive_files.iter().map(|f| &f.name).collect::<Vec<_>>(),
+                    "Enumerated archives");
+
+    let keep_going = cmd_args.keep_going || cmd_args.error_policy == ErrorPolicy::KeepGoing;
+    let error_count = AtomicU64::new(0);
+    let error_report = ErrorReport::default();
+    let (read_limiter, write_limiter) = (cmd_args.rate.read_limiter(),
+                                         cmd_args.rate.write_limiter());
+    let skipped_special_count = AtomicU64::new(0);
+    let progress = Arc::new(progress::Counters::with_callback(callback));
+    for file in archive_files.iter() {
+        progress.total_bytes.fetch_add(file.size, Ordering::Relaxed);
+    }
+    progress.total_files.store(archive_files.len() as u64, Ordering::Relaxed);
+    let reporter = if cmd_args.progress {
+        Some(progress::Reporter::spawn(progress.clone(), "archives")?)
+    } else {
+        None
+    };
+    let status_server = match cmd_args.status_socket {
+        Some(ref name) => Some(StatusServer::spawn(name, "decompress", progress.clone())?),
+        None => None,
+    };
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(args.threads)
+    let res = rayon::ThreadPoolBuilder::new()
+        .num_threads(cmd_args.extract_threads.unwrap_or(threads))
         .build()?
         .install(|| -> Result<()> {
-            archive_paths
+            archive_files
                 .into_par_iter()
                 .with_max_len(1) // 1 item per thread
-                .try_for_each(|archive_path: PathBuf| -> Result<()> {
+                .try_for_each(|archive_file: StoredFile| -> Result<()> {
+                    cancel::check(cancel.as_ref())?;
                     let _thread_span = tracing::debug_span!(
                         "decompress thread",
-                        archive_file_name = &*archive_path.file_name()
-                            .expect("archive_path.file_name().is_some()")
-                            .to_string_lossy()
+                        archive_file_name = &*archive_file.name
                     ).entered();
 
-                    let file_read = File::open(&*archive_path)?;
+                    let stream: Box<dyn Read + Send> = match source {
+                        Source::Dir(in_dir) => Box::new(File::open(
+                            in_dir.join(&archive_file.name))?),
+                        Source::Store(ref store) => store.open(&archive_file.name)?,
+                        Source::Stdin => match reader {
+                            Some(ref reader) => reader.take()?,
+                            None => Box::new(io::stdin()),
+                        },
+                    };
+                    let stream = Box::new(LimitedReader::new(stream, read_limiter.clone()));
+                    let Some(archive) = archive_set::open_stream(stream, keys.as_ref(),
+                                                                 dictionary.as_ref())? else {
+                        ensure!(!matches!(source, Source::Stdin),
+                                "stdin isn't a recognised archive");
+                        tracing::debug!(name = archive_file.name,
+                                        "Skipping file that isn't a recognised archive");
+                        progress.remove_file(archive_file.size);
+                        return Ok(());
+                    };
+                    tracing::debug!(codec = ?archive.codec, "Detected archive codec");
+                    progress.add_archive(archive.compressed_bytes.clone(),
+                                         archive.uncompressed_bytes.clone());
 
-                    let (source_prog_read, _source_bytes_read) = ProgressReader::new(file_read);
+                    // The tar stream's bytes are about those written to extracted files.
+                    let reader = LimitedReader::new(archive.reader, write_limiter.clone());
+                    let mut tar = tar::Archive::new(reader);
+                    // Read past the end of each archive concatenated on stdin.
+                    tar.set_ignore_zeros(matches!(source, Source::Stdin));
+                    // Each entry is extracted here rather than by `tar::Archive::unpack`,
+                    // which would extract special files as regular files, and stop at the
+                    // first entry that fails. Skipped entries are still read through, as
+                    // the archive is a stream.
+                    let mut archive_error_count = 0;
+                    // Record `err` from `operation` on the entry at `path`, and under
+                    // `--error-policy keep-going`, log and count it rather than returning
+                    // it.
+                    let mut entry_failed = |path: &Path, operation, err: anyhow::Error|
+                    -> Result<()>
+                    {
+                        if err.is::<Cancelled>() {
+                            return Err(err);
+                        }
+                        error_report.record(path, operation, &err);
+                        if !keep_going {
+                            return Err(err);
+                        }
+                        tracing::warn!(archive_file_name = &*archive_file.name,
+                                       err = format!("{err:#}"),
+                                       "Skipping entry that failed to extract");
+                        archive_error_count += 1;
+                        Ok(())
+                    };
+                    for entry in tar.entries()? {
+                        cancel::check(cancel.as_ref())?;
+                        let mut entry = match entry {
+                            Ok(entry) => entry,
+                            Err(err) => {
+                                entry_failed(Path::new(&archive_file.name), "read_header",
+                                             anyhow!(err).context("reading a tar header"))?;
+                                tracing::warn!(archive_file_name = &*archive_file.name,
+                                               "Skipping the rest of the archive, as the \
+                                                entries after a corrupt header can't be \
+                                                found");
+                                break;
+                            },
+                        };
+                        // Closure to catch errors with `?`.
+                        let res = (|| -> Result<()> {
+                            if !filter.is_match(&entry.path()?) {
+                                return Ok(());
+                            }
+                            if special_files::is_special_entry(entry.header().entry_type()) {
+                                return unpack_special(&mut entry, &cmd_args, &unpack,
+                                                      &skipped_special_count);
+                            }
+                            if has_parts && append_part(&mut entry, &unpack)? {
+                                return Ok(());
+                            }
+                            if has_chunks && unpack_chunk(&mut entry, &unpack)? {
+                                return Ok(());
+                            }
+                            match entry_keys {
+                                Some(ref keys) => keys.unpack(&mut entry, &unpack),
+                                None => unpack_in(&mut entry, &unpack),
+                            }
+                        })();
+                        if let Err(err) = res {
+                            let path = entry.path().map(|p| p.into_owned()).unwrap_or_default();
+                            let err = err.context(format!("extracting '{}'", path.display()));
+                            entry_failed(&path, "extract", err)?;
+                        }
+                    }
 
-                    let zstd_decoder = zstd::stream::read::Decoder::new(source_prog_read)?;
+                    // Archives with errors are extracted again on `--resume`.
+                    error_count.fetch_add(archive_error_count, Ordering::Relaxed);
+                    if archive_error_count == 0 && !matches!(source, Source::Stdin) {
+                        record_extracted(&state, &state_path, &archive_file)?;
+                    }
 
-                    let (uncompressed_prog_read, _uncompresed_bytes_read) =
-                        ProgressReader::new(zstd_decoder);
+                    let archives_done = progress.done_files.fetch_add(1, Ordering::Relaxed) + 1;
+                    progress.event(format!("Extracted archive {}", archive_file.name));
+                    progress.notify(|| ProgressEvent::ArchiveExtracted {
+                        name: archive_file.name.clone(),
+                        archives_done,
+                        bytes_done: progress.snapshot().done_bytes,
+                    });
+                    Ok(())
+                })?;
+            Ok(())
+        });
+    // Written even if extracting failed, to list the error it failed with.
+    if let Some(ref report_path) = cmd_args.error_report {
+        error_report.write(report_path)?;
+    }
+    res?;
+    unpack.finish_chunked()?;
 
-                    let _out_capacity = zstd::stream::read::Decoder::<'_, std::io::Empty>
-                        ::recommended_output_size();
-                    // let uncompressed_bufread = BufReader::with_capacity(out_capacity,
-                    //                                                     uncompressed_prog_read);
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+    if let Some(status_server) = status_server {
+        status_server.finish();
+    }
 
-                    let uncompressed_thread_offload_read =
-                        ThreadOffloadReader::new(uncompressed_prog_read);
+    let error_count = error_count.load(Ordering::SeqCst);
+    if error_count == 0 {
+        // Every archive is extracted, so there's nothing left to resume.
+        match fs::remove_file(&*state_path) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    if cmd_args.resume {
+        tracing::info!(skipped_count, "Resumed, skipping archives already extracted");
+    }
+    let skipped_special_count = skipped_special_count.load(Ordering::SeqCst);
+    if skipped_special_count > 0 {
+        tracing::warn!(skipped_special_count,
+                       "Skipped special files: they're only created with --special-files, \
+                        and devices only as root");
+    }
 
-                    let mut tar = tar::Archive::new(uncompressed_thread_offload_read);
-                    // let mut tar = tar::Archive::new(uncompressed_bufread);
-                    tar.unpack(&*cmd_args.out_dir)?;
+    let rejected_count = unpack.paths.rejected_count();
+    if rejected_count > 0 {
+        tracing::warn!(rejected_count, "Rejected entries with unsafe paths, logged above");
+    }
+    ensure!(error_count == 0, "Errors in decompress() count={error_count}{listed}",
+            listed = error_report::listed_in(cmd_args.error_report.as_deref()));
 
-                    Ok(())
-                })?;
-            Ok(())
-        })?;
+    let snap = progress.snapshot();
+    Ok(Report {
+        archive_count: snap.done_files,
+        compressed_bytes: snap.done_bytes,
+        uncompressed_bytes: snap.uncompressed_bytes.unwrap_or(0),
+        skipped_special_count,
+        rejected_count,
+        duration: start.elapsed(),
+    })
+}
 
+/// Record in the state file that `archive` has been extracted.
+fn record_extracted(state: &Mutex<State>, state_path: &Path, archive: &StoredFile)
+-> Result<()>
+{
+    let (mtime, mtime_nsec) = manifest::unix_time(archive.modified);
+    let mut state = state.lock().expect("state lock");
+    state.archives.insert(archive.name.clone(),
+                          ExtractedArchive {
+                              size: archive.size,
+                              mtime,
+                              mtime_nsec,
+                          });
+    // Write then rename so an interruption never leaves a partial file.
+    let mut tmp_path = state_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(&*state)?)?;
+    fs::rename(&tmp_path, state_path)?;
     Ok(())
 }
+
+/// If `entry` is a part after the first of a stream written by `StreamWriter`, append
+/// it to the file extracted from the earlier parts and return true.
+pub(crate) fn append_part<R: Read>(entry: &mut tar::Entry<R>, unpack: &UnpackOptions)
+-> Result<bool>
+{
+    if !stream_writer::is_part(entry)? {
+        return Ok(false);
+    }
+
+    let Some(dst) = unpack.paths.existing(&entry.path()?)? else {
+        return Ok(true);
+    };
+    // Not following a symlink, which can't be where the first part was extracted.
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&dst)
+        .with_context(|| format!("opening '{}' to append a part", dst.display()))?;
+    io::copy(entry, &mut RetryWriter::new(&mut file, unpack.io_retry, &dst))?;
+    drop(file);
+    unpack.set_mtime(entry, &dst)?;
+    Ok(true)
+}
+
+/// If `entry` is a chunk of a file split by `compress --chunk-size`, write it into the
+/// file at its offset, creating the file for the first chunk extracted, and return true.
+///
+/// Chunks may be extracted in any order, by any thread, so the file's metadata is set
+/// after each one.
+pub(crate) fn unpack_chunk<R: Read>(entry: &mut tar::Entry<R>, unpack: &UnpackOptions)
+-> Result<bool>
+{
+    let Some(chunk) = file_chunk::read(entry)? else {
+        return Ok(false);
+    };
+
+    let Some(dst) = unpack.paths.prepare(&entry.path()?)? else {
+        return Ok(true);
+    };
+    let mode = unpack.mode(entry.header())?;
+    // Writable by the owner, so later chunks can be written.
+    let writable_mode = mode | 0o200;
+    // Not truncated, as other chunks may already be written, and not following a
+    // symlink, which `prepare()` removed.
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .mode(writable_mode)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&dst)
+        .with_context(|| format!("opening '{}' to write a chunk", dst.display()))?;
+    file.set_len(chunk.file_size)?;
+    file.seek(SeekFrom::Start(chunk.offset))?;
+    io::copy(entry, &mut RetryWriter::new(&mut file, unpack.io_retry, &dst))?;
+
+    if let Some((uid, gid)) = unpack.owner(entry.header())? {
+        unix::fs::fchown(&file, Some(uid), Some(gid))
+            .with_context(|| format!("setting the owner of '{}' to {uid}:{gid}",
+                                     dst.display()))?;
+    }
+    // After creating the file, which applies the umask, and changing the owner, which
+    // clears the setuid and setgid bits.
+    file.set_permissions(fs::Permissions::from_mode(writable_mode))?;
+    drop(file);
+    if writable_mode != mode {
+        unpack.read_only_chunked.lock().expect("read_only_chunked lock")
+                                .push((dst.clone(), mode));
+    }
+
+    // After changing the owner, which clears file capabilities.
+    if unpack.xattrs {
+        xattrs::apply(entry, &dst)?;
+    }
+    if unpack.acls {
+        acls::apply(entry, &dst)?;
+    }
+    unpack.set_mtime(entry, &dst)?;
+    Ok(true)
+}
+
+/// Create the device or FIFO `entry` in the output directory with `--special-files`, or
+/// else count it as skipped.
+fn unpack_special<R: Read>(entry: &mut tar::Entry<R>, cmd_args: &Args, unpack: &UnpackOptions,
+                          skipped: &AtomicU64)
+-> Result<()>
+{
+    let path = entry.path()?.into_owned();
+    if cmd_args.special_files {
+        let Some(dst) = unpack.paths.prepare(&path)? else {
+            return Ok(());
+        };
+        if special_files::create(entry, &dst, unpack)? {
+            return Ok(());
+        }
+    }
+    let reason = if cmd_args.special_files {
+        "only root can create devices"
+    } else {
+        "special files are only created with --special-files"
+    };
+    tracing::warn!(path = %path.display(), reason, "Skipping special file");
+    skipped.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Extract an unencrypted `entry` into the output directory, restoring its metadata as
+/// `unpack` says.
+pub(crate) fn unpack_in<R: Read>(entry: &mut tar::Entry<R>, unpack: &UnpackOptions)
+-> Result<()>
+{
+    let kind = entry.header().entry_type();
+    if kind.is_pax_global_extensions() || kind.is_pax_local_extensions()
+       || kind.is_gnu_longname() || kind.is_gnu_longlink() {
+        // Metadata for other entries, not files.
+        return Ok(());
+    }
+    let path = entry.path()?.into_owned();
+    let Some(dst) = unpack.paths.prepare(&path)? else {
+        return Ok(());
+    };
+    entry.set_preserve_permissions(unpack.keeps_special_bits());
+    entry.set_mask(unpack.mask.unwrap_or(0));
+    // Set below, with the fractional part.
+    entry.set_preserve_mtime(false);
+    if kind.is_hard_link() {
+        let target = entry.link_name()?
+            .with_context(|| format!("hard link '{}' has no target", path.display()))?;
+        let Some(src) = unpack.paths.hard_link_src(&path, &target)? else {
+            return Ok(());
+        };
+        extract_path::remove_existing(&dst)?;
+        fs::hard_link(&src, &dst)
+            .with_context(|| format!("hard linking '{}' to '{}'", dst.display(),
+                                     src.display()))?;
+    } else if kind.is_file() && unpack.io_retry.is_enabled() {
+        unpack_file(entry, &dst, unpack)?;
+    } else {
+        entry.unpack(&dst)?;
+    }
+
+    unpack.set_owner(entry.header(), &dst)?;
+    // After changing the owner, which clears file capabilities.
+    if unpack.xattrs {
+        xattrs::apply(entry, &dst)?;
+    }
+    if unpack.acls {
+        acls::apply(entry, &dst)?;
+    }
+    unpack.set_mtime(entry, &dst)?;
+    Ok(())
+}
+
+/// Extract the regular file `entry` to `dst`, as `tar::Entry::unpack()` would, retrying
+/// failed writes with `--io-retries`.
+fn unpack_file<R: Read>(entry: &mut tar::Entry<R>, dst: &Path, unpack: &UnpackOptions)
+-> Result<()>
+{
+    extract_path::remove_existing(dst)?;
+    let mut file = File::create_new(dst)
+        .with_context(|| format!("creating '{}'", dst.display()))?;
+    io::copy(entry, &mut RetryWriter::new(&mut file, unpack.io_retry, dst))?;
+    file.set_permissions(fs::Permissions::from_mode(unpack.mode(entry.header())?))?;
+    Ok(())
+}
+
+impl UnpackOptions {
+    /// Options to extract into `out_dir`, which must exist.
+    pub(crate) fn new(out_dir: &Path, args: &UnpackArgs) -> Result<UnpackOptions> {
+        Ok(UnpackOptions {
+            paths: ExtractPaths::new(out_dir, args.strip_components, &args.transform,
+                                     args.allow_absolute_paths)?,
+            owners: args.same_owner.then(|| owners::Ids::new(args.numeric_owner)),
+            mask: args.no_permissions.then(umask),
+            mtime: !args.touch,
+            xattrs: args.xattrs,
+            acls: args.acls,
+            io_retry: args.io_retry,
+            read_only_chunked: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Take owner write permission back from the files reassembled from chunks whose
+    /// modes lack it, once all their chunks are written.
+    pub(crate) fn finish_chunked(&self) -> Result<()> {
+        let read_only = std::mem::take(&mut *self.read_only_chunked.lock()
+                                                 .expect("read_only_chunked lock"));
+        for (path, mode) in read_only {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+                .with_context(|| format!("setting the mode of '{}'", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Whether archived setuid, setgid and sticky bits are restored: only with the owner,
+    /// and not with `--no-permissions`.
+    fn keeps_special_bits(&self) -> bool {
+        self.owners.is_some() && self.mask.is_none()
+    }
+
+    /// The mode to extract a file with `header` with.
+    pub(crate) fn mode(&self, header: &tar::Header) -> Result<u32> {
+        let mode = header.mode()? & if self.keeps_special_bits() { 0o7777 } else { 0o777 };
+        Ok(mode & !self.mask.unwrap_or(0))
+    }
+
+    /// The owner to give a file extracted from `header`, with `--same-owner`.
+    pub(crate) fn owner(&self, header: &tar::Header) -> Result<Option<(u32, u32)>> {
+        self.owners.as_ref().map(|owners| owners.owner(header)).transpose()
+    }
+
+    /// Set the owner of the file extracted from `header` at `path`, with `--same-owner`,
+    /// and then its mode again, as changing the owner clears the setuid and setgid bits.
+    fn set_owner(&self, header: &tar::Header, path: &Path) -> Result<()> {
+        let Some((uid, gid)) = self.owner(header)? else {
+            return Ok(());
+        };
+        unix::fs::lchown(path, Some(uid), Some(gid))
+            .with_context(|| format!("setting the owner of '{}' to {uid}:{gid}",
+                                     path.display()))?;
+        if !header.entry_type().is_symlink() {
+            fs::set_permissions(path, fs::Permissions::from_mode(self.mode(header)?))?;
+        }
+        Ok(())
+    }
+
+    /// Set the mtime of the file extracted from `entry` at `path`, unless `--touch`. Its
+    /// access time is set to now, as GNU tar does.
+    pub(crate) fn set_mtime<R: Read>(&self, entry: &mut tar::Entry<R>, path: &Path)
+    -> Result<()>
+    {
+        if !self.mtime {
+            return Ok(());
+        }
+        let (secs, nsec) = entry_mtime(entry)?;
+        let times = [
+            libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+            libc::timespec { tv_sec: secs, tv_nsec: nsec.into() },
+        ];
+        let path_c = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: path_c is a valid C string and times is an array of 2 timespecs.
+        if unsafe { libc::utimensat(libc::AT_FDCWD, path_c.as_ptr(), times.as_ptr(),
+                                    libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("setting the mtime of '{}'", path.display()));
+        }
+        Ok(())
+    }
+}
+
+/// The mtime of `entry` in seconds and nanoseconds, from its pax `mtime` record if it has
+/// one, which keeps the fractional part, or else its header.
+fn entry_mtime<R: Read>(entry: &mut tar::Entry<R>) -> Result<(i64, u32)> {
+    if let Some(extensions) = entry.pax_extensions()? {
+        let mtime = extensions.filter_map(|e| e.ok())
+                              .filter(|e| e.key_bytes() == b"mtime")
+                              .find_map(|e| tar_format::parse_pax_time(e.value_bytes()));
+        if let Some(mtime) = mtime {
+            return Ok(mtime);
+        }
+    }
+    Ok((entry.header().mtime()?.try_into()?, 0))
+}
+
+/// The process's umask, from `/proc/self/status`. Kernels before 4.7 don't list it there,
+/// so then it's read by setting it and setting it straight back, which races with other
+/// threads creating files.
+fn umask() -> u32 {
+    if let Some(mask) = fs::read_to_string("/proc/self/status").ok()
+                           .and_then(|status| parse_umask(&status)) {
+        return mask;
+    }
+    // SAFETY: umask always succeeds.
+    let mask = unsafe { libc::umask(0o022) };
+    // SAFETY: as above.
+    unsafe { libc::umask(mask) };
+    mask
+}
+
+/// The mask on the `Umask:` line of `/proc/<pid>/status`.
+fn parse_umask(status: &str) -> Option<u32> {
+    status.lines()
+          .find_map(|line| line.strip_prefix("Umask:"))
+          .and_then(|mask| u32::from_str_radix(mask.trim(), 8).ok())
+}
+
+impl EntryKeys {
+    /// Extract `entry` into the output directory, decrypting its contents.
+    pub(crate) fn unpack<R: Read>(&self, entry: &mut tar::Entry<R>, unpack: &UnpackOptions)
+    -> Result<()>
+    {
+        let path = entry.path()?.into_owned();
+        if !entry.header().entry_type().is_file() {
+            // Only regular files' contents are encrypted.
+            unpack_in(entry, unpack)?;
+            return Ok(());
+        }
+        let Some(wrapped) = self.wrapped.get(&path) else {
+            tracing::info!(path = %path.display(),
+                           "Skipping encrypted file with no key in the manifest");
+            return Ok(());
+        };
+        let data_key = self.master_key.unwrap(wrapped, &path)?;
+
+        let Some(dst) = unpack.paths.prepare(&path)? else {
+            return Ok(());
+        };
+
+        let header = entry.header().clone();
+        let size = entry.size();
+        // Closure to catch errors with `?`.
+        let res = (|| -> Result<()> {
+            extract_path::remove_existing(&dst)?;
+            let mut file = File::create_new(&dst)?;
+            io::copy(&mut DecryptingReader::new(&mut *entry, &data_key, size),
+                     &mut RetryWriter::new(&mut file, unpack.io_retry, &dst))?;
+            if let Some((uid, gid)) = unpack.owner(&header)? {
+                unix::fs::fchown(&file, Some(uid), Some(gid))?;
+            }
+            // After changing the owner, which clears the setuid and setgid bits.
+            file.set_permissions(fs::Permissions::from_mode(unpack.mode(&header)?))?;
+            // After changing the owner, which clears file capabilities.
+            if unpack.xattrs {
+                xattrs::apply(entry, &dst)?;
+            }
+            // After setting the mode, which would change the ACL's mask.
+            if unpack.acls {
+                acls::apply(entry, &dst)?;
+            }
+            drop(file);
+            unpack.set_mtime(entry, &dst)?;
+            Ok(())
+        })();
+        if let Err(err) = res {
+            // Don't leave a partly decrypted file, which may not be authentic.
+            let _ = fs::remove_file(&dst);
+            return Err(err.context(format!("decrypting '{}'", path.display())));
+        }
+        Ok(())
+    }
+}
+
+impl DecompressOptions {
+    pub fn new(in_dir: impl Into<PathBuf>, out_dir: impl Into<PathBuf>) -> DecompressOptions {
+        DecompressOptions::from_args(
+            crate::default_cmd_args(&[("in-dir", in_dir.into().into_os_string()),
+                                      ("out-dir", out_dir.into().into_os_string())]),
+            crate::default_threads())
+    }
+
+    fn from_args(args: Args, threads: usize) -> DecompressOptions {
+        DecompressOptions {
+            args,
+            callback: None,
+            cancel: None,
+            passphrase: None,
+            reader: None,
+            threads,
+        }
+    }
+
+    /// Extract every archive in the input directory.
+    pub fn run(self) -> Result<Report> {
+        decompress(self)
+    }
+
+    /// Decrypt entries with the master key in `key_file`, like `--entry-key-file`.
+    pub fn entry_key_file(mut self, key_file: impl Into<PathBuf>) -> Self {
+        self.args.entry_key_file = Some(key_file.into());
+        self
+    }
+
+    /// Decrypt archives with the age identities in `identity_file`, like `--identity`.
+    pub fn identity(mut self, identity_file: impl Into<PathBuf>) -> Self {
+        self.args.identity = Some(identity_file.into());
+        self
+    }
+
+    /// Decompress archives with the zstd dictionary in the file at `path`, like `--dict`.
+    pub fn dict(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.dict = Some(path.into());
+        self
+    }
+
+    /// Decrypt archives with `passphrase`, like `--passphrase`.
+    pub fn passphrase(mut self, passphrase: SecretString) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    /// Stop extracting at the next entry when `token` is cancelled. Files already
+    /// extracted are left in place, and the run returns an error. A later run with
+    /// `resume(true)` skips the archives that were finished.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Call `callback` with progress as archives are extracted. It's called from the
+    /// worker threads, so should return quickly.
+    pub fn progress_callback(mut self, callback: ProgressCallback) -> Self {
+        self.callback = Some(callback.into());
+        self
+    }
+
+    /// Number of archives extracted concurrently. Defaults to the number of CPUs.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Like `--in -`: read a single archive from stdin instead of the input directory.
+    pub fn stdin(mut self) -> Self {
+        self.args.in_dir = None;
+        self.args.in_stream = Some("-".to_string());
+        self
+    }
+
+    /// Like `stdin()`, but read the archive stream from `reader`, e.g. a pipe or socket.
+    pub fn reader(mut self, reader: impl Read + Send + 'static) -> Self {
+        self.reader = Some(StreamReader(Arc::new(Mutex::new(Some(Box::new(reader))))));
+        self.stdin()
+    }
+
+    /// Add a glob of entries to extract.
+    pub fn include(mut self, glob: impl Into<String>) -> Self {
+        self.args.include.push(glob.into());
+        self
+    }
+
+    /// Add a glob of entries to skip.
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.args.exclude.push(glob.into());
+        self
+    }
+
+    /// Skip archives a previous interrupted run finished extracting, like `--resume`.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.args.resume = resume;
+        self
+    }
+
+    /// Remove `n` leading components from entries' paths, like `--strip-components`.
+    pub fn strip_components(mut self, n: usize) -> Self {
+        self.args.unpack.strip_components = n;
+        self
+    }
+
+    /// Rewrite entries' paths with a sed substitution, like `--transform`. May be called
+    /// again, to apply each in turn.
+    pub fn transform(mut self, expr: impl Into<String>) -> Self {
+        self.args.unpack.transform.push(expr.into());
+        self
+    }
+
+    /// Set all the options `UnpackArgs` has, for commands that extract with these.
+    pub(crate) fn unpack_args(mut self, unpack: UnpackArgs) -> Self {
+        self.args.unpack = unpack;
+        self
+    }
+
+    pub fn same_owner(mut self, same_owner: bool) -> Self {
+        self.args.unpack.same_owner = same_owner;
+        self
+    }
+
+    /// With `same_owner(true)`, restore the archived ids, ignoring owner names, like
+    /// `--numeric-owner`.
+    pub fn numeric_owner(mut self, numeric_owner: bool) -> Self {
+        self.args.unpack.numeric_owner = numeric_owner;
+        self
+    }
+
+    /// Restore modification times; the default. `false` leaves extracted files modified
+    /// at the time of extraction, like `--touch`.
+    pub fn preserve_mtime(mut self, preserve_mtime: bool) -> Self {
+        self.args.unpack.touch = !preserve_mtime;
+        self
+    }
+
+    /// Restore archived permissions; the default. `false` applies the umask to them
+    /// instead, like `--no-permissions`.
+    pub fn permissions(mut self, permissions: bool) -> Self {
+        self.args.unpack.no_permissions = !permissions;
+        self
+    }
+
+    /// Restore extended attributes, like `--xattrs`.
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.args.unpack.xattrs = xattrs;
+        self
+    }
+
+    /// Restore POSIX ACLs, like `--acls`.
+    pub fn acls(mut self, acls: bool) -> Self {
+        self.args.unpack.acls = acls;
+        self
+    }
+
+    /// Retry I/O that fails with transient errors `retries` times, first after `delay`,
+    /// like `--io-retries` and `--io-retry-delay`.
+    pub fn io_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.args.unpack.io_retry.io_retries = retries;
+        self.args.unpack.io_retry.io_retry_delay =
+            u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+        self
+    }
+
+    /// Read at most `rate` bytes per second, like `--limit-read-rate`.
+    pub fn limit_read_rate(mut self, rate: u64) -> Self {
+        self.args.rate.limit_read_rate = Some(rate);
+        self
+    }
+
+    /// Write at most `rate` bytes per second, like `--limit-write-rate`.
+    pub fn limit_write_rate(mut self, rate: u64) -> Self {
+        self.args.rate.limit_write_rate = Some(rate);
+        self
+    }
+
+    /// Create devices and FIFOs, like `--special-files`.
+    pub fn special_files(mut self, special_files: bool) -> Self {
+        self.args.special_files = special_files;
+        self
+    }
+
+    /// Whether to skip entries that fail to extract and continue, like `--error-policy`.
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.args.error_policy = error_policy;
+        self
+    }
+
+    /// Write the errors with files to `path` once the run ends, like `--error-report`.
+    pub fn error_report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.error_report = Some(path.into());
+        self
+    }
+
+    pub fn quota_check(mut self, quota_check: QuotaCheck) -> Self {
+        self.args.quota_check = quota_check;
+        self
+    }
+}
+
+impl StreamReader {
+    fn take(&self) -> Result<Box<dyn Read + Send>> {
+        self.0.lock().expect("reader lock").take()
+            .context("The reader has already been read by another run")
+    }
+}
+
+impl fmt::Debug for StreamReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StreamReader")
+    }
+}
+
+/// Parse `--in`, which only supports stdin for now.
+fn parse_in(s: &str) -> Result<String> {
+    ensure!(s == "-", "Only '-', for stdin, is supported; use --in-dir to read files");
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::Codec,
+        testsupport::{Corruption, Fixture, TempDir, TreeSpec, ensure_trees_equal},
+    };
+    use std::os::unix::fs::MetadataExt;
+
+    fn small_tree() -> TreeSpec {
+        TreeSpec {
+            files: 30,
+            max_file_size: 20_000,
+            ..TreeSpec::default()
+        }
+    }
+
+    /// The paths of the entries in the uncompressed tar archive at `path`, and the
+    /// offsets of their headers.
+    fn tar_entries(path: &Path) -> Vec<(PathBuf, u64)> {
+        let mut tar = tar::Archive::new(File::open(path).unwrap());
+        tar.entries().unwrap()
+           .map(|entry| {
+               let entry = entry.unwrap();
+               (entry.path().unwrap().into_owned(), entry.raw_header_position())
+           })
+           .collect()
+    }
+
+    /// Corrupt the checksum of the second header of the archive in `fixture` with the most
+    /// entries, so that entry and those after it can't be read. Returns the archive's
+    /// path and its entries.
+    fn corrupt_second_header(fixture: &Fixture) -> (PathBuf, Vec<(PathBuf, u64)>) {
+        let archives = fixture.archive_paths();
+        assert!(archives.len() > 2, "{archives:?}");
+        let (archive, entries) = archives.into_iter()
+            .map(|archive| {
+                let entries = tar_entries(&archive);
+                (archive, entries)
+            })
+            .max_by_key(|(_, entries)| entries.len())
+            .unwrap();
+        assert!(entries.len() > 1, "{entries:?}");
+        Corruption::FlipByte { offset: entries[1].1 + 148 }.apply(&archive).unwrap();
+        (archive, entries)
+    }
+
+    #[test]
+    fn keep_going_skips_corrupt_entry() {
+        let fixture = Fixture::build(&small_tree(), |options| {
+            options.threads(2).max_shard_size(16_000).codec(Codec::Tar)
+        }).unwrap();
+        let (_, entries) = corrupt_second_header(&fixture);
+        let lost = entries[1..].iter().map(|(path, _)| path).collect::<Vec<_>>();
+
+        assert!(fixture.extract().is_err());
+
+        let out = TempDir::new("ptar-test").unwrap();
+        let err = DecompressOptions::new(fixture.archives.path(), out.path())
+            .error_policy(ErrorPolicy::KeepGoing)
+            .run()
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("count=1"), "{err:#}");
+        for file in fixture.files.iter() {
+            let extracted = out.path().join(file);
+            if lost.contains(&file) {
+                assert!(!extracted.exists(), "{}", file.display());
+            } else {
+                assert_eq!(fs::read(extracted).unwrap(),
+                           fs::read(fixture.input.path().join(file)).unwrap(),
+                           "{}", file.display());
+            }
+        }
+    }
+    #[test]
+    fn resume_skips_extracted_archives() {
+        let fixture = Fixture::build(&small_tree(), |options| {
+            options.threads(2).max_shard_size(16_000).codec(Codec::Tar)
+        }).unwrap();
+        let (archive, entries) = corrupt_second_header(&fixture);
+
+        // The first run extracts every archive but the corrupt one, recording them in the
+        // state file.
+        let out = TempDir::new("ptar-test").unwrap();
+        let options = || DecompressOptions::new(fixture.archives.path(), out.path())
+            .error_policy(ErrorPolicy::KeepGoing);
+        assert!(options().run().is_err());
+        assert!(out.path().join(DEFAULT_STATE_FILE_NAME).exists());
+
+        // A file removed since isn't extracted again, as its archive is skipped.
+        let in_other = fixture.files.iter()
+            .find(|file| !entries.iter().any(|(path, _)| path == *file))
+            .unwrap();
+        fs::remove_file(out.path().join(in_other)).unwrap();
+        // Flipping the byte back repairs the archive.
+        Corruption::FlipByte { offset: entries[1].1 + 148 }.apply(&archive).unwrap();
+
+        let report = options().resume(true).run().unwrap();
+        assert_eq!(report.archive_count, 1);
+        assert!(!out.path().join(DEFAULT_STATE_FILE_NAME).exists());
+        for file in fixture.files.iter() {
+            let extracted = out.path().join(file);
+            if file == in_other {
+                assert!(!extracted.exists(), "{}", file.display());
+            } else {
+                assert_eq!(fs::read(extracted).unwrap(),
+                           fs::read(fixture.input.path().join(file)).unwrap(),
+                           "{}", file.display());
+            }
+        }
+    }
+    #[test]
+    fn strip_components() {
+        let mut tarb = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        tarb.append_data(&mut header, "top", io::empty()).unwrap();
+        for (path, contents) in [("top.txt", "top"), ("top/a.txt", "a"), ("top/sub/b.txt", "b")] {
+            let mut header = tar::Header::new_ustar();
+            header.set_mode(0o644);
+            header.set_size(contents.len() as u64);
+            tarb.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        tarb.append_link(&mut header, "top/sub/link", "top/a.txt").unwrap();
+
+        let archives = TempDir::new("ptar-test").unwrap();
+        fs::write(archives.path().join("00000000.tar"), tarb.into_inner().unwrap()).unwrap();
+        let out = TempDir::new("ptar-test").unwrap();
+        let report = DecompressOptions::new(archives.path(), out.path())
+            .strip_components(1)
+            .run()
+            .unwrap();
+
+        // Entries left with no path are dropped, not rejected.
+        assert_eq!(report.rejected_count, 0);
+        let mut names = fs::read_dir(out.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a.txt", "sub"]);
+        assert_eq!(fs::read_to_string(out.path().join("sub/b.txt")).unwrap(), "b");
+        // The link's target is stripped too.
+        let (a, link) = (fs::metadata(out.path().join("a.txt")).unwrap(),
+                         fs::metadata(out.path().join("sub/link")).unwrap());
+        assert_eq!((a.dev(), a.ino()), (link.dev(), link.ino()));
+        assert_eq!(fs::read_to_string(out.path().join("a.txt")).unwrap(), "a");
+    }
+    #[test]
+    fn reads_stream() {
+        let fixture = Fixture::build(&small_tree(), |options| {
+            options.threads(2).max_shard_size(16_000)
+        }).unwrap();
+        // Archives concatenated, as `compress --out -` writes them.
+        let mut stream = Vec::new();
+        for archive in fixture.archive_paths() {
+            stream.extend(fs::read(archive).unwrap());
+        }
+        let extracted = fixture.extract_with(|options| {
+            options.reader(io::Cursor::new(stream))
+        }).unwrap();
+        ensure_trees_equal(fixture.input.path(), extracted.path()).unwrap();
+    }
+    #[test]
+    fn parses_umask() {
+        assert_eq!(parse_umask("Name:\tptar\nUmask:\t0027\nState:\tR (running)\n"),
+                   Some(0o027));
+        assert_eq!(parse_umask("Name:\tptar\nState:\tR (running)\n"), None);
+    }
+}
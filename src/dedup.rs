@@ -0,0 +1,195 @@
+//! `ptar dedup`: back up a directory as a snapshot in a deduplicating repository, like
+//! borg or restic, storing only the chunks of its files no earlier snapshot has. See
+//! `dedup_repo` for the repository's format, and `dedup-restore` to restore it.
+//!
+//! Files whose size and mtime match the latest snapshot's reuse its chunk list without
+//! being read. Other files are read and chunked by several threads, each compressing
+//! the chunks it finds are new.
+
+use anyhow::{ensure, Context};
+use crate::{
+    Result, manifest, size,
+    dedup_repo::{self, Config, FileEntry, PackWriter, Repo},
+};
+use ignore::WalkBuilder;
+use rayon::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Directory to back up.
+    #[arg(long)]
+    in_path: PathBuf,
+
+    /// Repository directory, created if it doesn't exist.
+    #[arg(long)]
+    repo: PathBuf,
+
+    /// Name of the snapshot. Defaults to the current UTC time, e.g.
+    /// `2024-05-01T12-00-00Z`.
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Average size of the chunks files are split into, when creating the repository;
+    /// chunks range from a quarter to 4 times this. Smaller chunks find more duplicate
+    /// data, but take more space in snapshots and the index. Fixed once the repository
+    /// is created.
+    #[arg(long, value_parser = size::parse, value_name = "SIZE")]
+    avg_chunk_size: Option<u64>,
+
+    /// zstd level to compress new chunks with.
+    #[arg(long, default_value_t = 3, allow_negative_numbers = true)]
+    level: i32,
+}
+
+const DEFAULT_AVG_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// A file to back up.
+struct WalkedFile {
+    path: PathBuf,
+    rel_path: PathBuf,
+}
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    let start = Instant::now();
+    let config = Config::new(cmd_args.avg_chunk_size.unwrap_or(DEFAULT_AVG_CHUNK_SIZE))?;
+    let repo = Repo::open_or_create(&cmd_args.repo, config)?;
+    if let Some(avg_chunk_size) = cmd_args.avg_chunk_size {
+        ensure!(u64::from(repo.config.avg_chunk_size) == avg_chunk_size,
+                "The repository's average chunk size is {}, not {avg_chunk_size}; \
+                 it's fixed when the repository is created",
+                repo.config.avg_chunk_size);
+    }
+    let name = cmd_args.name.clone().unwrap_or_else(default_snapshot_name);
+    dedup_repo::check_name(&name)?;
+    ensure!(!repo.has_snapshot(&name), "Snapshot '{name}' already exists");
+
+    // Unchanged files are compared with the latest snapshot.
+    let previous = match repo.snapshot_names()?.last() {
+        Some(latest) => repo.read_snapshot(latest)?
+                            .into_iter()
+                            .map(|e| (e.path.clone(), e))
+                            .collect::<HashMap<_, _>>(),
+        None => HashMap::new(),
+    };
+    let known = Mutex::new(repo.read_index()?.into_keys().collect::<HashSet<_>>());
+
+    let files = walk(&cmd_args.in_path)?;
+    let packs = Mutex::new(PackWriter::new(&repo, name.clone()));
+    let stats = Stats::default();
+    let entries = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build()?
+        .install(|| {
+            files.par_iter()
+                 .map(|file| backup_file(file, &cmd_args, &repo.config, &previous, &known,
+                                         &packs, &stats))
+                 .collect::<Result<Vec<FileEntry>>>()
+        })?;
+    packs.into_inner().expect("packs lock").finish()?;
+    repo.write_snapshot(&name, &entries)?;
+
+    tracing::info!(snapshot = name, file_count = entries.len(),
+                   bytes = entries.iter().map(|e| e.size).sum::<u64>(),
+                   unchanged_count = stats.unchanged_files.load(Ordering::Relaxed),
+                   new_chunk_count = stats.new_chunks.load(Ordering::Relaxed),
+                   new_bytes = stats.new_bytes.load(Ordering::Relaxed),
+                   stored_bytes = stats.stored_bytes.load(Ordering::Relaxed),
+                   duration_ms = start.elapsed().as_millis(),
+                   "Backed up snapshot");
+    Ok(())
+}
+
+#[derive(Default)]
+struct Stats {
+    unchanged_files: AtomicU64,
+    new_chunks: AtomicU64,
+    /// Uncompressed and compressed sizes of the new chunks.
+    new_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+/// The regular files under `in_path`, with their paths relative to it.
+fn walk(in_path: &Path) -> Result<Vec<WalkedFile>> {
+    let mut files = Vec::new();
+    for entry in WalkBuilder::new(in_path).standard_filters(false).build() {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let rel_path = entry.path().strip_prefix(in_path)?.to_path_buf();
+        // `in_path` itself is a file.
+        let rel_path = match rel_path.as_os_str().is_empty() {
+            true => PathBuf::from(entry.file_name()),
+            false => rel_path,
+        };
+        files.push(WalkedFile {
+            path: entry.into_path(),
+            rel_path,
+        });
+    }
+    Ok(files)
+}
+
+/// Store the new chunks of `file`, and return its snapshot entry.
+fn backup_file(file: &WalkedFile, cmd_args: &Args, config: &Config,
+               previous: &HashMap<PathBuf, FileEntry>, known: &Mutex<HashSet<String>>,
+               packs: &Mutex<PackWriter>, stats: &Stats)
+-> Result<FileEntry>
+{
+    let opened = File::open(&file.path)
+        .with_context(|| format!("opening '{}'", file.path.display()))?;
+    // Use the metadata of the open file, in case the file changed since it was walked.
+    let meta = opened.metadata()?;
+    let (mtime, mtime_nsec) = manifest::unix_time(meta.modified()?);
+    let mut entry = FileEntry {
+        path: file.rel_path.clone(),
+        size: meta.len(),
+        mtime,
+        mtime_nsec,
+        mode: meta.mode() & 0o7777,
+        chunks: Vec::new(),
+    };
+
+    if let Some(prev) = previous.get(&file.rel_path) {
+        if (prev.size, prev.mtime, prev.mtime_nsec) == (entry.size, mtime, mtime_nsec) {
+            stats.unchanged_files.fetch_add(1, Ordering::Relaxed);
+            entry.chunks = prev.chunks.clone();
+            return Ok(entry);
+        }
+    }
+
+    for chunk in config.chunker(opened) {
+        let chunk = chunk.with_context(|| format!("reading '{}'", file.path.display()))?;
+        let id = blake3::hash(&chunk.data).to_hex().to_string();
+        let is_new = known.lock().expect("known lock").insert(id.clone());
+        if is_new {
+            let compressed = zstd::bulk::compress(&chunk.data, cmd_args.level)?;
+            stats.new_chunks.fetch_add(1, Ordering::Relaxed);
+            stats.new_bytes.fetch_add(chunk.data.len() as u64, Ordering::Relaxed);
+            stats.stored_bytes.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+            packs.lock().expect("packs lock")
+                 .write(id.clone(), &compressed, chunk.data.len() as u64)?;
+        }
+        entry.chunks.push(id);
+    }
+    Ok(entry)
+}
+
+/// The current UTC time, e.g. `2024-05-01T12-00-00Z`.
+fn default_snapshot_name() -> String {
+    let now = time::OffsetDateTime::now_utc();
+    format!("{:04}-{:02}-{:02}T{:02}-{:02}-{:02}Z", now.year(), now.month() as u8, now.day(),
+            now.hour(), now.minute(), now.second())
+}
@@ -0,0 +1,362 @@
+//! The repository `ptar dedup` writes and `ptar dedup-restore` reads: files split into
+//! content-defined chunks, each stored once however many files and snapshots share it.
+//!
+//! Chunk boundaries are found with FastCDC, a rolling hash of the contents, so an
+//! insertion or deletion in a file only changes the chunks around it. A repository
+//! directory holds:
+//!
+//! * `config.json`: the chunk sizes, fixed when the repository is created, as changing
+//!   them would move every chunk boundary.
+//! * `packs/<NAME>.pack`: chunks, each compressed as its own zstd frame, in the order
+//!   they were first seen.
+//! * `packs/<NAME>.idx`: JSON lines of each chunk in the pack of the same name: its
+//!   BLAKE3 ID, offset and length in the pack, and uncompressed size. Written once the
+//!   pack is synced, so after a crash a pack without one is ignored.
+//! * `snapshots/<NAME>.jsonl`: the tree manifest of one `dedup` run, JSON lines of each
+//!   file's path, metadata and chunk IDs. Written last, so it only refers to chunks in
+//!   indexed packs.
+
+use anyhow::{bail, ensure, Context};
+use crate::Result;
+use fastcdc::v2020::{self as fastcdc_v2020, StreamCDC};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+const CONFIG_FILE_NAME: &str = "config.json";
+const PACKS_DIR: &str = "packs";
+const SNAPSHOTS_DIR: &str = "snapshots";
+/// A pack is finished once it's at least this large.
+const PACK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Chunk sizes, in bytes, fixed for the repository.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Config {
+    pub min_chunk_size: u32,
+    pub avg_chunk_size: u32,
+    pub max_chunk_size: u32,
+}
+
+/// A file in a snapshot.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct FileEntry {
+    /// Path relative to the directory backed up.
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub mode: u32,
+    /// IDs of the file's chunks, in order.
+    pub chunks: Vec<String>,
+}
+
+/// Where a chunk is stored, as listed in a pack's index.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ChunkLocation {
+    pub id: String,
+    /// File name of the pack, without its extension. Not in the index file, which is
+    /// named after the pack.
+    #[serde(skip)]
+    pub pack: String,
+    pub offset: u64,
+    /// Compressed length.
+    pub len: u64,
+    /// Uncompressed size.
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub struct Repo {
+    dir: PathBuf,
+    pub config: Config,
+}
+
+impl Config {
+    /// Chunks averaging `avg_chunk_size` bytes, and from a quarter to 4 times that.
+    pub fn new(avg_chunk_size: u64) -> Result<Config> {
+        let range = fastcdc_v2020::AVERAGE_MIN..=fastcdc_v2020::AVERAGE_MAX;
+        let avg_chunk_size = u32::try_from(avg_chunk_size)
+            .ok()
+            .filter(|size| range.contains(size))
+            .with_context(|| format!("The average chunk size must be from {} to {} bytes",
+                                     range.start(), range.end()))?;
+        Ok(Config {
+            min_chunk_size: avg_chunk_size / 4,
+            avg_chunk_size,
+            max_chunk_size: avg_chunk_size * 4,
+        })
+    }
+
+    /// Split the contents read from `r` into chunks.
+    pub fn chunker<R: Read>(&self, r: R) -> StreamCDC<R> {
+        StreamCDC::new(r, self.min_chunk_size, self.avg_chunk_size, self.max_chunk_size)
+    }
+}
+
+impl Repo {
+    /// Open the repository in `dir`, creating it with `config` if it doesn't exist.
+    pub fn open_or_create(dir: &Path, config: Config) -> Result<Repo> {
+        if dir.join(CONFIG_FILE_NAME).exists() {
+            return Repo::open(dir);
+        }
+        fs::create_dir_all(dir.join(PACKS_DIR))?;
+        fs::create_dir_all(dir.join(SNAPSHOTS_DIR))?;
+        write_atomic(&dir.join(CONFIG_FILE_NAME), &serde_json::to_vec_pretty(&config)?)?;
+        tracing::info!(repo = %dir.display(), ?config, "Created repository");
+        Ok(Repo {
+            dir: dir.to_path_buf(),
+            config,
+        })
+    }
+
+    pub fn open(dir: &Path) -> Result<Repo> {
+        let config_path = dir.join(CONFIG_FILE_NAME);
+        let config = fs::read(&config_path)
+            .with_context(|| format!("'{}' isn't a dedup repository", dir.display()))?;
+        Ok(Repo {
+            dir: dir.to_path_buf(),
+            config: serde_json::from_slice(&config)
+                .with_context(|| format!("reading '{}'", config_path.display()))?,
+        })
+    }
+
+    /// Every chunk in an indexed pack, by ID.
+    pub fn read_index(&self) -> Result<HashMap<String, ChunkLocation>> {
+        let mut index = HashMap::new();
+        for dir_entry in fs::read_dir(self.dir.join(PACKS_DIR))? {
+            let path = dir_entry?.path();
+            if path.extension().is_none_or(|ext| ext != "idx") {
+                continue;
+            }
+            let pack = path.file_stem().expect("has an extension").to_string_lossy();
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let mut location = serde_json::from_str::<ChunkLocation>(&line?)
+                    .with_context(|| format!("reading '{}'", path.display()))?;
+                location.pack = pack.to_string();
+                index.insert(location.id.clone(), location);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Names of the snapshots, oldest first, by when their files were written.
+    pub fn snapshot_names(&self) -> Result<Vec<String>> {
+        let mut snapshots = Vec::new();
+        for dir_entry in fs::read_dir(self.dir.join(SNAPSHOTS_DIR))? {
+            let dir_entry = dir_entry?;
+            let path = dir_entry.path();
+            if path.extension().is_some_and(|ext| ext == "jsonl") {
+                let name = path.file_stem().expect("has an extension")
+                               .to_string_lossy().into_owned();
+                snapshots.push((dir_entry.metadata()?.modified()?, name));
+            }
+        }
+        snapshots.sort();
+        Ok(snapshots.into_iter().map(|(_, name)| name).collect())
+    }
+
+    pub fn read_snapshot(&self, name: &str) -> Result<Vec<FileEntry>> {
+        let path = self.snapshot_path(name);
+        let file = File::open(&path)
+            .with_context(|| format!("opening snapshot '{}'", path.display()))?;
+        BufReader::new(file).lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("reading snapshot '{}'", path.display()))
+    }
+
+    /// Whether snapshot `name` exists.
+    pub fn has_snapshot(&self, name: &str) -> bool {
+        self.snapshot_path(name).exists()
+    }
+
+    pub fn write_snapshot(&self, name: &str, entries: &[FileEntry]) -> Result<()> {
+        let mut data = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut data, entry)?;
+            data.push(b'\n');
+        }
+        write_atomic(&self.snapshot_path(name), &data)
+    }
+
+    /// The contents of the chunk at `location`, read through `packs`, a cache of open
+    /// pack files. Errors if they don't match its ID.
+    pub fn read_chunk(&self, location: &ChunkLocation, packs: &mut HashMap<String, File>)
+    -> Result<Vec<u8>>
+    {
+        let pack = match packs.get(&location.pack) {
+            Some(pack) => pack,
+            None => {
+                let path = self.pack_path(&location.pack, "pack");
+                let file = File::open(&path)
+                    .with_context(|| format!("opening pack '{}'", path.display()))?;
+                packs.entry(location.pack.clone()).or_insert(file)
+            },
+        };
+        let mut compressed = vec![0; usize::try_from(location.len)?];
+        pack.read_exact_at(&mut compressed, location.offset)?;
+        let data = zstd::bulk::decompress(&compressed, usize::try_from(location.size)?)
+            .with_context(|| format!("decompressing chunk {} in pack {}", location.id,
+                                     location.pack))?;
+        ensure!(blake3::hash(&data).to_hex().as_str() == location.id,
+                "Chunk {} in pack {} is corrupt: its contents don't match its ID",
+                location.id, location.pack);
+        Ok(data)
+    }
+
+    fn snapshot_path(&self, name: &str) -> PathBuf {
+        self.dir.join(SNAPSHOTS_DIR).join(format!("{name}.jsonl"))
+    }
+
+    fn pack_path(&self, name: &str, ext: &str) -> PathBuf {
+        self.dir.join(PACKS_DIR).join(format!("{name}.{ext}"))
+    }
+}
+
+/// Check `name` can name a snapshot or pack file.
+pub fn check_name(name: &str) -> Result<()> {
+    ensure!(!name.is_empty() && !name.starts_with('.') && !name.contains('/'),
+            "Snapshot name '{name}' must be non-empty, not start with '.' and not contain \
+             '/'");
+    Ok(())
+}
+
+/// Appends chunks to packs named `<PREFIX>-<NNNNNNNN>`, starting a new one after each
+/// `PACK_SIZE` bytes.
+pub struct PackWriter<'a> {
+    repo: &'a Repo,
+    prefix: String,
+    next_num: u64,
+    pack: Option<OpenPack>,
+}
+
+struct OpenPack {
+    name: String,
+    file: BufWriter<File>,
+    len: u64,
+    index: Vec<ChunkLocation>,
+}
+
+impl PackWriter<'_> {
+    pub fn new(repo: &Repo, prefix: String) -> PackWriter<'_> {
+        PackWriter {
+            repo,
+            prefix,
+            next_num: 0,
+            pack: None,
+        }
+    }
+
+    /// Append the chunk `id` of `size` bytes, already compressed to `compressed`.
+    pub fn write(&mut self, id: String, compressed: &[u8], size: u64) -> Result<()> {
+        let pack = match self.pack {
+            Some(ref mut pack) => pack,
+            None => {
+                let name = format!("{prefix}-{num:08}", prefix = self.prefix,
+                                   num = self.next_num);
+                self.next_num += 1;
+                let path = self.repo.pack_path(&name, "pack");
+                let file = fs::OpenOptions::new().write(true).create_new(true).open(&path)
+                    .with_context(|| format!("creating pack '{}'", path.display()))?;
+                self.pack.insert(OpenPack {
+                    name,
+                    file: BufWriter::with_capacity(128 * 1024, file),
+                    len: 0,
+                    index: Vec::new(),
+                })
+            },
+        };
+        pack.file.write_all(compressed)?;
+        pack.index.push(ChunkLocation {
+            id,
+            pack: pack.name.clone(),
+            offset: pack.len,
+            len: compressed.len() as u64,
+            size,
+        });
+        pack.len += compressed.len() as u64;
+        if pack.len >= PACK_SIZE {
+            self.finish_pack()?;
+        }
+        Ok(())
+    }
+
+    /// Finish the last pack.
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_pack()
+    }
+
+    /// Sync the current pack, then write its index, so the index only lists durable
+    /// chunks.
+    fn finish_pack(&mut self) -> Result<()> {
+        let Some(pack) = self.pack.take() else {
+            return Ok(());
+        };
+        pack.file.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        let mut index = Vec::new();
+        for location in pack.index.iter() {
+            serde_json::to_writer(&mut index, location)?;
+            index.push(b'\n');
+        }
+        write_atomic(&self.repo.pack_path(&pack.name, "idx"), &index)?;
+        tracing::debug!(pack = pack.name, len = pack.len, chunk_count = pack.index.len(),
+                        "Finished pack");
+        Ok(())
+    }
+}
+
+/// Write `data` to `path`, synced, through a temporary file so an interruption never
+/// leaves a partial file. Errors if `path` exists.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    if path.exists() {
+        bail!("'{}' already exists", path.display());
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::TempDir;
+
+    #[test]
+    fn packs_round_trip() {
+        let dir = TempDir::new("ptar-test").unwrap();
+        let repo = Repo::open_or_create(dir.path(), Config::new(1024).unwrap()).unwrap();
+        let chunks = [vec![1_u8; 3000], (0..5000_u32).map(|i| i as u8).collect()];
+        let mut writer = PackWriter::new(&repo, "s".to_string());
+        for chunk in chunks.iter() {
+            let id = blake3::hash(chunk).to_hex().to_string();
+            writer.write(id, &zstd::bulk::compress(chunk, 3).unwrap(), chunk.len() as u64)
+                  .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let repo = Repo::open(dir.path()).unwrap();
+        assert_eq!(repo.config.max_chunk_size, 4096);
+        let index = repo.read_index().unwrap();
+        assert_eq!(index.len(), 2);
+        let mut packs = HashMap::new();
+        for chunk in chunks.iter() {
+            let location = &index[blake3::hash(chunk).to_hex().as_str()];
+            assert_eq!(location.pack, "s-00000000");
+            assert_eq!(repo.read_chunk(location, &mut packs).unwrap(), *chunk);
+        }
+
+        // A chunk whose contents don't match its ID.
+        let mut wrong = index.values().next().unwrap().clone();
+        wrong.id = "0".repeat(64);
+        assert!(repo.read_chunk(&wrong, &mut packs).is_err());
+    }
+}
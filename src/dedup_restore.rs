@@ -0,0 +1,132 @@
+//! `ptar dedup-restore`: restore a snapshot from a repository written by `ptar dedup`,
+//! reassembling each file from its chunks.
+//!
+//! Files are restored with their permissions and mtimes, by several threads. Every
+//! chunk is checked against its ID as it's read, so a damaged repository is an error
+//! rather than a silently wrong file.
+
+use anyhow::{ensure, Context};
+use crate::{
+    Result,
+    dedup_repo::{self, ChunkLocation, FileEntry, Repo},
+    extract_path::{self, ExtractPaths},
+    path_filter::PathFilter,
+};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Repository directory written by `ptar dedup`.
+    #[arg(long)]
+    repo: PathBuf,
+
+    /// Name of the snapshot to restore. Defaults to the latest.
+    #[arg(long)]
+    snapshot: Option<String>,
+
+    #[arg(long)]
+    out_dir: PathBuf,
+
+    /// Restore only files whose paths match this glob. May be repeated.
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Don't restore files whose paths match this glob. May be repeated.
+    #[arg(long)]
+    exclude: Vec<String>,
+}
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    let start = Instant::now();
+    let repo = Repo::open(&cmd_args.repo)?;
+    let name = match cmd_args.snapshot {
+        Some(ref name) => {
+            dedup_repo::check_name(name)?;
+            name.clone()
+        },
+        None => repo.snapshot_names()?.pop().context("The repository has no snapshots")?,
+    };
+    let filter = PathFilter::new(&cmd_args.include, &cmd_args.exclude)?;
+    let entries = repo.read_snapshot(&name)?
+                      .into_iter()
+                      .filter(|e| filter.is_match(&e.path))
+                      .collect::<Vec<_>>();
+    let index = repo.read_index()?;
+
+    fs::create_dir_all(&*cmd_args.out_dir)?;
+    let paths = ExtractPaths::new(&cmd_args.out_dir, 0, &[], false)?;
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build()?
+        .install(|| {
+            entries.par_iter()
+                   // Each thread keeps the packs it has read open.
+                   .try_for_each_init(HashMap::new, |packs, entry| {
+                       restore_file(&repo, &index, &paths, entry, packs)
+                           .with_context(|| format!("restoring '{}'", entry.path.display()))
+                   })
+        })?;
+
+    let rejected_count = paths.rejected_count();
+    if rejected_count > 0 {
+        tracing::warn!(rejected_count, "Rejected files with unsafe paths, logged above");
+    }
+    tracing::info!(snapshot = name, file_count = entries.len(),
+                   bytes = entries.iter().map(|e| e.size).sum::<u64>(),
+                   duration_ms = start.elapsed().as_millis(),
+                   "Restored snapshot");
+    Ok(())
+}
+
+/// Restore `entry` in the output directory, reading its chunks through the open `packs`.
+fn restore_file(repo: &Repo, index: &HashMap<String, ChunkLocation>, paths: &ExtractPaths,
+                entry: &FileEntry, packs: &mut HashMap<String, File>)
+-> Result<()>
+{
+    let Some(dst) = paths.prepare(&entry.path)? else {
+        return Ok(());
+    };
+    // Replaced rather than written through, e.g. in case it's a hard link.
+    extract_path::remove_existing(&dst)?;
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&dst)
+        .with_context(|| format!("creating '{}'", dst.display()))?;
+    let mut bufw = BufWriter::with_capacity(128 * 1024, file);
+    for id in entry.chunks.iter() {
+        let location = index.get(id)
+            .with_context(|| format!("Chunk {id} isn't in the repository's index"))?;
+        bufw.write_all(&repo.read_chunk(location, packs)?)?;
+    }
+    let file = bufw.into_inner().map_err(|err| err.into_error())?;
+    let len = file.metadata()?.len();
+    ensure!(len == entry.size, "Restored {len} bytes, but the snapshot says it has {}",
+            entry.size);
+
+    // Without the setuid, setgid and sticky bits, as the owner isn't restored.
+    file.set_permissions(fs::Permissions::from_mode(entry.mode & 0o777))?;
+    file.set_modified(system_time(entry.mtime, entry.mtime_nsec))?;
+    Ok(())
+}
+
+/// The inverse of `manifest::unix_time()`.
+fn system_time(secs: i64, nsec: u32) -> SystemTime {
+    let since_epoch = Duration::new(secs.unsigned_abs(), 0);
+    let whole = if secs >= 0 {
+        SystemTime::UNIX_EPOCH + since_epoch
+    } else {
+        SystemTime::UNIX_EPOCH - since_epoch
+    };
+    whole + Duration::from_nanos(nsec.into())
+}
@@ -0,0 +1,86 @@
+//! Limiting concurrent file reads per device, so spinning disks aren't made to seek
+//! back and forth between many files at once.
+
+use std::{
+    collections::HashMap,
+    sync::{Condvar, Mutex},
+};
+
+/// Hands out permits to read from a device, at most `max_per_device` at a time for each
+/// device ID (`st_dev`).
+#[derive(Debug)]
+pub struct DeviceLimiter {
+    max_per_device: usize,
+    /// Number of permits held for each device.
+    reading: Mutex<HashMap<u64, usize>>,
+    released: Condvar,
+}
+
+/// Held while reading from a device. Dropping it lets another reader start.
+#[must_use]
+pub struct DevicePermit<'a> {
+    dev: u64,
+    limiter: &'a DeviceLimiter,
+}
+
+impl DeviceLimiter {
+    pub fn new(max_per_device: usize) -> DeviceLimiter {
+        DeviceLimiter {
+            max_per_device: max_per_device.max(1),
+            reading: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until there's a free permit for `dev`.
+    pub fn acquire(&self, dev: u64) -> DevicePermit<'_> {
+        let reading = self.reading.lock().expect("reading lock");
+        let mut reading = self.released
+            .wait_while(reading, |reading| {
+                reading.get(&dev).copied().unwrap_or(0) >= self.max_per_device
+            })
+            .expect("reading lock");
+        *reading.entry(dev).or_insert(0) += 1;
+        DevicePermit { dev, limiter: self }
+    }
+}
+
+impl Drop for DevicePermit<'_> {
+    fn drop(&mut self) {
+        let mut reading = self.limiter.reading.lock().expect("reading lock");
+        let count = reading.get_mut(&self.dev).expect("permit was counted");
+        *count -= 1;
+        if *count == 0 {
+            reading.remove(&self.dev);
+        }
+        drop(reading);
+        self.limiter.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn limits_each_device() {
+        let limiter = DeviceLimiter::new(1);
+        let permit = limiter.acquire(1);
+        // Other devices aren't limited.
+        drop(limiter.acquire(2));
+
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _permit = limiter.acquire(1);
+                tx.send(()).unwrap();
+            });
+            assert!(rx.recv_timeout(Duration::from_millis(100)).is_err(),
+                    "Second reader of device 1 should wait");
+            drop(permit);
+            rx.recv_timeout(Duration::from_secs(5))
+              .expect("Second reader of device 1 should start once the first finishes");
+        });
+    }
+}
@@ -0,0 +1,315 @@
+//! `ptar diff`: compare an archive set's manifest with a directory, e.g. the live tree
+//! it was archived from or one it was restored to, listing files added, removed or
+//! modified since.
+//!
+//! With `--against-set` it instead compares two archive sets' manifests, e.g. two
+//! nightly backups, without reading either's archives.
+//!
+//! Each difference is written to stdout as a tab separated line, sorted by path:
+//! `added <path>`, `removed <path>`, or `modified <path> <what>`, where `<what>` is a
+//! comma separated list of `size`, `mtime` and `checksum`. With `--json` each is
+//! instead a JSON object, e.g. `{"change":"modified","path":"a","what":["size"]}`.
+//!
+//! Against a directory, modification times are compared in whole seconds, as only pax
+//! archives keep the sub-second part, so files restored from others would otherwise all
+//! differ.
+
+use anyhow::{bail, Context};
+use crate::{Result, hasher, manifest};
+use ignore::WalkBuilder;
+use rayon::prelude::*;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Directory containing the archive set and its manifest.
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Directory to compare the archive set with.
+    #[arg(long, required_unless_present = "against_set")]
+    against: Option<PathBuf>,
+
+    /// Directory of a later archive set to compare the archive set with, by their
+    /// manifests. Files' checksums are compared when both manifests have them with the
+    /// same algorithm.
+    #[arg(long, conflicts_with_all = ["against", "checksums"])]
+    against_set: Option<PathBuf>,
+
+    /// Also hash files whose size and mtime match, and compare them with the manifest's
+    /// checksums, if it has them from `compress --checksums`.
+    #[arg(long)]
+    checksums: bool,
+
+    /// Write each difference as a JSON object on its own line.
+    #[arg(long)]
+    json: bool,
+}
+
+/// How a file differs between the archive set and the directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Change {
+    /// In the directory but not the archive set.
+    Added,
+    /// In the archive set but not the directory.
+    Removed,
+    /// In both, but with these differences.
+    Modified(Vec<&'static str>),
+}
+
+/// One line of `--json` output.
+#[derive(serde::Serialize)]
+struct Record<'a> {
+    change: &'static str,
+    path: &'a Path,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    what: &'a [&'static str],
+}
+
+/// A regular file found in the directory.
+struct LiveFile {
+    path: PathBuf,
+    size: u64,
+    mtime: i64,
+}
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    let entries = read_manifest(&cmd_args.in_dir)?;
+    let (changes, against_count) = match (&cmd_args.against, &cmd_args.against_set) {
+        (_, Some(against_set)) => {
+            let later = read_manifest(against_set)?;
+            (compare_sets(&entries, &later), later.len())
+        },
+        (Some(against), None) => {
+            let live = walk(against)?;
+            let hash_dir = cmd_args.checksums.then_some(against.as_path());
+            let changes = rayon::ThreadPoolBuilder::new()
+                .num_threads(args.threads())
+                .build()?
+                .install(|| compare(&entries, &live, hash_dir))?;
+            (changes, live.len())
+        },
+        (None, None) => unreachable!("clap requires --against or --against-set"),
+    };
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let (mut added, mut removed, mut modified) = (0_usize, 0_usize, 0_usize);
+    for (path, change) in changes.iter() {
+        let (name, what) = match change {
+            Change::Added => {
+                added += 1;
+                ("added", &[][..])
+            },
+            Change::Removed => {
+                removed += 1;
+                ("removed", &[][..])
+            },
+            Change::Modified(what) => {
+                modified += 1;
+                ("modified", what.as_slice())
+            },
+        };
+        if cmd_args.json {
+            serde_json::to_writer(&mut out, &Record { change: name, path, what })?;
+            writeln!(out)?;
+        } else if what.is_empty() {
+            writeln!(out, "{name}\t{}", path.display())?;
+        } else {
+            writeln!(out, "{name}\t{}\t{}", path.display(), what.join(","))?;
+        }
+    }
+    out.flush()?;
+
+    tracing::info!(archived_count = entries.len(), against_count, added, removed, modified,
+                   "diff finished");
+    if !changes.is_empty() {
+        bail!("diff found {} differences", changes.len());
+    }
+    Ok(())
+}
+
+fn read_manifest(in_dir: &Path) -> Result<HashMap<PathBuf, manifest::Entry>> {
+    let entries = manifest::read(in_dir)
+        .with_context(|| format!("diff requires the manifest of '{}'", in_dir.display()))?;
+    Ok(manifest::merge_chunks(entries)
+        .into_iter()
+        .map(|e| (e.path.clone(), e))
+        .collect())
+}
+
+/// Find the regular files under `dir`, with paths relative to it.
+fn walk(dir: &Path) -> Result<Vec<LiveFile>> {
+    let mut files = Vec::new();
+    for entry in WalkBuilder::new(dir).standard_filters(false).build() {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        let (mtime, _) = manifest::unix_time(meta.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+        files.push(LiveFile {
+            path: entry.path().strip_prefix(dir)?.to_path_buf(),
+            size: meta.len(),
+            mtime,
+        });
+    }
+    Ok(files)
+}
+
+/// Compare the manifest `entries` with the `live` files. With `hash_dir`, the directory
+/// the live files are in, files whose size and mtime match are also hashed.
+fn compare(entries: &HashMap<PathBuf, manifest::Entry>, live: &[LiveFile],
+           hash_dir: Option<&Path>)
+-> Result<BTreeMap<PathBuf, Change>>
+{
+    let mut changes = live.par_iter()
+        .map(|file| -> Result<Option<(PathBuf, Change)>> {
+            let Some(entry) = entries.get(&file.path) else {
+                return Ok(Some((file.path.clone(), Change::Added)));
+            };
+            let mut what = Vec::new();
+            if entry.size != file.size {
+                what.push("size");
+            }
+            if entry.mtime != file.mtime {
+                what.push("mtime");
+            }
+            if let (true, Some(dir), Some(checksum)) = (what.is_empty(), hash_dir,
+                                                        &entry.checksum) {
+                let (algorithm, digest) = hasher::Algorithm::parse_checksum(checksum)?;
+                let mut hasher = hasher::Hasher::new(algorithm);
+                let path = dir.join(&file.path);
+                io::copy(&mut fs::File::open(&*path)
+                                  .with_context(|| format!("opening '{}'", path.display()))?,
+                         &mut hasher)?;
+                if hasher.finalize_reset() != digest {
+                    what.push("checksum");
+                }
+            }
+            Ok((!what.is_empty()).then(|| (file.path.clone(), Change::Modified(what))))
+        })
+        .filter_map(Result::transpose)
+        .collect::<Result<BTreeMap<PathBuf, Change>>>()?;
+
+    let live_paths = live.iter().map(|f| f.path.as_path()).collect::<HashSet<&Path>>();
+    for path in entries.keys() {
+        if !live_paths.contains(path.as_path()) {
+            changes.insert(path.clone(), Change::Removed);
+        }
+    }
+    Ok(changes)
+}
+
+/// Compare the manifest `entries` of an archive set with the `later` entries of another.
+fn compare_sets(entries: &HashMap<PathBuf, manifest::Entry>,
+                later: &HashMap<PathBuf, manifest::Entry>)
+-> BTreeMap<PathBuf, Change>
+{
+    let mut changes = BTreeMap::new();
+    for (path, new) in later.iter() {
+        let Some(old) = entries.get(path) else {
+            changes.insert(path.clone(), Change::Added);
+            continue;
+        };
+        let mut what = Vec::new();
+        if old.size != new.size {
+            what.push("size");
+        }
+        if (old.mtime, old.mtime_nsec) != (new.mtime, new.mtime_nsec) {
+            what.push("mtime");
+        }
+        if let (Some(old_sum), Some(new_sum)) = (&old.checksum, &new.checksum) {
+            // Only comparable with the same algorithm, the part before the `:`.
+            let algorithm = |sum: &str| sum.split_once(':').map(|(alg, _)| alg.to_string());
+            if algorithm(old_sum) == algorithm(new_sum) && old_sum != new_sum {
+                what.push("checksum");
+            }
+        }
+        if !what.is_empty() {
+            changes.insert(path.clone(), Change::Modified(what));
+        }
+    }
+    for path in entries.keys() {
+        if !later.contains_key(path) {
+            changes.insert(path.clone(), Change::Removed);
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes() {
+        let entry = |path: &str, size, mtime| {
+            let mut e = manifest::Entry::new(PathBuf::from(path),
+                                             &fs::metadata(".").unwrap(), "a".to_string());
+            e.size = size;
+            e.mtime = mtime;
+            e.mtime_nsec = 123;
+            (e.path.clone(), e)
+        };
+        let live = |path: &str, size, mtime| LiveFile { path: PathBuf::from(path), size, mtime };
+
+        let entries = HashMap::from([
+            entry("same", 1, 10),
+            entry("grown", 1, 10),
+            entry("touched", 1, 10),
+            entry("gone", 1, 10),
+        ]);
+        let live = [
+            live("same", 1, 10),
+            live("grown", 2, 11),
+            live("touched", 1, 11),
+            live("new", 1, 10),
+        ];
+        let changes = compare(&entries, &live, None).unwrap().into_iter().collect::<Vec<_>>();
+        assert_eq!(changes, vec![
+            (PathBuf::from("gone"), Change::Removed),
+            (PathBuf::from("grown"), Change::Modified(vec!["size", "mtime"])),
+            (PathBuf::from("new"), Change::Added),
+            (PathBuf::from("touched"), Change::Modified(vec!["mtime"])),
+        ]);
+    }
+
+    #[test]
+    fn set_changes() {
+        let entry = |path: &str, mtime_nsec, checksum: &str| {
+            let mut e = manifest::Entry::new(PathBuf::from(path),
+                                             &fs::metadata(".").unwrap(), "a".to_string());
+            e.mtime_nsec = mtime_nsec;
+            e.checksum = (!checksum.is_empty()).then(|| checksum.to_string());
+            (e.path.clone(), e)
+        };
+
+        let old = HashMap::from([
+            entry("same", 1, "blake3:aa"),
+            entry("touched", 1, ""),
+            entry("edited", 1, "blake3:aa"),
+            entry("rehashed", 1, "blake3:aa"),
+            entry("gone", 1, ""),
+        ]);
+        let new = HashMap::from([
+            entry("same", 1, "blake3:aa"),
+            entry("touched", 2, ""),
+            entry("edited", 1, "blake3:bb"),
+            entry("rehashed", 1, "sha256:cc"),
+            entry("new", 1, ""),
+        ]);
+        assert_eq!(compare_sets(&old, &new).into_iter().collect::<Vec<_>>(), vec![
+            (PathBuf::from("edited"), Change::Modified(vec!["checksum"])),
+            (PathBuf::from("gone"), Change::Removed),
+            (PathBuf::from("new"), Change::Added),
+            (PathBuf::from("touched"), Change::Modified(vec!["mtime"])),
+        ]);
+    }
+}
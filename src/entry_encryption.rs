@@ -0,0 +1,312 @@
+//! Encrypting the contents of each archive entry with its own random data key.
+//!
+//! Each data key is wrapped (encrypted) by a master key and recorded in the file's
+//! manifest entry, so deleting the wrapped key from the manifest crypto-shreds that one
+//! file while the rest of the archive set stays readable.
+//!
+//! Entry contents are split into chunks of `CHUNK_LEN` bytes, each sealed with
+//! ChaCha20-Poly1305 under a nonce of a chunk counter and a final chunk flag, as in
+//! age's STREAM construction. So entries can be decrypted while streaming, and
+//! truncation or reordering is detected. The ciphertext length only depends on the
+//! plaintext length, which tar headers need up front.
+
+use anyhow::{bail, ensure, Context};
+use chacha20poly1305::{
+    AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::generic_array::typenum::Unsigned,
+};
+use crate::Result;
+use std::{
+    cmp,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+/// The key that wraps every data key, loaded from a key file.
+pub struct MasterKey {
+    cipher: ChaCha20Poly1305,
+    /// Identifies the master key in wrapped keys, without revealing it.
+    id: String,
+}
+
+/// A random key for one entry's contents.
+pub struct DataKey(Key);
+
+/// Encrypts `len` bytes read from `inner`.
+pub struct EncryptingReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    chunks: Chunks,
+    /// Plaintext bytes still to read from `inner`.
+    remaining: u64,
+    /// The current sealed chunk and how much of it has been read.
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+/// Decrypts `len` bytes of ciphertext read from `inner`.
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    chunks: Chunks,
+    /// Ciphertext bytes still to read from `inner`.
+    remaining: u64,
+    /// The current opened chunk and how much of it has been read.
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+/// Counts chunks to build their nonces.
+struct Chunks {
+    next: u64,
+    done: bool,
+}
+
+/// Plaintext bytes per chunk.
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = <ChaCha20Poly1305 as chacha20poly1305::AeadCore>::TagSize::USIZE;
+const NONCE_LEN: usize = <ChaCha20Poly1305 as chacha20poly1305::AeadCore>::NonceSize::USIZE;
+const KEY_LEN: usize = 32;
+/// Hex digits of the master key ID.
+const KEY_ID_LEN: usize = 16;
+
+impl MasterKey {
+    /// Load a 256-bit key written as 64 hex digits, e.g. by
+    /// `head -c 32 /dev/urandom | xxd -p -c 32`.
+    pub fn load(path: &Path) -> Result<MasterKey> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("reading key file '{}'", path.display()))?;
+        let key = from_hex(text.trim())
+            .with_context(|| format!("Key file '{}' isn't hex", path.display()))?;
+        ensure!(key.len() == KEY_LEN,
+                "Key file '{}' should hold {KEY_LEN} bytes as hex", path.display());
+        Ok(MasterKey {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            id: blake3::hash(&key).to_hex()[..KEY_ID_LEN].to_string(),
+        })
+    }
+
+    /// Encrypt `data_key` for the entry at `path`, as `<master key ID>:<hex>`.
+    ///
+    /// The path is authenticated, so a wrapped key can't be moved to another entry.
+    pub fn wrap(&self, data_key: &DataKey, path: &Path) -> Result<String> {
+        let mut nonce = [0_u8; NONCE_LEN];
+        random_bytes(&mut nonce)?;
+        let mut sealed = data_key.0.to_vec();
+        self.cipher.encrypt_in_place(Nonce::from_slice(&nonce), path.as_os_str().as_bytes(),
+                                     &mut sealed)
+            .map_err(|_| anyhow::anyhow!("Error wrapping data key"))?;
+        Ok(format!("{id}:{nonce}{sealed}",
+                   id = self.id, nonce = to_hex(&nonce), sealed = to_hex(&sealed)))
+    }
+
+    /// Decrypt a key from `wrap()` for the entry at `path`.
+    pub fn unwrap(&self, wrapped: &str, path: &Path) -> Result<DataKey> {
+        let (id, hex) = wrapped.split_once(':').context("Wrapped key has no key ID")?;
+        ensure!(id == self.id,
+                "'{}' was encrypted with master key {id}, not {}", path.display(), self.id);
+        let bytes = from_hex(hex)?;
+        ensure!(bytes.len() == NONCE_LEN + KEY_LEN + TAG_LEN,
+                "Wrapped key for '{}' is the wrong length", path.display());
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let mut key = sealed.to_vec();
+        self.cipher.decrypt_in_place(Nonce::from_slice(nonce), path.as_os_str().as_bytes(),
+                                     &mut key)
+            .map_err(|_| anyhow::anyhow!("Wrapped key for '{}' failed authentication",
+                                         path.display()))?;
+        Ok(DataKey(*Key::from_slice(&key)))
+    }
+}
+
+impl DataKey {
+    pub fn generate() -> Result<DataKey> {
+        let mut key = [0_u8; KEY_LEN];
+        random_bytes(&mut key)?;
+        Ok(DataKey(key.into()))
+    }
+}
+
+/// Length of the encrypted contents of a `len` byte file.
+pub fn encrypted_len(len: u64) -> u64 {
+    let chunks = len.div_ceil(CHUNK_LEN as u64).max(1);
+    len + chunks * TAG_LEN as u64
+}
+
+impl<R: Read> EncryptingReader<R> {
+    pub fn new(inner: R, key: &DataKey, len: u64) -> EncryptingReader<R> {
+        EncryptingReader {
+            inner,
+            cipher: ChaCha20Poly1305::new(&key.0),
+            chunks: Chunks { next: 0, done: false },
+            remaining: len,
+            out: Vec::with_capacity(CHUNK_LEN + TAG_LEN),
+            out_pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for EncryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.out_pos == self.out.len() {
+            if self.chunks.done {
+                return Ok(0);
+            }
+            let len = cmp::min(self.remaining, CHUNK_LEN as u64) as usize;
+            self.out.resize(len, 0);
+            self.inner.read_exact(&mut self.out).map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof =>
+                    io::Error::other("File shrank while it was being encrypted"),
+                _ => err,
+            })?;
+            self.remaining -= len as u64;
+            let nonce = self.chunks.next_nonce(self.remaining == 0);
+            self.cipher.encrypt_in_place(&nonce, b"", &mut self.out)
+                .map_err(|_| io::Error::other("Error encrypting entry"))?;
+            self.out_pos = 0;
+        }
+
+        let count = cmp::min(buf.len(), self.out.len() - self.out_pos);
+        buf[..count].copy_from_slice(&self.out[self.out_pos..(self.out_pos + count)]);
+        self.out_pos += count;
+        Ok(count)
+    }
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// `len` is the ciphertext length, e.g. the tar entry's size.
+    pub fn new(inner: R, key: &DataKey, len: u64) -> DecryptingReader<R> {
+        DecryptingReader {
+            inner,
+            cipher: ChaCha20Poly1305::new(&key.0),
+            chunks: Chunks { next: 0, done: false },
+            remaining: len,
+            out: Vec::with_capacity(CHUNK_LEN + TAG_LEN),
+            out_pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos == self.out.len() {
+            if self.chunks.done {
+                return Ok(0);
+            }
+            let len = cmp::min(self.remaining, (CHUNK_LEN + TAG_LEN) as u64) as usize;
+            if len < TAG_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "Encrypted entry is truncated"));
+            }
+            self.out.resize(len, 0);
+            self.inner.read_exact(&mut self.out)?;
+            self.remaining -= len as u64;
+            let nonce = self.chunks.next_nonce(self.remaining == 0);
+            self.cipher.decrypt_in_place(&nonce, b"", &mut self.out)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+                                            "Encrypted entry failed authentication"))?;
+            self.out_pos = 0;
+        }
+
+        let count = cmp::min(buf.len(), self.out.len() - self.out_pos);
+        buf[..count].copy_from_slice(&self.out[self.out_pos..(self.out_pos + count)]);
+        self.out_pos += count;
+        Ok(count)
+    }
+}
+
+impl Chunks {
+    /// The nonce for the next chunk: an 11 byte big-endian counter, then 1 for the last
+    /// chunk or 0 otherwise.
+    fn next_nonce(&mut self, last: bool) -> Nonce {
+        let mut nonce = [0_u8; NONCE_LEN];
+        nonce[3..11].copy_from_slice(&self.next.to_be_bytes());
+        nonce[11] = u8::from(last);
+        self.next += 1;
+        self.done = last;
+        nonce.into()
+    }
+}
+
+fn random_bytes(buf: &mut [u8]) -> Result<()> {
+    File::open("/dev/urandom")?.read_exact(buf)?;
+    Ok(())
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        bail!("Invalid hex '{hex}'");
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..(i + 2)], 16)
+                     .with_context(|| format!("Invalid hex '{hex}'")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(key: &DataKey, plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext = Vec::new();
+        EncryptingReader::new(plaintext, key, plaintext.len() as u64)
+            .read_to_end(&mut ciphertext).unwrap();
+        ciphertext
+    }
+
+    fn decrypt(key: &DataKey, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        DecryptingReader::new(ciphertext, key, ciphertext.len() as u64)
+            .read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = DataKey::generate().unwrap();
+        for len in [0, 1, CHUNK_LEN - 1, CHUNK_LEN, CHUNK_LEN + 1, 3 * CHUNK_LEN + 5] {
+            let plaintext = (0..len).map(|i| i as u8).collect::<Vec<u8>>();
+            let ciphertext = encrypt(&key, &plaintext);
+            assert_eq!(ciphertext.len() as u64, encrypted_len(len as u64), "len={len}");
+            assert_eq!(decrypt(&key, &ciphertext).unwrap(), plaintext, "len={len}");
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let key = DataKey::generate().unwrap();
+        let ciphertext = encrypt(&key, &[7_u8; 2 * CHUNK_LEN + 3]);
+
+        let mut flipped = ciphertext.clone();
+        flipped[CHUNK_LEN + 5] ^= 1;
+        assert!(decrypt(&key, &flipped).is_err());
+
+        // Dropping the last chunk leaves a valid chunk that isn't marked last.
+        assert!(decrypt(&key, &ciphertext[..(CHUNK_LEN + TAG_LEN)]).is_err());
+
+        assert!(decrypt(&DataKey::generate().unwrap(), &ciphertext).is_err());
+    }
+
+    #[test]
+    fn wrapped_keys() {
+        let path = std::env::temp_dir()
+            .join(format!("ptar-master-key-test-{}", std::process::id()));
+        fs::write(&path, format!("{}\n", "ab".repeat(KEY_LEN))).unwrap();
+        let master = MasterKey::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let data_key = DataKey::generate().unwrap();
+        let wrapped = master.wrap(&data_key, Path::new("a/b")).unwrap();
+        let unwrapped = master.unwrap(&wrapped, Path::new("a/b")).unwrap();
+        assert_eq!(unwrapped.0, data_key.0);
+
+        assert!(master.unwrap(&wrapped, Path::new("a/c")).is_err());
+        assert!(master.unwrap(&wrapped.replacen(&master.id, "0000000000000000", 1),
+                              Path::new("a/b")).is_err());
+    }
+}
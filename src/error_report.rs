@@ -0,0 +1,111 @@
+//! `--error-report`: a file listing each error with a file during a run, with the file's
+//! path, the operation that failed, the OS error number if any and the message, for
+//! scripts to act on rather than searching the logs.
+//!
+//! It's written once the run ends, as a JSON array, or as CSV if its name ends in
+//! `.csv`, and lists nothing if there were no errors.
+
+use anyhow::Context;
+use crate::Result;
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The errors recorded so far, shared between threads.
+#[derive(Debug, Default)]
+pub struct ErrorReport {
+    errors: Mutex<Vec<FileError>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct FileError {
+    /// Empty if the error isn't with a particular file.
+    path: PathBuf,
+    operation: &'static str,
+    errno: Option<i32>,
+    message: String,
+}
+
+impl ErrorReport {
+    /// Record `err` from `operation`, e.g. `open`, on the file at `path`.
+    pub fn record(&self, path: &Path, operation: &'static str, err: &anyhow::Error) {
+        let errno = err.chain().find_map(|e| e.downcast_ref::<io::Error>()?.raw_os_error());
+        self.errors.lock().expect("error report lock").push(FileError {
+            path: path.to_path_buf(),
+            operation,
+            errno,
+            message: format!("{err:#}"),
+        });
+    }
+
+    /// Write the errors recorded to `path`, sorted by file path.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut errors = self.errors.lock().expect("error report lock");
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        let data = if path.extension().is_some_and(|ext| ext == "csv") {
+            let mut csv = "path,operation,errno,message\n".to_string();
+            for e in errors.iter() {
+                csv.push_str(&[csv_field(&e.path.to_string_lossy()), csv_field(e.operation),
+                               e.errno.map(|n| n.to_string()).unwrap_or_default(),
+                               csv_field(&e.message)].join(","));
+                csv.push('\n');
+            }
+            csv.into_bytes()
+        } else {
+            let mut json = serde_json::to_vec_pretty(&*errors)?;
+            json.push(b'\n');
+            json
+        };
+        fs::write(path, data)
+            .with_context(|| format!("writing the error report '{}'", path.display()))
+    }
+}
+
+/// Where the errors counted in a run's final error are listed, with `--error-report`.
+pub fn listed_in(report_path: Option<&Path>) -> String {
+    report_path.map(|path| format!(", listed in '{}'", path.display())).unwrap_or_default()
+}
+
+/// `s` as a CSV field, quoted if it needs to be.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use crate::testsupport::TempDir;
+
+    #[test]
+    fn formats() {
+        let report = ErrorReport::default();
+        report.record(Path::new("b,\"c\""), "read", &anyhow!("bad\nthing"));
+        report.record(Path::new("a"), "open",
+                      &anyhow::Error::from(io::Error::from_raw_os_error(libc::EACCES))
+                          .context("opening 'a'"));
+        let dir = TempDir::new("ptar-test").unwrap();
+
+        let csv_path = dir.path().join("errors.csv");
+        report.write(&csv_path).unwrap();
+        assert_eq!(fs::read_to_string(&csv_path).unwrap(),
+                   "path,operation,errno,message\n\
+                    a,open,13,opening 'a': Permission denied (os error 13)\n\
+                    \"b,\"\"c\"\"\",read,,\"bad\nthing\"\n");
+
+        let json_path = dir.path().join("errors.json");
+        report.write(&json_path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_slice(&fs::read(&json_path).unwrap()).unwrap();
+        assert_eq!(json[0]["errno"], 13);
+        assert_eq!(json[1]["path"], "b,\"c\"");
+        assert_eq!(json[1]["errno"], serde_json::Value::Null);
+    }
+}
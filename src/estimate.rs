@@ -0,0 +1,143 @@
+//! `ptar estimate`: predict the compressed size and duration of compressing a directory,
+//! from a sample of its files, before running a large job.
+//!
+//! Every file's size is read from the walk, and a sample of the files, chosen by a hash
+//! of their paths as `verify --sample` chooses them, is compressed in memory as tar
+//! streams on `--threads` threads, as compress's shards would. The sample's compression
+//! ratio and throughput are then scaled up to the whole directory. The report is
+//! written to stdout as JSON.
+
+use anyhow::{ensure, Context};
+use crate::{
+    Result,
+    codec::Codec,
+    progress_writer::ProgressWriter,
+    verify::{is_sampled, parse_fraction},
+};
+use ignore::WalkBuilder;
+use rayon::prelude::*;
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    sync::atomic::Ordering,
+    time::Instant,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Directory to estimate compressing.
+    #[arg(long)]
+    in_path: PathBuf,
+
+    /// Fraction of the files to compress, e.g. `5%` or `0.05`. Larger samples give
+    /// better estimates, and take longer.
+    #[arg(long, value_parser = parse_fraction, default_value = "1%")]
+    sample: f64,
+
+    /// Seed for choosing the `--sample`. The same seed chooses the same files.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Compression format to estimate.
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    codec: Codec,
+
+    /// Compression level to estimate. Defaults to the codec's default level.
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+}
+
+/// The estimate written to stdout.
+#[derive(Debug, serde::Serialize)]
+struct Estimate {
+    file_count: u64,
+    uncompressed_bytes: u64,
+    sampled_file_count: u64,
+    sampled_bytes: u64,
+    /// Compressed bytes per uncompressed byte of tar stream in the sample.
+    ratio: f64,
+    estimated_compressed_bytes: u64,
+    estimated_duration_secs: f64,
+}
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    drop(cmd_args.codec.encoder(io::sink(), cmd_args.level, None)?);
+
+    // Tar stream bytes of every file: its header and its data padded to whole blocks.
+    let (mut file_count, mut uncompressed_bytes, mut tar_bytes) = (0, 0, 0);
+    let mut sampled = Vec::new();
+    for entry in WalkBuilder::new(&*cmd_args.in_path).standard_filters(false).build() {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let len = entry.metadata()?.len();
+        file_count += 1;
+        uncompressed_bytes += len;
+        tar_bytes += 512 + len.div_ceil(512) * 512;
+        if is_sampled(cmd_args.seed, entry.path(), cmd_args.sample) {
+            sampled.push(entry.into_path());
+        }
+    }
+    ensure!(file_count > 0, "No files in '{}'", cmd_args.in_path.display());
+    ensure!(!sampled.is_empty(), "No files were sampled; try a larger --sample");
+
+    // Share the sample out between threads, as compress shares files between shards.
+    let threads = args.threads().min(sampled.len());
+    let mut groups = vec![Vec::new(); threads];
+    for (i, path) in sampled.into_iter().enumerate() {
+        groups[i % threads].push(path);
+    }
+    let start = Instant::now();
+    let compressed = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?
+        .install(|| {
+            groups.par_iter()
+                  .with_max_len(1) // 1 group per thread
+                  .map(|paths| compress_sample(paths, &cmd_args))
+                  .collect::<Result<Vec<_>>>()
+        })?;
+    let duration = start.elapsed();
+
+    let sampled_file_count = groups.iter().map(Vec::len).sum::<usize>() as u64;
+    let (sampled_tar_bytes, sampled_compressed_bytes) = compressed.iter()
+        .fold((0, 0), |(tar, comp), (t, c)| (tar + t, comp + c));
+    let ratio = sampled_compressed_bytes as f64 / sampled_tar_bytes as f64;
+    let scale = tar_bytes as f64 / sampled_tar_bytes as f64;
+    let estimate = Estimate {
+        file_count,
+        uncompressed_bytes,
+        sampled_file_count,
+        sampled_bytes: sampled_tar_bytes,
+        ratio,
+        estimated_compressed_bytes: (tar_bytes as f64 * ratio).round() as u64,
+        estimated_duration_secs: duration.as_secs_f64() * scale,
+    };
+    tracing::info!(file_count, sampled_file_count, ratio,
+                   estimated_compressed_bytes = estimate.estimated_compressed_bytes,
+                   estimated_duration_secs = estimate.estimated_duration_secs,
+                   "Estimated compression");
+
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &estimate)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+/// Compress the files `paths` as one tar stream, discarding the output. Returns the
+/// tar stream's length and its compressed length.
+fn compress_sample(paths: &[PathBuf], cmd_args: &Args) -> Result<(u64, u64)> {
+    let (compressed, compressed_bytes) = ProgressWriter::new(io::sink());
+    let encoder = cmd_args.codec.encoder(compressed, cmd_args.level, None)?;
+    let (tar_writer, tar_bytes) = ProgressWriter::new(encoder);
+    let mut tarb = tar::Builder::new(tar_writer);
+    for path in paths {
+        let rel_path = path.strip_prefix(&*cmd_args.in_path).unwrap_or(path);
+        tarb.append_path_with_name(path, rel_path)
+            .with_context(|| format!("reading '{}'", path.display()))?;
+    }
+    tarb.into_inner()?.into_inner().finish()?;
+    Ok((tar_bytes.load(Ordering::SeqCst), compressed_bytes.load(Ordering::SeqCst)))
+}
@@ -0,0 +1,155 @@
+//! `ptar extract-one`: extract a single file, reading only the archive the manifest
+//! says holds it, and only from the file's offset in that archive.
+//!
+//! With `compress --seekable`, reading starts at the zstd frame holding the file.
+//! Otherwise the tar stream before the offset is still decompressed, but its entries
+//! aren't parsed or written. Manifests from before offsets were recorded fall back to
+//! reading the archive's entries until the file.
+//!
+//! A file split by `compress --chunk-size` is reassembled from each of its chunks, read
+//! the same way from where the manifest says it is.
+
+use anyhow::{bail, ensure, Context};
+use crate::{
+    Result, archive_set, manifest,
+    decompress::{self, EntryKeys, UnpackArgs, UnpackOptions},
+    entry_encryption::MasterKey,
+    shard_encryption::{self, DecryptionKeys},
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Path of the file to extract, relative to the archived directory, as listed in the
+    /// manifest, e.g. `docs/notes.txt`.
+    #[arg(long)]
+    path: PathBuf,
+
+    /// Directory to extract the file into, at its path relative to this.
+    #[arg(long)]
+    out_dir: PathBuf,
+
+    #[command(flatten)]
+    unpack: UnpackArgs,
+
+    /// Decrypt a file compressed with `--entry-key-file`, using the same master key file.
+    #[arg(long, value_name = "KEY_FILE")]
+    entry_key_file: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--encrypt`, using the age identities in this
+    /// file.
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    identity: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--passphrase`. The passphrase is read from the
+    /// `PTAR_PASSPHRASE` environment variable if set, or else prompted for.
+    #[arg(long, visible_alias = "passphrase-prompt")]
+    passphrase: bool,
+}
+
+pub fn main(cmd_args: Args, _args: crate::Args) -> Result<()> {
+    let path = manifest_path(&cmd_args.path);
+    ensure!(!path.as_os_str().is_empty(), "--path must name a file");
+
+    let mut entries = manifest::read(&cmd_args.in_dir)
+        .context("extract-one requires the manifest, to find the file's archive")?
+        .into_iter()
+        .filter(|e| e.path == path)
+        .collect::<Vec<_>>();
+    ensure!(!entries.is_empty(), "'{}' isn't in the manifest", path.display());
+    // A chunked file has an entry for each chunk, and any other file just one.
+    if entries[0].chunk_offset.is_none() {
+        entries.truncate(1);
+    }
+    let entry = &entries[0];
+
+    let entry_keys = match (&entry.wrapped_key, &cmd_args.entry_key_file) {
+        (Some(wrapped), Some(key_file)) => Some(EntryKeys {
+            master_key: MasterKey::load(key_file)?,
+            wrapped: HashMap::from([(path.clone(), wrapped.clone())]),
+        }),
+        (Some(_), None) => bail!("'{}' is encrypted; pass --entry-key-file", path.display()),
+        (None, _) => None,
+    };
+    let passphrase = cmd_args.passphrase.then(|| shard_encryption::read_passphrase(false))
+                                        .transpose()?;
+    let keys = DecryptionKeys::new(cmd_args.identity.as_deref(), passphrase)?;
+
+    fs::create_dir_all(&*cmd_args.out_dir)?;
+    let unpack = UnpackOptions::new(&cmd_args.out_dir, &cmd_args.unpack)?;
+    for entry in entries.iter() {
+        extract_entry(&cmd_args.in_dir, entry, keys.as_ref(), entry_keys.as_ref(), &unpack)?;
+    }
+    unpack.finish_chunked()?;
+    ensure!(unpack.paths.rejected_count() == 0,
+            "Refused to extract '{}', whose path is unsafe", path.display());
+
+    tracing::info!(path = %path.display(), archive = entries[0].archive,
+                   offset = entries[0].offset, chunk_count = entries.len(),
+                   "Extracted file");
+    Ok(())
+}
+
+/// Extract the file, or chunk of a file, that manifest `entry` is of.
+fn extract_entry(in_dir: &Path, entry: &manifest::Entry, keys: Option<&DecryptionKeys>,
+                 entry_keys: Option<&EntryKeys>, unpack: &UnpackOptions)
+-> Result<()>
+{
+    let path = &entry.path;
+    let archive_path = in_dir.join(&entry.archive);
+    let archive = archive_set::open_at_entry(&archive_path, entry, keys, None)?
+        .with_context(|| format!("'{}' isn't a recognised archive", archive_path.display()))?;
+
+    let mut tar = tar::Archive::new(archive.reader);
+    let mut found = false;
+    for tar_entry in tar.entries()? {
+        let mut tar_entry = tar_entry?;
+        if found {
+            // Later parts of a stream written by `StreamWriter` directly follow the first.
+            if entry.parts.is_none()
+               || manifest_path(&tar_entry.path()?) != *path
+               || !decompress::append_part(&mut tar_entry, unpack)? {
+                break;
+            }
+            continue;
+        }
+        // Tar archives converted by `from-tar` may have paths such as `./a/b`.
+        if manifest_path(&tar_entry.path()?) != *path {
+            ensure!(entry.offset.is_none(),
+                    "The manifest's offset of '{}' in '{}' is at another entry, '{}'",
+                    path.display(), archive_path.display(), tar_entry.path()?.display());
+            continue;
+        }
+        if entry.chunk_offset.is_some() {
+            ensure!(decompress::unpack_chunk(&mut tar_entry, unpack)?,
+                    "The manifest's chunk of '{}' in '{}' isn't a chunk", path.display(),
+                    archive_path.display());
+        } else {
+            match entry_keys {
+                Some(keys) => keys.unpack(&mut tar_entry, unpack)?,
+                None => decompress::unpack_in(&mut tar_entry, unpack)?,
+            }
+        }
+        found = true;
+        if entry.parts.is_none() {
+            break;
+        }
+    }
+    ensure!(found, "'{}' isn't in '{}'", path.display(), archive_path.display());
+    Ok(())
+}
+
+/// The manifest's form of `path`, e.g. `a/b` for `./a/b/`.
+fn manifest_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
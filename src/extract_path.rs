@@ -0,0 +1,262 @@
+//! Where entries are extracted, and refusing those that would write outside the output
+//! directory.
+//!
+//! Every extracted entry's path goes through [`ExtractPaths`] rather than relying on the
+//! `tar` crate's checks, as encrypted files, parts and special files are written here.
+//! An entry is rejected, logged and counted, but not fatal, if:
+//!
+//! * Its path is absolute, without `--allow-absolute-paths`.
+//! * Its path has a `..` component, before or after `--transform`.
+//! * Its parent directory is a symlink out of the output directory, e.g. one extracted
+//!   from an earlier entry.
+//! * It's a hard link to a file that would be rejected, or is out of the output
+//!   directory.
+//!
+//! A symlink already where an entry is extracted is replaced rather than written
+//! through.
+
+use crate::{Result, transform::Transform};
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Maps entries' paths to where they're extracted, and counts those rejected.
+#[derive(Debug)]
+pub struct ExtractPaths {
+    out_dir: PathBuf,
+    /// `out_dir` with symlinks resolved, to check extracted files stay inside it.
+    canonical_out_dir: PathBuf,
+    strip_components: usize,
+    transform: Transform,
+    allow_absolute: bool,
+    rejected_count: AtomicU64,
+}
+
+impl ExtractPaths {
+    /// Paths in `out_dir`, which must exist.
+    pub fn new(out_dir: &Path, strip_components: usize, transform: &[String],
+               allow_absolute: bool)
+    -> Result<ExtractPaths>
+    {
+        Ok(ExtractPaths {
+            out_dir: out_dir.to_path_buf(),
+            canonical_out_dir: out_dir.canonicalize()?,
+            strip_components,
+            transform: Transform::new(transform)?,
+            allow_absolute,
+            rejected_count: AtomicU64::new(0),
+        })
+    }
+
+    /// Where to extract the entry with `path`: at its path rewritten by `--transform`,
+    /// less `--strip-components` leading components, in the output directory, or with
+    /// `--allow-absolute-paths` at its absolute path. None if that leaves nothing, or if
+    /// it's rejected, so the entry is skipped.
+    pub fn dst(&self, path: &Path) -> Option<PathBuf> {
+        match self.map(path) {
+            Ok(dst) => dst,
+            Err(reason) => {
+                self.reject(path, reason);
+                None
+            },
+        }
+    }
+
+    /// `dst(path)`, after creating its parent directories and checking they're not
+    /// reached through a symlink out of the output directory. Any symlink at the returned
+    /// path is removed, so it can be created without following it.
+    pub fn prepare(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let Some(dst) = self.dst(path) else {
+            return Ok(None);
+        };
+        let parent = dst.parent().expect("dst has a parent directory");
+        if !self.create_dirs(parent)? {
+            self.reject(path, "parent directory is a symlink out of the output directory");
+            return Ok(None);
+        }
+        if dst.symlink_metadata().is_ok_and(|meta| meta.file_type().is_symlink()) {
+            remove_existing(&dst)?;
+        }
+        Ok(Some(dst))
+    }
+
+    /// `dst(path)`, for a file already extracted, e.g. to append to, checking its parent
+    /// directory isn't reached through a symlink out of the output directory.
+    pub fn existing(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let Some(dst) = self.dst(path) else {
+            return Ok(None);
+        };
+        if !self.is_inside(dst.parent().expect("dst has a parent directory"))? {
+            self.reject(path, "parent directory is a symlink out of the output directory");
+            return Ok(None);
+        }
+        Ok(Some(dst))
+    }
+
+    /// Where the target of the hard link `path` to `target` was extracted, to link to.
+    /// None if it's rejected.
+    pub fn hard_link_src(&self, path: &Path, target: &Path) -> Result<Option<PathBuf>> {
+        let src = match self.map(target) {
+            Ok(Some(src)) => src,
+            Ok(None) => {
+                self.reject(path, "hard link target is stripped by --strip-components");
+                return Ok(None);
+            },
+            Err(reason) => {
+                self.reject(path, &format!("hard link target: {reason}"));
+                return Ok(None);
+            },
+        };
+        // The target itself may be a symlink, which is linked rather than followed.
+        let parent = src.parent().expect("src has a parent directory");
+        if !self.is_inside(parent)? {
+            self.reject(path, "hard link target is through a symlink out of the output \
+                               directory");
+            return Ok(None);
+        }
+        Ok(Some(src))
+    }
+
+    /// How many entries were rejected.
+    pub fn rejected_count(&self) -> u64 {
+        self.rejected_count.load(Ordering::SeqCst)
+    }
+
+    /// `dst()`, or why `path` is rejected.
+    fn map(&self, path: &Path) -> std::result::Result<Option<PathBuf>, &'static str> {
+        let (absolute, rel_path) = normalize(path)?;
+        let (transformed_absolute, rel_path) = normalize(&self.transform.apply(&rel_path))?;
+        let absolute = absolute || transformed_absolute;
+        if absolute && !self.allow_absolute {
+            return Err("path is absolute; extract it there with --allow-absolute-paths");
+        }
+        let rel_path = rel_path.components()
+                               .skip(self.strip_components)
+                               .collect::<PathBuf>();
+        if rel_path.as_os_str().is_empty() {
+            return Ok(None);
+        }
+        let base = if absolute { Path::new("/") } else { &*self.out_dir };
+        Ok(Some(base.join(rel_path)))
+    }
+
+    /// Create the directory `dir` and its parents. In the output directory, they're
+    /// created one at a time, checking each symlink on the way resolves inside it before
+    /// creating anything through it. Returns false, having created nothing through it,
+    /// if one doesn't.
+    fn create_dirs(&self, dir: &Path) -> Result<bool> {
+        let Ok(rel_dir) = dir.strip_prefix(&self.out_dir) else {
+            fs::create_dir_all(dir)?;
+            return Ok(true);
+        };
+        let mut path = self.out_dir.clone();
+        for component in rel_dir.components() {
+            path.push(component);
+            match fs::create_dir(&path) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    if path.symlink_metadata()?.file_type().is_symlink()
+                       && !self.is_inside(&path)? {
+                        return Ok(false);
+                    }
+                },
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether the existing directory `dir` is in the output directory, with symlinks
+    /// resolved. Always true for directories from absolute paths, outside the output
+    /// directory, which are only extracted with `--allow-absolute-paths`.
+    fn is_inside(&self, dir: &Path) -> Result<bool> {
+        if !dir.starts_with(&self.out_dir) {
+            return Ok(true);
+        }
+        Ok(dir.canonicalize()?.starts_with(&self.canonical_out_dir))
+    }
+
+    fn reject(&self, path: &Path, reason: &str) {
+        tracing::warn!(path = %path.display(), reason, "Rejected unsafe entry");
+        self.rejected_count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Remove the file at `path` if there is one, e.g. to replace it with a new file rather
+/// than write through a symlink.
+pub fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `path` is absolute, and its normal components. Leading `.`s are ignored, as
+/// GNU tar and the `tar` crate do, but `..`s are rejected.
+fn normalize(path: &Path) -> std::result::Result<(bool, PathBuf), &'static str> {
+    let mut absolute = false;
+    let mut rel_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => absolute = true,
+            Component::CurDir => (),
+            Component::ParentDir => return Err("path contains `..`"),
+            Component::Normal(component) => rel_path.push(component),
+        }
+    }
+    Ok((absolute, rel_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::TempDir;
+
+    #[test]
+    fn maps_and_rejects() {
+        let out_dir = std::env::temp_dir();
+        let paths = |strip, transform: &[&str], allow_absolute| {
+            let transform = transform.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            ExtractPaths::new(&out_dir, strip, &transform, allow_absolute).unwrap()
+        };
+
+        let default = paths(0, &[], false);
+        assert_eq!(default.dst(Path::new("./a/b")), Some(out_dir.join("a/b")));
+        assert_eq!(default.dst(Path::new("/etc/passwd")), None);
+        assert_eq!(default.dst(Path::new("a/../../b")), None);
+        assert_eq!(default.rejected_count(), 2);
+
+        let stripped = paths(1, &["s,^x,../x,"], false);
+        assert_eq!(stripped.dst(Path::new("a/b")), Some(out_dir.join("b")));
+        assert_eq!(stripped.dst(Path::new("a")), None);
+        assert_eq!(stripped.dst(Path::new("x/b")), None);
+        assert_eq!(stripped.rejected_count(), 1);
+
+        let absolute = paths(0, &[], true);
+        assert_eq!(absolute.dst(Path::new("/etc/passwd")), Some(PathBuf::from("/etc/passwd")));
+        assert_eq!(absolute.dst(Path::new("/etc/../root")), None);
+    }
+
+    #[test]
+    fn rejects_symlink_escapes() {
+        let out_dir = TempDir::new("ptar-test").unwrap();
+        let target = TempDir::new("ptar-test").unwrap();
+        std::os::unix::fs::symlink(target.path(), out_dir.path().join("link")).unwrap();
+        fs::create_dir(out_dir.path().join("d")).unwrap();
+        std::os::unix::fs::symlink("d", out_dir.path().join("inner")).unwrap();
+        let paths = ExtractPaths::new(out_dir.path(), 0, &[], false).unwrap();
+
+        assert_eq!(paths.prepare(Path::new("link/x/y")).unwrap(), None);
+        assert_eq!(paths.existing(Path::new("link/y")).unwrap(), None);
+        assert_eq!(paths.rejected_count(), 2);
+        assert_eq!(fs::read_dir(target.path()).unwrap().count(), 0);
+
+        assert_eq!(paths.prepare(Path::new("inner/x/y")).unwrap(),
+                   Some(out_dir.path().join("inner/x/y")));
+        assert!(out_dir.path().join("d/x").is_dir());
+        assert_eq!(paths.rejected_count(), 2);
+    }
+}
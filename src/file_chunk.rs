@@ -0,0 +1,108 @@
+//! Files split into chunks with `compress --chunk-size`, so the chunks of one large
+//! file are compressed in parallel by several shards, and reassembled on extraction.
+//!
+//! Each chunk is a tar entry with the file's path and metadata, holding its range of the
+//! file's contents, with pax records of where that range starts and the whole file's
+//! size. Each chunk is listed in the manifest, with its offset in `chunk_offset`.
+//!
+//! Other tar tools see several entries with the same path, each extracted over the one
+//! before, so only one chunk of the file is left.
+
+use anyhow::Context;
+use crate::Result;
+use std::io::Read;
+
+pub const OFFSET_PAX_KEY: &str = "PTAR.chunk.offset";
+pub const FILE_SIZE_PAX_KEY: &str = "PTAR.chunk.size";
+
+/// A range of a file's contents, archived as one tar entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Chunk {
+    pub offset: u64,
+    pub len: u64,
+    /// Size of the whole file.
+    pub file_size: u64,
+}
+
+/// The chunks of at most `chunk_size` bytes of a file of `file_size` bytes.
+pub fn split(file_size: u64, chunk_size: u64) -> impl Iterator<Item = Chunk> {
+    assert!(chunk_size > 0);
+    (0..file_size).step_by(chunk_size as usize).map(move |offset| Chunk {
+        offset,
+        len: chunk_size.min(file_size - offset),
+        file_size,
+    })
+}
+
+impl Chunk {
+    /// The pax records marking an entry as this chunk.
+    pub fn records(&self) -> [(String, Vec<u8>); 2] {
+        [
+            (OFFSET_PAX_KEY.to_string(), self.offset.to_string().into_bytes()),
+            (FILE_SIZE_PAX_KEY.to_string(), self.file_size.to_string().into_bytes()),
+        ]
+    }
+}
+
+/// The chunk `entry` holds, if it's a chunk of a file.
+pub fn read<R: Read>(entry: &mut tar::Entry<R>) -> Result<Option<Chunk>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(None);
+    };
+    let (mut offset, mut file_size) = (None, None);
+    for extension in extensions {
+        let extension = extension?;
+        let field = match extension.key_bytes() {
+            key if key == OFFSET_PAX_KEY.as_bytes() => &mut offset,
+            key if key == FILE_SIZE_PAX_KEY.as_bytes() => &mut file_size,
+            _ => continue,
+        };
+        *field = Some(extension.value()
+                               .ok()
+                               .and_then(|value| value.parse::<u64>().ok())
+                               .context("Invalid chunk pax record")?);
+    }
+    let (Some(offset), Some(file_size)) = (offset, file_size) else {
+        return Ok(None);
+    };
+    Ok(Some(Chunk {
+        offset,
+        len: entry.header().size()?,
+        file_size,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar_format::TarFormat;
+
+    #[test]
+    fn splits_and_reads() {
+        let chunks = split(10, 4).collect::<Vec<_>>();
+        assert_eq!(chunks.iter().map(|c| (c.offset, c.len)).collect::<Vec<_>>(),
+                   [(0, 4), (4, 4), (8, 2)]);
+        assert_eq!(split(8, 4).count(), 2);
+
+        let mut tarb = tar::Builder::new(Vec::new());
+        for chunk in [None, Some(chunks[2])] {
+            let mut header = TarFormat::Pax.new_header();
+            header.set_size(2);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            let records = chunk.iter().flat_map(|c| c.records()).collect::<Vec<_>>();
+            let records = records.iter()
+                                 .map(|(key, value)| (key.as_str(), value.as_slice()))
+                                 .collect::<Vec<_>>();
+            TarFormat::Pax.append_with_records(&mut tarb, &mut header, "f".as_ref(),
+                                               &b"ab"[..], &records).unwrap();
+        }
+        let data = tarb.into_inner().unwrap();
+        let mut archive = tar::Archive::new(&*data);
+        let read_chunks = archive.entries().unwrap()
+                                 .map(|entry| read(&mut entry.unwrap()).unwrap())
+                                 .collect::<Vec<_>>();
+        assert_eq!(read_chunks, [None, Some(chunks[2])]);
+    }
+}
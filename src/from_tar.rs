@@ -0,0 +1,265 @@
+//! `ptar from-tar`: convert a tar archive, e.g. one written by another tool, to an
+//! archive set, so it can be extracted in parallel.
+//!
+//! The tar archive is read once, in any codec decompress detects, and its entries are
+//! shared out between the shards, each to the one with the fewest bytes so far, except
+//! hard links, which go to the same shard as their target. Each shard compresses its
+//! entries on its own thread into one archive, copying them as they are in the input.
+//! The manifest lists the regular files.
+
+use anyhow::{anyhow, ensure, Context};
+use crate::{
+    Result, archive_set, manifest, owners, tar_stream,
+    codec::Codec,
+};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    os::unix::ffi::OsStringExt,
+    path::{Component, Path, PathBuf},
+    thread,
+    time::Instant,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Tar archive to convert, uncompressed or in any codec decompress detects.
+    #[arg(long = "in", value_name = "FILE")]
+    in_path: PathBuf,
+
+    /// Directory to write the archive set to, created if it doesn't exist.
+    #[arg(long)]
+    out_dir: PathBuf,
+
+    /// Number of archives to write, each compressed on its own thread. Defaults to
+    /// `--threads`.
+    #[arg(long, value_name = "N")]
+    shards: Option<usize>,
+
+    /// Compression format of the archives.
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    codec: Codec,
+
+    /// Compression level. Defaults to the codec's default level.
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+}
+
+/// Bytes of entry data sent to a shard at a time.
+const DATA_BUF_LEN: u64 = 1024 * 1024;
+
+/// Messages queued to each shard's thread.
+enum Msg {
+    /// An entry's header blocks, and its manifest entry if it's a regular file.
+    Headers(Vec<u8>, Option<Box<manifest::Entry>>),
+    /// Part of the current entry's data.
+    Data(Vec<u8>),
+    /// The whole input has been read, so the archive is complete.
+    Finish,
+}
+
+/// A shard's queue, and the bytes sent to it so far.
+struct Shard {
+    tx: crossbeam_channel::Sender<Msg>,
+    bytes: u64,
+    thread: thread::JoinHandle<Result<Vec<manifest::Entry>>>,
+}
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    let start = Instant::now();
+    let shard_count = cmd_args.shards.unwrap_or_else(|| args.threads());
+    ensure!(shard_count > 0, "--shards must be at least 1");
+    drop(cmd_args.codec.encoder(io::sink(), cmd_args.level, None)?);
+    let mut archive = archive_set::open(&cmd_args.in_path, None, None)
+        .with_context(|| format!("opening '{}'", cmd_args.in_path.display()))?
+        .with_context(|| format!("'{}' isn't a tar archive", cmd_args.in_path.display()))?;
+
+    fs::create_dir_all(&*cmd_args.out_dir)?;
+    // Created first, so converting into an existing set fails before writing archives.
+    let manifest_writer = manifest::Writer::create(&cmd_args.out_dir, manifest::Format::Jsonl)
+        .with_context(|| format!("creating the manifest in '{}'",
+                                 cmd_args.out_dir.display()))?;
+
+    let mut shards = Vec::with_capacity(shard_count);
+    for num in 0..shard_count {
+        let (tx, rx) = crossbeam_channel::bounded(16);
+        let name = format!("{num:08}.{ext}", ext = cmd_args.codec.extension());
+        let (out_dir, codec, level) = (cmd_args.out_dir.clone(), cmd_args.codec,
+                                       cmd_args.level);
+        shards.push(Shard {
+            tx,
+            bytes: 0,
+            thread: thread::Builder::new()
+                .name(format!("shard-{num}"))
+                .spawn(move || write_shard(name, &out_dir, codec, level, rx))?,
+        });
+    }
+
+    let read_res = read_entries(&mut archive.reader, &mut shards);
+    let mut entries = Vec::new();
+    let mut shard_res = Ok(());
+    for shard in shards {
+        if read_res.is_ok() {
+            // Senders are dropped without this on errors, so the shards don't complete
+            // their archives.
+            let _ = shard.tx.send(Msg::Finish);
+        }
+        drop(shard.tx);
+        match shard.thread.join().expect("joining shard thread") {
+            Ok(shard_entries) => entries.extend(shard_entries),
+            Err(err) => if shard_res.is_ok() {
+                shard_res = Err(err);
+            },
+        }
+    }
+    // A shard's error is why sending to it failed.
+    shard_res?;
+    let file_count = read_res.with_context(|| format!("reading '{}'",
+                                                      cmd_args.in_path.display()))?;
+
+    // Only now every archive is complete can the manifest refer to them.
+    let tx = manifest_writer.sender();
+    for entry in entries {
+        tx.send(entry).expect("manifest writer thread is running");
+    }
+    drop(tx);
+    manifest_writer.finish()?;
+
+    tracing::info!(in_path = %cmd_args.in_path.display(), file_count, shard_count,
+                   duration_ms = start.elapsed().as_millis(), "Converted from tar");
+    Ok(())
+}
+
+/// Read the entries of the tar stream `r`, and send each to a shard. Returns the
+/// number of regular files.
+fn read_entries(r: &mut impl Read, shards: &mut [Shard]) -> Result<u64> {
+    // The shard of each regular file, by path, for hard links to it.
+    let mut file_shards = HashMap::<Vec<u8>, usize>::new();
+    let mut file_count = 0;
+    let send = |shard: &Shard, msg: Msg| -> Result<()> {
+        shard.tx.send(msg).map_err(|_| anyhow!("Shard thread stopped"))
+    };
+
+    while let Some(headers) = tar_stream::read_headers(r)? {
+        let entry_type = headers.header.entry_type();
+        if entry_type.is_pax_global_extensions() {
+            // It applies to every entry after it, whichever shard they're in.
+            for shard in shards.iter() {
+                send(shard, Msg::Headers(headers.bytes.clone(), None))?;
+            }
+            continue;
+        }
+
+        let linked_shard = match headers.link_path {
+            Some(ref target) if entry_type.is_hard_link() => file_shards.get(target).copied(),
+            _ => None,
+        };
+        let num = match linked_shard {
+            Some(num) => num,
+            None => shards.iter()
+                          .enumerate()
+                          .min_by_key(|(_, shard)| shard.bytes)
+                          .map(|(num, _)| num)
+                          .expect("at least 1 shard"),
+        };
+        let entry = entry_type.is_file().then(|| {
+            file_count += 1;
+            file_shards.insert(headers.path.clone(), num);
+            Box::new(manifest::Entry {
+                // As compress writes them, e.g. `d/f` rather than `./d/f` or `/d/f`.
+                path: Path::new(&OsString::from_vec(headers.path.clone()))
+                          .components()
+                          .filter(|c| !matches!(c, Component::CurDir | Component::RootDir))
+                          .collect(),
+                size: headers.size,
+                mtime: headers.mtime.0,
+                mtime_nsec: headers.mtime.1,
+                archive: String::new(),
+                uid: u32::try_from(headers.uid).ok(),
+                gid: u32::try_from(headers.gid).ok(),
+                uname: owners::header_name(headers.header.username()),
+                gname: owners::header_name(headers.header.groupname()),
+                checksum: None,
+                wrapped_key: None,
+                parts: None,
+                original_path: None,
+                case_collision: None,
+                offset: None,
+                frame_offset: None,
+                frame_tar_offset: None,
+                chunk_offset: None,
+            })
+        });
+
+        let shard = &mut shards[num];
+        shard.bytes += headers.bytes.len() as u64 + headers.padded_size();
+        let padded_size = headers.padded_size();
+        send(shard, Msg::Headers(headers.bytes, entry))?;
+        let mut remaining = padded_size;
+        while remaining > 0 {
+            let mut buf = vec![0; remaining.min(DATA_BUF_LEN) as usize];
+            r.read_exact(&mut buf).context("Tar stream ends inside an entry")?;
+            remaining -= buf.len() as u64;
+            send(shard, Msg::Data(buf))?;
+        }
+    }
+    Ok(file_count)
+}
+
+/// Write the entries from `rx` to the archive `name` in `out_dir`, and return their
+/// manifest entries. If the input isn't all read, the archive is left incomplete, as
+/// `name` with `PARTIAL_SUFFIX`. A shard sent no entries writes no archive.
+fn write_shard(name: String, out_dir: &Path, codec: Codec, level: Option<i32>,
+               rx: crossbeam_channel::Receiver<Msg>)
+-> Result<Vec<manifest::Entry>>
+{
+    let out_path = out_dir.join(&*name);
+    let partial_path = out_dir.join(format!("{name}{}", archive_set::PARTIAL_SUFFIX));
+    ensure!(!out_path.exists(), "'{}' already exists", out_path.display());
+    let file = File::options().write(true).create_new(true).open(&*partial_path)
+        .with_context(|| format!("creating '{}'", partial_path.display()))?;
+    let mut encoder = codec.encoder(BufWriter::with_capacity(1024 * 1024, file), level,
+                                    None)?;
+
+    let mut entries = Vec::new();
+    let mut tar_bytes = 0;
+    let mut finished = false;
+    for msg in rx.iter() {
+        match msg {
+            Msg::Headers(bytes, entry) => {
+                if let Some(mut entry) = entry {
+                    entry.archive = name.clone();
+                    entry.offset = Some(tar_bytes);
+                    entries.push(*entry);
+                }
+                encoder.write_all(&bytes)?;
+                tar_bytes += bytes.len() as u64;
+            },
+            Msg::Data(buf) => {
+                encoder.write_all(&buf)?;
+                tar_bytes += buf.len() as u64;
+            },
+            Msg::Finish => finished = true,
+        }
+    }
+    if !finished {
+        return Ok(Vec::new());
+    }
+    if tar_bytes == 0 {
+        drop(encoder);
+        fs::remove_file(&*partial_path)?;
+        return Ok(Vec::new());
+    }
+
+    // The end of archive marker.
+    encoder.write_all(&[0; 1024])?;
+    let file = encoder.finish()?.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(&*partial_path, &*out_path)?;
+    tracing::debug!(archive = name, file_count = entries.len(), tar_bytes,
+                    compressed_bytes = file.metadata()?.len(), "Wrote archive");
+    Ok(entries)
+}
@@ -0,0 +1,254 @@
+//! Check, and optionally repair, an archive set after a crash.
+//!
+//! compress keeps an archive set consistent with this protocol:
+//!
+//! 1. Each archive is written to `<name>.partial`, synced to disk, and renamed into
+//!    place before any manifest entries referring to it are sent to the manifest writer.
+//! 2. The manifest writer syncs the manifest after each burst of entries, so a crash
+//!    loses at most the entries of the archives finished just before it, and leaves at
+//!    most one partial line at the end of `manifest.jsonl`.
+//! 3. Other state files are written to `<name>.tmp` and renamed into place.
+//!
+//! So after a crash, the manifest's complete entries all refer to complete archives,
+//! and the leftovers are: a partial last manifest line, `.partial` archives and their
+//! checksums files, and `.tmp` files. `fsck --repair` removes those, and so does the
+//! next compress into the directory, bar the manifest line, if the lock file confirms
+//! the crash. Archives from before `.partial` files were used may also be incomplete,
+//! so fsck still reads through every archive to check.
+
+use anyhow::{bail, ensure};
+use crate::{
+    Result, archive_set, codec, manifest,
+    archive_name::NameArgs,
+    shard_encryption::{self, DecryptionKeys},
+};
+use rayon::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Directory containing the archive set.
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Fix the problems found, deleting incomplete archives and temporary files, and
+    /// truncating the manifest after its last complete entry.
+    #[arg(long)]
+    repair: bool,
+
+    /// Decrypt archives compressed with `--encrypt`, using the age identities in this
+    /// file.
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    identity: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--passphrase`. The passphrase is read from the
+    /// `PTAR_PASSPHRASE` environment variable if set, or else prompted for.
+    #[arg(long, visible_alias = "passphrase-prompt")]
+    passphrase: bool,
+
+    #[command(flatten)]
+    names: NameArgs,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ArchiveState {
+    Complete,
+    Incomplete,
+    Missing,
+}
+
+/// Suffixes of the checksums files written alongside archives by `--checksums`.
+const CHECKSUMS_FILE_SUFFIXES: &[&str] = &[".blake3", ".sha256"];
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    let dir = &*cmd_args.in_dir;
+    let repair = cmd_args.repair;
+    let mut problem_count = 0_usize;
+
+    let candidate_paths = archive_set::candidate_paths(dir)?;
+
+    for path in candidate_paths.iter() {
+        if path.extension().is_some_and(|ext| ext == "tmp") {
+            tracing::warn!(path = %path.display(), "Found orphan temporary file");
+            problem_count += 1;
+            if repair {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+
+    for path in archive_set::partial_paths(dir)? {
+        tracing::warn!(path = %path.display(), "Found incomplete archive");
+        problem_count += 1;
+        if repair {
+            let name = file_name(&path);
+            remove_if_exists(&path)?;
+            remove_checksums_files(dir, name.strip_suffix(archive_set::PARTIAL_SUFFIX)
+                                            .expect("partial path has the suffix"))?;
+        }
+    }
+
+    let jsonl_path = dir.join(manifest::MANIFEST_FILE_NAME);
+    let mut entries = if jsonl_path.exists() {
+        let file_len = fs::metadata(&*jsonl_path)?.len();
+        let (entries, durable_len) = manifest::read_jsonl_prefix(File::open(&*jsonl_path)?)?;
+        if durable_len < file_len {
+            tracing::warn!(durable_len, file_len, entry_count = entries.len(),
+                           "Manifest has an incomplete record after its last complete one");
+            problem_count += 1;
+            if repair {
+                let file = fs::OpenOptions::new().write(true).open(&*jsonl_path)?;
+                file.set_len(durable_len)?;
+                file.sync_all()?;
+            }
+        }
+        entries
+    } else {
+        // Parquet manifests are only complete once their footer is written, so they
+        // can't be repaired, only checked.
+        manifest::read(dir)?
+    };
+
+    // Check every archive the manifest refers to, and every file that may be an archive.
+    let referenced = entries.iter().map(|e| e.archive.clone()).collect::<BTreeSet<_>>();
+    let mut archive_names = referenced.clone();
+    let archive_pattern = cmd_args.names.pattern()?;
+    for path in candidate_paths.iter() {
+        let name = file_name(path);
+        if archive_pattern.is_match(&name) {
+            archive_names.insert(name);
+        }
+    }
+
+    // Without the keys, encrypted archives would look incomplete and be removed.
+    ensure!(cmd_args.identity.is_some() || cmd_args.passphrase
+            || !archive_names.iter().any(|name| {
+                Path::new(name).extension().is_some_and(|ext| ext == shard_encryption::EXTENSION)
+            }),
+            "The archive set is encrypted; pass --identity or --passphrase to check it");
+    let passphrase = cmd_args.passphrase.then(|| shard_encryption::read_passphrase(false))
+                                        .transpose()?;
+    let keys = DecryptionKeys::new(cmd_args.identity.as_deref(), passphrase)?;
+
+    let states = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build()?
+        .install(|| {
+            archive_names.into_par_iter()
+                .map(|name| -> Result<(String, ArchiveState)> {
+                    let state = archive_state(&dir.join(&*name), keys.as_ref())?;
+                    Ok((name, state))
+                })
+                .collect::<Result<BTreeMap<String, ArchiveState>>>()
+        })?;
+
+    let mut dropped_archives = BTreeSet::new();
+    for (name, state) in states.iter() {
+        let is_referenced = referenced.contains(name);
+        match (state, is_referenced) {
+            (ArchiveState::Complete, true) => (),
+            (ArchiveState::Complete, false) => {
+                // Its entries may have been lost from the manifest, so keep the data.
+                tracing::warn!(archive = name, "Complete archive is not in the manifest");
+            },
+            (ArchiveState::Missing, _) | (ArchiveState::Incomplete, _) => {
+                tracing::warn!(archive = name, ?state, referenced = is_referenced,
+                               "Archive is not complete");
+                problem_count += 1;
+                dropped_archives.insert(name.clone());
+                if repair {
+                    remove_archive(dir, name)?;
+                }
+            },
+        }
+    }
+
+    let orphan_entry_count = entries.iter()
+                                    .filter(|e| dropped_archives.contains(&e.archive))
+                                    .count();
+    if orphan_entry_count > 0 && repair {
+        ensure!(jsonl_path.exists(),
+                "Can't remove {orphan_entry_count} entries from a Parquet manifest");
+        entries.retain(|e| !dropped_archives.contains(&e.archive));
+        manifest::rewrite_jsonl(dir, &entries)?;
+        tracing::info!(orphan_entry_count, "Removed manifest entries for incomplete archives");
+    }
+
+    tracing::info!(archive_count = states.len(), entry_count = entries.len(),
+                   problem_count, repaired = repair, "fsck finished");
+    if problem_count > 0 && !repair {
+        bail!("fsck found {problem_count} problems; run with --repair to fix them");
+    }
+    Ok(())
+}
+
+/// Read through an archive to check it's complete.
+fn archive_state(path: &Path, keys: Option<&DecryptionKeys>) -> Result<ArchiveState> {
+    if !path.exists() {
+        return Ok(ArchiveState::Missing);
+    }
+    // Closure to catch errors with `?`.
+    let res = (|| -> Result<bool> {
+        let Some(archive) = archive_set::open(path, keys, None)? else {
+            // E.g. empty, or cut off before the end of the first frame header.
+            return Ok(false);
+        };
+        let mut tar = tar::Archive::new(archive.reader);
+        for entry in tar.entries()? {
+            io::copy(&mut entry?, &mut io::sink())?;
+        }
+        // Read to the end of the compressed stream.
+        io::copy(&mut tar.into_inner(), &mut io::sink())?;
+        Ok(true)
+    })();
+    match res {
+        Ok(true) => Ok(ArchiveState::Complete),
+        Ok(false) => Ok(ArchiveState::Incomplete),
+        // A key or dictionary mismatch says nothing about whether the archive is
+        // complete.
+        Err(err) if shard_encryption::is_wrong_key(&err)
+                    || err.is::<codec::WrongDictionary>() => {
+            Err(err.context(format!("reading archive '{}'", path.display())))
+        },
+        Err(err) => {
+            tracing::debug!(path = %path.display(), %err, "Error reading archive");
+            Ok(ArchiveState::Incomplete)
+        },
+    }
+}
+
+/// Delete an archive and its checksums files, if they exist.
+fn remove_archive(dir: &Path, name: &str) -> Result<()> {
+    remove_if_exists(&dir.join(name))?;
+    remove_checksums_files(dir, name)
+}
+
+/// Delete the checksums files of the archive `name`, if they exist.
+pub(crate) fn remove_checksums_files(dir: &Path, name: &str) -> Result<()> {
+    for suffix in CHECKSUMS_FILE_SUFFIXES {
+        remove_if_exists(&dir.join(format!("{name}{suffix}")))?;
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => tracing::info!(path = %path.display(), "Removed"),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("candidate path has a file name")
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,297 @@
+//! Hashing file contents on a separate thread from compression.
+//!
+//! A `HashingReader` tees each buffer read from a file to a `HasherThread`, which
+//! hashes it concurrently with the compression thread and fills in the checksum of the
+//! file's manifest entry. The entries are returned at the end of each archive, so they
+//! can be written to the manifest once the archive is durable. The hasher thread also
+//! writes a checksums file per archive, in the format `sha256sum --check` and
+//! `b3sum --check` read.
+
+use anyhow::{anyhow, bail};
+use crate::{Result, manifest};
+use sha2::Digest;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+};
+use valuable::Valuable;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum Algorithm {
+    Blake3,
+    Sha256,
+}
+
+/// An in-progress hash using any `Algorithm`.
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+enum Msg {
+    /// The next bytes of the current file.
+    Data(Vec<u8>),
+    /// The current file is complete; its checksum goes in this manifest entry.
+    End(Box<manifest::Entry>),
+    /// The current archive is complete; finish its checksums file and reply with its
+    /// manifest entries.
+    EndArchive(crossbeam_channel::Sender<Result<Vec<manifest::Entry>>>),
+    /// The current archive is abandoned; delete its checksums file, forget its manifest
+    /// entries and any partly hashed file, then reply.
+    DiscardArchive(crossbeam_channel::Sender<Result<()>>),
+}
+
+pub struct HasherThread {
+    tx: crossbeam_channel::Sender<Msg>,
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+/// Passes reads through from `inner` and sends a copy of the bytes read to a
+/// `HasherThread`.
+pub struct HashingReader<'a, R: Read> {
+    inner: R,
+    tx: &'a crossbeam_channel::Sender<Msg>,
+}
+
+/// The checksums file for one archive.
+struct ChecksumsFile {
+    archive: String,
+    bufw: BufWriter<File>,
+    path: PathBuf,
+}
+
+/// Capacity of the queue of buffers waiting to be hashed.
+const QUEUE_LEN: usize = 64;
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// Split a checksum formatted as `<algorithm>:<hex digest>`.
+    pub fn parse_checksum(checksum: &str) -> Result<(Algorithm, &str)> {
+        let Some((name, digest)) = checksum.split_once(':') else {
+            bail!("Checksum '{checksum}' has no algorithm prefix");
+        };
+        let algorithm = match name {
+            "blake3" => Algorithm::Blake3,
+            "sha256" => Algorithm::Sha256,
+            _ => bail!("Unknown checksum algorithm '{name}'"),
+        };
+        Ok((algorithm, digest))
+    }
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Hasher {
+        match algorithm {
+            Algorithm::Blake3 => Hasher::Blake3(Box::default()),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            Hasher::Blake3(h) => { h.update(buf); },
+            Hasher::Sha256(h) => h.update(buf),
+        }
+    }
+
+    /// Returns the hex digest of the bytes hashed so far and resets the hasher.
+    pub fn finalize_reset(&mut self) -> String {
+        match self {
+            Hasher::Blake3(h) => {
+                let digest = h.finalize().to_hex().to_string();
+                h.reset();
+                digest
+            },
+            Hasher::Sha256(h) => format!("{:x}", h.finalize_reset()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HasherThread {
+    /// Spawn a hasher thread that writes each archive's checksums file to `out_dir`.
+    pub fn spawn(name: String, algorithm: Algorithm, out_dir: PathBuf)
+    -> Result<HasherThread>
+    {
+        let (tx, rx) = crossbeam_channel::bounded::<Msg>(QUEUE_LEN);
+        let thread = thread::Builder::new()
+            .name(name)
+            .spawn(move || -> Result<()> {
+                let mut hasher = Hasher::new(algorithm);
+                let mut checksums_file: Option<ChecksumsFile> = None;
+                let mut entries = Vec::new();
+                for msg in rx.iter() {
+                    match msg {
+                        Msg::Data(buf) => hasher.update(&buf),
+                        Msg::End(mut entry) => {
+                            let digest = hasher.finalize_reset();
+
+                            if checksums_file.as_ref().map(|f| &*f.archive)
+                                   != Some(&*entry.archive) {
+                                if let Some(f) = checksums_file.take() {
+                                    f.finish()?;
+                                }
+                                checksums_file = Some(ChecksumsFile::create(
+                                    &out_dir, entry.archive.clone(), algorithm)?);
+                            }
+                            let f = checksums_file.as_mut().expect("opened above");
+                            writeln!(f.bufw, "{digest}  {path}", path = entry.path.display())?;
+
+                            entry.checksum = Some(format!("{alg}:{digest}",
+                                                          alg = algorithm.name()));
+                            entries.push(*entry);
+                        },
+                        Msg::EndArchive(reply_tx) => {
+                            let res = match checksums_file.take() {
+                                Some(f) => f.finish(),
+                                None => Ok(()),
+                            };
+                            let res = res.map(|()| std::mem::take(&mut entries));
+                            // The ShardWriter is waiting for the reply.
+                            let _ = reply_tx.send(res);
+                        },
+                        Msg::DiscardArchive(reply_tx) => {
+                            let _ = hasher.finalize_reset();
+                            entries.clear();
+                            let res = match checksums_file.take() {
+                                Some(f) => f.discard(),
+                                None => Ok(()),
+                            };
+                            let _ = reply_tx.send(res);
+                        },
+                    }
+                }
+                if let Some(f) = checksums_file.take() {
+                    f.finish()?;
+                }
+                Ok(())
+            })?;
+
+        Ok(HasherThread { tx, thread })
+    }
+
+    /// Wrap `inner` to hash the bytes read from it as the current file.
+    pub fn reader<R: Read>(&self, inner: R) -> HashingReader<'_, R> {
+        HashingReader {
+            inner,
+            tx: &self.tx,
+        }
+    }
+
+    /// Mark the end of the current file, whose manifest entry is `entry`.
+    pub fn end_file(&self, entry: manifest::Entry) -> Result<()> {
+        self.tx.send(Msg::End(Box::new(entry)))
+            .map_err(|_| anyhow!("Hasher thread stopped"))
+    }
+
+    /// Wait for the files of the current archive to be hashed and its checksums file to
+    /// be synced, then return their manifest entries.
+    pub fn end_archive(&self) -> Result<Vec<manifest::Entry>> {
+        let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+        self.tx.send(Msg::EndArchive(reply_tx))
+            .map_err(|_| anyhow!("Hasher thread stopped"))?;
+        reply_rx.recv().map_err(|_| anyhow!("Hasher thread stopped"))?
+    }
+
+    /// Abandon the current archive, deleting its checksums file.
+    pub fn discard_archive(&self) -> Result<()> {
+        let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+        self.tx.send(Msg::DiscardArchive(reply_tx))
+            .map_err(|_| anyhow!("Hasher thread stopped"))?;
+        reply_rx.recv().map_err(|_| anyhow!("Hasher thread stopped"))?
+    }
+
+    /// Wait for the hasher thread to hash all files sent so far.
+    pub fn finish(self) -> Result<()> {
+        drop(self.tx);
+        self.thread.join().expect("joining hasher thread")
+    }
+}
+
+impl ChecksumsFile {
+    /// Create `<archive>.<algorithm>` in `out_dir`.
+    fn create(out_dir: &Path, archive: String, algorithm: Algorithm) -> Result<ChecksumsFile> {
+        let path = out_dir.join(format!("{archive}.{alg}", alg = algorithm.name()));
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&*path)?;
+        Ok(ChecksumsFile {
+            archive,
+            bufw: BufWriter::new(file),
+            path,
+        })
+    }
+
+    fn finish(self) -> Result<()> {
+        let file = self.bufw.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn discard(self) -> Result<()> {
+        drop(self.bufw);
+        fs::remove_file(&*self.path)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count > 0 {
+            self.tx.send(Msg::Data(buf[..count].to_vec()))
+                .map_err(|_| io::Error::other("HashingReader: hasher thread stopped"))?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_digests() {
+        let mut h = Hasher::new(Algorithm::Sha256);
+        h.update(b"abc");
+        assert_eq!(h.finalize_reset(),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // Reset, so hashing again gives the same digest.
+        h.update(b"abc");
+        assert_eq!(h.finalize_reset(),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let mut h = Hasher::new(Algorithm::Blake3);
+        h.update(b"abc");
+        assert_eq!(h.finalize_reset(),
+                   "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    }
+
+    #[test]
+    fn parse_checksum() {
+        let (alg, digest) = Algorithm::parse_checksum("sha256:ab").unwrap();
+        assert_eq!(alg, Algorithm::Sha256);
+        assert_eq!(digest, "ab");
+        assert!(Algorithm::parse_checksum("md5:ab").is_err());
+        assert!(Algorithm::parse_checksum("ab").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Incremental backups: comparing walked files against the manifests of previous runs,
+//! so only new and changed files are archived, and recording the files deleted since.
+//!
+//! An increment's deleted files are listed in `deletions.jsonl` beside its manifest,
+//! one `{"path": ...}` record per line, so `ptar restore --delete` can remove them.
+
+use anyhow::{Context, ensure};
+use crate::{Result, hasher, manifest};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+pub const DELETIONS_FILE_NAME: &str = "deletions.jsonl";
+
+/// The files recorded by a full backup and the increments since, by path.
+pub struct Snapshot {
+    entries: HashMap<PathBuf, manifest::Entry>,
+    /// Whether to also compare the contents of files whose size and mtime are unchanged.
+    compare_checksums: bool,
+    /// Paths of the snapshot's files that have been walked in this run.
+    seen: Mutex<HashSet<PathBuf>>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Deletion {
+    path: PathBuf,
+}
+
+impl Snapshot {
+    /// Read the manifests at `paths`, oldest first, so later entries for a path replace
+    /// earlier ones. Each path is a previous run's output directory or manifest file.
+    pub fn load(paths: &[PathBuf], compare_checksums: bool) -> Result<Snapshot> {
+        let mut entries = HashMap::new();
+        for path in paths {
+            let (manifest, dir) = if path.is_dir() {
+                (manifest::read(path), path.as_path())
+            } else {
+                (manifest::read_file(path), path.parent().unwrap_or(Path::new(".")))
+            };
+            let manifest = manifest
+                .with_context(|| format!("reading manifest '{}'", path.display()))?;
+            for deleted in read_deletions(dir)? {
+                entries.remove(&deleted);
+            }
+            entries.extend(manifest::merge_chunks(manifest).into_iter()
+                                                           .map(|e| (e.path.clone(), e)));
+        }
+        tracing::debug!(entry_count = entries.len(), "Loaded snapshot manifests");
+        Ok(Snapshot {
+            entries,
+            compare_checksums,
+            seen: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Paths of the snapshot's files that haven't been seen by `is_unchanged()`, sorted.
+    pub fn deleted_paths(&self) -> Vec<PathBuf> {
+        let seen = self.seen.lock().expect("seen lock");
+        let mut deleted = self.entries.keys()
+                                      .filter(|path| !seen.contains(*path))
+                                      .cloned()
+                                      .collect::<Vec<PathBuf>>();
+        deleted.sort();
+        deleted
+    }
+
+    /// Whether the file at `path`, archived as `rel_path`, is the same as in the
+    /// snapshot. Also records that `rel_path` still exists, for `deleted_paths()`.
+    ///
+    /// Files are unchanged if their size and mtime match. With `compare_checksums`
+    /// they're then also hashed and compared to the checksum in the manifest, if it has
+    /// one.
+    pub fn is_unchanged(&self, rel_path: &Path, meta: &fs::Metadata, path: &Path)
+    -> Result<bool>
+    {
+        let Some(prev) = self.entries.get(rel_path) else {
+            return Ok(false);
+        };
+        self.seen.lock().expect("seen lock").insert(rel_path.to_path_buf());
+        let (mtime, mtime_nsec) =
+            manifest::unix_time(meta.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+        if prev.size != meta.len() || prev.mtime != mtime || prev.mtime_nsec != mtime_nsec {
+            return Ok(false);
+        }
+        match prev.checksum {
+            Some(ref checksum) if self.compare_checksums => {
+                let (algorithm, digest) = hasher::Algorithm::parse_checksum(checksum)?;
+                let mut hasher = hasher::Hasher::new(algorithm);
+                io::copy(&mut File::open(path)?, &mut hasher)?;
+                Ok(hasher.finalize_reset() == digest)
+            },
+            _ => Ok(true),
+        }
+    }
+}
+
+/// Write the list of deleted `paths` to `dir`.
+pub fn write_deletions(dir: &Path, paths: &[PathBuf]) -> Result<()> {
+    // Write then rename so an interruption never leaves a partial file.
+    let tmp_path = dir.join(format!("{DELETIONS_FILE_NAME}.tmp"));
+    let mut bufw = BufWriter::new(File::create(&*tmp_path)?);
+    for path in paths {
+        serde_json::to_writer(&mut bufw, &Deletion { path: path.clone() })?;
+        bufw.write_all(b"\n")?;
+    }
+    bufw.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&*tmp_path, dir.join(DELETIONS_FILE_NAME))?;
+    Ok(())
+}
+
+/// Read the paths deleted before the increment in `dir`. Empty if it has no deletions
+/// file, as for a full backup.
+pub fn read_deletions(dir: &Path) -> Result<Vec<PathBuf>> {
+    let path = dir.join(DELETIONS_FILE_NAME);
+    let file = match File::open(&*path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut paths = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let deletion: Deletion = serde_json::from_str(&line)
+            .with_context(|| format!("parsing '{}'", path.display()))?;
+        ensure!(deletion.path.components().all(|c| matches!(c, Component::Normal(_))),
+                "Deleted path '{}' in '{}' is not a plain relative path",
+                deletion.path.display(), path.display());
+        paths.push(deletion.path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::TempDir;
+
+    #[test]
+    fn changed_files() {
+        let dir = TempDir::new("ptar-test").unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, "hello").unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        let mut entry = manifest::Entry::new("a".into(), &meta, "00000000.tar.zstd".into());
+        entry.checksum = Some(format!("blake3:{}", blake3::hash(b"hello").to_hex()));
+
+        let snapshot = |entry: &manifest::Entry, compare_checksums| Snapshot {
+            entries: [(entry.path.clone(), entry.clone())].into(),
+            compare_checksums,
+            seen: Mutex::new(HashSet::new()),
+        };
+        let is_unchanged = |snapshot: Snapshot, rel_path: &str| {
+            snapshot.is_unchanged(Path::new(rel_path), &meta, &path).unwrap()
+        };
+        assert!(is_unchanged(snapshot(&entry, true), "a"));
+        assert!(!is_unchanged(snapshot(&entry, true), "new"));
+
+        let mut other_size = entry.clone();
+        other_size.size += 1;
+        assert!(!is_unchanged(snapshot(&other_size, false), "a"));
+
+        let mut other_mtime = entry.clone();
+        other_mtime.mtime_nsec = (other_mtime.mtime_nsec + 1) % 1_000_000_000;
+        assert!(!is_unchanged(snapshot(&other_mtime, false), "a"));
+
+        // Same size and mtime, different contents.
+        let mut other_checksum = entry.clone();
+        other_checksum.checksum = Some(format!("blake3:{}", blake3::hash(b"world").to_hex()));
+        assert!(is_unchanged(snapshot(&other_checksum, false), "a"));
+        assert!(!is_unchanged(snapshot(&other_checksum, true), "a"));
+    }
+
+    #[test]
+    fn deletions() {
+        let dir = TempDir::new("ptar-test").unwrap();
+        assert!(read_deletions(dir.path()).unwrap().is_empty());
+
+        let paths = vec![PathBuf::from("a/b"), PathBuf::from("c")];
+        write_deletions(dir.path(), &paths).unwrap();
+        assert_eq!(read_deletions(dir.path()).unwrap(), paths);
+
+        fs::write(dir.path().join(DELETIONS_FILE_NAME), "{\"path\":\"../x\"}\n").unwrap();
+        assert!(read_deletions(dir.path()).is_err());
+    }
+}
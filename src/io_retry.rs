@@ -0,0 +1,147 @@
+//! Retrying reads and writes of files that fail with transient errors, as flaky network
+//! filesystems such as NFS or SMB mounts give while their server is briefly unreachable,
+//! with `--io-retries`.
+//!
+//! A failed read or write transfers nothing, so retrying the same call continues the
+//! file where it left off. Syncs aren't retried: after a failed sync the kernel may have
+//! dropped the unwritten data.
+
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Copy, Debug, Default, Valuable)]
+pub struct RetryArgs {
+    /// Times to retry opening, reading or writing a file after a transient error, e.g.
+    /// `EIO` or a timeout on a flaky NFS or SMB mount, before it counts as an error.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub(crate) io_retries: u32,
+
+    /// Milliseconds to wait before the first retry, doubling before each one after.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub(crate) io_retry_delay: u64,
+}
+
+impl RetryArgs {
+    /// Call `f`, an operation on the file at `path`, until it succeeds, fails with an
+    /// error that isn't transient, or has been retried `--io-retries` times.
+    pub fn run<T>(&self, path: &Path, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut delay = Duration::from_millis(self.io_retry_delay);
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(err) if attempt < self.io_retries && is_transient(&err) => {
+                    attempt += 1;
+                    tracing::warn!(path = %path.display(), %err, attempt,
+                                   delay_ms = delay.as_millis(), "Retrying after I/O error");
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                },
+                res => return res,
+            }
+        }
+    }
+
+    /// Whether retries are enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.io_retries > 0
+    }
+}
+
+/// Whether `err` may go away if the operation is retried.
+fn is_transient(err: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(err.kind(), TimedOut | WouldBlock | Interrupted | ConnectionReset
+                         | ConnectionAborted | NotConnected | NetworkDown
+                         | NetworkUnreachable | HostUnreachable | ResourceBusy)
+    || err.raw_os_error() == Some(libc::EIO)
+}
+
+/// A reader of the file at `path` retrying failed reads.
+pub struct RetryReader<R> {
+    inner: R,
+    retry: RetryArgs,
+    path: PathBuf,
+}
+
+impl<R> RetryReader<R> {
+    pub fn new(inner: R, retry: RetryArgs, path: &Path) -> RetryReader<R> {
+        RetryReader { inner, retry, path: path.to_path_buf() }
+    }
+}
+
+impl<R: Read> Read for RetryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.retry.run(&self.path, || self.inner.read(buf))
+    }
+}
+
+/// A writer to the file at `path` retrying failed writes.
+pub struct RetryWriter<W> {
+    inner: W,
+    retry: RetryArgs,
+    path: PathBuf,
+}
+
+impl<W> RetryWriter<W> {
+    pub fn new(inner: W, retry: RetryArgs, path: &Path) -> RetryWriter<W> {
+        RetryWriter { inner, retry, path: path.to_path_buf() }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for RetryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry.run(&self.path, || self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry.run(&self.path, || self.inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails each call with `errs` in turn before succeeding.
+    struct Flaky(Vec<io::Error>);
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop() {
+                Some(err) => Err(err),
+                None => {
+                    buf[0] = 7;
+                    Ok(1)
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let retry = RetryArgs { io_retries: 2, io_retry_delay: 0 };
+        let eio = || io::Error::from_raw_os_error(libc::EIO);
+        let mut buf = [0];
+
+        let mut r = RetryReader::new(Flaky(vec![eio(), io::ErrorKind::TimedOut.into()]),
+                                     retry, Path::new("f"));
+        assert_eq!(r.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf, [7]);
+
+        let mut r = RetryReader::new(Flaky(vec![eio(), eio(), eio()]), retry, Path::new("f"));
+        assert_eq!(r.read(&mut buf).unwrap_err().raw_os_error(), Some(libc::EIO));
+
+        let mut r = RetryReader::new(Flaky(vec![io::ErrorKind::NotFound.into()]), retry,
+                                     Path::new("f"));
+        assert_eq!(r.read(&mut buf).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
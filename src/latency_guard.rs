@@ -0,0 +1,168 @@
+//! Limiting concurrent file reads by the source's read latency, so a backup doesn't
+//! slow down other workloads on the same filesystem.
+//!
+//! Reads are timed, and every [`WINDOW`] the 99th percentile is compared to the
+//! threshold: above it, the number of files read at once is halved; below it, it's
+//! raised by one, back up to the number of shards.
+
+use std::{
+    io::{self, Read},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often the limit is adjusted.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Fewest reads in a window to adjust the limit by.
+const MIN_SAMPLES: usize = 20;
+
+/// Hands out permits to read a file, adjusting how many are available by read latency.
+#[derive(Debug)]
+pub struct LatencyGuard {
+    threshold: Duration,
+    max_readers: usize,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Number of permits that may be held at once.
+    allowed: usize,
+    /// Number of permits held.
+    reading: usize,
+    /// Latencies of the reads in this window.
+    samples: Vec<Duration>,
+    window_start: Instant,
+}
+
+/// Held while reading a file. Dropping it lets another reader start.
+#[must_use]
+pub struct LatencyPermit<'a> {
+    guard: &'a LatencyGuard,
+}
+
+/// Times each read of the inner reader.
+pub struct LatencyReader<'a, R: Read> {
+    guard: Option<&'a LatencyGuard>,
+    inner: R,
+}
+
+impl LatencyGuard {
+    pub fn new(threshold: Duration, max_readers: usize) -> LatencyGuard {
+        let max_readers = max_readers.max(1);
+        LatencyGuard {
+            threshold,
+            max_readers,
+            state: Mutex::new(State {
+                allowed: max_readers,
+                reading: 0,
+                samples: Vec::new(),
+                window_start: Instant::now(),
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until there's a free permit.
+    pub fn acquire(&self) -> LatencyPermit<'_> {
+        let state = self.state.lock().expect("state lock");
+        let mut state = self.released
+            .wait_while(state, |state| state.reading >= state.allowed)
+            .expect("state lock");
+        state.reading += 1;
+        LatencyPermit { guard: self }
+    }
+
+    /// Record the latency of one read (or open), adjusting the limit at the end of each
+    /// window.
+    pub fn record(&self, latency: Duration) {
+        let mut state = self.state.lock().expect("state lock");
+        state.samples.push(latency);
+        if state.window_start.elapsed() < WINDOW || state.samples.len() < MIN_SAMPLES {
+            return;
+        }
+
+        let p99 = p99(&mut state.samples);
+        let allowed = if p99 > self.threshold {
+            (state.allowed / 2).max(1)
+        } else {
+            (state.allowed + 1).min(self.max_readers)
+        };
+        if allowed < state.allowed {
+            tracing::warn!(p99_us = p99.as_micros() as u64, readers = allowed,
+                           "Source read latency is over --latency-guard, reading fewer \
+                            files at once");
+        } else if allowed > state.allowed {
+            tracing::info!(p99_us = p99.as_micros() as u64, readers = allowed,
+                           "Source read latency is under --latency-guard, reading more \
+                            files at once");
+        }
+        let raised = allowed > state.allowed;
+        state.allowed = allowed;
+        state.samples.clear();
+        state.window_start = Instant::now();
+        drop(state);
+        if raised {
+            self.released.notify_all();
+        }
+    }
+}
+
+impl Drop for LatencyPermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.guard.state.lock().expect("state lock");
+        state.reading -= 1;
+        drop(state);
+        self.guard.released.notify_all();
+    }
+}
+
+impl<'a, R: Read> LatencyReader<'a, R> {
+    /// Time reads of `inner` if there's a guard, otherwise just pass them through.
+    pub fn new(inner: R, guard: Option<&'a LatencyGuard>) -> LatencyReader<'a, R> {
+        LatencyReader { guard, inner }
+    }
+}
+
+impl<R: Read> Read for LatencyReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(guard) = self.guard else {
+            return self.inner.read(buf);
+        };
+        let start = Instant::now();
+        let count = self.inner.read(buf)?;
+        guard.record(start.elapsed());
+        Ok(count)
+    }
+}
+
+/// The 99th percentile of `samples`, which mustn't be empty. Reorders them.
+fn p99(samples: &mut [Duration]) -> Duration {
+    let index = (samples.len() * 99 / 100).min(samples.len() - 1);
+    *samples.select_nth_unstable(index).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjusts_readers() {
+        let guard = LatencyGuard::new(Duration::from_millis(10), 8);
+        let window = |latency| {
+            guard.state.lock().unwrap().window_start -= WINDOW;
+            for _ in 0..MIN_SAMPLES {
+                guard.record(latency);
+            }
+            guard.state.lock().unwrap().allowed
+        };
+        assert_eq!(window(Duration::from_millis(50)), 4);
+        assert_eq!(window(Duration::from_millis(50)), 2);
+        assert_eq!(window(Duration::from_millis(50)), 1);
+        assert_eq!(window(Duration::from_millis(50)), 1);
+        assert_eq!(window(Duration::from_millis(1)), 2);
+        assert_eq!(window(Duration::from_millis(1)), 3);
+    }
+}
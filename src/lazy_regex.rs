@@ -63,7 +63,7 @@ mod tests {
         assert!(lazy_regex!("a").is_match("a"));
         assert!(lazy_regex!("a", "b").is_match("ab"));
 
-        const X: &'static str = "x";
+        const X: &str = "x";
 
         assert!(lazy_regex!("a", X, "b").is_match("axb"));
 
@@ -0,0 +1,208 @@
+//! Parallel tar: archive a directory tree to a set of compressed tar archives using
+//! many threads, and extract them again.
+//!
+//! The `ptar` binary is a thin command line interface over this library. To embed
+//! ptar in another program, use `CompressOptions` and `DecompressOptions`. To write
+//! streams such as database dumps without temporary files, use `StreamWriterOptions`.
+
+// Declare this first so other modules can use the macro.
+#[macro_use]
+mod lazy_regex;
+
+mod acls;
+mod adaptive_level;
+mod anonymize;
+mod archive_name;
+mod archive_set;
+mod cancel;
+mod case_collision;
+pub mod cat;
+pub mod chaos;
+pub mod codec;
+pub mod compress;
+mod content_type;
+pub mod decompress;
+pub mod dedup;
+mod dedup_repo;
+pub mod dedup_restore;
+mod device_limit;
+pub mod diff;
+mod entry_encryption;
+mod error_report;
+pub mod estimate;
+mod extract_path;
+pub mod extract_one;
+mod file_chunk;
+pub mod from_tar;
+pub mod fsck;
+pub mod hasher;
+mod incremental;
+mod io_retry;
+mod latency_guard;
+mod long_path;
+pub mod manifest;
+mod manifest_parquet;
+mod manifest_signature;
+pub mod merge;
+mod nodump;
+mod owners;
+mod path_filter;
+pub mod plan_restore;
+mod progress;
+mod progress_reader;
+mod progress_writer;
+mod quota;
+mod rate_limit;
+mod remote;
+pub mod repack;
+pub mod restore;
+mod run_lock;
+mod s3;
+mod sftp;
+mod shard_encryption;
+mod size;
+mod special_files;
+pub mod status;
+mod status_socket;
+mod stream_writer;
+pub mod tar_format;
+mod tar_stream;
+#[cfg(any(test, feature = "testsupport"))]
+pub mod testsupport;
+mod thread_offload_reader;
+pub mod to_tar;
+pub mod train_dict;
+mod transform;
+pub mod verify;
+mod xattrs;
+
+pub use crate::cancel::{
+    CancelPolicy, Cancelled, CancellationToken, EXIT_CODE as CANCELLED_EXIT_CODE,
+};
+pub use crate::compress::{CompressOptions, Report as CompressReport};
+pub use crate::decompress::{DecompressOptions, Report as DecompressReport};
+pub use crate::progress::{ProgressCallback, ProgressEvent};
+pub use crate::quota::QuotaCheck;
+pub use crate::stream_writer::{
+    EntryMetadata, StreamEntry, StreamWriter, StreamWriterOptions,
+};
+
+use crate::progress_reader::ProgressReader;
+use crate::progress_writer::ProgressWriter;
+use crate::thread_offload_reader::ThreadOffloadReader;
+
+use std::ffi::OsString;
+use valuable::Valuable;
+
+/// Command line arguments of the `ptar` binary.
+#[derive(clap::Parser, Valuable)]
+pub struct Args {
+    /// Threads to use. Defaults to the number of CPUs. Commands may split this further,
+    /// e.g. `compress --walk-threads` and `--compress-threads`.
+    #[arg(long)]
+    pub threads: Option<usize>,
+    #[arg(long)]
+    pub log_json: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// Parsed once per run, so the size of the largest variant doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(clap::Subcommand, Clone, Debug, Valuable)]
+pub enum Command {
+    /// Copy an archive set and damage the copy, to rehearse recovery.
+    #[command(hide = true)]
+    Chaos(chaos::Args),
+    /// Write one archived file's contents to stdout.
+    Cat(cat::Args),
+    Compress(compress::Args),
+    Decompress(decompress::Args),
+    /// Back up a directory as a snapshot in a deduplicating repository.
+    Dedup(dedup::Args),
+    /// Restore a snapshot from a repository written by `dedup`.
+    DedupRestore(dedup_restore::Args),
+    /// List files added, removed or modified in a directory or archive set since another.
+    Diff(diff::Args),
+    /// Estimate the compressed size and duration of compressing a directory from a
+    /// sample of its files.
+    Estimate(estimate::Args),
+    /// Extract one file, reading only the part of its archive from the file on.
+    ExtractOne(extract_one::Args),
+    /// Convert a tar archive to an archive set.
+    FromTar(from_tar::Args),
+    /// Check an archive set is consistent after a crash, and optionally repair it.
+    Fsck(fsck::Args),
+    /// Rewrite an archive set's archives as fewer, balanced archives.
+    Merge(merge::Args),
+    /// Check an archive set's files can be restored onto a target filesystem.
+    PlanRestore(plan_restore::Args),
+    /// Recompress an archive set into a new directory, e.g. at a higher level.
+    Repack(repack::Args),
+    /// Restore a full backup and its increments, in order, to one directory.
+    Restore(restore::Args),
+    /// Print the status of the last run that wrote to a destination directory.
+    Status(status::Args),
+    /// Convert an archive set to one tar archive other tools can read.
+    ToTar(to_tar::Args),
+    /// Train a zstd dictionary on a directory's files, for `compress --dict`.
+    TrainDict(train_dict::Args),
+    /// Check every archive in a set decodes, and file checksums match the manifest.
+    Verify(verify::Args),
+}
+
+impl Args {
+    /// `--threads`, or its default.
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or_else(default_threads)
+    }
+}
+
+pub type Error = anyhow::Error;
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Run the subcommand in `args`.
+pub fn run(args: Args) -> Result<()> {
+    match &args.command {
+        Command::Chaos(cmd_args) => chaos::main(cmd_args.clone(), args),
+        Command::Cat(cmd_args) => cat::main(cmd_args.clone(), args),
+        Command::Compress(cmd_args) => compress::main(cmd_args.clone(), args),
+        Command::Decompress(cmd_args) => decompress::main(cmd_args.clone(), args),
+        Command::Dedup(cmd_args) => dedup::main(cmd_args.clone(), args),
+        Command::DedupRestore(cmd_args) => dedup_restore::main(cmd_args.clone(), args),
+        Command::Diff(cmd_args) => diff::main(cmd_args.clone(), args),
+        Command::Estimate(cmd_args) => estimate::main(cmd_args.clone(), args),
+        Command::ExtractOne(cmd_args) => extract_one::main(cmd_args.clone(), args),
+        Command::FromTar(cmd_args) => from_tar::main(cmd_args.clone(), args),
+        Command::Fsck(cmd_args) => fsck::main(cmd_args.clone(), args),
+        Command::Merge(cmd_args) => merge::main(cmd_args.clone(), args),
+        Command::PlanRestore(cmd_args) => plan_restore::main(cmd_args.clone(), args),
+        Command::Repack(cmd_args) => repack::main(cmd_args.clone(), args),
+        Command::Restore(cmd_args) => restore::main(cmd_args.clone(), args),
+        Command::Status(cmd_args) => status::main(cmd_args.clone(), args),
+        Command::ToTar(cmd_args) => to_tar::main(cmd_args.clone(), args),
+        Command::TrainDict(cmd_args) => train_dict::main(cmd_args.clone(), args),
+        Command::Verify(cmd_args) => verify::main(cmd_args.clone(), args),
+    }
+}
+
+/// Parse a subcommand's arguments from only its required `--name=value` arguments, so
+/// every other argument gets its command line default.
+fn default_cmd_args<A: clap::Args + clap::FromArgMatches>(required: &[(&str, OsString)]) -> A {
+    let argv = std::iter::once(OsString::from("ptar"))
+        .chain(required.iter().map(|(name, value)| {
+            let mut arg = OsString::from(format!("--{name}="));
+            arg.push(value);
+            arg
+        }));
+    let matches = A::augment_args(clap::Command::new("ptar"))
+        .try_get_matches_from(argv)
+        .expect("Only required arguments are given");
+    A::from_arg_matches(&matches).expect("Only required arguments are given")
+}
+
+/// The default thread count for the library API: one per CPU.
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
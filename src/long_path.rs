@@ -0,0 +1,128 @@
+//! Finding paths too long for `--tar-format ustar` or `v7`, or `--max-path-len`, while
+//! walking, so they're handled by the `--long-path-policy` before anything is archived,
+//! rather than failing partway through an archive.
+
+use crate::tar_format::TarFormat;
+use std::{
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// The limits a relative path must fit to be archived as is.
+#[derive(Clone, Copy, Debug)]
+pub struct PathLimits {
+    pub tar_format: TarFormat,
+    /// Longest path in bytes, with `--max-path-len`.
+    pub max_len: Option<usize>,
+}
+
+/// Counts the long paths found, keeping the first few as examples for the summary.
+#[derive(Debug, Default)]
+pub struct LongPaths {
+    count: AtomicU64,
+    examples: Mutex<Vec<PathBuf>>,
+}
+
+/// Number of long paths kept as examples.
+const MAX_EXAMPLES: usize = 10;
+
+/// Hex digits of the path's hash in shortened names.
+const HASH_LEN: usize = 16;
+
+/// Longest extension kept on shortened names, including the `.`.
+const MAX_EXTENSION_LEN: usize = 16;
+
+impl PathLimits {
+    pub fn fits(&self, path: &Path) -> bool {
+        self.max_len.is_none_or(|max| path.as_os_str().len() <= max)
+            && self.tar_format.fits_path(path)
+    }
+
+    /// Shorten `path` to fit, deterministically, so later runs give the same path.
+    ///
+    /// The file name's stem is truncated and followed by `~` and a hash of the whole
+    /// path, keeping the extension, e.g. `dir/long-na~0123456789abcdef.txt`. If the
+    /// directories alone are too long, the file moves to the top level. Returns None if
+    /// even that doesn't fit.
+    pub fn shorten(&self, path: &Path) -> Option<PathBuf> {
+        let hash = blake3::hash(path.as_os_str().as_bytes()).to_hex();
+        let hash = &hash.as_str()[..HASH_LEN];
+        let name = path.file_name()?.to_string_lossy();
+        let (stem, extension) = match name.rfind('.') {
+            Some(i) if i > 0 && name.len() - i <= MAX_EXTENSION_LEN => name.split_at(i),
+            _ => (&*name, ""),
+        };
+        let parent = path.parent().unwrap_or(Path::new(""));
+        for dir in [parent, Path::new("")] {
+            let mut end = stem.len();
+            loop {
+                while !stem.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let short = dir.join(format!("{}~{hash}{extension}", &stem[..end]));
+                if self.fits(&short) {
+                    return Some(short);
+                }
+                if end == 0 {
+                    break;
+                }
+                end -= 1;
+            }
+        }
+        None
+    }
+}
+
+impl LongPaths {
+    pub fn record(&self, path: &Path) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let mut examples = self.examples.lock().expect("examples lock");
+        if examples.len() < MAX_EXAMPLES {
+            examples.push(path.to_path_buf());
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The first paths recorded, sorted.
+    pub fn examples(&self) -> Vec<PathBuf> {
+        let mut examples = self.examples.lock().expect("examples lock").clone();
+        examples.sort();
+        examples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shorten() {
+        let limits = PathLimits { tar_format: TarFormat::Pax, max_len: Some(40) };
+        assert!(limits.fits(Path::new("dir/short.txt")));
+
+        let long = Path::new("dir/a-file-name-much-too-long-to-fit-in-40-bytes.txt");
+        assert!(!limits.fits(long));
+        let short = limits.shorten(long).unwrap();
+        assert!(limits.fits(&short));
+        assert_eq!(short.parent(), Some(Path::new("dir")));
+        let name = short.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("a-file-name") && name.ends_with(".txt"), "{name}");
+        assert_eq!(limits.shorten(long), Some(short));
+
+        // Directories too long for any name move the file to the top level.
+        let deep = Path::new("a-directory-name-too-long-to-keep-at-all/f");
+        assert!(limits.shorten(deep).unwrap().parent() == Some(Path::new("")));
+
+        let ustar = PathLimits { tar_format: TarFormat::Ustar, max_len: None };
+        let long_name = PathBuf::from(format!("dir/{}.rs", "é".repeat(60)));
+        assert!(!ustar.fits(&long_name));
+        assert!(ustar.fits(&ustar.shorten(&long_name).unwrap()));
+    }
+}
@@ -1,45 +1,14 @@
-// Declare this first so other modules can use the macro.
-#[macro_use]
-mod lazy_regex;
-
-mod compress;
-mod decompress;
-mod progress_reader;
-mod thread_offload_reader;
-
-use crate::progress_reader::ProgressReader;
-use crate::thread_offload_reader::ThreadOffloadReader;
-
 use clap::Parser;
+use ptar::{Args, Result};
 use std::time::Instant;
 use valuable::Valuable;
 
-#[derive(clap::Parser, Valuable)]
-pub struct Args {
-    #[arg(long)]
-    threads: usize,
-    #[arg(long)]
-    log_json: bool,
-
-    #[command(subcommand)]
-    command: Command,
-}
-
-#[derive(clap::Subcommand, Clone, Debug, Valuable)]
-pub enum Command {
-    Compress(compress::Args),
-    Decompress(decompress::Args),
-}
-
 #[derive(Eq, PartialEq)]
 enum LogMode {
     Pretty,
     Json,
 }
 
-type Error = anyhow::Error;
-type Result<T> = std::result::Result<T, Error>;
-
 fn main() -> Result<()> {
     let start = Instant::now();
 
@@ -49,14 +18,14 @@ fn main() -> Result<()> {
 
     tracing::info!(args = args.as_value(), "Starting");
 
-    let res = match &args.command {
-        Command::Compress(cmd_args) => compress::main(cmd_args.clone(), args),
-        Command::Decompress(cmd_args) => decompress::main(cmd_args.clone(), args),
-    };
+    let res = ptar::run(args);
 
     if let Err(err) = res {
         // tracing::error! to show it nicely formatted, potentially in JSON.
         tracing::error!(err = %err, "Error");
+        if err.is::<ptar::Cancelled>() {
+            std::process::exit(ptar::CANCELLED_EXIT_CODE);
+        }
         // Return the error too to show a Rust backtrace on the CLI.
         return Err(err);
     }
@@ -0,0 +1,351 @@
+//! The manifest of an archive set: one record per archived file recording which
+//! archive it's in, so files can be found without scanning every archive. Files split
+//! by `compress --chunk-size` have a record per chunk.
+//!
+//! By default it's JSON lines in `manifest.jsonl`; `--manifest-format parquet` writes
+//! `manifest.parquet` instead.
+
+use crate::{Result, manifest_parquet};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    thread,
+    time::SystemTime,
+};
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.jsonl";
+pub const PARQUET_MANIFEST_FILE_NAME: &str = "manifest.parquet";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, valuable::Valuable)]
+pub enum Format {
+    Jsonl,
+    Parquet,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Entry {
+    /// Path of the file relative to the archive root, as stored in the tar archive.
+    pub path: PathBuf,
+    /// Size of the file, or with `chunk_offset`, of this chunk of it.
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: i64,
+    /// Sub-second part of the modification time.
+    pub mtime_nsec: u32,
+    /// File name of the archive containing the file, relative to the manifest's directory.
+    pub archive: String,
+    /// Owner user and group IDs. Absent in manifests from older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    /// Owner user and group names, as recorded in the archive. Absent if the ids have
+    /// no names, and in manifests from older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gname: Option<String>,
+    /// Checksum of the file's contents as `<algorithm>:<hex digest>`, with `--checksums`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// With `--entry-key-file`, the key of the file's encrypted contents, wrapped by the
+    /// master key. Deleting it makes the file unrecoverable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<String>,
+    /// With `StreamWriter`, the number of tar entries the file's contents are split
+    /// into, if more than one. Each part after the first is appended to the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parts: Option<u64>,
+    /// With `--long-path-policy shorten`, the file's path before it was shortened to
+    /// fit, when it didn't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_path: Option<PathBuf>,
+    /// If the path, or one of its parent directories, differs only in case from a path
+    /// archived before it in the same run, the shortest such prefix of the path. It
+    /// would overwrite the other on a case insensitive filesystem.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub case_collision: Option<PathBuf>,
+    /// Offset of the file's first tar header in its archive's uncompressed tar stream,
+    /// so it can be read without parsing the entries before it. Absent in manifests
+    /// from older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    /// With `compress --seekable`, the offset in the archive file of the zstd frame
+    /// holding the start of the file's entry, and where that frame starts in the
+    /// uncompressed tar stream, so reading can start there instead of at the beginning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_offset: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_tar_offset: Option<u64>,
+    /// With `compress --chunk-size`, where this chunk starts in the file, which has an
+    /// entry for each of its chunks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_offset: Option<u64>,
+}
+
+/// Writes manifest entries sent from any thread to `MANIFEST_FILE_NAME`.
+///
+/// Entries must only be sent once their archive is synced to disk, so that after a
+/// crash every entry in the manifest refers to a complete archive. See `fsck`.
+pub struct Writer {
+    tx: crossbeam_channel::Sender<Entry>,
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+impl Entry {
+    pub fn new(path: PathBuf, meta: &fs::Metadata, archive: String) -> Entry {
+        let (mtime, mtime_nsec) = unix_time(meta.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+        Entry {
+            path,
+            size: meta.len(),
+            mtime,
+            mtime_nsec,
+            archive,
+            uid: Some(meta.uid()),
+            gid: Some(meta.gid()),
+            uname: None,
+            gname: None,
+            checksum: None,
+            wrapped_key: None,
+            parts: None,
+            original_path: None,
+            case_collision: None,
+            offset: None,
+            frame_offset: None,
+            frame_tar_offset: None,
+            chunk_offset: None,
+        }
+    }
+}
+
+impl Writer {
+    pub fn create(out_dir: &Path, format: Format) -> Result<Writer> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(out_dir.join(format.file_name()))?;
+        let (tx, rx) = crossbeam_channel::bounded::<Entry>(1024);
+
+        let thread = thread::Builder::new()
+            .name("manifest".to_string())
+            .spawn(move || -> Result<()> {
+                let file = match format {
+                    Format::Jsonl => {
+                        let mut bufw = BufWriter::new(file);
+                        for entry in rx.iter() {
+                            serde_json::to_writer(&mut bufw, &entry)?;
+                            bufw.write_all(b"\n")?;
+                            // Entries arrive in a burst per finished archive; make each
+                            // burst durable, so little is lost in a crash.
+                            if rx.is_empty() {
+                                bufw.flush()?;
+                                bufw.get_ref().sync_data()?;
+                            }
+                        }
+                        bufw.into_inner().map_err(|err| err.into_error())?
+                    },
+                    Format::Parquet => {
+                        let mut w = manifest_parquet::Writer::new(file)?;
+                        for entry in rx.iter() {
+                            w.write(entry)?;
+                        }
+                        w.finish()?
+                    },
+                };
+                file.sync_all()?;
+                Ok(())
+            })?;
+
+        Ok(Writer { tx, thread })
+    }
+
+    pub fn sender(&self) -> crossbeam_channel::Sender<Entry> {
+        self.tx.clone()
+    }
+
+    /// Wait for all senders to be dropped, then flush the manifest to disk.
+    pub fn finish(self) -> Result<()> {
+        drop(self.tx);
+        self.thread.join().expect("joining manifest writer thread")
+    }
+}
+
+impl Format {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Format::Jsonl => MANIFEST_FILE_NAME,
+            Format::Parquet => PARQUET_MANIFEST_FILE_NAME,
+        }
+    }
+}
+
+/// Read all entries of the manifest in `dir`, in either format.
+pub fn read(dir: &Path) -> Result<Vec<Entry>> {
+    let parquet_path = dir.join(PARQUET_MANIFEST_FILE_NAME);
+    if !dir.join(MANIFEST_FILE_NAME).exists() && parquet_path.exists() {
+        return manifest_parquet::read(&parquet_path);
+    }
+
+    read_jsonl(&dir.join(MANIFEST_FILE_NAME))
+}
+
+/// Read all entries of the manifest file at `path`, in the format its extension names.
+pub fn read_file(path: &Path) -> Result<Vec<Entry>> {
+    if path.extension().is_some_and(|ext| ext == "parquet") {
+        manifest_parquet::read(path)
+    } else {
+        read_jsonl(path)
+    }
+}
+
+/// Read all entries of a manifest file named `name`, in the format its extension names,
+/// from its contents.
+pub fn read_bytes(name: &str, data: Vec<u8>) -> Result<Vec<Entry>> {
+    if name.ends_with(".parquet") {
+        manifest_parquet::read_chunks(bytes::Bytes::from(data))
+    } else {
+        read_jsonl_lines(&*data)
+    }
+}
+
+fn read_jsonl(path: &Path) -> Result<Vec<Entry>> {
+    read_jsonl_lines(BufReader::new(File::open(path)?))
+}
+
+fn read_jsonl_lines(r: impl BufRead) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+    Ok(entries)
+}
+
+/// Read the complete JSON lines entries from the start of `r`, stopping at the first
+/// partial or unparseable line, as left by a crash while writing.
+///
+/// Returns the entries and the length in bytes of the lines they were read from.
+pub fn read_jsonl_prefix(r: impl Read) -> Result<(Vec<Entry>, u64)> {
+    let mut r = BufReader::new(r);
+    let mut entries = Vec::new();
+    let mut len = 0_u64;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        r.read_until(b'\n', &mut line)?;
+        if line.last() != Some(&b'\n') {
+            break;
+        }
+        if line.len() > 1 {
+            let Ok(entry) = serde_json::from_slice(&line) else {
+                break;
+            };
+            entries.push(entry);
+        }
+        len += line.len() as u64;
+    }
+    Ok((entries, len))
+}
+
+/// Replace the JSON lines manifest in `dir` with `entries`.
+pub fn rewrite_jsonl(dir: &Path, entries: &[Entry]) -> Result<()> {
+    // Write then rename so an interruption never leaves a partial file.
+    let tmp_path = dir.join(format!("{MANIFEST_FILE_NAME}.tmp"));
+    let mut bufw = BufWriter::new(File::create(&*tmp_path)?);
+    for entry in entries {
+        serde_json::to_writer(&mut bufw, entry)?;
+        bufw.write_all(b"\n")?;
+    }
+    bufw.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&*tmp_path, dir.join(MANIFEST_FILE_NAME))?;
+    Ok(())
+}
+
+/// Combine the entries of each file split into chunks by `compress --chunk-size` into
+/// one entry for the whole file, in place of its first, for comparing whole files.
+pub fn merge_chunks(entries: Vec<Entry>) -> Vec<Entry> {
+    let mut merged = Vec::with_capacity(entries.len());
+    // Index in `merged` of each chunked file's entry.
+    let mut chunked = HashMap::<PathBuf, usize>::new();
+    for mut entry in entries {
+        if entry.chunk_offset.is_none() {
+            merged.push(entry);
+            continue;
+        }
+        match chunked.get(&entry.path) {
+            Some(&i) => merged[i].size += entry.size,
+            None => {
+                chunked.insert(entry.path.clone(), merged.len());
+                entry.chunk_offset = None;
+                merged.push(entry);
+            },
+        }
+    }
+    merged
+}
+
+/// Split a `SystemTime` into whole seconds and nanoseconds since the Unix epoch.
+pub fn unix_time(t: SystemTime) -> (i64, u32) {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(err) => {
+            // Before the epoch: round down to the previous whole second.
+            let d = err.duration();
+            if d.subsec_nanos() == 0 {
+                (-(d.as_secs() as i64), 0)
+            } else {
+                (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jsonl_prefix() {
+        let line = r#"{"path":"a","size":1,"mtime":2,"mtime_nsec":3,"archive":"x"}"#;
+        let complete = format!("{line}\n\n{line}\n");
+
+        let (entries, len) = read_jsonl_prefix(complete.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(len, complete.len() as u64);
+
+        // A partial last line, with or without its newline, is left out.
+        for partial in [&line[..10], line] {
+            let data = format!("{complete}{partial}");
+            let (entries, len) = read_jsonl_prefix(data.as_bytes()).unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(len, complete.len() as u64);
+        }
+        let data = format!("{complete}{partial}\n{line}\n", partial = &line[..10]);
+        let (entries, len) = read_jsonl_prefix(data.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(len, complete.len() as u64);
+    }
+
+    #[test]
+    fn merges_chunks() {
+        let entries = [
+            r#"{"path":"a","size":4,"mtime":2,"mtime_nsec":0,"archive":"x","chunk_offset":4}"#,
+            r#"{"path":"b","size":1,"mtime":2,"mtime_nsec":0,"archive":"x"}"#,
+            r#"{"path":"a","size":4,"mtime":2,"mtime_nsec":0,"archive":"y","chunk_offset":0}"#,
+            r#"{"path":"a","size":2,"mtime":2,"mtime_nsec":0,"archive":"y","chunk_offset":8}"#,
+        ];
+        let entries = entries.iter()
+                             .map(|line| serde_json::from_str(line).unwrap())
+                             .collect::<Vec<Entry>>();
+        let merged = merge_chunks(entries);
+        assert_eq!(merged.iter().map(|e| (e.path.to_str().unwrap(), e.size)).collect::<Vec<_>>(),
+                   [("a", 10), ("b", 1)]);
+        assert_eq!(merged[0].chunk_offset, None);
+    }
+}
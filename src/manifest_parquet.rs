@@ -0,0 +1,320 @@
+//! Apache Parquet encoding of the manifest, for querying the metadata of very large
+//! archive sets with tools like DataFusion or DuckDB.
+
+use anyhow::{anyhow, bail};
+use crate::{Result, manifest::Entry};
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type},
+    file::{
+        properties::WriterProperties,
+        reader::{ChunkReader, FileReader, SerializedFileReader},
+        writer::SerializedFileWriter,
+    },
+    record::Field,
+    schema::{parser::parse_message_type, types::Type},
+};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const SCHEMA: &str = "
+    message manifest_entry {
+        required binary path (UTF8);
+        required int64 size;
+        required int64 mtime;
+        required int32 mtime_nsec;
+        required binary archive (UTF8);
+        optional int64 uid;
+        optional int64 gid;
+        optional binary checksum (UTF8);
+        optional binary wrapped_key (UTF8);
+        optional int64 parts;
+        optional binary original_path (UTF8);
+        optional binary case_collision (UTF8);
+        optional int64 offset;
+        optional int64 frame_offset;
+        optional int64 frame_tar_offset;
+        optional int64 chunk_offset;
+        optional binary uname (UTF8);
+        optional binary gname (UTF8);
+    }
+";
+
+/// Entries buffered before they're written as a row group.
+const ROW_GROUP_LEN: usize = 1024 * 1024;
+
+pub struct Writer {
+    entries: Vec<Entry>,
+    inner: SerializedFileWriter<BufWriter<File>>,
+}
+
+impl Writer {
+    pub fn new(file: File) -> Result<Writer> {
+        let schema = Arc::new(parse_message_type(SCHEMA)?);
+        let props = Arc::new(WriterProperties::builder()
+                                 .set_compression(Compression::SNAPPY)
+                                 .build());
+        Ok(Writer {
+            entries: Vec::with_capacity(ROW_GROUP_LEN),
+            inner: SerializedFileWriter::new(BufWriter::new(file), schema, props)?,
+        })
+    }
+
+    pub fn write(&mut self, entry: Entry) -> Result<()> {
+        self.entries.push(entry);
+        if self.entries.len() >= ROW_GROUP_LEN {
+            self.flush_row_group()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<File> {
+        self.flush_row_group()?;
+        let bufw = self.inner.into_inner()?;
+        Ok(bufw.into_inner().map_err(|err| err.into_error())?)
+    }
+
+    fn flush_row_group(&mut self) -> Result<()> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+        let entries = &self.entries;
+        let mut row_group = self.inner.next_row_group()?;
+        let mut column_idx = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match column_idx {
+                0 => write_strings(column.typed::<ByteArrayType>(), entries,
+                                   |e| Some(e.path.to_string_lossy().into_owned()))?,
+                1 => write_i64s(column.typed::<Int64Type>(), entries,
+                                |e| Some(i64::try_from(e.size).unwrap_or(i64::MAX)))?,
+                2 => write_i64s(column.typed::<Int64Type>(), entries, |e| Some(e.mtime))?,
+                3 => {
+                    let values = entries.iter()
+                                        .map(|e| e.mtime_nsec as i32)
+                                        .collect::<Vec<i32>>();
+                    column.typed::<Int32Type>().write_batch(&values, None, None)?;
+                },
+                4 => write_strings(column.typed::<ByteArrayType>(), entries,
+                                   |e| Some(e.archive.clone()))?,
+                5 => write_i64s(column.typed::<Int64Type>(), entries,
+                                |e| e.uid.map(i64::from))?,
+                6 => write_i64s(column.typed::<Int64Type>(), entries,
+                                |e| e.gid.map(i64::from))?,
+                7 => write_strings(column.typed::<ByteArrayType>(), entries,
+                                   |e| e.checksum.clone())?,
+                8 => write_strings(column.typed::<ByteArrayType>(), entries,
+                                   |e| e.wrapped_key.clone())?,
+                9 => write_i64s(column.typed::<Int64Type>(), entries,
+                                |e| e.parts.map(|p| i64::try_from(p).unwrap_or(i64::MAX)))?,
+                10 => write_strings(column.typed::<ByteArrayType>(), entries, |e| {
+                    e.original_path.as_ref().map(|p| p.to_string_lossy().into_owned())
+                })?,
+                11 => write_strings(column.typed::<ByteArrayType>(), entries, |e| {
+                    e.case_collision.as_ref().map(|p| p.to_string_lossy().into_owned())
+                })?,
+                12 => write_i64s(column.typed::<Int64Type>(), entries,
+                                 |e| e.offset.map(|o| i64::try_from(o).unwrap_or(i64::MAX)))?,
+                13 => write_i64s(column.typed::<Int64Type>(), entries, |e| {
+                    e.frame_offset.map(|o| i64::try_from(o).unwrap_or(i64::MAX))
+                })?,
+                14 => write_i64s(column.typed::<Int64Type>(), entries, |e| {
+                    e.frame_tar_offset.map(|o| i64::try_from(o).unwrap_or(i64::MAX))
+                })?,
+                15 => write_i64s(column.typed::<Int64Type>(), entries, |e| {
+                    e.chunk_offset.map(|o| i64::try_from(o).unwrap_or(i64::MAX))
+                })?,
+                16 => write_strings(column.typed::<ByteArrayType>(), entries,
+                                    |e| e.uname.clone())?,
+                17 => write_strings(column.typed::<ByteArrayType>(), entries,
+                                    |e| e.gname.clone())?,
+                _ => bail!("Unexpected manifest column {column_idx}"),
+            }
+            column.close()?;
+            column_idx += 1;
+        }
+        row_group.close()?;
+        self.entries.clear();
+        Ok(())
+    }
+}
+
+/// Write a column of optional or required strings. Definition levels are ignored for
+/// required columns.
+fn write_strings(
+    w: &mut parquet::column::writer::ColumnWriterImpl<'_, ByteArrayType>,
+    entries: &[Entry],
+    f: impl Fn(&Entry) -> Option<String>,
+) -> Result<()> {
+    let mut values = Vec::with_capacity(entries.len());
+    let mut def_levels = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match f(entry) {
+            Some(s) => {
+                values.push(ByteArray::from(s.into_bytes()));
+                def_levels.push(1);
+            },
+            None => def_levels.push(0),
+        }
+    }
+    w.write_batch(&values, Some(&def_levels), None)?;
+    Ok(())
+}
+
+/// Write a column of optional or required i64s. Definition levels are ignored for
+/// required columns.
+fn write_i64s(
+    w: &mut parquet::column::writer::ColumnWriterImpl<'_, Int64Type>,
+    entries: &[Entry],
+    f: impl Fn(&Entry) -> Option<i64>,
+) -> Result<()> {
+    let mut values = Vec::with_capacity(entries.len());
+    let mut def_levels = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match f(entry) {
+            Some(v) => {
+                values.push(v);
+                def_levels.push(1);
+            },
+            None => def_levels.push(0),
+        }
+    }
+    w.write_batch(&values, Some(&def_levels), None)?;
+    Ok(())
+}
+
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    read_chunks(File::open(path)?)
+}
+
+/// Read the entries of a manifest from `chunks`, e.g. a `File` or its contents as
+/// `Bytes`.
+pub fn read_chunks<R: ChunkReader + 'static>(chunks: R) -> Result<Vec<Entry>> {
+    let reader = SerializedFileReader::new(chunks)?;
+    let mut entries = Vec::new();
+    for row in reader.get_row_iter(None::<Type>)? {
+        let row = row?;
+        let mut entry = Entry {
+            path: PathBuf::new(),
+            size: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            archive: String::new(),
+            uid: None,
+            gid: None,
+            uname: None,
+            gname: None,
+            checksum: None,
+            wrapped_key: None,
+            parts: None,
+            original_path: None,
+            case_collision: None,
+            offset: None,
+            frame_offset: None,
+            frame_tar_offset: None,
+            chunk_offset: None,
+        };
+        for (name, field) in row.get_column_iter() {
+            match (name.as_str(), field) {
+                ("path", Field::Str(s)) => entry.path = PathBuf::from(s),
+                ("size", Field::Long(v)) => entry.size = u64::try_from(*v)?,
+                ("mtime", Field::Long(v)) => entry.mtime = *v,
+                ("mtime_nsec", Field::Int(v)) => entry.mtime_nsec = u32::try_from(*v)?,
+                ("archive", Field::Str(s)) => entry.archive = s.clone(),
+                ("uid", Field::Long(v)) => entry.uid = Some(u32::try_from(*v)?),
+                ("gid", Field::Long(v)) => entry.gid = Some(u32::try_from(*v)?),
+                ("uname", Field::Str(s)) => entry.uname = Some(s.clone()),
+                ("gname", Field::Str(s)) => entry.gname = Some(s.clone()),
+                ("checksum", Field::Str(s)) => entry.checksum = Some(s.clone()),
+                ("wrapped_key", Field::Str(s)) => entry.wrapped_key = Some(s.clone()),
+                ("parts", Field::Long(v)) => entry.parts = Some(u64::try_from(*v)?),
+                ("original_path", Field::Str(s)) => {
+                    entry.original_path = Some(PathBuf::from(s));
+                },
+                ("case_collision", Field::Str(s)) => {
+                    entry.case_collision = Some(PathBuf::from(s));
+                },
+                ("offset", Field::Long(v)) => entry.offset = Some(u64::try_from(*v)?),
+                ("frame_offset", Field::Long(v)) => {
+                    entry.frame_offset = Some(u64::try_from(*v)?);
+                },
+                ("frame_tar_offset", Field::Long(v)) => {
+                    entry.frame_tar_offset = Some(u64::try_from(*v)?);
+                },
+                ("chunk_offset", Field::Long(v)) => {
+                    entry.chunk_offset = Some(u64::try_from(*v)?);
+                },
+                (_, Field::Null) => (),
+                (name, field) => return Err(anyhow!("Unexpected manifest field {name}={field}")),
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("ptar-manifest-test-{}.parquet", std::process::id()));
+        let entries = vec![
+            Entry {
+                path: PathBuf::from("a/b.txt"),
+                size: 3,
+                mtime: 1_700_000_000,
+                mtime_nsec: 5,
+                archive: "00000000.tar.zstd".to_string(),
+                uid: Some(1000),
+                gid: Some(100),
+                uname: Some("alice".to_string()),
+                gname: Some("users".to_string()),
+                checksum: Some("blake3:00".to_string()),
+                wrapped_key: Some("0123456789abcdef:00".to_string()),
+                parts: Some(3),
+                original_path: Some(PathBuf::from("a/long-b.txt")),
+                case_collision: Some(PathBuf::from("a")),
+                offset: Some(1536),
+                frame_offset: Some(700),
+                frame_tar_offset: Some(1024),
+                chunk_offset: Some(4096),
+            },
+            Entry {
+                path: PathBuf::from("c"),
+                size: 0,
+                mtime: -1,
+                mtime_nsec: 0,
+                archive: "00000001.tar.zstd".to_string(),
+                uid: None,
+                gid: None,
+                uname: None,
+                gname: None,
+                checksum: None,
+                wrapped_key: None,
+                parts: None,
+                original_path: None,
+                case_collision: None,
+                offset: None,
+                frame_offset: None,
+                frame_tar_offset: None,
+                chunk_offset: None,
+            },
+        ];
+
+        let mut w = Writer::new(File::create(&path).unwrap()).unwrap();
+        for entry in entries.iter() {
+            w.write(entry.clone()).unwrap();
+        }
+        w.finish().unwrap();
+
+        let read = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(format!("{read:?}"), format!("{entries:?}"));
+    }
+}
@@ -0,0 +1,169 @@
+//! Detached ed25519 signatures of archive sets, to prove a set hasn't been tampered with
+//! since compress wrote it.
+//!
+//! `manifest.sig` lists the BLAKE3 hash of the manifest, the deletions file if any, and
+//! each archive, one per line as `<hash>  <name>` like `b3sum` output, followed by a
+//! signature of those lines:
+//!
+//! ```text
+//! ptar manifest signature v1
+//! 5d4e…  manifest.jsonl
+//! 9a0b…  archive-000.tar.zst
+//! signature: 3f1c…
+//! ```
+//!
+//! Keys are 32 random bytes written as 64 hex digits, like `--entry-key-file` keys.
+
+use anyhow::{anyhow, bail, ensure, Context};
+use crate::{
+    Result, incremental, manifest,
+    entry_encryption::{from_hex, to_hex},
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+pub const SIGNATURE_FILE_NAME: &str = "manifest.sig";
+
+/// First line of the signed message, to version the format.
+const HEADER: &str = "ptar manifest signature v1\n";
+
+const SIGNATURE_PREFIX: &str = "signature: ";
+
+/// A secret key to sign archive sets with.
+pub struct ManifestSigningKey(SigningKey);
+
+impl ManifestSigningKey {
+    /// Load a key written as 64 hex digits, e.g. by
+    /// `head -c 32 /dev/urandom | xxd -p -c 32`.
+    pub fn load(path: &Path) -> Result<ManifestSigningKey> {
+        Ok(ManifestSigningKey(SigningKey::from_bytes(&read_key(path)?)))
+    }
+
+    /// The public key as hex, to pass to `verify --pubkey`.
+    pub fn public_key_hex(&self) -> String {
+        to_hex(self.0.verifying_key().as_bytes())
+    }
+
+    /// Sign the manifest and `archives`, file names in `dir`, writing `manifest.sig`
+    /// beside them.
+    pub fn sign(&self, dir: &Path, archives: &[String]) -> Result<()> {
+        let mut message = HEADER.to_string();
+        let metadata_names = [manifest::MANIFEST_FILE_NAME,
+                              manifest::PARQUET_MANIFEST_FILE_NAME,
+                              incremental::DELETIONS_FILE_NAME];
+        let names = metadata_names.into_iter()
+                                  .filter(|name| dir.join(name).exists())
+                                  .chain(archives.iter().map(|name| name.as_str()));
+        for name in names {
+            message.push_str(&format!("{}  {name}\n", hash_file(&dir.join(name))?));
+        }
+        let signature = self.0.sign(message.as_bytes());
+        message.push_str(&format!("{SIGNATURE_PREFIX}{}\n", to_hex(&signature.to_bytes())));
+
+        // Write then rename so an interruption never leaves a partial file.
+        let tmp_path = dir.join(format!("{SIGNATURE_FILE_NAME}.tmp"));
+        fs::write(&*tmp_path, message)?;
+        File::open(&*tmp_path)?.sync_all()?;
+        fs::rename(&*tmp_path, dir.join(SIGNATURE_FILE_NAME))?;
+        Ok(())
+    }
+}
+
+/// Check `manifest.sig` in `dir` is signed by the public key in the file at
+/// `pubkey_path`, and that every file it lists is unchanged. Returns the number of
+/// files checked.
+pub fn verify(dir: &Path, pubkey_path: &Path) -> Result<usize> {
+    let pubkey = VerifyingKey::from_bytes(&read_key(pubkey_path)?)
+        .with_context(|| format!("Public key '{}' is invalid", pubkey_path.display()))?;
+    let sig_path = dir.join(SIGNATURE_FILE_NAME);
+    let text = fs::read_to_string(&*sig_path)
+        .with_context(|| format!("reading signature file '{}'", sig_path.display()))?;
+    let malformed = || anyhow!("Signature file '{}' is malformed", sig_path.display());
+
+    let sig_start = text.rfind(SIGNATURE_PREFIX).ok_or_else(malformed)?;
+    let (message, sig_line) = text.split_at(sig_start);
+    let sig_bytes: [u8; 64] = from_hex(sig_line[SIGNATURE_PREFIX.len()..].trim()).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(malformed)?;
+    pubkey.verify_strict(message.as_bytes(), &Signature::from_bytes(&sig_bytes))
+        .map_err(|_| anyhow!("Signature file '{}' wasn't signed by the public key '{}', or \
+                              has been tampered with",
+                             sig_path.display(), pubkey_path.display()))?;
+
+    // Only the signed lines are trusted from here on.
+    let lines = message.strip_prefix(HEADER).ok_or_else(malformed)?;
+    let mut count = 0;
+    for line in lines.lines() {
+        let (hash, name) = line.split_once("  ").ok_or_else(malformed)?;
+        ensure!(!name.contains('/') && name != "..",
+                "Signature file '{}' lists a file outside the directory: '{name}'",
+                sig_path.display());
+        let path = dir.join(name);
+        if !path.exists() {
+            bail!("File '{}' listed in the signature file is missing", path.display());
+        }
+        ensure!(hash_file(&path)? == hash,
+                "File '{}' has changed since it was signed", path.display());
+        count += 1;
+    }
+    ensure!(count > 0, malformed());
+    Ok(count)
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)
+                 .with_context(|| format!("opening '{}' to hash", path.display()))?,
+             &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Read a 32 byte key written as hex. Errors don't include the file's contents.
+fn read_key(path: &Path) -> Result<[u8; 32]> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("reading key file '{}'", path.display()))?;
+    from_hex(text.trim()).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Key file '{}' should hold 32 bytes as 64 hex digits",
+                               path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::TempDir;
+
+    #[test]
+    fn sign_and_verify() {
+        let dir = TempDir::new("ptar-test").unwrap();
+        let dir = dir.path();
+        let key_path = dir.join("key");
+        fs::write(&key_path, format!("{}\n", "07".repeat(32))).unwrap();
+        fs::write(dir.join(manifest::MANIFEST_FILE_NAME), "{}\n").unwrap();
+        fs::write(dir.join("archive-000.tar"), "archive").unwrap();
+
+        let key = ManifestSigningKey::load(&key_path).unwrap();
+        key.sign(dir, &["archive-000.tar".to_string()]).unwrap();
+        let pubkey_path = dir.join("key.pub");
+        fs::write(&pubkey_path, key.public_key_hex()).unwrap();
+        assert_eq!(verify(dir, &pubkey_path).unwrap(), 2);
+
+        // A changed archive.
+        fs::write(dir.join("archive-000.tar"), "Archive").unwrap();
+        assert!(verify(dir, &pubkey_path).is_err());
+        fs::write(dir.join("archive-000.tar"), "archive").unwrap();
+
+        // A changed listing.
+        let sig_path = dir.join(SIGNATURE_FILE_NAME);
+        let text = fs::read_to_string(&sig_path).unwrap();
+        fs::write(&sig_path, text.replace("archive-000.tar", "archive-001.tar")).unwrap();
+        assert!(verify(dir, &pubkey_path).is_err());
+
+        fs::write(&key_path, "07").unwrap();
+        assert!(ManifestSigningKey::load(&key_path).is_err());
+    }
+}
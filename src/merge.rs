@@ -0,0 +1,256 @@
+//! `ptar merge`: rewrite the archives of a set as fewer, balanced archives, e.g. to
+//! consolidate the many small archives of an incremental run.
+//!
+//! Input archives are shared out between the output archives by size, largest first,
+//! each to the output with the least so far. Each output then streams the tar entries of
+//! its inputs, in name order, into one tar stream, without extracting any files. The
+//! manifest is rewritten with the new archive names and offsets, and each archive's
+//! checksums file is concatenated into its output's.
+
+use anyhow::{ensure, Context};
+use crate::{
+    Result, archive_set, incremental, manifest, manifest_signature, tar_stream,
+    codec::Codec,
+};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Directory to write the merged set to, created if it doesn't exist.
+    #[arg(long)]
+    out_dir: PathBuf,
+
+    /// Number of archives to write, at most the number in the input set.
+    #[arg(long, value_name = "N")]
+    shards: usize,
+
+    /// Compression format of the new archives.
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    codec: Codec,
+
+    /// Compression level of the new archives. Defaults to the codec's default level.
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+}
+
+/// An input archive, and where its tar stream starts in its output archive's.
+struct Input {
+    name: String,
+    base_offset: u64,
+}
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    let start = Instant::now();
+    ensure!(cmd_args.shards > 0, "--shards must be at least 1");
+    // Check the level before creating the output directory.
+    drop(cmd_args.codec.encoder(io::sink(), cmd_args.level, None)?);
+    let in_dir = fs::canonicalize(&*cmd_args.in_dir)
+        .with_context(|| format!("reading '{}'", cmd_args.in_dir.display()))?;
+    fs::create_dir_all(&*cmd_args.out_dir)?;
+    ensure!(in_dir != fs::canonicalize(&*cmd_args.out_dir)?,
+            "--out-dir must differ from --in-dir");
+
+    let manifest_format = match (cmd_args.in_dir.join(manifest::MANIFEST_FILE_NAME).exists(),
+                                 cmd_args.in_dir.join(manifest::PARQUET_MANIFEST_FILE_NAME)
+                                                .exists()) {
+        (false, true) => Some(manifest::Format::Parquet),
+        (true, _) => Some(manifest::Format::Jsonl),
+        (false, false) => None,
+    };
+    // Created first, so merging into an existing set fails before writing archives.
+    let manifest_writer = manifest_format
+        .map(|format| manifest::Writer::create(&cmd_args.out_dir, format)
+                          .with_context(|| format!("creating the manifest in '{}'",
+                                                   cmd_args.out_dir.display())))
+        .transpose()?;
+
+    // Sort out the archives from the other files by their magic bytes.
+    let mut archives = Vec::new();
+    let mut sidecars = Vec::new();
+    for path in archive_set::candidate_paths(&cmd_args.in_dir)? {
+        match archive_set::open(&path, None, None)
+                  .with_context(|| format!("opening '{}'", path.display()))? {
+            Some(_) => archives.push((fs::metadata(&*path)?.len(), path)),
+            None => sidecars.push(path),
+        }
+    }
+    ensure!(!archives.is_empty(), "No archives in '{}'", cmd_args.in_dir.display());
+    let outputs = balance(archives, cmd_args.shards);
+
+    let merged = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build()?
+        .install(|| {
+            outputs.into_par_iter()
+                   .enumerate()
+                   .with_max_len(1) // 1 output archive per thread
+                   .map(|(num, inputs)| merge_archives(num, inputs, &cmd_args))
+                   .collect::<Result<Vec<_>>>()
+        })?;
+    // Input archive name to its output's name and its start in the output's tar stream.
+    let moves = merged.iter()
+        .flat_map(|(name, inputs)| inputs.iter().map(move |input| {
+            (input.name.clone(), (name.clone(), input.base_offset))
+        }))
+        .collect::<HashMap<_, _>>();
+
+    for path in sidecars.iter() {
+        let name = file_name(path);
+        if name == incremental::DELETIONS_FILE_NAME {
+            fs::copy(path, cmd_args.out_dir.join(&*name))
+                .with_context(|| format!("copying '{}'", path.display()))?;
+        }
+    }
+    for (name, inputs) in merged.iter() {
+        merge_checksums(name, inputs, &sidecars, &cmd_args.out_dir)?;
+    }
+
+    if let Some(writer) = manifest_writer {
+        let tx = writer.sender();
+        let mut entries = manifest::read(&cmd_args.in_dir)?;
+        // Group the entries by output archive, in their order in it.
+        entries.sort_by_key(|entry| match moves.get(&entry.archive) {
+            Some((name, base_offset)) => (Some(name.clone()), *base_offset),
+            None => (None, 0),
+        });
+        for mut entry in entries {
+            let (name, base_offset) = moves.get(&entry.archive)
+                .with_context(|| format!("The manifest's archive '{}' for '{}' isn't in \
+                                          the set", entry.archive, entry.path.display()))?;
+            entry.archive = name.clone();
+            entry.offset = entry.offset.map(|offset| base_offset + offset);
+            entry.frame_offset = None;
+            entry.frame_tar_offset = None;
+            tx.send(entry).expect("manifest writer thread is running");
+        }
+        drop(tx);
+        writer.finish()?;
+    }
+    if cmd_args.in_dir.join(manifest_signature::SIGNATURE_FILE_NAME).exists() {
+        tracing::warn!("The merged set isn't signed, as the signature is of the old \
+                        archives; sign it again to verify it with --pubkey");
+    }
+
+    tracing::info!(in_archive_count = moves.len(), out_archive_count = merged.len(),
+                   duration_ms = start.elapsed().as_millis(), "Merged the archive set");
+    Ok(())
+}
+
+/// Share out `archives`, each with its size, between at most `shards` outputs, largest
+/// first to the output with the least so far. Each output's archives are in name order.
+fn balance(mut archives: Vec<(u64, PathBuf)>, shards: usize) -> Vec<Vec<PathBuf>> {
+    archives.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    let mut outputs = vec![(0_u64, Vec::new()); shards.min(archives.len())];
+    for (size, path) in archives {
+        let output = outputs.iter_mut()
+                            .min_by_key(|(total, _)| *total)
+                            .expect("at least 1 output");
+        output.0 += size;
+        output.1.push(path);
+    }
+    outputs.into_iter()
+           .map(|(_, mut paths)| {
+               paths.sort();
+               paths
+           })
+           .collect()
+}
+
+/// Write output archive number `num` from the tar entries of `paths`. Returns its name
+/// and its inputs.
+fn merge_archives(num: usize, paths: Vec<PathBuf>, cmd_args: &Args)
+-> Result<(String, Vec<Input>)>
+{
+    let name = format!("{num:08}.{ext}", ext = cmd_args.codec.extension());
+    let out_path = cmd_args.out_dir.join(&*name);
+    let partial_path = cmd_args.out_dir.join(format!("{name}{}", archive_set::PARTIAL_SUFFIX));
+    ensure!(!out_path.exists(), "'{}' already exists", out_path.display());
+
+    let file = File::options().write(true).create_new(true).open(&*partial_path)
+        .with_context(|| format!("creating '{}'", partial_path.display()))?;
+    let mut encoder = cmd_args.codec.encoder(BufWriter::with_capacity(1024 * 1024, file),
+                                             cmd_args.level, None)?;
+    let mut inputs = Vec::with_capacity(paths.len());
+    let mut tar_bytes = 0;
+    for path in paths {
+        let mut archive = archive_set::open(&path, None, None)?
+            .with_context(|| format!("'{}' is no longer an archive", path.display()))?;
+        let len = tar_stream::copy_entries(&mut archive.reader, &mut encoder)
+            .with_context(|| format!("merging '{}'", path.display()))?;
+        inputs.push(Input {
+            name: file_name(&path),
+            base_offset: tar_bytes,
+        });
+        tar_bytes += len;
+    }
+    // The end of archive marker.
+    encoder.write_all(&[0; 1024])?;
+    let file = encoder.finish()?.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(&*partial_path, &*out_path)?;
+
+    tracing::debug!(archive = name, in_archive_count = inputs.len(), tar_bytes,
+                    compressed_bytes = file.metadata()?.len(), "Merged archive");
+    Ok((name, inputs))
+}
+
+/// Concatenate each input archive's sidecar files named `<archive>.<ext>`, i.e. its
+/// checksums files, into the output archive's `<name>.<ext>`.
+fn merge_checksums(name: &str, inputs: &[Input], sidecars: &[PathBuf], out_dir: &Path)
+-> Result<()>
+{
+    let mut out_files = HashMap::<String, BufWriter<File>>::new();
+    for input in inputs {
+        let prefix = format!("{}.", input.name);
+        for path in sidecars {
+            let Some(ext) = file_name(path).strip_prefix(&*prefix).map(str::to_string) else {
+                continue;
+            };
+            if !out_files.contains_key(&ext) {
+                let out_path = out_dir.join(format!("{name}.{ext}"));
+                let file = File::options().write(true).create_new(true).open(&*out_path)
+                    .with_context(|| format!("creating '{}'", out_path.display()))?;
+                out_files.insert(ext.clone(), BufWriter::new(file));
+            }
+            let out = out_files.get_mut(&ext).expect("inserted above");
+            io::copy(&mut File::open(path)?, out)?;
+        }
+    }
+    for (_, bufw) in out_files {
+        bufw.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("archive path has a file name")
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balances() {
+        let outputs = balance(vec![(10, "a".into()), (4, "b".into()), (5, "c".into()),
+                                   (3, "d".into())],
+                              2);
+        assert_eq!(outputs, [vec![PathBuf::from("a")],
+                             ["b", "c", "d"].map(PathBuf::from).to_vec()]);
+        assert_eq!(balance(vec![(1, "a".into())], 3).len(), 1);
+    }
+}
@@ -0,0 +1,58 @@
+//! The Linux `nodump` file attribute, set with `chattr +d`, which marks files and
+//! directories that backups should skip, for `compress --honor-nodump`.
+
+use std::{io, path::Path};
+
+/// Whether the file or directory at `path` has the `nodump` attribute. False for
+/// other file types, on filesystems without file attributes and on other platforms.
+pub fn is_nodump(path: &Path, file_type: std::fs::FileType) -> io::Result<bool> {
+    // Opening devices or FIFOs to read their attributes could block or have side
+    // effects, and symlinks have no attributes of their own.
+    if !(file_type.is_file() || file_type.is_dir()) {
+        return Ok(false);
+    }
+    sys::is_nodump(path)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        fs::File,
+        io,
+        os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+        path::Path,
+    };
+
+    /// `FS_NODUMP_FL` from `linux/fs.h`.
+    const FS_NODUMP_FL: libc::c_int = 0x40;
+
+    pub fn is_nodump(path: &Path) -> io::Result<bool> {
+        let file = File::options()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(path)?;
+        let mut flags: libc::c_int = 0;
+        // SAFETY: file is open, and FS_IOC_GETFLAGS writes an int to flags.
+        let res = unsafe {
+            libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags as *mut libc::c_int)
+        };
+        if res != 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // The filesystem has no file attributes.
+                Some(libc::ENOTTY) | Some(libc::ENOTSUP) | Some(libc::EINVAL) => Ok(false),
+                _ => Err(err),
+            };
+        }
+        Ok(flags & FS_NODUMP_FL != 0)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::{io, path::Path};
+
+    pub fn is_nodump(_path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+}
@@ -0,0 +1,195 @@
+//! Owner names: recorded in tar headers alongside the numeric ids when compressing, and
+//! mapped back to this system's ids when extracting with `--same-owner`, unless
+//! `--numeric-owner`, as GNU tar does. So a restore on another machine, whose ids
+//! differ, keeps files' users and groups.
+//!
+//! Lookups go through NSS, which can be slow, e.g. with LDAP, so results are cached.
+
+use crate::Result;
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    hash::Hash,
+    mem::MaybeUninit,
+    ptr,
+    sync::Mutex,
+};
+
+/// Largest buffer offered to the `get{pw,gr}*_r` functions for an entry's strings.
+const MAX_BUF_LEN: usize = 1 << 20;
+
+/// Cached lookups of user and group names by id, to record in headers.
+#[derive(Debug, Default)]
+pub struct Names {
+    users: Mutex<HashMap<u32, Option<String>>>,
+    groups: Mutex<HashMap<u32, Option<String>>>,
+}
+
+/// Cached lookups of user and group ids by name, to restore owners.
+#[derive(Debug, Default)]
+pub struct Ids {
+    /// Use the archived ids, ignoring names, with `--numeric-owner`.
+    numeric: bool,
+    users: Mutex<HashMap<String, Option<u32>>>,
+    groups: Mutex<HashMap<String, Option<u32>>>,
+}
+
+impl Names {
+    /// Set the owner names in `header` from its ids. Ids without names, and names too
+    /// long for the header, are left out.
+    pub fn set_header(&self, header: &mut tar::Header) -> Result<()> {
+        let uid = u32::try_from(header.uid()?)?;
+        let gid = u32::try_from(header.gid()?)?;
+        if let Some(name) = cached(&self.users, uid, user_name) {
+            let _ = header.set_username(&name);
+        }
+        if let Some(name) = cached(&self.groups, gid, group_name) {
+            let _ = header.set_groupname(&name);
+        }
+        Ok(())
+    }
+}
+
+impl Ids {
+    pub fn new(numeric: bool) -> Ids {
+        Ids { numeric, ..Ids::default() }
+    }
+
+    /// The local user and group ids for the owner in `header`: those of its names, if
+    /// they exist here, or else its numeric ids.
+    pub fn owner(&self, header: &tar::Header) -> Result<(u32, u32)> {
+        let uid = u32::try_from(header.uid()?)?;
+        let gid = u32::try_from(header.gid()?)?;
+        Ok((self.uid(uid, header.username()?), self.gid(gid, header.groupname()?)))
+    }
+
+    /// The local user id for the archived `uid` and user `name`, as `owner()` maps them.
+    pub fn uid(&self, uid: u32, name: Option<&str>) -> u32 {
+        match name.filter(|name| !self.numeric && !name.is_empty()) {
+            Some(name) => cached(&self.users, name.to_string(), |name| user_id(&name))
+                .unwrap_or(uid),
+            None => uid,
+        }
+    }
+
+    /// The local group id for the archived `gid` and group `name`, as `owner()` maps
+    /// them.
+    pub fn gid(&self, gid: u32, name: Option<&str>) -> u32 {
+        match name.filter(|name| !self.numeric && !name.is_empty()) {
+            Some(name) => cached(&self.groups, name.to_string(), |name| group_id(&name))
+                .unwrap_or(gid),
+            None => gid,
+        }
+    }
+}
+
+/// An owner name from a tar header, e.g. `header.username()`, to record in the
+/// manifest. None if it's unset, empty or not UTF-8.
+pub fn header_name<E>(name: std::result::Result<Option<&str>, E>) -> Option<String> {
+    name.ok().flatten().filter(|name| !name.is_empty()).map(String::from)
+}
+
+fn cached<K: Clone + Eq + Hash, V: Clone>(cache: &Mutex<HashMap<K, Option<V>>>, key: K,
+                                          lookup: impl FnOnce(K) -> Option<V>)
+-> Option<V>
+{
+    if let Some(value) = cache.lock().expect("lock").get(&key) {
+        return value.clone();
+    }
+    // Not under the lock, as lookups can be slow.
+    let value = lookup(key.clone());
+    cache.lock().expect("lock").insert(key, value.clone());
+    value
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    lookup(|passwd: *mut libc::passwd, buf: &mut [u8], result| {
+               // SAFETY: the pointers are valid, and buf is buf.len() bytes long.
+               unsafe { libc::getpwuid_r(uid, passwd, buf.as_mut_ptr().cast(), buf.len(),
+                                         result) }
+           },
+           // SAFETY: pw_name is a C string in the buffer.
+           |passwd| unsafe { CStr::from_ptr(passwd.pw_name) }.to_str().ok().map(String::from))
+    .flatten()
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    lookup(|group: *mut libc::group, buf: &mut [u8], result| {
+               // SAFETY: the pointers are valid, and buf is buf.len() bytes long.
+               unsafe { libc::getgrgid_r(gid, group, buf.as_mut_ptr().cast(), buf.len(),
+                                         result) }
+           },
+           // SAFETY: gr_name is a C string in the buffer.
+           |group| unsafe { CStr::from_ptr(group.gr_name) }.to_str().ok().map(String::from))
+    .flatten()
+}
+
+fn user_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    lookup(|passwd: *mut libc::passwd, buf: &mut [u8], result| {
+               // SAFETY: the pointers are valid, and buf is buf.len() bytes long.
+               unsafe { libc::getpwnam_r(name.as_ptr(), passwd, buf.as_mut_ptr().cast(),
+                                         buf.len(), result) }
+           },
+           |passwd| passwd.pw_uid)
+}
+
+fn group_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    lookup(|group: *mut libc::group, buf: &mut [u8], result| {
+               // SAFETY: the pointers are valid, and buf is buf.len() bytes long.
+               unsafe { libc::getgrnam_r(name.as_ptr(), group, buf.as_mut_ptr().cast(),
+                                         buf.len(), result) }
+           },
+           |group| group.gr_gid)
+}
+
+/// Look up an entry with `call`, a `get{pw,gr}*_r` function, and `read` what's needed
+/// from it while its buffer is alive. None if there's no such entry, or the lookup
+/// fails.
+fn lookup<E, T>(call: impl Fn(*mut E, &mut [u8], *mut *mut E) -> libc::c_int,
+                read: impl FnOnce(&E) -> T)
+-> Option<T>
+{
+    let mut buf = vec![0_u8; 1024];
+    loop {
+        let mut entry = MaybeUninit::<E>::uninit();
+        let mut result = ptr::null_mut();
+        match call(entry.as_mut_ptr(), &mut buf, &mut result) {
+            libc::ERANGE if buf.len() < MAX_BUF_LEN => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() => {
+                // SAFETY: the lookup found the entry, so it's initialised, and its strings
+                // point into buf, which is still alive.
+                return Some(read(unsafe { entry.assume_init_ref() }));
+            },
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_round_trip() {
+        assert_eq!(user_name(0).as_deref(), Some("root"));
+        assert_eq!(user_id("root"), Some(0));
+        assert_eq!(group_id(&group_name(0).unwrap()), Some(0));
+        assert_eq!(user_id("no-such-user-ptar"), None);
+
+        let mut header = tar::Header::new_ustar();
+        header.set_uid(0);
+        header.set_gid(0);
+        Names::default().set_header(&mut header).unwrap();
+        assert_eq!(header.username().unwrap(), Some("root"));
+
+        // Names take precedence over ids, unless numeric.
+        header.set_uid(12345);
+        header.set_gid(12345);
+        assert_eq!(Ids::new(false).owner(&header).unwrap(), (0, 0));
+        assert_eq!(Ids::new(true).owner(&header).unwrap(), (12345, 12345));
+        header.set_username("no-such-user-ptar").unwrap();
+        assert_eq!(Ids::new(false).owner(&header).unwrap().0, 12345);
+    }
+}
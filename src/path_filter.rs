@@ -0,0 +1,77 @@
+//! Selecting archive entries by `--include` and `--exclude` globs.
+
+use crate::Result;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+/// Matches relative paths against include and exclude globs.
+///
+/// A path is selected if it or one of its parent directories matches an include glob
+/// (or there are no include globs), and neither it nor a parent directory matches an
+/// exclude glob. So `--include src` selects everything under `src/`.
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter> {
+        Ok(PathFilter {
+            include: if include.is_empty() { None } else { Some(build(include)?) },
+            exclude: build(exclude)?,
+        })
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        let mut ancestors = path.ancestors()
+                                .filter(|p| !p.as_os_str().is_empty());
+        if let Some(ref include) = self.include {
+            if !ancestors.clone().any(|p| include.is_match(p)) {
+                return false;
+            }
+        }
+        !ancestors.any(|p| self.exclude.is_match(p))
+    }
+}
+
+fn build(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathFilter;
+    use std::path::Path;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let to_strings = |globs: &[&str]| globs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        PathFilter::new(&to_strings(include), &to_strings(exclude)).unwrap()
+    }
+
+    #[test]
+    fn empty_selects_everything() {
+        let f = filter(&[], &[]);
+        assert!(f.is_match(Path::new("a/b.txt")));
+    }
+
+    #[test]
+    fn include_matches_parent_dirs() {
+        let f = filter(&["src", "*.md"], &[]);
+        assert!(f.is_match(Path::new("src/main.rs")));
+        assert!(f.is_match(Path::new("README.md")));
+        assert!(f.is_match(Path::new("doc/todo.md")));
+        assert!(!f.is_match(Path::new("bin/bench")));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let f = filter(&["src"], &["src/gen", "*.o"]);
+        assert!(f.is_match(Path::new("src/main.rs")));
+        assert!(!f.is_match(Path::new("src/gen/x.rs")));
+        assert!(!f.is_match(Path::new("src/a.o")));
+    }
+}
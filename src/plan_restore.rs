@@ -0,0 +1,208 @@
+//! `ptar plan-restore`: check an archive set's manifest for files that can't be
+//! restored as they are onto a target filesystem, e.g. removable media formatted FAT32
+//! or a Windows share, before attempting the restore.
+//!
+//! ptar only archives regular files, so symlinks and other special files can't be
+//! incompatible with the target.
+
+use anyhow::bail;
+use crate::{Result, manifest, path_filter::PathFilter};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Directory containing the archive set and its manifest.
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Filesystem the archive set would be restored onto.
+    #[arg(long, value_enum)]
+    target_fs: TargetFs,
+
+    /// Only check files matching this glob, as for `decompress --include`. May be
+    /// repeated.
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Skip files matching this glob, as for `decompress --exclude`. May be repeated.
+    #[arg(long)]
+    exclude: Vec<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum TargetFs {
+    /// FAT32, e.g. USB sticks and SD cards: Windows' naming rules, and files under 4 GiB.
+    Fat32,
+    /// NTFS, e.g. Windows drives and shares: Windows' naming rules.
+    Ntfs,
+    /// ext4 and most other Linux filesystems: names up to 255 bytes.
+    Ext4,
+}
+
+/// A reason a file can't be restored as it is.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Problem {
+    /// A name contains a character Windows doesn't allow, e.g. `:` or `?`.
+    InvalidCharacter,
+    /// A name is reserved by Windows, e.g. `CON` or `lpt1.txt`.
+    ReservedName,
+    /// A name ends with a space or `.`, which Windows strips.
+    TrailingDotOrSpace,
+    /// A name is longer than the filesystem allows.
+    NameTooLong,
+    /// The file is 4 GiB or larger, too large for FAT32.
+    FileTooLarge,
+    /// The path differs only in case from another, and the filesystem is case
+    /// insensitive, so one would overwrite the other.
+    CaseCollision,
+}
+
+/// Number of example paths logged for each problem.
+const MAX_EXAMPLES: usize = 10;
+
+/// Largest file FAT32 can hold.
+const FAT32_MAX_FILE_SIZE: u64 = (4 << 30) - 1;
+
+/// Longest file name, in UTF-16 code units for Windows filesystems and bytes for ext4.
+const MAX_NAME_LEN: usize = 255;
+
+/// Characters Windows doesn't allow in names, besides control characters.
+const WINDOWS_INVALID_CHARS: &[char] = &['"', '*', ':', '<', '>', '?', '\\', '|'];
+
+/// Names Windows reserves for devices, with or without an extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+pub fn main(cmd_args: Args, _args: crate::Args) -> Result<()> {
+    let filter = PathFilter::new(&cmd_args.include, &cmd_args.exclude)?;
+    let entries = manifest::merge_chunks(manifest::read(&cmd_args.in_dir)?);
+    let paths = entries.iter()
+                       .filter(|e| filter.is_match(&e.path))
+                       .map(|e| (e.path.as_path(), e.size))
+                       .collect::<Vec<_>>();
+    let problems = check(&paths, cmd_args.target_fs);
+
+    let mut problem_count = 0;
+    for (problem, mut paths) in problems {
+        problem_count += paths.len();
+        paths.sort();
+        tracing::warn!(?problem, count = paths.len(),
+                       examples = ?&paths[..paths.len().min(MAX_EXAMPLES)],
+                       "Files can't be restored as they are onto the target filesystem");
+    }
+    tracing::info!(file_count = paths.len(), problem_count, target_fs = ?cmd_args.target_fs,
+                   "plan-restore finished");
+    if problem_count > 0 {
+        bail!("plan-restore found {problem_count} problems restoring onto {target_fs:?}",
+              target_fs = cmd_args.target_fs);
+    }
+    Ok(())
+}
+
+/// Check the files at `paths` with sizes, relative to the restore directory, against
+/// `target`'s limits. A path may have several problems.
+fn check(paths: &[(&Path, u64)], target: TargetFs) -> BTreeMap<Problem, Vec<PathBuf>> {
+    let windows = matches!(target, TargetFs::Fat32 | TargetFs::Ntfs);
+    let mut problems = BTreeMap::<Problem, Vec<PathBuf>>::new();
+    // Each directory and file path seen, by its case folded form.
+    let mut folded = HashMap::<String, PathBuf>::new();
+
+    for &(path, size) in paths {
+        let mut found = Vec::new();
+        if target == TargetFs::Fat32 && size > FAT32_MAX_FILE_SIZE {
+            found.push(Problem::FileTooLarge);
+        }
+        for name in path.iter() {
+            let name = name.to_string_lossy();
+            if windows {
+                found.extend(windows_name_problems(&name));
+            } else if name.len() > MAX_NAME_LEN {
+                found.push(Problem::NameTooLong);
+            }
+        }
+        if windows {
+            // Check the file and each parent directory, as `a/x` and `A/y` collide too.
+            let mut prefix = PathBuf::new();
+            for name in path.iter() {
+                prefix.push(name);
+                let other = folded.entry(prefix.to_string_lossy().to_lowercase())
+                                  .or_insert_with(|| prefix.clone());
+                if *other != prefix {
+                    found.push(Problem::CaseCollision);
+                    break;
+                }
+            }
+        }
+        found.sort();
+        found.dedup();
+        for problem in found {
+            problems.entry(problem).or_default().push(path.to_path_buf());
+        }
+    }
+    problems
+}
+
+fn windows_name_problems(name: &str) -> Vec<Problem> {
+    let mut found = Vec::new();
+    if name.chars().any(|c| c.is_control() || WINDOWS_INVALID_CHARS.contains(&c)) {
+        found.push(Problem::InvalidCharacter);
+    }
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if WINDOWS_RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        found.push(Problem::ReservedName);
+    }
+    if name.ends_with(['.', ' ']) {
+        found.push(Problem::TrailingDotOrSpace);
+    }
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        found.push(Problem::NameTooLong);
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_paths(paths: &[(&str, u64)], target: TargetFs) -> Vec<(Problem, Vec<PathBuf>)> {
+        let paths = paths.iter().map(|&(p, size)| (Path::new(p), size)).collect::<Vec<_>>();
+        check(&paths, target).into_iter().collect()
+    }
+
+    #[test]
+    fn problems() {
+        let long_name = "é".repeat(200);
+        let paths = [
+            ("ok/file.txt", 5 << 30),
+            ("a:b", 0),
+            ("dir/con.txt", 0),
+            ("dir/console.txt", 0),
+            ("trailing.", 0),
+            (long_name.as_str(), 0),
+            ("Dir/other", 0),
+        ];
+        assert_eq!(check_paths(&paths, TargetFs::Fat32), vec![
+            (Problem::InvalidCharacter, vec![PathBuf::from("a:b")]),
+            (Problem::ReservedName, vec![PathBuf::from("dir/con.txt")]),
+            (Problem::TrailingDotOrSpace, vec![PathBuf::from("trailing.")]),
+            (Problem::FileTooLarge, vec![PathBuf::from("ok/file.txt")]),
+            (Problem::CaseCollision, vec![PathBuf::from("Dir/other")]),
+        ]);
+
+        // No size limit on NTFS.
+        assert!(!check_paths(&paths, TargetFs::Ntfs).iter()
+                    .any(|(problem, _)| *problem == Problem::FileTooLarge));
+
+        // 400 bytes but only 200 UTF-16 code units, so only too long for ext4.
+        assert_eq!(check_paths(&paths, TargetFs::Ext4), vec![
+            (Problem::NameTooLong, vec![PathBuf::from(&long_name)]),
+        ]);
+    }
+}
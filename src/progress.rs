@@ -0,0 +1,260 @@
+//! Progress reporting, summing counters shared by all the worker threads.
+//!
+//! Progress is drawn as a bar on stderr when it's a terminal, and otherwise logged
+//! periodically.
+
+use crate::{Result, status};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Counters updated by the worker threads and read by the `Reporter`.
+///
+/// The totals may grow while the work is in progress, e.g. while walking the input.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub total_files: AtomicU64,
+    pub total_bytes: AtomicU64,
+    pub done_files: AtomicU64,
+    /// An `Arc` so it can be shared with a `ProgressReader`.
+    pub done_bytes: Arc<AtomicU64>,
+
+    /// Byte counters of each archive opened for reading. Their compressed bytes count
+    /// towards `done_bytes`.
+    archives: Mutex<Vec<ArchiveCounters>>,
+
+    /// The most recent `MAX_EVENTS` events, oldest first.
+    events: Mutex<VecDeque<Event>>,
+
+    /// Set by library users to follow progress.
+    callback: Option<Callback>,
+}
+
+/// Called from worker threads with each `ProgressEvent`, so programs embedding ptar can
+/// show their own progress.
+pub type ProgressCallback = Box<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// A `ProgressCallback` shared by the options and `Counters`.
+#[derive(Clone)]
+pub struct Callback(Arc<dyn Fn(ProgressEvent) + Send + Sync>);
+
+/// Progress passed to a `ProgressCallback`. Totals are summed over all threads.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ProgressEvent {
+    /// compress started reading a file.
+    FileStarted {
+        path: PathBuf,
+    },
+    /// compress finished archiving a file.
+    FileDone {
+        path: PathBuf,
+        files_done: u64,
+        bytes_done: u64,
+    },
+    /// compress finished an archive, either at the end of a shard or on rolling over to
+    /// a new archive at `--max-shard-size`.
+    ArchiveFinished {
+        name: String,
+        compressed_bytes: u64,
+    },
+    /// decompress finished extracting an archive.
+    ArchiveExtracted {
+        name: String,
+        archives_done: u64,
+        /// Compressed bytes read.
+        bytes_done: u64,
+    },
+}
+
+/// A notable step in the work, e.g. an archive finished, for status socket clients.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Event {
+    pub time: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+struct ArchiveCounters {
+    compressed_bytes: Arc<AtomicU64>,
+    uncompressed_bytes: Arc<AtomicU64>,
+}
+
+/// Reports progress from `Counters` on its own thread until finished.
+pub struct Reporter {
+    stop_tx: crossbeam_channel::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// A snapshot of `Counters`.
+#[derive(Debug, serde::Serialize)]
+pub struct Snapshot {
+    pub total_files: u64,
+    pub total_bytes: u64,
+    pub done_files: u64,
+    pub done_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uncompressed_bytes: Option<u64>,
+}
+
+const TEMPLATE: &str =
+    "[{elapsed_precise}] {wide_bar} {percent:>3}% {bytes}/{total_bytes} \
+     {binary_bytes_per_sec} {msg} ETA {eta}";
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Interval between progress log events when stderr isn't a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+const MAX_EVENTS: usize = 100;
+
+impl Counters {
+    pub fn with_callback(callback: Option<Callback>) -> Counters {
+        Counters {
+            callback,
+            ..Counters::default()
+        }
+    }
+
+    /// Pass the event built by `event` to the callback, if there is one.
+    pub fn notify(&self, event: impl FnOnce() -> ProgressEvent) {
+        if let Some(Callback(ref callback)) = self.callback {
+            callback(event());
+        }
+    }
+
+    /// Remove a file from the totals, e.g. one skipped after an error.
+    pub fn remove_file(&self, len: u64) {
+        self.total_files.fetch_sub(1, Ordering::Relaxed);
+        self.total_bytes.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Include an archive's byte counters, as from `archive_set::OpenArchive`.
+    pub fn add_archive(&self, compressed_bytes: Arc<AtomicU64>,
+                       uncompressed_bytes: Arc<AtomicU64>) {
+        self.archives.lock().expect("archives lock").push(ArchiveCounters {
+            compressed_bytes,
+            uncompressed_bytes,
+        });
+    }
+
+    /// Record an event, dropping the oldest if there are too many.
+    pub fn event(&self, message: String) {
+        let mut events = self.events.lock().expect("events lock");
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(Event {
+            time: status::format_time(SystemTime::now()),
+            message,
+        });
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().expect("events lock").iter().cloned().collect()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let archives = self.archives.lock().expect("archives lock");
+        let archive_bytes = archives.iter()
+                                    .map(|a| a.compressed_bytes.load(Ordering::Relaxed))
+                                    .sum::<u64>();
+        let uncompressed_bytes = (!archives.is_empty()).then(|| {
+            archives.iter().map(|a| a.uncompressed_bytes.load(Ordering::Relaxed)).sum()
+        });
+        Snapshot {
+            total_files: self.total_files.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            done_files: self.done_files.load(Ordering::Relaxed),
+            done_bytes: self.done_bytes.load(Ordering::Relaxed) + archive_bytes,
+            uncompressed_bytes,
+        }
+    }
+}
+
+impl From<ProgressCallback> for Callback {
+    fn from(callback: ProgressCallback) -> Callback {
+        Callback(Arc::from(callback))
+    }
+}
+
+impl fmt::Debug for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+impl Reporter {
+    /// `unit` names what `Counters` counts as files, e.g. "archives".
+    pub fn spawn(counters: Arc<Counters>, unit: &'static str) -> Result<Reporter> {
+        let (stop_tx, stop_rx) = crossbeam_channel::bounded::<()>(0);
+        let bar = ProgressBar::new(0);
+        bar.set_style(ProgressStyle::with_template(TEMPLATE)?);
+        let thread = thread::Builder::new()
+            .name("progress".to_string())
+            .spawn(move || {
+                let start = Instant::now();
+                let mut last_log = start;
+                loop {
+                    let snap = counters.snapshot();
+                    let files_per_sec = snap.done_files as f64 / start.elapsed().as_secs_f64();
+                    let mut msg = format!("{done_files}/{total_files} {unit} \
+                                           {files_per_sec:.0} {unit}/s",
+                                          done_files = snap.done_files,
+                                          total_files = snap.total_files);
+                    if let Some(uncompressed_bytes) = snap.uncompressed_bytes {
+                        msg += &format!(" {} uncompressed", HumanBytes(uncompressed_bytes));
+                    }
+                    bar.set_length(snap.total_bytes);
+                    bar.set_position(snap.done_bytes);
+                    bar.set_message(msg);
+
+                    // Disconnected when the Reporter is finished.
+                    let stopped = stop_rx.recv_timeout(REFRESH_INTERVAL)
+                                         .is_err_and(|err| err.is_disconnected());
+
+                    if bar.is_hidden() && (stopped || last_log.elapsed() >= LOG_INTERVAL) {
+                        last_log = Instant::now();
+                        snap.log(unit);
+                    }
+                    if stopped {
+                        break;
+                    }
+                }
+                bar.finish();
+            })?;
+        Ok(Reporter { stop_tx, thread })
+    }
+
+    /// Report the final progress and stop.
+    pub fn finish(self) {
+        drop(self.stop_tx);
+        self.thread.join().expect("joining progress thread");
+    }
+}
+
+impl Snapshot {
+    fn log(&self, unit: &str) {
+        let percent = match self.total_bytes {
+            0 => 100.0,
+            total => 100.0 * self.done_bytes as f64 / total as f64,
+        };
+        tracing::info!(unit,
+                       done_files = self.done_files,
+                       total_files = self.total_files,
+                       done_bytes = self.done_bytes,
+                       total_bytes = self.total_bytes,
+                       uncompressed_bytes = self.uncompressed_bytes,
+                       percent = format!("{percent:.1}"),
+                       "Progress");
+    }
+}
@@ -23,6 +23,14 @@ impl<R: Read> ProgressReader<R> {
         )
     }
 
+    /// Add the bytes read to an existing counter, e.g. one shared by several readers.
+    pub fn with_counter(inner: R, bytes_read: Arc<AtomicU64>) -> ProgressReader<R> {
+        ProgressReader {
+            bytes_read,
+            inner,
+        }
+    }
+
     #[allow(dead_code)] // Not used yet.
     pub fn bytes_read(&self) -> Arc<AtomicU64> {
         self.bytes_read.clone()
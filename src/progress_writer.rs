@@ -0,0 +1,61 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+pub struct ProgressWriter<W: Write> {
+    bytes_written: Arc<AtomicU64>,
+    /// Some after `time_writes`.
+    busy_nanos: Option<Arc<AtomicU64>>,
+    inner: W,
+}
+
+impl<W: Write> ProgressWriter<W> {
+    pub fn new(inner: W) -> (ProgressWriter<W>, Arc<AtomicU64>) {
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        (
+            ProgressWriter {
+                bytes_written: bytes_written.clone(),
+                busy_nanos: None,
+                inner,
+            },
+            bytes_written
+        )
+    }
+
+    /// Also count the nanoseconds spent in writes to the inner writer, e.g. blocked on a
+    /// slow destination.
+    pub fn time_writes(&mut self) -> Arc<AtomicU64> {
+        self.busy_nanos.get_or_insert_with(|| Arc::new(AtomicU64::new(0))).clone()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = self.busy_nanos.is_some().then(Instant::now);
+        let count = self.inner.write(buf)?;
+        if let (Some(busy_nanos), Some(start)) = (&self.busy_nanos, start) {
+            busy_nanos.fetch_add(u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX),
+                                 Ordering::Relaxed);
+        }
+        self.bytes_written.fetch_add(u64::try_from(count).expect("usize to u64"),
+                                     Ordering::SeqCst);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,228 @@
+//! Checking per-user disk quotas before extracting with `--same-owner`, so a restore
+//! fails up front rather than part way through a user's files.
+//!
+//! Files count against the users they'll be owned by once extracted: those of their
+//! owner names in the manifest, unless `--numeric-owner`, as `owners` maps them.
+
+use anyhow::bail;
+use crate::{Result, manifest, owners, path_filter::PathFilter};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+};
+use valuable::Valuable;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum QuotaCheck {
+    /// Don't check quotas.
+    Off,
+    /// Log a warning for each user whose quota would be exceeded.
+    Warn,
+    /// Fail before extracting anything if any user's quota would be exceeded.
+    Fail,
+}
+
+/// A user's block quota on the destination filesystem, in bytes.
+#[derive(Debug)]
+struct UserQuota {
+    used: u64,
+    /// 0 means no limit.
+    soft_limit: u64,
+    /// 0 means no limit.
+    hard_limit: u64,
+}
+
+/// Sum the sizes of the manifest entries selected by `filter` for each local owner uid,
+/// mapping archived owners by `ids` as extraction does.
+pub fn bytes_by_uid(entries: &[manifest::Entry], filter: &PathFilter, ids: &owners::Ids)
+-> BTreeMap<u32, u64>
+{
+    let mut totals = BTreeMap::new();
+    for entry in entries.iter().filter(|e| filter.is_match(&e.path)) {
+        let Some(uid) = entry.uid else {
+            continue;
+        };
+        *totals.entry(ids.uid(uid, entry.uname.as_deref())).or_insert(0) += entry.size;
+    }
+    totals
+}
+
+/// Check each user's quota on the filesystem containing `out_dir` can hold their bytes
+/// in `bytes_by_uid`.
+pub fn check(mode: QuotaCheck, out_dir: &Path, bytes_by_uid: &BTreeMap<u32, u64>)
+-> Result<()>
+{
+    if mode == QuotaCheck::Off || bytes_by_uid.is_empty() {
+        return Ok(());
+    }
+
+    let Some(device) = sys::block_device(out_dir)? else {
+        tracing::warn!(out_dir = %out_dir.display(),
+                       "Couldn't find the block device for out_dir, skipping quota check");
+        return Ok(());
+    };
+
+    let mut over_quota = 0_usize;
+    for (&uid, &bytes) in bytes_by_uid.iter() {
+        let quota = match sys::user_quota(&device, uid) {
+            Ok(Some(q)) => q,
+            Ok(None) => {
+                tracing::debug!(device = %device.display(),
+                                "Quotas not enabled on out_dir's filesystem");
+                return Ok(());
+            },
+            Err(err) => {
+                tracing::warn!(uid, %err, "Error reading user quota, skipping quota check");
+                return Ok(());
+            },
+        };
+        tracing::debug!(uid, bytes, ?quota, "User quota");
+
+        let after = quota.used.saturating_add(bytes);
+        if quota.hard_limit > 0 && after > quota.hard_limit {
+            tracing::warn!(uid, bytes, used = quota.used, hard_limit = quota.hard_limit,
+                           "Extracting would exceed user's hard quota");
+            over_quota += 1;
+        } else if quota.soft_limit > 0 && after > quota.soft_limit {
+            tracing::warn!(uid, bytes, used = quota.used, soft_limit = quota.soft_limit,
+                           "Extracting would exceed user's soft quota");
+        }
+    }
+
+    if mode == QuotaCheck::Fail && over_quota > 0 {
+        bail!("Extracting would exceed the hard quota of {over_quota} user(s)");
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use crate::Result;
+    use super::UserQuota;
+    use std::{
+        ffi::CString,
+        fs,
+        io,
+        os::unix::{ffi::OsStrExt, fs::MetadataExt},
+        path::{Path, PathBuf},
+    };
+
+    const Q_GETQUOTA: libc::c_int = 0x800007;
+    const USRQUOTA: libc::c_int = 0;
+    /// Quota block limits are in units of 1 KiB.
+    const QIF_DQBLKSIZE: u64 = 1024;
+
+    /// Find the block device of the filesystem containing `path` from
+    /// `/proc/self/mountinfo`.
+    pub fn block_device(path: &Path) -> Result<Option<PathBuf>> {
+        let dev = fs::metadata(path)?.dev();
+        let (major, minor) = (libc::major(dev), libc::minor(dev));
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+        for line in mountinfo.lines() {
+            // Format: id parent major:minor root mount_point options [tags...] - fstype source
+            let mut fields = line.split(' ');
+            let Some(dev_field) = fields.nth(2) else {
+                continue;
+            };
+            if dev_field != format!("{major}:{minor}") {
+                continue;
+            }
+            let mut after_sep = line.split(" - ").nth(1).unwrap_or("").split(' ');
+            if let Some(source) = after_sep.nth(1) {
+                if source.starts_with('/') {
+                    return Ok(Some(PathBuf::from(source)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns None if quotas aren't enabled on the device.
+    pub fn user_quota(device: &Path, uid: u32) -> Result<Option<UserQuota>> {
+        let device_c = CString::new(device.as_os_str().as_bytes())?;
+        // SAFETY: dqblk is plain old data, so all zeroes is a valid value.
+        let mut dq: libc::dqblk = unsafe { std::mem::zeroed() };
+        let cmd = (Q_GETQUOTA << 8) | USRQUOTA;
+        // SAFETY: device_c is a valid C string and dq is a valid dqblk for
+        // Q_GETQUOTA to write to.
+        let res = unsafe {
+            libc::quotactl(cmd, device_c.as_ptr(), uid as libc::c_int,
+                           &mut dq as *mut libc::dqblk as *mut libc::c_char)
+        };
+        if res != 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ESRCH) | Some(libc::ENOSYS) | Some(libc::ENOTSUP) => Ok(None),
+                _ => Err(err.into()),
+            };
+        }
+        Ok(Some(UserQuota {
+            used: dq.dqb_curspace,
+            soft_limit: dq.dqb_bsoftlimit.saturating_mul(QIF_DQBLKSIZE),
+            hard_limit: dq.dqb_bhardlimit.saturating_mul(QIF_DQBLKSIZE),
+        }))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use crate::Result;
+    use super::UserQuota;
+    use std::path::{Path, PathBuf};
+
+    pub fn block_device(_path: &Path) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+
+    pub fn user_quota(_device: &Path, _uid: u32) -> Result<Option<UserQuota>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    fn entry(path: &str, uid: Option<u32>, size: u64) -> manifest::Entry {
+        let meta = fs::metadata(".").unwrap();
+        let mut entry = manifest::Entry::new(PathBuf::from(path), &meta,
+                                             "00000000.tar.zstd".to_string());
+        entry.uid = uid;
+        entry.size = size;
+        entry
+    }
+
+    fn named(name: &str, entry: manifest::Entry) -> manifest::Entry {
+        manifest::Entry { uname: Some(name.to_string()), ..entry }
+    }
+
+    #[test]
+    fn sums_bytes_by_uid() {
+        let entries = [
+            entry("a/1", Some(1000), 10),
+            entry("a/2", Some(1000), 5),
+            entry("b/1", Some(1001), 7),
+            entry("c/1", Some(1002), 3),
+            entry("a/old", None, 100),
+        ];
+        let filter = PathFilter::new(&[], &["c/**".to_string()]).unwrap();
+        assert_eq!(bytes_by_uid(&entries, &filter, &owners::Ids::new(false)),
+                   BTreeMap::from([(1000, 15), (1001, 7)]));
+    }
+
+    #[test]
+    fn maps_owner_names() {
+        // Archived on a machine where root's uid differed, and by a user unknown here.
+        let entries = [
+            named("root", entry("a", Some(12345), 10)),
+            named("no-such-user-ptar", entry("b", Some(1001), 7)),
+        ];
+        let filter = PathFilter::new(&[], &[]).unwrap();
+        assert_eq!(bytes_by_uid(&entries, &filter, &owners::Ids::new(false)),
+                   BTreeMap::from([(0, 10), (1001, 7)]));
+        assert_eq!(bytes_by_uid(&entries, &filter, &owners::Ids::new(true)),
+                   BTreeMap::from([(1001, 7), (12345, 10)]));
+    }
+}
@@ -0,0 +1,166 @@
+//! Throttling reads and writes to a number of bytes per second, shared between threads
+//! by a token bucket, with `--limit-read-rate` and `--limit-write-rate`, so a backup
+//! doesn't saturate the disks or network link other workloads use.
+//!
+//! The bucket holds up to a second's worth of bytes. Each read or write takes its bytes
+//! from it once done, and if that leaves the bucket in debt, sleeps until it's refilled,
+//! so threads sharing it wait their turn in proportion to what they transferred.
+
+use anyhow::ensure;
+use crate::{Result, size};
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Default, Valuable)]
+pub struct RateArgs {
+    /// Read at most RATE bytes per second across all threads, e.g. `50MiB`: the files
+    /// archived when compressing, or the archives, local or remote, when decompressing.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub(crate) limit_read_rate: Option<u64>,
+
+    /// Write at most RATE bytes per second across all threads, e.g. `50MiB`: the
+    /// archives, to disk or remote storage, when compressing, or the extracted files
+    /// when decompressing.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub(crate) limit_write_rate: Option<u64>,
+}
+
+impl RateArgs {
+    /// The limiter shared by all reads, with `--limit-read-rate`.
+    pub fn read_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.limit_read_rate.map(|rate| Arc::new(RateLimiter::new(rate)))
+    }
+
+    /// The limiter shared by all writes, with `--limit-write-rate`.
+    pub fn write_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.limit_write_rate.map(|rate| Arc::new(RateLimiter::new(rate)))
+    }
+}
+
+fn parse_rate(s: &str) -> Result<u64> {
+    let rate = size::parse(s)?;
+    ensure!(rate > 0, "The rate must be more than 0 bytes per second");
+    Ok(rate)
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes per second.
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may be transferred now, negative if in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        let rate = rate.max(1);
+        RateLimiter {
+            rate: rate as f64,
+            bucket: Mutex::new(Bucket { tokens: rate as f64, refilled: Instant::now() }),
+        }
+    }
+
+    /// Count `len` bytes transferred, sleeping until the rate allows them.
+    pub fn take(&self, len: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("rate limiter lock");
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.rate;
+            bucket.tokens = (bucket.tokens + refill).min(self.rate) - len as f64;
+            bucket.refilled = now;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / self.rate))
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// A reader limited to a rate, if given a limiter.
+pub struct LimitedReader<R> {
+    inner: R,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(inner: R, limiter: Option<Arc<RateLimiter>>) -> LimitedReader<R> {
+        LimitedReader { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(ref limiter) = self.limiter {
+            limiter.take(len);
+        }
+        Ok(len)
+    }
+}
+
+/// A writer limited to a rate, if given a limiter.
+pub struct LimitedWriter<W> {
+    inner: W,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<W> LimitedWriter<W> {
+    pub fn new(inner: W, limiter: Option<Arc<RateLimiter>>) -> LimitedWriter<W> {
+        LimitedWriter { inner, limiter }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if let Some(ref limiter) = self.limiter {
+            limiter.take(len);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_rate() {
+        let limiter = Arc::new(RateLimiter::new(100_000));
+        let start = Instant::now();
+        // A second's worth is in the bucket to start with, so this much takes about 0.5s
+        // across the threads.
+        let threads = (0..3).map(|_| {
+            let limiter = limiter.clone();
+            thread::spawn(move || {
+                let mut r = LimitedReader::new(io::repeat(0).take(50_000), Some(limiter));
+                io::copy(&mut r, &mut io::sink()).unwrap();
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+    }
+}
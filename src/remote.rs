@@ -0,0 +1,102 @@
+//! Archive sets kept in remote storage, given by URL, e.g. `s3://bucket/prefix/` or
+//! `sftp://user@host/path`.
+//!
+//! compress uploads archives as they're written, instead of writing them to
+//! `--out-dir`, then uploads the manifest and other metadata. decompress reads them
+//! back as streams.
+
+use anyhow::{bail, Context};
+use crate::{Result, archive_name::NameArgs, manifest, s3::S3Store, sftp::SftpStore, size};
+use std::{
+    fs,
+    io::{Read, Write},
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+use valuable::Valuable;
+
+/// Options for all remote stores.
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct RemoteArgs {
+    /// Number of parts of archives uploaded at once, across all archives.
+    #[arg(long, default_value_t = 4)]
+    pub(crate) upload_concurrency: usize,
+
+    /// Size of each part of an archive upload, e.g. `64MiB`. At least 5MiB; an archive
+    /// can have at most 10,000 parts. Each part being uploaded is held in memory.
+    #[arg(long, value_parser = size::parse, default_value = "16MiB")]
+    pub(crate) part_size: u64,
+
+    /// Times to retry a failed request to an S3 store, waiting longer after each.
+    #[arg(long, default_value_t = 5)]
+    pub(crate) retries: u32,
+
+    /// Endpoint of an S3 compatible store, e.g. `http://localhost:9000` for MinIO.
+    /// Defaults to `AWS_ENDPOINT_URL`, or else AWS S3 in the region from `AWS_REGION`.
+    /// Credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    #[arg(long, value_name = "URL")]
+    pub(crate) s3_endpoint: Option<String>,
+
+    /// Command to run ssh for `sftp://` URLs, split on whitespace, e.g.
+    /// `ssh -i ~/.ssh/backup_key`. It's passed the port, user, host and `-s sftp`.
+    #[arg(long, value_name = "COMMAND", default_value = "ssh")]
+    pub(crate) ssh_command: String,
+}
+
+/// A directory-like prefix in remote storage holding an archive set.
+pub trait Store: Send + Sync {
+    /// Start uploading the file `name` under the prefix.
+    fn create(&self, name: &str) -> Result<Box<dyn Upload>>;
+
+    /// The files directly under the prefix, sorted by name.
+    fn list(&self) -> Result<Vec<StoredFile>>;
+
+    /// Read the file `name` under the prefix.
+    fn open(&self, name: &str) -> Result<Box<dyn Read + Send>>;
+}
+
+/// A file being uploaded. It's only visible in the store once finished.
+pub trait Upload: Write + Send {
+    fn finish(self: Box<Self>) -> Result<()>;
+
+    /// Abandon the upload, cleaning up any parts already uploaded.
+    fn abort(self: Box<Self>) -> Result<()>;
+}
+
+#[derive(Clone, Debug)]
+pub struct StoredFile {
+    pub name: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Connect to the store at `url`.
+pub fn open(url: &str, args: &RemoteArgs) -> Result<Arc<dyn Store>> {
+    match url.split_once("://") {
+        Some(("s3", location)) => Ok(Arc::new(S3Store::new(location, args)?)),
+        Some(("sftp", location)) => Ok(Arc::new(SftpStore::new(location, args)?)),
+        _ => bail!("Unsupported store URL '{url}'; expected 's3://<BUCKET>/<PREFIX>' or \
+                    'sftp://[<USER>@]<HOST>[:<PORT>]/<PATH>'"),
+    }
+}
+
+/// Upload the local file at `path` to `store` as `name`.
+pub fn upload_file(store: &dyn Store, name: &str, path: &Path) -> Result<()> {
+    let mut upload = store.create(name)?;
+    upload.write_all(&fs::read(path)?)?;
+    upload.finish().with_context(|| format!("uploading '{}'", path.display()))
+}
+
+/// Read all entries of the manifest in `store`, in either format, named with
+/// `name_args`'s prefix.
+pub fn read_manifest(store: &dyn Store, name_args: &NameArgs) -> Result<Vec<manifest::Entry>> {
+    let names = store.list()?.into_iter().map(|file| file.name).collect::<Vec<_>>();
+    let name = [manifest::MANIFEST_FILE_NAME, manifest::PARQUET_MANIFEST_FILE_NAME]
+        .into_iter()
+        .find(|name| names.contains(&name_args.metadata_name(name)))
+        .context("No manifest in the store")?;
+    let mut data = Vec::new();
+    store.open(&name_args.metadata_name(name))?.read_to_end(&mut data)?;
+    manifest::read_bytes(name, data)
+}
@@ -0,0 +1,259 @@
+//! `ptar repack`: recompress an archive set into a new directory, e.g. quick level 0
+//! backups into high-ratio copies for cold storage.
+//!
+//! Each archive's tar stream is decoded and re-encoded as it's read, by several threads,
+//! without extracting any files. The tar streams are unchanged, so the manifest's
+//! offsets stay valid; it's rewritten with the new archive names, without the zstd frame
+//! offsets `compress --seekable` records. Checksums files and the deletions file are
+//! copied. The signature isn't, as it signs the old archives.
+
+use anyhow::{ensure, Context};
+use crate::{
+    Result, archive_set, incremental, manifest, manifest_signature,
+    codec::Codec,
+};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Directory to write the recompressed set to, created if it doesn't exist.
+    #[arg(long)]
+    out_dir: PathBuf,
+
+    /// Compression format of the new archives.
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    codec: Codec,
+
+    /// Compression level of the new archives. Defaults to the codec's default level.
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+}
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    let start = Instant::now();
+    // Check the level before creating the output directory.
+    drop(cmd_args.codec.encoder(io::sink(), cmd_args.level, None)?);
+    fs::create_dir_all(&*cmd_args.out_dir)?;
+    ensure!(fs::canonicalize(&*cmd_args.in_dir)? != fs::canonicalize(&*cmd_args.out_dir)?,
+            "--out-dir must differ from --in-dir");
+
+    let manifest_format = match (cmd_args.in_dir.join(manifest::MANIFEST_FILE_NAME).exists(),
+                                 cmd_args.in_dir.join(manifest::PARQUET_MANIFEST_FILE_NAME)
+                                                .exists()) {
+        (false, true) => Some(manifest::Format::Parquet),
+        (true, _) => Some(manifest::Format::Jsonl),
+        (false, false) => None,
+    };
+    // Created first, so repacking into an existing set fails before writing archives.
+    let manifest_writer = manifest_format
+        .map(|format| manifest::Writer::create(&cmd_args.out_dir, format)
+                          .with_context(|| format!("creating the manifest in '{}'",
+                                                   cmd_args.out_dir.display())))
+        .transpose()?;
+
+    let paths = archive_set::candidate_paths(&cmd_args.in_dir)?;
+    let repacked = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build()?
+        .install(|| {
+            paths.par_iter()
+                 .with_max_len(1) // 1 archive per thread
+                 .map(|path| repack_archive(path, &cmd_args)
+                                 .with_context(|| format!("repacking '{}'", path.display())))
+                 .collect::<Result<Vec<_>>>()
+        })?;
+
+    // Old archive name to new, for each archive.
+    let mut names = HashMap::new();
+    let mut sidecars = Vec::new();
+    let (mut in_bytes, mut out_bytes) = (0, 0);
+    for (path, repacked) in paths.iter().zip(repacked) {
+        match repacked {
+            Some(r) => {
+                in_bytes += fs::metadata(path)?.len();
+                out_bytes += r.compressed_bytes;
+                names.insert(file_name(path), r.name);
+            },
+            None => sidecars.push(path),
+        }
+    }
+    for path in sidecars {
+        copy_sidecar(path, &names, &cmd_args.out_dir)?;
+    }
+
+    if let Some(writer) = manifest_writer {
+        let tx = writer.sender();
+        for mut entry in manifest::read(&cmd_args.in_dir)? {
+            entry.archive = names.get(&entry.archive)
+                .with_context(|| format!("The manifest's archive '{}' for '{}' isn't in \
+                                          the set", entry.archive, entry.path.display()))?
+                .clone();
+            entry.frame_offset = None;
+            entry.frame_tar_offset = None;
+            tx.send(entry).expect("manifest writer thread is running");
+        }
+        drop(tx);
+        writer.finish()?;
+    }
+    if cmd_args.in_dir.join(manifest_signature::SIGNATURE_FILE_NAME).exists() {
+        tracing::warn!("The repacked set isn't signed, as the signature is of the old \
+                        archives; sign it again to verify it with --pubkey");
+    }
+
+    tracing::info!(archive_count = names.len(), in_bytes, out_bytes,
+                   duration_ms = start.elapsed().as_millis(), "Repacked the archive set");
+    Ok(())
+}
+
+/// A recompressed archive.
+struct Repacked {
+    name: String,
+    compressed_bytes: u64,
+}
+
+/// Recompress the archive at `path` into `--out-dir`. Returns None if it's not an
+/// archive.
+fn repack_archive(path: &Path, cmd_args: &Args) -> Result<Option<Repacked>> {
+    let Some(mut archive) = archive_set::open(path, None, None)? else {
+        return Ok(None);
+    };
+    let old_name = file_name(path);
+    let stem = old_name.strip_suffix(archive.codec.extension())
+                       .and_then(|stem| stem.strip_suffix('.'))
+                       .unwrap_or(&old_name);
+    let name = format!("{stem}.{ext}", ext = cmd_args.codec.extension());
+    let out_path = cmd_args.out_dir.join(&*name);
+    let partial_path = cmd_args.out_dir.join(format!("{name}{}", archive_set::PARTIAL_SUFFIX));
+    ensure!(!out_path.exists(), "'{}' already exists", out_path.display());
+
+    let file = File::options().write(true).create_new(true).open(&*partial_path)
+        .with_context(|| format!("creating '{}'", partial_path.display()))?;
+    let mut encoder = cmd_args.codec.encoder(BufWriter::with_capacity(1024 * 1024, file),
+                                             cmd_args.level, None)?;
+    let tar_bytes = io::copy(&mut archive.reader, &mut encoder)?;
+    let file = encoder.finish()?.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    let compressed_bytes = file.metadata()?.len();
+    fs::rename(&*partial_path, &*out_path)?;
+
+    tracing::debug!(archive = old_name, new_archive = name, tar_bytes, compressed_bytes,
+                    "Repacked archive");
+    Ok(Some(Repacked {
+        name,
+        compressed_bytes,
+    }))
+}
+
+/// Copy a file of the set that isn't an archive to `out_dir`: the deletions file, or an
+/// archive's checksums file, renamed after the new archive. Manifests are rewritten
+/// instead, and other files, e.g. state files, are skipped.
+fn copy_sidecar(path: &Path, names: &HashMap<String, String>, out_dir: &Path) -> Result<()> {
+    let name = &*file_name(path);
+    let new_name = if name == incremental::DELETIONS_FILE_NAME {
+        name.to_string()
+    } else {
+        // `<archive>.<hash algorithm>`
+        let Some((archive, alg)) = name.rsplit_once('.') else {
+            return Ok(());
+        };
+        match names.get(archive) {
+            Some(new_archive) => format!("{new_archive}.{alg}"),
+            None => {
+                tracing::debug!(file = name, "Skipping file that isn't an archive");
+                return Ok(());
+            },
+        }
+    };
+    fs::copy(path, out_dir.join(new_name))
+        .with_context(|| format!("copying '{}'", path.display()))?;
+    Ok(())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("archive path has a file name")
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        DecompressOptions,
+        testsupport::{Fixture, TempDir, TreeSpec, ensure_trees_equal},
+    };
+
+    #[test]
+    fn round_trip() {
+        let spec = TreeSpec {
+            files: 30,
+            max_file_size: 20_000,
+            ..TreeSpec::default()
+        };
+        let fixture = Fixture::build(&spec, |options| {
+            options.threads(2).max_shard_size(16_000).codec(Codec::Zstd)
+        }).unwrap();
+        let old_archives = fixture.archive_paths().iter()
+            .map(|path| fs::read(path).unwrap())
+            .collect::<Vec<_>>();
+
+        let out = TempDir::new("ptar-test").unwrap();
+        let cmd_args: Args = crate::default_cmd_args(&[
+            ("in-dir", fixture.archives.path().into()),
+            ("out-dir", out.path().into()),
+            ("codec", "gzip".into()),
+            ("level", "9".into()),
+        ]);
+        main(cmd_args.clone(), crate::Args {
+            threads: Some(2),
+            log_json: false,
+            command: crate::Command::Repack(cmd_args),
+        }).unwrap();
+
+        // The old set is untouched, and each new archive was renamed into place whole.
+        let rename = |name: &str| name.replace(".tar.zstd", ".tar.gz");
+        let new_archives = fixture.report.archives.iter()
+            .map(|name| out.path().join(rename(name)))
+            .collect::<Vec<_>>();
+        for (path, old) in fixture.archive_paths().iter().zip(&old_archives) {
+            assert_eq!(&fs::read(path).unwrap(), old, "{}", path.display());
+        }
+        for path in new_archives.iter() {
+            assert_eq!(archive_set::open(path, None, None).unwrap().unwrap().codec,
+                       Codec::Gzip);
+        }
+        for dir_entry in fs::read_dir(out.path()).unwrap() {
+            let name = dir_entry.unwrap().file_name();
+            assert!(!name.to_string_lossy().ends_with(archive_set::PARTIAL_SUFFIX),
+                    "{name:?}");
+        }
+
+        // The manifest is the same but for the archive names.
+        let manifest = |dir: &Path, rename: &dyn Fn(&str) -> String| {
+            let mut entries = manifest::read(dir).unwrap();
+            for entry in entries.iter_mut() {
+                entry.archive = rename(&entry.archive);
+            }
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            serde_json::to_value(entries).unwrap()
+        };
+        assert_eq!(manifest(fixture.archives.path(), &rename),
+                   manifest(out.path(), &str::to_string));
+
+        let extracted = TempDir::new("ptar-test").unwrap();
+        DecompressOptions::new(out.path(), extracted.path()).run().unwrap();
+        ensure_trees_equal(fixture.input.path(), extracted.path()).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+//! `ptar restore`: reconstruct a tree from a full backup and the increments made since
+//! with `compress --since-manifest`.
+
+use anyhow::{Context, ensure};
+use crate::{
+    CancellationToken, DecompressOptions, Result, incremental, manifest, shard_encryption,
+    compress::ErrorPolicy,
+    decompress::{UnpackArgs, UnpackOptions},
+};
+use std::{
+    fs, io,
+    path::PathBuf,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Archive set directories to extract, the full backup first and then each
+    /// increment in the order they were made.
+    #[arg(long, num_args = 1.., required = true, value_name = "DIR")]
+    snapshots: Vec<PathBuf>,
+
+    #[arg(long)]
+    out_dir: PathBuf,
+
+    #[command(flatten)]
+    unpack: UnpackArgs,
+
+    /// Create character and block devices and FIFOs archived with
+    /// `compress --special-files`. Only root can create devices, so otherwise they're
+    /// skipped, and counted in a warning, as all special files are without this.
+    #[arg(long)]
+    special_files: bool,
+
+    /// What to do when an entry can't be extracted. With `keep-going` such entries are
+    /// logged and skipped, and restore stops with an error after the snapshot they're
+    /// in, rather than apply later increments to an incomplete tree.
+    #[arg(long, value_enum, default_value_t = ErrorPolicy::FailFast)]
+    error_policy: ErrorPolicy,
+
+    /// Short for `--error-policy keep-going`.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Decrypt files compressed with `--entry-key-file`, using the same master key file.
+    #[arg(long, value_name = "KEY_FILE")]
+    entry_key_file: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--encrypt`, using the age identities in this
+    /// file.
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    identity: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--passphrase`. The passphrase is read from the
+    /// `PTAR_PASSPHRASE` environment variable if set, or else prompted for.
+    #[arg(long, visible_alias = "passphrase-prompt")]
+    passphrase: bool,
+
+    /// After extracting each increment, delete the files it records as deleted since
+    /// the previous one, so the result matches the tree at the last increment.
+    #[arg(long)]
+    delete: bool,
+}
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    // Check every snapshot up front, rather than failing after extracting some.
+    for dir in cmd_args.snapshots.iter() {
+        let entries = manifest::read(dir)
+            .with_context(|| format!("reading the manifest of snapshot '{}'", dir.display()))?;
+        tracing::debug!(snapshot = %dir.display(), entry_count = entries.len(),
+                        "Read snapshot manifest");
+    }
+    ensure!(!cmd_args.snapshots.contains(&cmd_args.out_dir),
+            "--out-dir can't be one of the snapshots");
+
+    let passphrase = cmd_args.passphrase.then(|| shard_encryption::read_passphrase(false))
+                                        .transpose()?;
+
+    let cancel = CancellationToken::new();
+    cancel.cancel_on_signals()?;
+
+    // Extracting in order means files in later snapshots replace earlier versions.
+    for (i, dir) in cmd_args.snapshots.iter().enumerate() {
+        tracing::info!(snapshot = %dir.display(), number = i + 1,
+                       count = cmd_args.snapshots.len(), "Restoring snapshot");
+        let mut options = DecompressOptions::new(dir, &cmd_args.out_dir)
+            .threads(args.threads())
+            .unpack_args(cmd_args.unpack.clone())
+            .special_files(cmd_args.special_files)
+            .error_policy(if cmd_args.keep_going {
+                              ErrorPolicy::KeepGoing
+                          } else {
+                              cmd_args.error_policy
+                          })
+            .cancellation(cancel.clone());
+        if let Some(ref key_file) = cmd_args.entry_key_file {
+            options = options.entry_key_file(key_file);
+        }
+        if let Some(ref identity_file) = cmd_args.identity {
+            options = options.identity(identity_file);
+        }
+        if let Some(ref passphrase) = passphrase {
+            options = options.passphrase(passphrase.clone());
+        }
+        let report = options.run()
+            .with_context(|| format!("restoring snapshot '{}'", dir.display()))?;
+        let deleted_count = if cmd_args.delete {
+            // After the first snapshot has created `out_dir`.
+            let unpack = UnpackOptions::new(&cmd_args.out_dir, &cmd_args.unpack)?;
+            delete_files(&unpack, &incremental::read_deletions(dir)?)?
+        } else {
+            0
+        };
+        tracing::info!(snapshot = %dir.display(), archive_count = report.archive_count,
+                       uncompressed_bytes = report.uncompressed_bytes, deleted_count,
+                       "Restored snapshot");
+    }
+    Ok(())
+}
+
+/// Delete `paths` where they were extracted if they exist, returning how many did.
+fn delete_files(unpack: &UnpackOptions, paths: &[PathBuf]) -> Result<usize> {
+    let mut count = 0;
+    for path in paths {
+        // Paths were checked to be relative with no `..` when they were read.
+        let Some(dst) = unpack.paths.dst(path) else {
+            continue;
+        };
+        match fs::remove_file(dst) {
+            Ok(()) => count += 1,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err)
+                .with_context(|| format!("deleting '{}'", path.display())),
+        }
+    }
+    Ok(count)
+}
@@ -0,0 +1,228 @@
+//! Advisory locks on an archive set's directory, so a compress writing to it doesn't run
+//! alongside another compress, interleaving archive numbers, or a decompress reading it.
+//!
+//! The lock is a `flock` on `LOCK_FILE_NAME` in the directory, released when the
+//! process exits, however it exits. Compress takes it exclusively and decompress shared,
+//! so several decompresses can read a set at once.
+//!
+//! A compress also writes its pid and host to the lock file, to name it to runs it
+//! turns away, and clears them when it ends. Finding them when taking the lock means the
+//! run that wrote them may have crashed. If it ran on this host and its pid is gone, it
+//! certainly did, so its leftover files are garbage; otherwise, e.g. with the directory
+//! on a network filesystem shared by several hosts, it's only logged.
+
+use anyhow::{bail, Context};
+use crate::Result;
+use std::{
+    fs::{File, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::Path,
+    process,
+};
+
+pub const LOCK_FILE_NAME: &str = "ptar.lock";
+
+/// A held lock, released when dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    file: File,
+    /// Whether this run's owner is written in the file, to clear when it ends.
+    exclusive: bool,
+    /// Whether the lock was last held by a run on this host that's no longer running.
+    crashed: bool,
+}
+
+/// The run that wrote a lock file.
+#[derive(Debug, Eq, PartialEq)]
+struct Owner {
+    pid: u32,
+    host: String,
+}
+
+impl RunLock {
+    /// Lock `dir` for writing, failing at once if another run holds its lock.
+    pub fn exclusive(dir: &Path) -> Result<RunLock> {
+        let mut file = open(dir)
+            .with_context(|| format!("creating the lock file in '{}'", dir.display()))?;
+        match file.try_lock() {
+            Ok(()) => {
+                let crashed = match read_owner(&mut file) {
+                    Some(owner) if owner.has_crashed() => {
+                        tracing::warn!(dir = %dir.display(), pid = owner.pid,
+                                       host = owner.host,
+                                       "Taking over the lock of a run that crashed");
+                        true
+                    },
+                    Some(owner) => {
+                        tracing::warn!(dir = %dir.display(), pid = owner.pid,
+                                       host = owner.host,
+                                       "Taking over the lock of a run that may not have \
+                                        crashed, so keeping its files");
+                        false
+                    },
+                    None => false,
+                };
+                write_owner(&mut file, Some(&Owner::this_run()))
+                    .with_context(|| format!("writing the lock file in '{}'", dir.display()))?;
+                Ok(RunLock { file, exclusive: true, crashed })
+            },
+            Err(TryLockError::WouldBlock) => {
+                bail!("Another ptar run{} is using '{}'; wait for it to finish",
+                      owner_suffix(&mut file), dir.display())
+            },
+            Err(TryLockError::Error(err)) => Err(err)
+                .with_context(|| format!("locking '{}'", dir.display())),
+        }
+    }
+
+    /// Lock `dir` for reading, failing at once if a run writing to it holds its lock.
+    /// Returns None if the lock file can't be created, e.g. on read-only media, where
+    /// nothing can be writing.
+    pub fn shared(dir: &Path) -> Result<Option<RunLock>> {
+        let mut file = match open(dir) {
+            Ok(file) => file,
+            Err(err) if is_read_only(&err) => {
+                tracing::debug!(dir = %dir.display(), %err,
+                                "Can't create the lock file, so reading without a lock");
+                return Ok(None);
+            },
+            Err(err) => return Err(err)
+                .with_context(|| format!("creating the lock file in '{}'", dir.display())),
+        };
+        match file.try_lock_shared() {
+            Ok(()) => Ok(Some(RunLock { file, exclusive: false, crashed: false })),
+            Err(TryLockError::WouldBlock) => {
+                bail!("A ptar compress{} is writing to '{}'; wait for it to finish",
+                      owner_suffix(&mut file), dir.display())
+            },
+            Err(TryLockError::Error(err)) => Err(err)
+                .with_context(|| format!("locking '{}'", dir.display())),
+        }
+    }
+}
+
+impl RunLock {
+    /// Whether the run that held the lock before this one crashed, as confirmed by its
+    /// pid and host, so the files it was writing can be removed.
+    pub fn follows_crash(&self) -> bool {
+        self.crashed
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if self.exclusive {
+            if let Err(err) = write_owner(&mut self.file, None) {
+                tracing::warn!(%err, "Error clearing the lock file");
+            }
+        }
+    }
+}
+
+impl Owner {
+    fn this_run() -> Owner {
+        Owner { pid: process::id(), host: host_name() }
+    }
+
+    /// Whether the run is known to have ended: it ran on this host and its pid is gone.
+    /// Runs on other hosts can't be checked.
+    fn has_crashed(&self) -> bool {
+        let Ok(pid) = libc::pid_t::try_from(self.pid) else {
+            return false;
+        };
+        if self.host != host_name() || pid <= 0 {
+            return false;
+        }
+        // SAFETY: signal 0 only checks the process exists.
+        let res = unsafe { libc::kill(pid, 0) };
+        res != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+    }
+}
+
+/// The owner written in a lock file, if any.
+fn read_owner(file: &mut File) -> Option<Owner> {
+    let mut s = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut s).ok()?;
+    let (pid, host) = s.trim_end().split_once(' ')?;
+    Some(Owner { pid: pid.parse().ok()?, host: host.to_string() })
+}
+
+fn write_owner(file: &mut File, owner: Option<&Owner>) -> io::Result<()> {
+    file.set_len(0)?;
+    if let Some(owner) = owner {
+        file.rewind()?;
+        writeln!(file, "{} {}", owner.pid, owner.host)?;
+    }
+    Ok(())
+}
+
+/// The owner of a held lock, to name in an error, or nothing if it's unknown.
+fn owner_suffix(file: &mut File) -> String {
+    read_owner(file).map(|o| format!(" (pid {} on {})", o.pid, o.host)).unwrap_or_default()
+}
+
+pub(crate) fn host_name() -> String {
+    let mut buf = [0_u8; 256];
+    // SAFETY: buf is buf.len() bytes long.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn open(dir: &Path) -> io::Result<File> {
+    File::options().read(true).write(true).create(true).truncate(false)
+                   .open(dir.join(LOCK_FILE_NAME))
+}
+
+fn is_read_only(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::PermissionDenied
+    || err.kind() == io::ErrorKind::ReadOnlyFilesystem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::TempDir;
+
+    #[test]
+    fn excludes_other_runs() {
+        let dir = TempDir::new("ptar-test").unwrap();
+        let lock = RunLock::exclusive(dir.path()).unwrap();
+        let err = RunLock::exclusive(dir.path()).unwrap_err().to_string();
+        assert!(err.contains(&format!("(pid {} on ", process::id())), "{err}");
+        assert!(RunLock::shared(dir.path()).is_err());
+        drop(lock);
+
+        let reader = RunLock::shared(dir.path()).unwrap();
+        assert!(RunLock::shared(dir.path()).unwrap().is_some());
+        assert!(RunLock::exclusive(dir.path()).is_err());
+        drop(reader);
+        RunLock::exclusive(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn records_owner() {
+        let dir = TempDir::new("ptar-test").unwrap();
+        let path = dir.path().join(LOCK_FILE_NAME);
+        // Left by a crashed run.
+        std::fs::write(&path, "4000000000 elsewhere\n").unwrap();
+
+        let lock = RunLock::exclusive(dir.path()).unwrap();
+        // Maybe still running on the other host.
+        assert!(!lock.follows_crash());
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(read_owner(&mut file), Some(Owner::this_run()));
+        drop(lock);
+        assert_eq!(read_owner(&mut file), None);
+
+        let mut child = process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        std::fs::write(&path, format!("{} {}\n", child.id(), host_name())).unwrap();
+        assert!(RunLock::exclusive(dir.path()).unwrap().follows_crash());
+        std::fs::write(&path, format!("{} {}\n", process::id(), host_name())).unwrap();
+        assert!(!RunLock::exclusive(dir.path()).unwrap().follows_crash());
+    }
+}
@@ -0,0 +1,633 @@
+//! SFTP stores, e.g. `sftp://user@host/path`, reached by running `ssh` with the `sftp`
+//! subsystem, as the `sftp` command does, so the user's ssh config, keys and agent
+//! apply, and servers that only allow SFTP work too.
+//!
+//! Speaks version 3 of the protocol
+//! (<https://datatracker.ietf.org/doc/html/draft-ietf-secsh-filexfer-02>), which OpenSSH
+//! implements. All the shard writers share one connection; each file's reads and writes
+//! are pipelined, with many requests in flight, so throughput doesn't wait on latency.
+
+use anyhow::{anyhow, bail, ensure, Context};
+use crate::{
+    Result, archive_set,
+    remote::{RemoteArgs, Store, StoredFile, Upload},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+pub struct SftpStore {
+    session: Arc<Session>,
+    /// Directory of the archive set's files, relative to the login directory unless
+    /// it starts with `/`.
+    dir: String,
+    /// Set once `dir` has been created, by the first upload.
+    dir_created: once_cell::sync::OnceCell<()>,
+}
+
+/// A connection to the SFTP server.
+struct Session {
+    child: Mutex<Child>,
+    stdin: Mutex<ChildStdin>,
+    next_id: AtomicU32,
+    /// Receives the response to each request in flight, by request ID.
+    pending: Arc<Mutex<HashMap<u32, crossbeam_channel::Sender<Packet>>>>,
+}
+
+/// A response from the server.
+struct Packet {
+    kind: u8,
+    body: Vec<u8>,
+}
+
+struct SftpUpload {
+    session: Arc<Session>,
+    path: String,
+    partial_path: String,
+    handle: Vec<u8>,
+    offset: u64,
+    buf: Vec<u8>,
+    /// Replies to the writes in flight.
+    writes: VecDeque<crossbeam_channel::Receiver<Packet>>,
+}
+
+struct SftpReader {
+    session: Arc<Session>,
+    path: String,
+    handle: Vec<u8>,
+    /// Offset of the next read to request.
+    next_offset: u64,
+    /// Reads in flight, in offset order: offset, length and reply.
+    reads: VecDeque<(u64, u32, crossbeam_channel::Receiver<Packet>)>,
+    data: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x02;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x08;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x80000000;
+
+/// Largest read or write in one request that every server accepts.
+const CHUNK_LEN: usize = 32 * 1024;
+/// Reads or writes in flight for each file.
+const MAX_IN_FLIGHT: usize = 64;
+/// Largest packet accepted from the server, as OpenSSH's limit: larger lengths are
+/// a corrupt stream rather than anything worth allocating for.
+const MAX_PACKET_LEN: usize = 256 * 1024;
+
+impl SftpStore {
+    /// Connect to `location`, an SFTP URL without the `sftp://`, e.g.
+    /// `user@host:2222/path`. Paths are absolute, except under `/~/`, which is the login
+    /// directory.
+    pub fn new(location: &str, args: &RemoteArgs) -> Result<SftpStore> {
+        let (authority, path) = location.split_once('/').unwrap_or((location, ""));
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (Some(user), host_port),
+            None => (None, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (host_port, None),
+        };
+        ensure!(!host.is_empty(), "No host in the SFTP URL 'sftp://{location}'");
+        let dir = match path.strip_prefix('~') {
+            Some(rest) => rest.trim_matches('/').to_string(),
+            None => format!("/{}", path.trim_end_matches('/')),
+        };
+        let dir = if dir.is_empty() { ".".to_string() } else { dir };
+
+        let mut ssh_command = args.ssh_command.split_whitespace();
+        let mut command = Command::new(ssh_command.next().context("--ssh-command is empty")?);
+        command.args(ssh_command);
+        if let Some(port) = port {
+            command.args(["-p", port]);
+        }
+        if let Some(user) = user {
+            command.args(["-l", user]);
+        }
+        command.args(["-s", host, "sftp"]);
+        tracing::info!(host, dir, ?command, "Connecting to SFTP store");
+        let session = Session::connect(command)
+            .with_context(|| format!("connecting to sftp://{location}"))?;
+        Ok(SftpStore {
+            session: Arc::new(session),
+            dir,
+            dir_created: once_cell::sync::OnceCell::new(),
+        })
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("{}/{name}", self.dir)
+    }
+
+    /// Create `dir` and its parents if they don't exist.
+    fn create_dir(&self) -> Result<()> {
+        let mut path = String::new();
+        for (i, component) in self.dir.split('/').enumerate() {
+            if i > 0 {
+                path.push('/');
+            }
+            path.push_str(component);
+            if component.is_empty() || component == "." {
+                continue;
+            }
+            match self.session.call(SSH_FXP_STAT, &string_field(&path)) {
+                Ok(packet) if packet.kind == SSH_FXP_ATTRS => continue,
+                _ => {
+                    let mut body = string_field(&path);
+                    body.extend(0_u32.to_be_bytes()); // No attributes.
+                    self.session.call_status(SSH_FXP_MKDIR, &body)
+                        .with_context(|| format!("creating directory '{path}'"))?;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Store for SftpStore {
+    fn create(&self, name: &str) -> Result<Box<dyn Upload>> {
+        self.dir_created.get_or_try_init(|| self.create_dir())?;
+        let path = self.path(name);
+        let partial_path = format!("{path}{}", archive_set::PARTIAL_SUFFIX);
+        let handle = self.session.open(&partial_path,
+                                       SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC)?;
+        Ok(Box::new(SftpUpload {
+            session: self.session.clone(),
+            path,
+            partial_path,
+            handle,
+            offset: 0,
+            buf: Vec::with_capacity(CHUNK_LEN),
+            writes: VecDeque::new(),
+        }))
+    }
+
+    fn list(&self) -> Result<Vec<StoredFile>> {
+        let handle = self.session.handle(SSH_FXP_OPENDIR, &string_field(&self.dir))
+            .with_context(|| format!("listing '{}'", self.dir))?;
+        let mut files = Vec::new();
+        loop {
+            let packet = self.session.call(SSH_FXP_READDIR, &bytes_field(&handle))?;
+            if packet.kind == SSH_FXP_STATUS {
+                let (code, message) = Fields(&packet.body).status()?;
+                if code == SSH_FX_EOF {
+                    break;
+                }
+                bail!("listing '{}': {message}", self.dir);
+            }
+            ensure!(packet.kind == SSH_FXP_NAME, "Unexpected SFTP response {}", packet.kind);
+            let mut fields = Fields(&packet.body);
+            for _ in 0..fields.u32()? {
+                let name = String::from_utf8_lossy(fields.bytes()?).into_owned();
+                fields.bytes()?; // The `ls -l` style long name.
+                let attrs = fields.attrs()?;
+                // Regular files only.
+                if attrs.permissions.is_some_and(|mode| mode & 0o170000 != 0o100000)
+                   || name.ends_with(archive_set::PARTIAL_SUFFIX) {
+                    continue;
+                }
+                files.push(StoredFile {
+                    name,
+                    size: attrs.size.unwrap_or(0),
+                    modified: SystemTime::UNIX_EPOCH
+                        + Duration::from_secs(attrs.mtime.unwrap_or(0).into()),
+                });
+            }
+        }
+        self.session.close(&handle);
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn Read + Send>> {
+        let path = self.path(name);
+        let handle = self.session.open(&path, SSH_FXF_READ)?;
+        Ok(Box::new(SftpReader {
+            session: self.session.clone(),
+            path,
+            handle,
+            next_offset: 0,
+            reads: VecDeque::new(),
+            data: Vec::new(),
+            pos: 0,
+            eof: false,
+        }))
+    }
+}
+
+impl Session {
+    /// Start `command`, which runs the `sftp` subsystem over ssh, and negotiate the
+    /// protocol version.
+    fn connect(mut command: Command) -> Result<Session> {
+        // stderr is inherited, for ssh's errors and prompts.
+        let mut child = command.stdin(Stdio::piped())
+                               .stdout(Stdio::piped())
+                               .spawn()
+                               .with_context(|| format!("running {command:?}"))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+
+        let mut init = Vec::new();
+        init.extend(5_u32.to_be_bytes());
+        init.push(SSH_FXP_INIT);
+        init.extend(3_u32.to_be_bytes());
+        stdin.write_all(&init)?;
+        stdin.flush()?;
+        let (kind, body) = read_frame(&mut stdout)
+            .context("reading the SFTP version; is the server reachable?")?;
+        ensure!(kind == SSH_FXP_VERSION && Fields(&body).u32()? >= 3,
+                "The server doesn't speak SFTP version 3");
+
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let reader_pending: Arc<Mutex<HashMap<u32, crossbeam_channel::Sender<Packet>>>> =
+            pending.clone();
+        thread::Builder::new()
+            .name("sftp-reader".to_string())
+            .spawn(move || {
+                while let Ok((kind, body)) = read_frame(&mut stdout) {
+                    let Some(id) = body.get(..4) else {
+                        break;
+                    };
+                    let id = u32::from_be_bytes(id.try_into().expect("4 bytes"));
+                    let reply = reader_pending.lock().expect("pending lock").remove(&id);
+                    if let Some(reply) = reply {
+                        let _ = reply.send(Packet { kind, body: body[4..].to_vec() });
+                    }
+                }
+                // Dropping the senders fails every request still waiting.
+                reader_pending.lock().expect("pending lock").clear();
+            })?;
+
+        Ok(Session {
+            child: Mutex::new(child),
+            stdin: Mutex::new(stdin),
+            next_id: AtomicU32::new(0),
+            pending,
+        })
+    }
+
+    /// Send a request, returning a receiver of its response.
+    fn send(&self, kind: u8, body: &[u8]) -> Result<crossbeam_channel::Receiver<Packet>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.pending.lock().expect("pending lock").insert(id, tx);
+        let mut frame = Vec::with_capacity(9 + body.len());
+        frame.extend((5 + body.len() as u32).to_be_bytes());
+        frame.push(kind);
+        frame.extend(id.to_be_bytes());
+        frame.extend(body);
+        let mut stdin = self.stdin.lock().expect("stdin lock");
+        stdin.write_all(&frame).and_then(|()| stdin.flush())
+             .context("SFTP connection closed")?;
+        Ok(rx)
+    }
+
+    fn call(&self, kind: u8, body: &[u8]) -> Result<Packet> {
+        receive(&self.send(kind, body)?)
+    }
+
+    /// Make a request whose response is a status, failing unless it's OK.
+    fn call_status(&self, kind: u8, body: &[u8]) -> Result<()> {
+        check_status(self.call(kind, body)?)
+    }
+
+    /// Make a request whose response is a handle.
+    fn handle(&self, kind: u8, body: &[u8]) -> Result<Vec<u8>> {
+        let packet = self.call(kind, body)?;
+        if packet.kind == SSH_FXP_STATUS {
+            bail!("{}", Fields(&packet.body).status()?.1);
+        }
+        ensure!(packet.kind == SSH_FXP_HANDLE, "Unexpected SFTP response {}", packet.kind);
+        Ok(Fields(&packet.body).bytes()?.to_vec())
+    }
+
+    fn open(&self, path: &str, flags: u32) -> Result<Vec<u8>> {
+        let mut body = string_field(path);
+        body.extend(flags.to_be_bytes());
+        body.extend(0_u32.to_be_bytes()); // No attributes.
+        self.handle(SSH_FXP_OPEN, &body).with_context(|| format!("opening '{path}'"))
+    }
+
+    /// Close `handle`, without waiting for the response.
+    fn close(&self, handle: &[u8]) {
+        let _ = self.send(SSH_FXP_CLOSE, &bytes_field(handle));
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Every file is closed by now, so there's nothing to lose.
+        let mut child = self.child.lock().expect("child lock");
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+impl SftpUpload {
+    /// Send the buffered data as a write, first waiting for the oldest write if there
+    /// are too many in flight.
+    fn send_write(&mut self) -> Result<()> {
+        if self.writes.len() >= MAX_IN_FLIGHT {
+            self.wait_for_write()?;
+        }
+        let mut body = bytes_field(&self.handle);
+        body.extend(self.offset.to_be_bytes());
+        body.extend(bytes_field(&self.buf));
+        self.writes.push_back(self.session.send(SSH_FXP_WRITE, &body)?);
+        self.offset += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    fn wait_for_write(&mut self) -> Result<()> {
+        let reply = self.writes.pop_front().expect("a write is in flight");
+        check_status(receive(&reply)?)
+            .with_context(|| format!("writing '{}'", self.partial_path))
+    }
+
+    /// Send any buffered data, wait for every write, and close the file.
+    fn flush_and_close(&mut self) -> Result<()> {
+        if !self.buf.is_empty() {
+            self.send_write()?;
+        }
+        while !self.writes.is_empty() {
+            self.wait_for_write()?;
+        }
+        self.session.call_status(SSH_FXP_CLOSE, &bytes_field(&self.handle))
+            .with_context(|| format!("closing '{}'", self.partial_path))
+    }
+}
+
+impl Upload for SftpUpload {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush_and_close()?;
+        // Version 3 renames fail if the target exists, e.g. from an earlier run.
+        let _ = self.session.call(SSH_FXP_REMOVE, &string_field(&self.path));
+        let mut body = string_field(&self.partial_path);
+        body.extend(string_field(&self.path));
+        self.session.call_status(SSH_FXP_RENAME, &body)
+            .with_context(|| format!("renaming '{}' to '{}'", self.partial_path, self.path))
+    }
+
+    fn abort(mut self: Box<Self>) -> Result<()> {
+        let _ = self.flush_and_close();
+        self.session.call_status(SSH_FXP_REMOVE, &string_field(&self.partial_path))
+            .with_context(|| format!("deleting '{}'", self.partial_path))
+    }
+}
+
+impl Write for SftpUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_LEN - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        if self.buf.len() == CHUNK_LEN {
+            self.send_write().map_err(io::Error::other)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SftpReader {
+    fn request(&mut self, offset: u64, len: u32)
+               -> Result<(u64, u32, crossbeam_channel::Receiver<Packet>)> {
+        let mut body = bytes_field(&self.handle);
+        body.extend(offset.to_be_bytes());
+        body.extend(len.to_be_bytes());
+        Ok((offset, len, self.session.send(SSH_FXP_READ, &body)?))
+    }
+
+    /// Read the next chunk into `data`, or set `eof`.
+    fn next_chunk(&mut self) -> Result<()> {
+        while !self.eof && self.reads.len() < MAX_IN_FLIGHT {
+            let read = self.request(self.next_offset, CHUNK_LEN as u32)?;
+            self.reads.push_back(read);
+            self.next_offset += CHUNK_LEN as u64;
+        }
+        let Some((offset, len, reply)) = self.reads.pop_front() else {
+            return Ok(());
+        };
+        let packet = receive(&reply)?;
+        match packet.kind {
+            SSH_FXP_DATA => {
+                self.data = Fields(&packet.body).bytes()?.to_vec();
+                self.pos = 0;
+                let got = self.data.len() as u32;
+                if got < len {
+                    // A short read: request the rest before the reads already in flight.
+                    let read = self.request(offset + got as u64, len - got)?;
+                    self.reads.push_front(read);
+                }
+                Ok(())
+            },
+            SSH_FXP_STATUS => {
+                let (code, message) = Fields(&packet.body).status()?;
+                ensure!(code == SSH_FX_EOF, "reading '{}': {message}", self.path);
+                // Later reads are past the end too.
+                self.eof = true;
+                self.reads.clear();
+                Ok(())
+            },
+            kind => bail!("Unexpected SFTP response {kind}"),
+        }
+    }
+}
+
+impl Read for SftpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.data.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.next_chunk().map_err(io::Error::other)?;
+        }
+        let len = buf.len().min(self.data.len() - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Drop for SftpReader {
+    fn drop(&mut self) {
+        self.session.close(&self.handle);
+    }
+}
+
+/// Read one packet: its type and the rest of its body.
+fn read_frame(r: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty SFTP packet"));
+    }
+    if len > MAX_PACKET_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("SFTP packet of {len} bytes is too large")));
+    }
+    let mut frame = vec![0; len];
+    r.read_exact(&mut frame)?;
+    Ok((frame[0], frame.split_off(1)))
+}
+
+fn receive(reply: &crossbeam_channel::Receiver<Packet>) -> Result<Packet> {
+    reply.recv().map_err(|_| anyhow!("SFTP connection closed"))
+}
+
+fn check_status(packet: Packet) -> Result<()> {
+    ensure!(packet.kind == SSH_FXP_STATUS, "Unexpected SFTP response {}", packet.kind);
+    let (code, message) = Fields(&packet.body).status()?;
+    match code {
+        SSH_FX_OK => Ok(()),
+        SSH_FX_NO_SUCH_FILE => bail!("No such file: {message}"),
+        _ => bail!("{message}"),
+    }
+}
+
+fn bytes_field(bytes: &[u8]) -> Vec<u8> {
+    let mut field = Vec::with_capacity(4 + bytes.len());
+    field.extend((bytes.len() as u32).to_be_bytes());
+    field.extend(bytes);
+    field
+}
+
+fn string_field(s: &str) -> Vec<u8> {
+    bytes_field(s.as_bytes())
+}
+
+/// Parses the fields of a packet body in order.
+struct Fields<'a>(&'a [u8]);
+
+#[derive(Debug, Default)]
+struct Attrs {
+    size: Option<u64>,
+    permissions: Option<u32>,
+    mtime: Option<u32>,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(self.0.len() >= len, "Truncated SFTP packet");
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().expect("8 bytes")))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn attrs(&mut self) -> Result<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            self.take(8)?;
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            self.u32()?; // atime
+            attrs.mtime = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(attrs)
+    }
+
+    /// A status response's code and message.
+    fn status(&mut self) -> Result<(u32, String)> {
+        let code = self.u32()?;
+        // Some servers omit the message.
+        let message = self.bytes().map(|m| String::from_utf8_lossy(m).into_owned())
+                          .unwrap_or_default();
+        Ok((code, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let mut body = string_field("a.tar");
+        body.extend(SSH_FILEXFER_ATTR_SIZE.to_be_bytes());
+        body.extend(7_u64.to_be_bytes());
+        let mut fields = Fields(&body);
+        assert_eq!(fields.bytes().unwrap(), b"a.tar");
+        let attrs = fields.attrs().unwrap();
+        assert_eq!((attrs.size, attrs.permissions), (Some(7), None));
+        assert!(fields.u32().is_err());
+    }
+
+    #[test]
+    fn frames() {
+        let frame = |len: u32, body: &[u8]| [&len.to_be_bytes()[..], body].concat();
+        let (kind, body) = read_frame(&mut &frame(3, &[SSH_FXP_DATA, 1, 2])[..]).unwrap();
+        assert_eq!((kind, body), (SSH_FXP_DATA, vec![1, 2]));
+        for len in [0, u32::MAX] {
+            let err = read_frame(&mut &frame(len, &[SSH_FXP_DATA])[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{len}");
+        }
+    }
+}
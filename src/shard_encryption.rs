@@ -0,0 +1,249 @@
+//! Encrypting whole archives with age (<https://age-encryption.org>), so an archive set
+//! can be kept on untrusted storage. Archives are compressed, then encrypted, and
+//! named `<archive>.age`.
+//!
+//! Archives are encrypted either to public recipients, or with a passphrase, from which
+//! age derives each archive's key with scrypt. Identities, the recipients' secret keys,
+//! are read from files, and passphrases from the environment or a terminal prompt, so
+//! neither is in the command line arguments, which are logged.
+
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{anyhow, bail, ensure, Context};
+use crate::Result;
+use std::{
+    env,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+/// How to encrypt archives.
+#[derive(Clone)]
+pub enum Encryption {
+    /// To public keys. Any of the matching identities can decrypt them.
+    Recipients(Vec<age::x25519::Recipient>),
+    /// With a passphrase. Deriving the key takes about a second for each archive.
+    Passphrase(SecretString),
+}
+
+/// Keys to try when decrypting archives.
+#[derive(Default)]
+pub struct DecryptionKeys {
+    identities: Vec<age::x25519::Identity>,
+    passphrase: Option<SecretString>,
+}
+
+/// Writes its input to the inner writer, encrypted if there are recipients.
+pub enum EncryptingWriter<W: Write> {
+    Plain(W),
+    Age(age::stream::StreamWriter<W>),
+}
+
+/// Environment variable to read the passphrase from instead of prompting for it.
+pub const PASSPHRASE_ENV_VAR: &str = "PTAR_PASSPHRASE";
+
+/// Start of every age encrypted file.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+/// File name extension appended to encrypted archives' names.
+pub const EXTENSION: &str = "age";
+
+impl Encryption {
+    /// Parse `--encrypt` specs of the form `age:<recipient>`, e.g.
+    /// `age:age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p`.
+    pub fn parse_recipients(specs: &[String]) -> Result<Encryption> {
+        let mut recipients = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let Some(recipient) = spec.strip_prefix("age:") else {
+                bail!("Encryption '{spec}' isn't supported; expected 'age:<RECIPIENT>'");
+            };
+            recipients.push(recipient.parse::<age::x25519::Recipient>()
+                .map_err(|err| anyhow!("Invalid age recipient '{recipient}': {err}"))?);
+        }
+        ensure!(!recipients.is_empty(), "No recipients to encrypt to");
+        Ok(Encryption::Recipients(recipients))
+    }
+
+    /// Wrap `inner` in a writer that encrypts its input.
+    pub fn writer<W: Write>(&self, inner: W) -> Result<EncryptingWriter<W>> {
+        let encryptor = match self {
+            Encryption::Recipients(recipients) => {
+                let recipients = recipients.iter()
+                    .map(|r| Box::new(r.clone()) as Box<dyn age::Recipient + Send>)
+                    .collect::<Vec<_>>();
+                age::Encryptor::with_recipients(recipients)
+                    .expect("parsed at least 1 recipient")
+            },
+            Encryption::Passphrase(passphrase) => {
+                age::Encryptor::with_user_passphrase(passphrase.clone())
+            },
+        };
+        Ok(EncryptingWriter::Age(encryptor.wrap_output(inner)?))
+    }
+}
+
+impl DecryptionKeys {
+    /// Read identities from `identity_file`, an age identity file as written by
+    /// `age-keygen`, and try `passphrase` too. None if there are neither.
+    pub fn new(identity_file: Option<&Path>, passphrase: Option<SecretString>)
+    -> Result<Option<DecryptionKeys>>
+    {
+        let mut keys = DecryptionKeys { passphrase, ..DecryptionKeys::default() };
+        if let Some(path) = identity_file {
+            let file = age::IdentityFile::from_file(path.to_string_lossy().into_owned())
+                .with_context(|| format!("reading identity file '{}'", path.display()))?;
+            keys.identities = file.into_identities()
+                                  .into_iter()
+                                  .map(|entry| match entry {
+                                      age::IdentityFileEntry::Native(identity) => identity,
+                                  })
+                                  .collect();
+            ensure!(!keys.identities.is_empty(),
+                    "Identity file '{}' has no identities", path.display());
+        }
+        Ok((!keys.identities.is_empty() || keys.passphrase.is_some()).then_some(keys))
+    }
+
+    /// Decrypt the age encrypted stream `inner`.
+    pub fn decrypt<R: Read>(&self, inner: R) -> Result<age::stream::StreamReader<R>> {
+        Ok(match age::Decryptor::new(inner)? {
+            age::Decryptor::Recipients(decryptor) => {
+                ensure!(!self.identities.is_empty(),
+                        "Archive is encrypted to recipients; pass --identity to decrypt it");
+                decryptor.decrypt(self.identities.iter().map(|i| i as &dyn age::Identity))?
+            },
+            age::Decryptor::Passphrase(decryptor) => {
+                let Some(ref passphrase) = self.passphrase else {
+                    bail!("Archive is encrypted with a passphrase; pass --passphrase to \
+                           decrypt it");
+                };
+                decryptor.decrypt(passphrase, None)?
+            },
+        })
+    }
+}
+
+/// Read the passphrase from `PTAR_PASSPHRASE` if it's set, or else prompt for it on the
+/// terminal, twice if `confirm`.
+pub fn read_passphrase(confirm: bool) -> Result<SecretString> {
+    let passphrase = match env::var_os(PASSPHRASE_ENV_VAR) {
+        Some(passphrase) => passphrase.into_string()
+            .map_err(|_| anyhow!("{PASSPHRASE_ENV_VAR} is not valid UTF-8"))?,
+        None => {
+            let prompt = |prompt| rpassword::prompt_password(prompt).with_context(|| {
+                format!("prompting for the passphrase; without a terminal, set \
+                         {PASSPHRASE_ENV_VAR}")
+            });
+            let passphrase = prompt("Passphrase: ")?;
+            if confirm {
+                ensure!(prompt("Confirm passphrase: ")? == passphrase,
+                        "Passphrases don't match");
+            }
+            passphrase
+        },
+    };
+    let passphrase = SecretString::new(passphrase);
+    ensure!(!passphrase.expose_secret().is_empty(), "The passphrase is empty");
+    Ok(passphrase)
+}
+
+/// Whether a file starting with `header` is age encrypted.
+pub fn is_encrypted(header: &[u8]) -> bool {
+    header.starts_with(AGE_MAGIC)
+}
+
+/// Whether `err` is from trying the wrong identities or passphrase, rather than from a
+/// damaged archive.
+pub fn is_wrong_key(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<age::DecryptError>(),
+             Some(age::DecryptError::NoMatchingKeys | age::DecryptError::DecryptionFailed))
+}
+
+/// Peek at the start of `inner`, returning whether it's age encrypted and a reader of
+/// the whole stream.
+pub fn sniff<R: Read>(mut inner: R) -> io::Result<(bool, impl Read)> {
+    let mut header = Vec::with_capacity(AGE_MAGIC.len());
+    (&mut inner).take(AGE_MAGIC.len() as u64).read_to_end(&mut header)?;
+    Ok((is_encrypted(&header), io::Cursor::new(header).chain(inner)))
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Finish the encrypted stream and return the inner writer.
+    pub fn finish(self) -> Result<W> {
+        Ok(match self {
+            EncryptingWriter::Plain(w) => w,
+            EncryptingWriter::Age(w) => w.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            EncryptingWriter::Plain(w) => w.write(buf),
+            EncryptingWriter::Age(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            EncryptingWriter::Plain(w) => w.flush(),
+            EncryptingWriter::Age(w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(encryption: &Encryption) -> Vec<u8> {
+        let mut w = encryption.writer(Vec::new()).unwrap();
+        w.write_all(b"secret archive").unwrap();
+        let encrypted = w.finish().unwrap();
+        assert!(is_encrypted(&encrypted));
+        encrypted
+    }
+
+    fn decrypt(keys: &DecryptionKeys, encrypted: &[u8]) -> Result<Vec<u8>> {
+        let (is_age, reader) = sniff(encrypted)?;
+        assert!(is_age);
+        let mut decrypted = Vec::new();
+        keys.decrypt(reader)?.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn recipients() {
+        let identity = age::x25519::Identity::generate();
+        let spec = format!("age:{}", identity.to_public());
+        let encrypted = encrypt(&Encryption::parse_recipients(&[spec]).unwrap());
+
+        let keys = DecryptionKeys { identities: vec![identity], passphrase: None };
+        assert_eq!(decrypt(&keys, &encrypted).unwrap(), b"secret archive");
+
+        let other = DecryptionKeys {
+            identities: vec![age::x25519::Identity::generate()],
+            passphrase: None,
+        };
+        assert!(is_wrong_key(&decrypt(&other, &encrypted).unwrap_err()));
+        assert!(decrypt(&DecryptionKeys::default(), &encrypted).is_err());
+
+        assert!(Encryption::parse_recipients(&["aes:key".to_string()]).is_err());
+        assert!(Encryption::parse_recipients(&["age:nonsense".to_string()]).is_err());
+    }
+
+    #[test]
+    fn passphrase() {
+        let passphrase = || SecretString::new("correct horse".to_string());
+        let encrypted = encrypt(&Encryption::Passphrase(passphrase()));
+
+        let keys = DecryptionKeys { identities: Vec::new(), passphrase: Some(passphrase()) };
+        assert_eq!(decrypt(&keys, &encrypted).unwrap(), b"secret archive");
+
+        let wrong = DecryptionKeys {
+            identities: Vec::new(),
+            passphrase: Some(SecretString::new("battery staple".to_string())),
+        };
+        assert!(is_wrong_key(&decrypt(&wrong, &encrypted).unwrap_err()));
+    }
+}
@@ -0,0 +1,69 @@
+//! Parsing human-readable byte sizes from the command line.
+
+use anyhow::{bail, Context};
+use crate::Result;
+
+/// Parse a byte count such as `5368709120`, `500MB`, `5GiB` or `1.5T`.
+///
+/// Decimal suffixes (`K`, `KB`, `M`, `MB`, ...) are powers of 1000 and binary suffixes
+/// (`KiB`, `MiB`, ...) are powers of 1024. Suffixes are case-insensitive and a trailing
+/// `B` on its own means bytes.
+pub fn parse(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                 .unwrap_or(s.len());
+    let (num, suffix) = s.split_at(split);
+    let multiplier: u64 = match &*suffix.trim().to_ascii_lowercase() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000_u64.pow(2),
+        "g" | "gb" => 1000_u64.pow(3),
+        "t" | "tb" => 1000_u64.pow(4),
+        "ki" | "kib" => 1 << 10,
+        "mi" | "mib" => 1 << 20,
+        "gi" | "gib" => 1 << 30,
+        "ti" | "tib" => 1 << 40,
+        _ => bail!("Unknown size suffix '{suffix}' in '{s}'"),
+    };
+
+    if let Ok(n) = num.parse::<u64>() {
+        return n.checked_mul(multiplier)
+                .with_context(|| format!("Size '{s}' is too large"));
+    }
+
+    let n: f64 = num.parse().with_context(|| format!("Invalid size '{s}'"))?;
+    let bytes = (n * multiplier as f64).round();
+    if !(0.0..=(u64::MAX as f64)).contains(&bytes) {
+        bail!("Size '{s}' is out of range");
+    }
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn plain_bytes() {
+        assert_eq!(parse("0").unwrap(), 0);
+        assert_eq!(parse("1234").unwrap(), 1234);
+        assert_eq!(parse("1234B").unwrap(), 1234);
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(parse("5k").unwrap(), 5_000);
+        assert_eq!(parse("5KiB").unwrap(), 5 * 1024);
+        assert_eq!(parse("5GiB").unwrap(), 5 * 1024 * 1024 * 1024);
+        assert_eq!(parse("2 MB").unwrap(), 2_000_000);
+        assert_eq!(parse("1.5Ki").unwrap(), 1536);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("").is_err());
+        assert!(parse("5XB").is_err());
+        assert!(parse("1.2.3").is_err());
+        assert!(parse("99999999999TiB").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Character and block devices and FIFOs, archived with `--special-files` as tar
+//! entries with no contents, and created again with `mknod` on extraction, as the `tar`
+//! crate would extract them as empty regular files.
+//!
+//! Sockets are never archived: they only exist while a process listens on them, and
+//! tar has no entry type for them.
+
+use anyhow::Context;
+use crate::{Result, decompress::UnpackOptions, extract_path};
+use std::{
+    ffi::CString,
+    fs,
+    io::{self, Read},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    },
+    path::Path,
+};
+
+/// Whether `file_type` is a device or FIFO, which `--special-files` archives.
+pub fn is_special(file_type: fs::FileType) -> bool {
+    file_type.is_char_device() || file_type.is_block_device() || file_type.is_fifo()
+}
+
+/// Whether `entry_type` is a device or FIFO, which `create()` extracts.
+pub fn is_special_entry(entry_type: tar::EntryType) -> bool {
+    entry_type.is_character_special() || entry_type.is_block_special()
+    || entry_type.is_fifo()
+}
+
+/// Set the device numbers and zero size of a special file in `header`, whose other
+/// metadata is already set from `meta`.
+pub fn set_header(header: &mut tar::Header, meta: &fs::Metadata) -> Result<()> {
+    header.set_size(0);
+    let file_type = meta.file_type();
+    if file_type.is_char_device() || file_type.is_block_device() {
+        header.set_device_major(libc::major(meta.rdev()))?;
+        header.set_device_minor(libc::minor(meta.rdev()))?;
+    }
+    Ok(())
+}
+
+/// Create the device or FIFO `entry` at `dst`, replacing any file there. Returns false
+/// without creating it if it's a device and this process isn't root, as only root can
+/// create devices.
+pub fn create<R: Read>(entry: &mut tar::Entry<R>, dst: &Path, unpack: &UnpackOptions)
+-> Result<bool>
+{
+    let header = entry.header();
+    let entry_type = header.entry_type();
+    let (file_type, device) = if entry_type.is_fifo() {
+        (libc::S_IFIFO, 0)
+    } else {
+        // SAFETY: geteuid always succeeds.
+        if unsafe { libc::geteuid() } != 0 {
+            return Ok(false);
+        }
+        let major = header.device_major()?.context("device entry has no major number")?;
+        let minor = header.device_minor()?.context("device entry has no minor number")?;
+        let file_type = if entry_type.is_character_special() {
+            libc::S_IFCHR
+        } else {
+            libc::S_IFBLK
+        };
+        (file_type, libc::makedev(major, minor))
+    };
+    let mode = unpack.mode(header)?;
+
+    extract_path::remove_existing(dst)?;
+    let dst_c = CString::new(dst.as_os_str().as_bytes())?;
+    // SAFETY: dst_c is a valid C string.
+    if unsafe { libc::mknod(dst_c.as_ptr(), file_type | mode, device) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("creating '{}'", dst.display()));
+    }
+
+    if let Some((uid, gid)) = unpack.owner(header)? {
+        std::os::unix::fs::lchown(dst, Some(uid), Some(gid))?;
+    }
+    // After mknod, which applies the umask, and changing the owner, which clears the
+    // setuid and setgid bits.
+    fs::set_permissions(dst, fs::Permissions::from_mode(mode))?;
+    // By path, as opening a device or FIFO to set it could block or have side effects.
+    unpack.set_mtime(entry, dst)?;
+    Ok(true)
+}
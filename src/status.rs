@@ -0,0 +1,117 @@
+use crate::Result;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Output directory of a previous compress run.
+    #[arg(long)]
+    out_dir: PathBuf,
+}
+
+/// Summary of the last run that wrote to a destination directory, stored as JSON in
+/// `STATUS_FILE_NAME` so monitoring scripts can check it without parsing logs.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Status {
+    pub run_id: String,
+    pub command: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_ms: u64,
+    pub result: RunResult,
+    /// The error message if `result` is `Failure`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Total compressed bytes written.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunResult {
+    Success,
+    Failure,
+}
+
+/// Tracks a run from start to finish to produce its `Status`.
+pub struct Run {
+    command: &'static str,
+    run_id: String,
+    started_at: SystemTime,
+}
+
+pub const STATUS_FILE_NAME: &str = "ptar-status.json";
+
+pub fn main(cmd_args: Args, _args: crate::Args) -> Result<()> {
+    let path = cmd_args.out_dir.join(STATUS_FILE_NAME);
+    let json = fs::read_to_string(&*path)
+        .map_err(|err| anyhow::anyhow!("Error reading status file '{path}': {err}",
+                                       path = path.display()))?;
+    // Parse before printing so a corrupt status file is an error.
+    let status: Status = serde_json::from_str(&json)?;
+
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &status)?;
+    writeln!(stdout)?;
+
+    Ok(())
+}
+
+impl Run {
+    pub fn start(command: &'static str) -> Run {
+        let started_at = SystemTime::now();
+        let started_ms = started_at.duration_since(SystemTime::UNIX_EPOCH)
+                                   .unwrap_or(Duration::ZERO)
+                                   .as_millis();
+        Run {
+            command,
+            run_id: format!("{started_ms:x}-{pid}", pid = std::process::id()),
+            started_at,
+        }
+    }
+
+    /// Write the status file for this run to `out_dir`.
+    ///
+    /// Errors are logged rather than returned, so they don't mask the run's own result.
+    pub fn finish<T>(self, out_dir: &Path, res: &Result<T>, bytes: u64) {
+        let finished_at = SystemTime::now();
+        let status = Status {
+            run_id: self.run_id,
+            command: self.command.to_string(),
+            started_at: format_time(self.started_at),
+            finished_at: format_time(finished_at),
+            duration_ms: finished_at.duration_since(self.started_at)
+                                    .unwrap_or(Duration::ZERO)
+                                    .as_millis()
+                                    .try_into()
+                                    .unwrap_or(u64::MAX),
+            result: if res.is_ok() { RunResult::Success } else { RunResult::Failure },
+            error: res.as_ref().err().map(|err| err.to_string()),
+            bytes,
+        };
+
+        if let Err(err) = write(out_dir, &status) {
+            tracing::error!(%err, out_dir = %out_dir.display(), "Error writing status file");
+        }
+    }
+}
+
+fn write(out_dir: &Path, status: &Status) -> Result<()> {
+    fs::create_dir_all(out_dir)?;
+    // Write then rename so readers never see a partial file.
+    let tmp_path = out_dir.join(format!("{STATUS_FILE_NAME}.tmp"));
+    fs::write(&*tmp_path, serde_json::to_vec_pretty(status)?)?;
+    fs::rename(&*tmp_path, out_dir.join(STATUS_FILE_NAME))?;
+    Ok(())
+}
+
+pub fn format_time(t: SystemTime) -> String {
+    time::OffsetDateTime::from(t)
+        .format(&time::format_description::well_known::Rfc3339)
+        .expect("formatting a SystemTime as RFC 3339")
+}
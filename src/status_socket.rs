@@ -0,0 +1,94 @@
+//! Serving live progress as JSON over a Unix socket, for wrapper UIs and dashboards.
+//!
+//! Each client that connects is sent one JSON snapshot followed by a newline, then the
+//! connection is closed, so polling is as simple as `socat - ABSTRACT-CONNECT:<name>`.
+
+use crate::{Result, progress};
+use std::{
+    io::{self, Write},
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixListener},
+    },
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Serves snapshots of `progress::Counters` on its own thread until finished.
+pub struct StatusServer {
+    stop_tx: crossbeam_channel::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+#[derive(serde::Serialize)]
+struct Response<'a> {
+    command: &'a str,
+    elapsed_ms: u64,
+    progress: progress::Snapshot,
+    recent_events: Vec<progress::Event>,
+}
+
+/// Interval between checks for new connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time allowed for a client to read its response.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl StatusServer {
+    /// Listen on the abstract Unix socket named `name`.
+    pub fn spawn(name: &str, command: &'static str, counters: Arc<progress::Counters>)
+    -> Result<StatusServer>
+    {
+        let listener = UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?)?;
+        listener.set_nonblocking(true)?;
+        tracing::info!(socket_name = name, "Serving status on abstract Unix socket");
+
+        let (stop_tx, stop_rx) = crossbeam_channel::bounded::<()>(0);
+        let thread = thread::Builder::new()
+            .name("status-socket".to_string())
+            .spawn(move || {
+                let start = Instant::now();
+                loop {
+                    match listener.accept() {
+                        Ok((mut stream, _addr)) => {
+                            let response = Response {
+                                command,
+                                elapsed_ms: start.elapsed().as_millis()
+                                                 .try_into().unwrap_or(u64::MAX),
+                                progress: counters.snapshot(),
+                                recent_events: counters.events(),
+                            };
+                            // Closure to catch errors with `?`.
+                            let res = (|| -> Result<()> {
+                                stream.set_nonblocking(false)?;
+                                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                                serde_json::to_writer(&mut stream, &response)?;
+                                stream.write_all(b"\n")?;
+                                Ok(())
+                            })();
+                            if let Err(err) = res {
+                                tracing::debug!(%err, "Error writing to status socket client");
+                            }
+                            continue;
+                        },
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(err) => tracing::warn!(%err, "Error accepting status socket client"),
+                    }
+
+                    // Disconnected when the StatusServer is finished.
+                    if stop_rx.recv_timeout(POLL_INTERVAL)
+                              .is_err_and(|err| err.is_disconnected()) {
+                        break;
+                    }
+                }
+            })?;
+        Ok(StatusServer { stop_tx, thread })
+    }
+
+    /// Stop serving and close the socket.
+    pub fn finish(self) {
+        drop(self.stop_tx);
+        self.thread.join().expect("joining status socket thread");
+    }
+}
@@ -0,0 +1,321 @@
+//! Writing an archive set entry by entry from streams of unknown length, e.g. a database
+//! dump piped from another process, without staging it in a temporary file.
+//!
+//! A tar header records its entry's size before the data, so a stream is buffered in
+//! chunks, and each chunk is written as its own entry with the stream's path. Entries
+//! after the first carry a `PTAR.part=<N>` pax record, and `decompress` appends them to
+//! the file. Plain tar would instead keep only the last part, so extract these archive
+//! sets with ptar.
+//!
+//! ```no_run
+//! use std::{io, process::{Command, Stdio}};
+//!
+//! let mut writer = ptar::StreamWriterOptions::new("/backup").create()?;
+//! let mut dump = Command::new("pg_dumpall").stdout(Stdio::piped()).spawn()?;
+//! let mut entry = writer.append_stream("db/dump.sql", ptar::EntryMetadata::default())?;
+//! io::copy(dump.stdout.as_mut().expect("piped"), &mut entry)?;
+//! entry.finish()?;
+//! writer.finish()?;
+//! # Ok::<(), ptar::Error>(())
+//! ```
+
+use anyhow::ensure;
+use crate::{
+    Result, archive_set,
+    codec::{self, Codec},
+    manifest,
+    tar_format::TarFormat,
+};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Options for a `StreamWriter`.
+#[derive(Clone, Debug)]
+pub struct StreamWriterOptions {
+    chunk_size: usize,
+    codec: Codec,
+    level: Option<i32>,
+    manifest_format: manifest::Format,
+    out_dir: PathBuf,
+}
+
+/// Writes an archive set of one archive, `00000000.<EXT>`, to an output directory.
+///
+/// Like compress, the archive is written to a `.partial` file, and the manifest only
+/// lists entries once `finish()` has synced the archive to disk.
+pub struct StreamWriter {
+    archive_name: String,
+    chunk_size: usize,
+    manifest_writer: manifest::Writer,
+    out_dir: PathBuf,
+    /// Manifest entries held back until the archive is synced to disk.
+    pending_entries: Vec<manifest::Entry>,
+    tarb: tar::Builder<codec::Encoder<BufWriter<File>>>,
+}
+
+/// Metadata of an entry written with `StreamWriter::append_stream()`.
+#[derive(Clone, Debug)]
+pub struct EntryMetadata {
+    /// Permission bits, e.g. `0o644`.
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: i64,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// An entry being streamed into a `StreamWriter`. Write its contents, then call
+/// `finish()`.
+///
+/// Dropping it without calling `finish()` leaves the entry incomplete: its parts so far
+/// are in the archive, but it isn't added to the manifest.
+pub struct StreamEntry<'a> {
+    buf: Vec<u8>,
+    meta: EntryMetadata,
+    /// Number of parts written so far.
+    parts: u64,
+    path: PathBuf,
+    size: u64,
+    writer: &'a mut StreamWriter,
+}
+
+/// Pax record key marking the parts of a stream after the first.
+pub const PART_PAX_KEY: &str = "PTAR.part";
+
+const DEFAULT_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+impl StreamWriterOptions {
+    pub fn new(out_dir: impl Into<PathBuf>) -> StreamWriterOptions {
+        StreamWriterOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            codec: Codec::Zstd,
+            level: None,
+            manifest_format: manifest::Format::Jsonl,
+            out_dir: out_dir.into(),
+        }
+    }
+
+    /// Bytes of each stream buffered in memory and written as one part. Defaults to
+    /// 16 MiB.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compression level, or the codec's default if not set.
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    pub fn manifest_format(mut self, format: manifest::Format) -> Self {
+        self.manifest_format = format;
+        self
+    }
+
+    /// Create the output directory if needed, and start writing the archive.
+    pub fn create(self) -> Result<StreamWriter> {
+        ensure!(self.chunk_size > 0, "chunk_size must be more than 0");
+        fs::create_dir_all(&*self.out_dir)?;
+        let manifest_writer = manifest::Writer::create(&self.out_dir, self.manifest_format)?;
+
+        let archive_name = format!("{:08}.{ext}", 0, ext = self.codec.extension());
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(partial_path(&self.out_dir, &archive_name))?;
+        let bufw = BufWriter::with_capacity(128 * 1024, file);
+        let encoder = self.codec.encoder(bufw, self.level, None)?;
+
+        Ok(StreamWriter {
+            archive_name,
+            chunk_size: self.chunk_size,
+            manifest_writer,
+            out_dir: self.out_dir,
+            pending_entries: Vec::new(),
+            tarb: tar::Builder::new(encoder),
+        })
+    }
+}
+
+impl StreamWriter {
+    /// Start an entry at `path`, relative to the archive root, whose contents are
+    /// written to the returned `StreamEntry`.
+    pub fn append_stream(&mut self, path: impl Into<PathBuf>, meta: EntryMetadata)
+    -> Result<StreamEntry<'_>>
+    {
+        let path = path.into();
+        ensure!(path.is_relative(), "Entry path '{}' must be relative", path.display());
+        Ok(StreamEntry {
+            buf: Vec::with_capacity(self.chunk_size),
+            meta,
+            parts: 0,
+            path,
+            size: 0,
+            writer: self,
+        })
+    }
+
+    /// Finish and sync the archive, then write and sync the manifest.
+    pub fn finish(self) -> Result<()> {
+        let file = self.tarb.into_inner()?
+                            .finish()?
+                            .into_inner()
+                            .map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(partial_path(&self.out_dir, &self.archive_name),
+                   self.out_dir.join(&self.archive_name))?;
+        File::open(&*self.out_dir)?.sync_all()?;
+
+        let manifest_tx = self.manifest_writer.sender();
+        for entry in self.pending_entries {
+            manifest_tx.send(entry).expect("manifest writer is running");
+        }
+        drop(manifest_tx);
+        self.manifest_writer.finish()
+    }
+
+    fn append_part(&mut self, path: &Path, meta: &EntryMetadata, part: u64, data: &[u8])
+    -> Result<()>
+    {
+        let mut header = TarFormat::Pax.new_header();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        header.set_mode(meta.mode);
+        header.set_mtime(u64::try_from(meta.mtime).unwrap_or(0));
+        header.set_uid(meta.uid.into());
+        header.set_gid(meta.gid.into());
+        let part_string = part.to_string();
+        let records: &[(&str, &[u8])] = if part == 0 {
+            &[]
+        } else {
+            &[(PART_PAX_KEY, part_string.as_bytes())]
+        };
+        TarFormat::Pax.append_with_records(&mut self.tarb, &mut header, path, data, records)
+    }
+}
+
+impl StreamEntry<'_> {
+    /// Write the last part of the entry, and add it to the manifest.
+    pub fn finish(mut self) -> Result<()> {
+        // An empty stream still gets one, empty, part.
+        if !self.buf.is_empty() || self.parts == 0 {
+            self.write_part()?;
+        }
+        let writer = &mut *self.writer;
+        writer.pending_entries.push(manifest::Entry {
+            path: self.path,
+            size: self.size,
+            mtime: self.meta.mtime,
+            mtime_nsec: 0,
+            archive: writer.archive_name.clone(),
+            uid: Some(self.meta.uid),
+            gid: Some(self.meta.gid),
+            uname: None,
+            gname: None,
+            checksum: None,
+            wrapped_key: None,
+            parts: (self.parts > 1).then_some(self.parts),
+            original_path: None,
+            case_collision: None,
+            offset: None,
+            frame_offset: None,
+            frame_tar_offset: None,
+            chunk_offset: None,
+        });
+        Ok(())
+    }
+
+    fn write_part(&mut self) -> Result<()> {
+        self.writer.append_part(&self.path, &self.meta, self.parts, &self.buf)?;
+        self.parts += 1;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Write for StreamEntry<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.writer.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        self.size += len as u64;
+        if self.buf.len() == self.writer.chunk_size {
+            self.write_part().map_err(io::Error::other)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Default for EntryMetadata {
+    /// Mode `0o644`, modified now, and owned by the current user and group.
+    fn default() -> EntryMetadata {
+        let (mtime, _) = manifest::unix_time(SystemTime::now());
+        EntryMetadata {
+            mode: 0o644,
+            mtime,
+            // SAFETY: getuid and getgid always succeed.
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+}
+
+/// Whether `entry` is a part after the first of a stream, marked with `PART_PAX_KEY`.
+pub(crate) fn is_part<R: io::Read>(entry: &mut tar::Entry<R>) -> Result<bool> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(false);
+    };
+    for extension in extensions {
+        if extension?.key_bytes() == PART_PAX_KEY.as_bytes() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn partial_path(out_dir: &Path, archive_name: &str) -> PathBuf {
+    out_dir.join(format!("{archive_name}{suffix}", suffix = archive_set::PARTIAL_SUFFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DecompressOptions, testsupport::TempDir};
+
+    #[test]
+    fn chunked_round_trip() {
+        let out = TempDir::new("ptar-test").unwrap();
+        let mut writer = StreamWriterOptions::new(out.path()).chunk_size(1000).create().unwrap();
+        let contents = (0..2500_u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let mut entry = writer.append_stream("a/big", EntryMetadata::default()).unwrap();
+        entry.write_all(&contents).unwrap();
+        entry.finish().unwrap();
+        let entry = writer.append_stream("empty", EntryMetadata::default()).unwrap();
+        entry.finish().unwrap();
+        writer.finish().unwrap();
+
+        let entries = manifest::read(out.path()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].size, entries[0].parts), (2500, Some(3)));
+        assert_eq!((entries[1].size, entries[1].parts), (0, None));
+
+        let extracted = TempDir::new("ptar-test").unwrap();
+        DecompressOptions::new(out.path(), extracted.path()).run().unwrap();
+        assert_eq!(fs::read(extracted.path().join("a/big")).unwrap(), contents);
+        assert_eq!(fs::read(extracted.path().join("empty")).unwrap(), b"");
+    }
+}
@@ -0,0 +1,310 @@
+//! Writing tar entry headers in a chosen format, for compatibility with consumers that
+//! only understand some header flavours.
+
+use anyhow::ensure;
+use crate::Result;
+use std::{
+    io::{Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+use valuable::Valuable;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq, Valuable)]
+pub enum TarFormat {
+    /// POSIX.1-1988 ustar only. Entries that don't fit its limits, e.g. paths longer
+    /// than 255 bytes or files of 8 GiB or more, are errors.
+    Ustar,
+    /// POSIX.1-2001 pax: ustar headers, with a pax extended header before entries that
+    /// don't fit ustar's limits, or whose mtimes have a fractional part, which it keeps.
+    Pax,
+    /// ustar with GNU extensions: long name entries and base-256 numbers.
+    Gnu,
+    /// Unix V7, which predates ustar, for the oldest tar implementations. It has no
+    /// owner names, device or FIFO entries, or directory prefix, so paths are limited to
+    /// 100 bytes. Otherwise it has ustar's limits.
+    V7,
+}
+
+/// Largest value of ustar's 12 byte octal fields: size and mtime.
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+/// Largest value of ustar's 8 byte octal fields: uid and gid.
+const USTAR_MAX_ID: u64 = 0o7777777;
+/// Length of ustar's name field, the last part of the path.
+const USTAR_NAME_LEN: usize = 100;
+
+impl TarFormat {
+    pub fn new_header(self) -> tar::Header {
+        match self {
+            TarFormat::Ustar | TarFormat::Pax => tar::Header::new_ustar(),
+            TarFormat::Gnu => tar::Header::new_gnu(),
+            TarFormat::V7 => tar::Header::new_old(),
+        }
+    }
+
+    /// Whether `path` can be stored in this format. ustar limits path lengths to a 155
+    /// byte directory prefix and a 100 byte name, and v7 to 100 bytes in all.
+    pub fn fits_path(self, path: &Path) -> bool {
+        match self {
+            TarFormat::Ustar | TarFormat::V7 => self.new_header().set_path(path).is_ok(),
+            TarFormat::Pax | TarFormat::Gnu => true,
+        }
+    }
+
+    /// Name of the format, as passed to `--tar-format`.
+    pub fn name(self) -> &'static str {
+        match self {
+            TarFormat::Ustar => "ustar",
+            TarFormat::Pax => "pax",
+            TarFormat::Gnu => "gnu",
+            TarFormat::V7 => "v7",
+        }
+    }
+
+    /// Append an entry for `path` with `data`, whose other metadata is already in
+    /// `header`, which must come from `new_header()`.
+    pub fn append<W: Write, R: Read>(self,
+                                     tarb: &mut tar::Builder<W>,
+                                     header: &mut tar::Header,
+                                     path: &Path,
+                                     data: R)
+    -> Result<()>
+    {
+        self.append_with_records(tarb, header, path, data, &[])
+    }
+
+    /// Like `append()`, also writing `extra_records` as pax extended header records
+    /// for the entry. Only pax supports extra records.
+    pub fn append_with_records<W: Write, R: Read>(self,
+                                                  tarb: &mut tar::Builder<W>,
+                                                  header: &mut tar::Header,
+                                                  path: &Path,
+                                                  data: R,
+                                                  extra_records: &[(&str, &[u8])])
+    -> Result<()>
+    {
+        ensure!(self == TarFormat::Pax || extra_records.is_empty(),
+                "{self:?} tar format can't store pax records for '{}'", path.display());
+        match self {
+            TarFormat::Gnu => tarb.append_data(header, path, data)?,
+            TarFormat::Ustar | TarFormat::V7 => {
+                let format = self.name();
+                let size = header.size()?;
+                ensure!(size <= USTAR_MAX_SIZE,
+                        "File '{}' of {size} bytes is too large for {format}", path.display());
+                for (name, id) in [("uid", header.uid()?), ("gid", header.gid()?)] {
+                    ensure!(id <= USTAR_MAX_ID,
+                            "File '{}' {name} {id} is too large for {format}",
+                            path.display());
+                }
+                ensure!((0..=USTAR_MAX_SIZE).contains(&header.mtime()?),
+                        "File '{}' mtime is out of range for {format}", path.display());
+                header.set_path(path).map_err(|err| anyhow::anyhow!(
+                    "File '{}' path doesn't fit in {format}: {err}", path.display()))?;
+                header.set_cksum();
+                tarb.append(header, data)?;
+            },
+            TarFormat::Pax => {
+                let mut records = pax_records(header, path, extra_records)?;
+                for (key, value) in extra_records {
+                    append_pax_record(&mut records, key, value);
+                }
+                if !records.is_empty() {
+                    let mut pax_header = tar::Header::new_ustar();
+                    pax_header.set_entry_type(tar::EntryType::XHeader);
+                    pax_header.set_path(Path::new("PaxHeaders").join(short_name(path)))?;
+                    pax_header.set_size(records.len() as u64);
+                    pax_header.set_mode(0o644);
+                    pax_header.set_mtime(header.mtime()?);
+                    pax_header.set_cksum();
+                    tarb.append(&pax_header, &*records)?;
+                }
+                header.set_cksum();
+                tarb.append(header, data)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Format a time as pax `mtime` records do: seconds since the epoch, with a fractional
+/// part if `nsec` isn't 0. `nsec` is added to `secs`, even if that's negative, as
+/// `manifest::unix_time` returns.
+pub fn pax_time(secs: i64, nsec: u32) -> String {
+    if nsec == 0 {
+        secs.to_string()
+    } else if secs >= 0 {
+        format!("{secs}.{nsec:09}")
+    } else {
+        format!("-{}.{:09}", -(secs + 1), 1_000_000_000 - nsec)
+    }
+}
+
+/// Parse a pax time, e.g. `1700000000.5`, into seconds and nanoseconds as `pax_time`
+/// takes them. Digits past nanoseconds are truncated.
+pub fn parse_pax_time(value: &[u8]) -> Option<(i64, u32)> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value),
+    };
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    if secs.is_empty() || !secs.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let secs = secs.parse::<i64>().ok()?;
+    let nsec = format!("{:0<9}", &frac[..frac.len().min(9)]).parse::<u32>().ok()?;
+    Some(match (negative, nsec) {
+        (false, _) => (secs, nsec),
+        (true, 0) => (-secs, 0),
+        (true, _) => (-secs - 1, 1_000_000_000 - nsec),
+    })
+}
+
+/// Build the pax extended header records for the fields of an entry that don't fit
+/// in `header`, and set those fields in `header` to placeholders. Also sets the path in
+/// `header`. Fields whose records are in `extra_records` aren't repeated.
+fn pax_records(header: &mut tar::Header, path: &Path, extra_records: &[(&str, &[u8])])
+-> Result<Vec<u8>>
+{
+    let mut records = Vec::new();
+    let mut append = |key: &str, value: &[u8]| {
+        if !extra_records.iter().any(|(extra_key, _)| *extra_key == key) {
+            append_pax_record(&mut records, key, value);
+        }
+    };
+
+    if header.set_path(path).is_err() {
+        append("path", path.as_os_str().as_bytes());
+        header.set_path(short_name(path))?;
+    }
+    let size = header.size()?;
+    if size > USTAR_MAX_SIZE {
+        append("size", size.to_string().as_bytes());
+        header.set_size(0);
+    }
+    let uid = header.uid()?;
+    if uid > USTAR_MAX_ID {
+        append("uid", uid.to_string().as_bytes());
+        header.set_uid(0);
+    }
+    let gid = header.gid()?;
+    if gid > USTAR_MAX_ID {
+        append("gid", gid.to_string().as_bytes());
+        header.set_gid(0);
+    }
+    let mtime = header.mtime()?;
+    if mtime > USTAR_MAX_SIZE {
+        append("mtime", mtime.to_string().as_bytes());
+        header.set_mtime(0);
+    }
+
+    Ok(records)
+}
+
+/// Append a record formatted as `<length> <key>=<value>\n`, where the length counts
+/// the whole record including its own digits.
+fn append_pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let base_len = key.len() + value.len() + 3;
+    let mut len = base_len + 1;
+    while len != base_len + len.to_string().len() {
+        len = base_len + len.to_string().len();
+    }
+    records.extend_from_slice(format!("{len} {key}=").as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+/// The file name of `path`, truncated to fit a ustar name field, for headers whose real
+/// path is in a pax record.
+fn short_name(path: &Path) -> String {
+    let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy();
+    let mut end = name.len().min(USTAR_NAME_LEN - 1);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io, path::PathBuf};
+
+    fn round_trip(format: TarFormat, path: &Path, uid: u64) -> Result<PathBuf> {
+        let mut tarb = tar::Builder::new(Vec::new());
+        let mut header = format.new_header();
+        header.set_size(3);
+        header.set_uid(uid);
+        header.set_gid(0);
+        header.set_mtime(0);
+        format.append(&mut tarb, &mut header, path, &b"abc"[..])?;
+        let bytes = tarb.into_inner()?;
+
+        let mut archive = tar::Archive::new(io::Cursor::new(bytes));
+        let mut entries = archive.entries()?;
+        let mut entry = entries.next().expect("1 entry")?;
+        assert_eq!(entry.header().uid()?, uid);
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        assert_eq!(data, b"abc");
+        let path = entry.path()?.into_owned();
+        assert!(entries.next().is_none());
+        Ok(path)
+    }
+
+    #[test]
+    fn pax_record_lengths() {
+        let mut records = Vec::new();
+        append_pax_record(&mut records, "path", b"a");
+        // 8 bytes of " path=a\n", plus 1 length digit.
+        assert_eq!(records, b"9 path=a\n");
+
+        let mut records = Vec::new();
+        // 98 bytes plus 2 digits would be 100, which has 3 digits.
+        append_pax_record(&mut records, "path", &[b'x'; 91]);
+        assert_eq!(records.len(), 101);
+        assert!(records.starts_with(b"101 path="));
+    }
+
+    #[test]
+    fn pax_times() {
+        for (secs, nsec, text) in [
+            (1_700_000_000, 0, "1700000000"),
+            (1_700_000_000, 5, "1700000000.000000005"),
+            (-1, 500_000_000, "-0.500000000"),
+            (-2, 0, "-2"),
+        ] {
+            assert_eq!(pax_time(secs, nsec), text);
+            assert_eq!(parse_pax_time(text.as_bytes()), Some((secs, nsec)));
+        }
+        assert_eq!(parse_pax_time(b"1.5"), Some((1, 500_000_000)));
+        assert_eq!(parse_pax_time(b"1.1234567899"), Some((1, 123_456_789)));
+        assert_eq!(parse_pax_time(b"-1.25"), Some((-2, 750_000_000)));
+        assert_eq!(parse_pax_time(b""), None);
+        assert_eq!(parse_pax_time(b".5"), None);
+        assert_eq!(parse_pax_time(b"1e9"), None);
+    }
+
+    #[test]
+    fn long_paths() {
+        let long = PathBuf::from(format!("{}/{}", "d".repeat(200), "f".repeat(150)));
+
+        let short = Path::new("a/b");
+
+        for format in [TarFormat::Pax, TarFormat::Gnu] {
+            assert_eq!(round_trip(format, &long, 3_000_000).unwrap(), long);
+            assert_eq!(round_trip(format, short, 3_000_000).unwrap(), short);
+        }
+
+        assert_eq!(round_trip(TarFormat::Ustar, short, 1000).unwrap(), short);
+        assert!(round_trip(TarFormat::Ustar, &long, 1000).is_err());
+        assert!(round_trip(TarFormat::Ustar, short, 3_000_000).is_err());
+
+        let v7_max = PathBuf::from(format!("d/{}", "f".repeat(98)));
+        assert_eq!(round_trip(TarFormat::V7, &v7_max, 1000).unwrap(), v7_max);
+        assert!(!TarFormat::V7.fits_path(&Path::new("d").join(&v7_max)));
+        assert!(round_trip(TarFormat::V7, &Path::new("d").join(&v7_max), 1000).is_err());
+        assert!(round_trip(TarFormat::V7, short, 3_000_000).is_err());
+    }
+}
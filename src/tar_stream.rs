@@ -0,0 +1,202 @@
+//! Reading a tar stream as raw entries, each its header blocks as read, so entries can
+//! be copied between tar streams, e.g. by `merge` and `from-tar`, without being
+//! re-encoded.
+
+use anyhow::{ensure, Context};
+use crate::{Result, codec, tar_format};
+use std::io::{self, Read, Write};
+
+/// The header blocks of one tar entry: any pax extended or GNU long name headers and
+/// their data, then the entry's own header. Its data follows in the stream.
+///
+/// A pax global header is returned alone, with its data in `bytes`, as it applies to
+/// every entry after it rather than one.
+pub struct RawHeaders {
+    /// The header blocks as read.
+    pub bytes: Vec<u8>,
+    /// The entry's own header.
+    pub header: tar::Header,
+    /// The entry's path, from its pax or long name header if it has one.
+    pub path: Vec<u8>,
+    /// The entry's link target, for links.
+    pub link_path: Option<Vec<u8>>,
+    /// Length of the entry's data, excluding padding to a whole block.
+    pub size: u64,
+    pub mtime: (i64, u32),
+    pub uid: u64,
+    pub gid: u64,
+}
+
+impl RawHeaders {
+    /// Length of the entry's data padded to a whole block, as it is in the stream.
+    pub fn padded_size(&self) -> u64 {
+        self.size.div_ceil(512) * 512
+    }
+}
+
+/// Read the headers of the next entry of the tar stream `r`, leaving it positioned at
+/// the entry's data. Returns None at the end of archive marker.
+pub fn read_headers(r: &mut impl Read) -> Result<Option<RawHeaders>> {
+    let mut bytes = Vec::new();
+    let (mut path, mut link_path, mut size, mut mtime, mut uid, mut gid) =
+        (None, None, None, None, None, None);
+    loop {
+        let mut block = [0; 512];
+        if let Err(err) = r.read_exact(&mut block) {
+            ensure!(err.kind() != io::ErrorKind::UnexpectedEof,
+                    "Tar stream ends without an end of archive marker");
+            return Err(err.into());
+        }
+        if block.iter().all(|&b| b == 0) {
+            ensure!(bytes.is_empty(), "Tar stream ends after an extension header");
+            return Ok(None);
+        }
+        ensure!(codec::is_tar_header(&block), "Invalid tar header");
+        let header = tar::Header::from_byte_slice(&block).clone();
+        bytes.extend_from_slice(&block);
+
+        let entry_type = header.entry_type();
+        let is_extension = entry_type.is_pax_local_extensions()
+                           || entry_type.is_pax_global_extensions()
+                           || entry_type.is_gnu_longname()
+                           || entry_type.is_gnu_longlink();
+        if !is_extension {
+            return Ok(Some(RawHeaders {
+                bytes,
+                path: match path {
+                    Some(path) => path,
+                    None => header.path_bytes().into_owned(),
+                },
+                link_path: link_path.or_else(|| {
+                    header.link_name_bytes().map(|name| name.into_owned())
+                }),
+                size: match size {
+                    Some(size) => size,
+                    None => header.entry_size()?,
+                },
+                mtime: match mtime {
+                    Some(mtime) => mtime,
+                    None => (header.mtime()? as i64, 0),
+                },
+                uid: match uid {
+                    Some(uid) => uid,
+                    None => header.uid()?,
+                },
+                gid: match gid {
+                    Some(gid) => gid,
+                    None => header.gid()?,
+                },
+                header,
+            }));
+        }
+
+        let data_len = header.entry_size()?;
+        let start = bytes.len();
+        (&mut *r).take(data_len.div_ceil(512) * 512).read_to_end(&mut bytes)?;
+        ensure!((bytes.len() - start) as u64 == data_len.div_ceil(512) * 512,
+                "Tar stream ends inside an extension header");
+        let data = &bytes[start..(start + data_len as usize)];
+        if entry_type.is_pax_global_extensions() {
+            ensure!(start == 512, "pax global header after another extension header");
+            return Ok(Some(RawHeaders {
+                path: header.path_bytes().into_owned(),
+                link_path: None,
+                size: 0,
+                mtime: (0, 0),
+                uid: 0,
+                gid: 0,
+                header,
+                bytes,
+            }));
+        }
+        if entry_type.is_gnu_longname() || entry_type.is_gnu_longlink() {
+            let name = data.split(|&b| b == 0).next().unwrap_or_default().to_vec();
+            if entry_type.is_gnu_longname() {
+                path = Some(name);
+            } else {
+                link_path = Some(name);
+            }
+            continue;
+        }
+        for extension in tar::PaxExtensions::new(data) {
+            let extension = extension?;
+            let value = extension.value_bytes();
+            let parse = || -> Result<u64> {
+                std::str::from_utf8(value).ok()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .with_context(|| format!("Invalid pax {} record",
+                                             String::from_utf8_lossy(extension.key_bytes())))
+            };
+            match extension.key_bytes() {
+                b"path" => path = Some(value.to_vec()),
+                b"linkpath" => link_path = Some(value.to_vec()),
+                b"size" => size = Some(parse()?),
+                b"uid" => uid = Some(parse()?),
+                b"gid" => gid = Some(parse()?),
+                b"mtime" => mtime = Some(tar_format::parse_pax_time(value)
+                                             .context("Invalid pax mtime record")?),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Copy the entries of the tar stream `r` to `w`, stopping at its end of archive
+/// marker, which isn't copied. Returns the bytes copied.
+pub fn copy_entries(mut r: impl Read, mut w: impl Write) -> Result<u64> {
+    let mut copied = 0;
+    while let Some(headers) = read_headers(&mut r)
+                                  .with_context(|| format!("at offset {copied}"))? {
+        w.write_all(&headers.bytes)?;
+        let len = io::copy(&mut (&mut r).take(headers.padded_size()), &mut w)?;
+        ensure!(len == headers.padded_size(), "Tar stream ends inside an entry");
+        copied += headers.bytes.len() as u64 + len;
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar_format::TarFormat;
+    use std::path::PathBuf;
+
+    #[test]
+    fn reads_and_copies_entries() {
+        // A path too long for ustar, so it has a pax header.
+        let long_path = "b".repeat(150);
+        let mut tarb = tar::Builder::new(Vec::new());
+        for (path, len) in [("a", 3), (&*long_path, 600)] {
+            let mut header = TarFormat::Pax.new_header();
+            header.set_size(len);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(7);
+            TarFormat::Pax.append(&mut tarb, &mut header, path.as_ref(),
+                                  &*vec![b'x'; len as usize]).unwrap();
+        }
+        let data = tarb.into_inner().unwrap();
+
+        let mut r = &*data;
+        let mut read = Vec::new();
+        while let Some(headers) = read_headers(&mut r).unwrap() {
+            io::copy(&mut (&mut r).take(headers.padded_size()), &mut io::sink()).unwrap();
+            read.push((String::from_utf8(headers.path).unwrap(), headers.size,
+                       headers.mtime, headers.bytes.len()));
+        }
+        assert_eq!(read, [("a".to_string(), 3, (7, 0), 512),
+                          (long_path.clone(), 600, (7, 0), 3 * 512)]);
+
+        let mut merged = Vec::new();
+        let len = copy_entries(&*data, &mut merged).unwrap();
+        assert_eq!(len, data.len() as u64 - 1024);
+        copy_entries(&*data, &mut merged).unwrap();
+        merged.extend_from_slice(&[0; 1024]);
+        let mut archive = tar::Archive::new(&*merged);
+        let paths = archive.entries().unwrap()
+                           .map(|entry| entry.unwrap().path().unwrap().into_owned())
+                           .collect::<Vec<_>>();
+        assert_eq!(paths, ["a", &long_path, "a", &long_path].map(PathBuf::from));
+        assert!(copy_entries(&data[..512], io::sink()).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Fixtures for testing programs that embed ptar: generating input trees, building
+//! archive sets from them, and damaging archive sets the way crashes and bad storage
+//! do. Enabled by the `testsupport` feature.
+//!
+//! ```no_run
+//! use ptar::testsupport::{Corruption, Fixture, TreeSpec, ensure_trees_equal};
+//!
+//! let fixture = Fixture::build(&TreeSpec::default(), |options| options.threads(2))?;
+//! let extracted = fixture.extract()?;
+//! ensure_trees_equal(fixture.input.path(), extracted.path())?;
+//!
+//! Corruption::Truncate { keep: 100 }.apply(&fixture.archive_paths()[0])?;
+//! assert!(fixture.extract().is_err());
+//! # Ok::<(), ptar::Error>(())
+//! ```
+
+use anyhow::{ensure, Context};
+pub use crate::chaos::Corruption;
+use crate::{CompressOptions, CompressReport, DecompressOptions, Result, chaos::Rng, manifest};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A directory under the system temporary directory, deleted when dropped.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+/// Describes a tree of files to generate. The same spec always generates the same tree.
+#[derive(Clone, Debug)]
+pub struct TreeSpec {
+    pub seed: u64,
+    pub files: usize,
+    /// Files are at most this many directories deep.
+    pub max_depth: usize,
+    pub max_file_size: u64,
+}
+
+/// A generated input tree and the archive set compressed from it.
+#[derive(Debug)]
+pub struct Fixture {
+    pub input: TempDir,
+    pub archives: TempDir,
+    /// Paths of the generated files, relative to `input`.
+    pub files: Vec<PathBuf>,
+    pub report: CompressReport,
+}
+
+static TEMP_DIR_COUNT: AtomicU64 = AtomicU64::new(0);
+
+impl TempDir {
+    /// Create a new empty directory whose name starts with `prefix`.
+    pub fn new(prefix: &str) -> Result<TempDir> {
+        let path = std::env::temp_dir().join(format!(
+            "{prefix}-{pid}-{count}",
+            pid = std::process::id(),
+            count = TEMP_DIR_COUNT.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir(&path)
+            .with_context(|| format!("creating temp dir '{}'", path.display()))?;
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+impl Default for TreeSpec {
+    fn default() -> TreeSpec {
+        TreeSpec {
+            seed: 0,
+            files: 100,
+            max_depth: 3,
+            max_file_size: 64 * 1024,
+        }
+    }
+}
+
+impl TreeSpec {
+    /// Write the tree's files under `root`, and return their paths relative to it.
+    ///
+    /// About half the files are repetitive text and the rest random bytes, so archives
+    /// have a realistic mix of compressible and incompressible data. Some are empty.
+    pub fn generate(&self, root: &Path) -> Result<Vec<PathBuf>> {
+        let mut rng = Rng::new(self.seed);
+        let mut paths = Vec::with_capacity(self.files);
+        for i in 0..self.files {
+            let mut path = PathBuf::new();
+            for _ in 0..rng.below(self.max_depth as u64 + 1) {
+                // Few names per level, so files share directories.
+                path.push(format!("d{}", rng.below(4)));
+            }
+            let text = rng.below(2) == 0;
+            path.push(format!("f{i}.{ext}", ext = if text { "txt" } else { "bin" }));
+
+            let len = match rng.below(10) {
+                0 => 0,
+                _ => rng.below(self.max_file_size + 1),
+            };
+            let mut contents = vec![0_u8; len as usize];
+            if text {
+                for (j, byte) in contents.iter_mut().enumerate() {
+                    *byte = b"lorem ipsum dolor sit amet\n"[j % 27];
+                }
+            } else {
+                rng.fill(&mut contents);
+            }
+
+            let abs_path = root.join(&path);
+            fs::create_dir_all(abs_path.parent().expect("has a file name"))?;
+            fs::write(&abs_path, &contents)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+impl Fixture {
+    /// Generate a tree from `spec` and compress it with the options from `configure`.
+    pub fn build(spec: &TreeSpec,
+                 configure: impl FnOnce(CompressOptions) -> CompressOptions)
+    -> Result<Fixture>
+    {
+        let input = TempDir::new("ptar-fixture-input")?;
+        let archives = TempDir::new("ptar-fixture-archives")?;
+        let files = spec.generate(input.path())?;
+        let report = configure(CompressOptions::new(input.path(), archives.path())).run()?;
+        Ok(Fixture {
+            input,
+            archives,
+            files,
+            report,
+        })
+    }
+
+    /// Paths of the archives in the set, sorted.
+    pub fn archive_paths(&self) -> Vec<PathBuf> {
+        self.report.archives.iter().map(|name| self.archives.path().join(name)).collect()
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.archives.path().join(manifest::Format::Jsonl.file_name())
+    }
+
+    /// Decompress the archive set to a new temporary directory.
+    pub fn extract(&self) -> Result<TempDir> {
+        self.extract_with(|options| options)
+    }
+
+    /// Decompress the archive set with the options from `configure` to a new temporary
+    /// directory.
+    pub fn extract_with(&self, configure: impl FnOnce(DecompressOptions) -> DecompressOptions)
+    -> Result<TempDir>
+    {
+        let out = TempDir::new("ptar-fixture-extracted")?;
+        configure(DecompressOptions::new(self.archives.path(), out.path())).run()?;
+        Ok(out)
+    }
+}
+
+/// Compare the regular files under two directories, returning the relative paths that
+/// are missing from either or whose contents differ.
+pub fn diff_trees(a: &Path, b: &Path) -> Result<Vec<PathBuf>> {
+    let a_files = list_files(a)?;
+    let b_files = list_files(b)?;
+    let mut diffs = Vec::new();
+    for path in a_files.union(&b_files) {
+        if !(a_files.contains(path) && b_files.contains(path))
+           || fs::read(a.join(path))? != fs::read(b.join(path))? {
+            diffs.push(path.clone());
+        }
+    }
+    Ok(diffs)
+}
+
+/// Return an error listing the differences if `diff_trees(a, b)` finds any.
+pub fn ensure_trees_equal(a: &Path, b: &Path) -> Result<()> {
+    let diffs = diff_trees(a, b)?;
+    ensure!(diffs.is_empty(), "Trees '{}' and '{}' differ at {diffs:?}",
+            a.display(), b.display());
+    Ok(())
+}
+
+/// Relative paths of the regular files under `root`.
+fn list_files(root: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let file_type = dir_entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(dir_entry.path());
+            } else if file_type.is_file() {
+                files.insert(dir_entry.path().strip_prefix(root)?.to_path_buf());
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_tree() -> TreeSpec {
+        TreeSpec {
+            files: 30,
+            max_file_size: 20_000,
+            ..TreeSpec::default()
+        }
+    }
+
+    #[test]
+    fn trees_are_deterministic() {
+        let (a, b) = (TempDir::new("ptar-test").unwrap(), TempDir::new("ptar-test").unwrap());
+        small_tree().generate(a.path()).unwrap();
+        small_tree().generate(b.path()).unwrap();
+        ensure_trees_equal(a.path(), b.path()).unwrap();
+
+        let c = TempDir::new("ptar-test").unwrap();
+        TreeSpec { seed: 1, ..small_tree() }.generate(c.path()).unwrap();
+        assert!(!diff_trees(a.path(), c.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trip_and_corruption() {
+        let fixture = Fixture::build(&small_tree(), |options| {
+            options.threads(2).max_shard_size(16_000)
+        }).unwrap();
+        assert_eq!(fixture.report.file_count, 30);
+        let extracted = fixture.extract().unwrap();
+        ensure_trees_equal(fixture.input.path(), extracted.path()).unwrap();
+
+        let archive = &fixture.archive_paths()[0];
+        let len = fs::metadata(archive).unwrap().len();
+        Corruption::Truncate { keep: len / 2 }.apply(archive).unwrap();
+        assert!(fixture.extract().is_err());
+    }
+}
@@ -17,7 +17,8 @@ pub struct ThreadOffloadReader {
     /// Some except during drop().
     offload_thread: Option<thread::JoinHandle<()>>,
     read_timeout: Duration,
-    ready_chunks_rx: crossbeam_channel::Receiver<VecDeque<u8>>,
+    /// Receives the next chunk read, or the error that stopped the offload thread.
+    ready_chunks_rx: crossbeam_channel::Receiver<io::Result<VecDeque<u8>>>,
     reuse_chunks_tx: crossbeam_channel::Sender<VecDeque<u8>>,
     curr_chunk: Option<VecDeque<u8>>,
     should_stop: Arc<AtomicBool>,
@@ -25,7 +26,7 @@ pub struct ThreadOffloadReader {
 
 struct OffloadThread {
     inner: Box::<dyn Read + Send>,
-    ready_chunks_tx: crossbeam_channel::Sender<VecDeque<u8>>,
+    ready_chunks_tx: crossbeam_channel::Sender<io::Result<VecDeque<u8>>>,
     reuse_chunks_rx: crossbeam_channel::Receiver<VecDeque<u8>>,
     buf_len: usize,
     should_stop: Arc<AtomicBool>,
@@ -41,7 +42,8 @@ type ThreadResult<T> = StdResult<T, ThreadError>;
 impl ThreadOffloadReader {
     pub fn new<R: Read + Send + 'static>(inner: R) -> ThreadOffloadReader {
         let inner_boxed: Box<dyn Read + Send> = Box::new(inner);
-        let (ready_chunks_tx, ready_chunks_rx) = crossbeam_channel::bounded::<VecDeque<u8>>(10);
+        let (ready_chunks_tx, ready_chunks_rx) =
+            crossbeam_channel::bounded::<io::Result<VecDeque<u8>>>(10);
         let (reuse_chunks_tx, reuse_chunks_rx) = crossbeam_channel::bounded::<VecDeque<u8>>(10);
         let should_stop = Arc::new(AtomicBool::new(false));
 
@@ -98,10 +100,10 @@ impl OffloadThread {
                 buf.truncate(read);
 
                 let send_span = tracing::trace_span!("OffloadThread ready_chunks_tx.send()");
-                let res = send_span.in_scope(|| self.ready_chunks_tx.send(buf));
+                let res = send_span.in_scope(|| self.ready_chunks_tx.send(Ok(buf)));
                 drop(send_span);
 
-                if let Err(_) = res {
+                if res.is_err() {
                     return Err(ThreadError::Shutdown);
                 }
             }
@@ -112,8 +114,13 @@ impl OffloadThread {
         match res {
             Ok(()) => (),
             Err(ThreadError::Shutdown) => (),
-            Err(ThreadError::Error(err)) =>
-                tracing::error!(%err, "Error in ThreadOffloadReader's offload thread"),
+            Err(ThreadError::Error(err)) => {
+                tracing::debug!(%err, "Error in ThreadOffloadReader's offload thread");
+                // Pass the error on to the reader to handle, so it isn't mistaken for
+                // the end of the stream.
+                let io_err = err.downcast::<io::Error>().unwrap_or_else(io::Error::other);
+                let _ = self.ready_chunks_tx.send(Err(io_err));
+            },
         };
     }
 
@@ -154,19 +161,19 @@ impl<E: StdError + Send + Sync + 'static> From<E> for ThreadError {
 
 impl Read for ThreadOffloadReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if let None = self.curr_chunk {
+        if self.curr_chunk.is_none() {
             let recv_span = tracing::trace_span!(
                 "ThreadOffloadReader::read: ready_chunks_rx.recv_timeout");
             let res = recv_span.in_scope(|| self.ready_chunks_rx.recv_timeout(self.read_timeout));
             drop(recv_span);
 
             let next = match res {
-                Ok(buf) => buf,
+                Ok(Ok(buf)) => buf,
+                Ok(Err(err)) => return Err(err),
                 // Offload thread has terminated.
                 Err(RecvTimeoutError::Disconnected) => return Ok(0),
                 Err(RecvTimeoutError::Timeout) =>
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
+                    return Err(io::Error::other(
                         "ThreadOffloadReader::read: timeout receiving next buffer.")),
             };
             self.curr_chunk = Some(next);
@@ -215,7 +222,7 @@ impl Drop for ThreadOffloadReader {
                                  .expect("self.offload_thread() is Some(_) until now");
         while start.elapsed() < self.read_timeout {
             if offload_thread.is_finished() {
-                let _ = offload_thread.join().expect(
+                offload_thread.join().expect(
                     "ThreadOffloadReader::drop() - joining offload thread.");
                 return;
             }
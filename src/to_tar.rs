@@ -0,0 +1,112 @@
+//! `ptar to-tar`: convert an archive set to one tar archive, for tools that read
+//! standard tar archives rather than ptar's sets.
+//!
+//! The tar entries of each archive in the set are copied in name order into one tar
+//! stream, compressed by the codec the output's extension names. Files split into
+//! several entries, e.g. with `compress --chunk-size`, are left split, so other tools
+//! extract only the last entry of each, and files encrypted with `--entry-key-file`
+//! stay encrypted.
+
+use anyhow::{bail, ensure, Context};
+use crate::{
+    Result, archive_set, manifest, tar_stream,
+    codec::Codec,
+};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    time::Instant,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// Tar archive to write, e.g. `backup.tar` or `backup.tar.zst`.
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Compression format of the tar archive. Defaults to the one `--out`'s extension
+    /// names: `.tar`, `.zst`, `.gz`, `.xz` or `.lz4`.
+    #[arg(long, value_enum)]
+    codec: Option<Codec>,
+
+    /// Compression level. Defaults to the codec's default level.
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+}
+
+pub fn main(cmd_args: Args, _args: crate::Args) -> Result<()> {
+    let start = Instant::now();
+    let out_name = cmd_args.out.file_name()
+        .with_context(|| format!("--out '{}' has no file name", cmd_args.out.display()))?
+        .to_string_lossy()
+        .into_owned();
+    let codec = match cmd_args.codec {
+        Some(codec) => codec,
+        None => match Codec::from_file_name(&out_name) {
+            Some(codec) => codec,
+            None => bail!("Can't tell the compression format from '{out_name}'; name it \
+                           e.g. '.tar.zst' or pass --codec"),
+        },
+    };
+    drop(codec.encoder(io::sink(), cmd_args.level, None)?);
+    ensure!(!cmd_args.out.exists(), "'{}' already exists", cmd_args.out.display());
+    warn_unportable(&cmd_args)?;
+
+    let partial_path = cmd_args.out.with_file_name(
+        format!("{out_name}{}", archive_set::PARTIAL_SUFFIX));
+    let file = File::options().write(true).create_new(true).open(&*partial_path)
+        .with_context(|| format!("creating '{}'", partial_path.display()))?;
+    let mut encoder = codec.encoder(BufWriter::with_capacity(1024 * 1024, file),
+                                    cmd_args.level, None)?;
+    let mut archive_count = 0;
+    let mut tar_bytes = 0;
+    for path in archive_set::candidate_paths(&cmd_args.in_dir)? {
+        let Some(mut archive) = archive_set::open(&path, None, None)
+                                    .with_context(|| format!("opening '{}'", path.display()))?
+        else {
+            continue;
+        };
+        tar_bytes += tar_stream::copy_entries(&mut archive.reader, &mut encoder)
+            .with_context(|| format!("converting '{}'", path.display()))?;
+        archive_count += 1;
+    }
+    ensure!(archive_count > 0, "No archives in '{}'", cmd_args.in_dir.display());
+    // The end of archive marker.
+    encoder.write_all(&[0; 1024])?;
+    let file = encoder.finish()?.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(&*partial_path, &*cmd_args.out)?;
+
+    tracing::info!(out = %cmd_args.out.display(), archive_count, tar_bytes,
+                   compressed_bytes = file.metadata()?.len(),
+                   duration_ms = start.elapsed().as_millis(), "Converted to tar");
+    Ok(())
+}
+
+/// Warn about files other tools can't extract as ptar does, from the set's manifest.
+fn warn_unportable(cmd_args: &Args) -> Result<()> {
+    let has_manifest = [manifest::MANIFEST_FILE_NAME, manifest::PARQUET_MANIFEST_FILE_NAME]
+        .iter()
+        .any(|name| cmd_args.in_dir.join(name).exists());
+    if !has_manifest {
+        return Ok(());
+    }
+    let entries = manifest::read(&cmd_args.in_dir)?;
+    if entries.iter().any(|e| e.chunk_offset.is_some() || e.parts.is_some()) {
+        tracing::warn!("The set has files split into several entries, by \
+                        `compress --chunk-size` or a `StreamWriter`; other tools will \
+                        extract only the last of each");
+    }
+    let encrypted_count = entries.iter().filter(|e| e.wrapped_key.is_some()).count();
+    if encrypted_count > 0 {
+        tracing::warn!(encrypted_count, "The set has files encrypted with \
+                                         `compress --entry-key-file`; other tools will \
+                                         extract them encrypted");
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! `ptar train-dict`: train a zstd dictionary on a sample of a directory's files, for
+//! `compress --dict` and `decompress --dict`.
+//!
+//! The sample is the start of each file, up to `MAX_SAMPLE_LEN` bytes, taking every
+//! file if they fit in `SAMPLE_BUDGET` bytes, or else evenly spaced files in walk order
+//! that do.
+
+use anyhow::{ensure, Context};
+use crate::{Result, codec::Dictionary, size};
+use ignore::WalkBuilder;
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    /// Directory of files to sample, e.g. the one to compress, or a representative part
+    /// of it.
+    #[arg(long)]
+    in_path: PathBuf,
+
+    /// File to write the dictionary to.
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Largest dictionary to write. Larger dictionaries can help more, but each zstd
+    /// frame starts by loading the whole dictionary.
+    #[arg(long, value_parser = size::parse, default_value = "110KiB")]
+    max_size: u64,
+}
+
+/// Bytes sampled from the start of each file. Dictionaries mostly help with the start
+/// of each zstd frame, so later parts of large files add little.
+const MAX_SAMPLE_LEN: u64 = 128 * 1024;
+
+/// Total bytes sampled, so training takes bounded memory and time.
+const SAMPLE_BUDGET: u64 = 128 * 1024 * 1024;
+
+pub fn main(cmd_args: Args, _args: crate::Args) -> Result<()> {
+    let files = sample_files(&cmd_args.in_path)?;
+    ensure!(!files.is_empty(), "No files to sample in '{}'", cmd_args.in_path.display());
+
+    let samples = files.iter()
+        .map(|path| -> Result<Vec<u8>> {
+            let mut sample = Vec::new();
+            File::open(path)?.take(MAX_SAMPLE_LEN).read_to_end(&mut sample)?;
+            Ok(sample)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let sample_bytes = samples.iter().map(Vec::len).sum::<usize>();
+
+    let max_size = usize::try_from(cmd_args.max_size)?;
+    let dictionary = zstd::dict::from_samples(&samples, max_size)
+        .context("Training the dictionary failed; try sampling more files")?;
+    let id = Dictionary::from_bytes(dictionary.clone())?.id();
+    fs::write(&*cmd_args.out, &dictionary)
+        .with_context(|| format!("writing '{}'", cmd_args.out.display()))?;
+
+    tracing::info!(out = %cmd_args.out.display(), id, size = dictionary.len(),
+                   samples = samples.len(), sample_bytes, "Trained dictionary");
+    Ok(())
+}
+
+/// The non-empty regular files under `dir` to sample, within `SAMPLE_BUDGET`.
+fn sample_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut total = 0;
+    for entry in WalkBuilder::new(dir).standard_filters(false).build() {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let len = entry.metadata()?.len().min(MAX_SAMPLE_LEN);
+        if len == 0 {
+            continue;
+        }
+        total += len;
+        files.push(entry.into_path());
+    }
+
+    let step = total.div_ceil(SAMPLE_BUDGET).max(1) as usize;
+    Ok(files.into_iter().step_by(step).collect())
+}
@@ -0,0 +1,140 @@
+//! Rewriting paths with `--transform` sed substitutions, as GNU tar does, e.g.
+//! `s,^project-1.2/,project/,`, applied to files' archived paths when compressing and
+//! to entries' paths when extracting.
+//!
+//! Expressions are `s/REGEX/REPLACEMENT/FLAGS`, with any delimiter in place of `/`.
+//! REGEX is in the `regex` crate's syntax, which is like extended regular expressions
+//! (`sed -E`), not GNU tar's default basic ones, so groups are `(...)`. REPLACEMENT
+//! may refer to the whole match with `&` and to groups with `\1` to `\9`. FLAGS are `g`
+//! to replace every match rather than the first, and `i` to ignore case.
+
+use anyhow::{bail, ensure, Context};
+use crate::Result;
+use regex::bytes::{Regex, RegexBuilder};
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Substitutions applied to each path in turn.
+#[derive(Debug, Default)]
+pub struct Transform {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    regex: Regex,
+    /// In the `regex` crate's syntax, e.g. `${1}`.
+    replacement: Vec<u8>,
+    global: bool,
+}
+
+impl Transform {
+    pub fn new(exprs: &[String]) -> Result<Transform> {
+        let rules = exprs.iter()
+            .map(|expr| parse(expr)
+                            .with_context(|| format!("parsing --transform '{expr}'")))
+            .collect::<Result<Vec<Rule>>>()?;
+        Ok(Transform { rules })
+    }
+
+    /// `path` with each substitution applied to the result of the previous one.
+    pub fn apply(&self, path: &Path) -> PathBuf {
+        let mut bytes = path.as_os_str().as_bytes().to_vec();
+        for rule in self.rules.iter() {
+            let limit = if rule.global { 0 } else { 1 };
+            bytes = rule.regex.replacen(&bytes, limit, rule.replacement.as_slice()).into_owned();
+        }
+        PathBuf::from(OsStr::from_bytes(&bytes))
+    }
+}
+
+fn parse(expr: &str) -> Result<Rule> {
+    let mut chars = expr.chars();
+    ensure!(chars.next() == Some('s'), "Only `s` substitutions are supported");
+    let delim = chars.next().context("Missing delimiter after `s`")?;
+    ensure!(!delim.is_alphanumeric() && delim != '\\' && delim != '\n',
+            "Invalid delimiter '{delim}'");
+
+    let mut regex = String::new();
+    loop {
+        match chars.next().context("Unterminated regex")? {
+            c if c == delim => break,
+            '\\' => match chars.next().context("Trailing `\\` in regex")? {
+                c if c == delim => regex.push_str(&regex::escape(&c.to_string())),
+                c => {
+                    regex.push('\\');
+                    regex.push(c);
+                },
+            },
+            c => regex.push(c),
+        }
+    }
+
+    let mut replacement = String::new();
+    loop {
+        match chars.next().context("Unterminated replacement")? {
+            c if c == delim => break,
+            '&' => replacement.push_str("${0}"),
+            '$' => replacement.push_str("$$"),
+            '\\' => match chars.next().context("Trailing `\\` in replacement")? {
+                c @ '0'..='9' => replacement.push_str(&format!("${{{c}}}")),
+                'n' => replacement.push('\n'),
+                c => replacement.push(c),
+            },
+            c => replacement.push(c),
+        }
+    }
+
+    let mut global = false;
+    let mut case_insensitive = false;
+    for flag in chars {
+        match flag {
+            'g' => global = true,
+            'i' => case_insensitive = true,
+            _ => bail!("Unsupported flag '{flag}'; supported flags are `g` and `i`"),
+        }
+    }
+
+    Ok(Rule {
+        regex: RegexBuilder::new(&regex).case_insensitive(case_insensitive).build()?,
+        replacement: replacement.into_bytes(),
+        global,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(exprs: &[&str], path: &str) -> String {
+        let exprs = exprs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let transform = Transform::new(&exprs).unwrap();
+        transform.apply(Path::new(path)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn substitutions() {
+        assert_eq!(apply(&[], "a/b"), "a/b");
+        assert_eq!(apply(&["s,^project-1.2/,project/,"], "project-1.2/src/main.rs"),
+                   "project/src/main.rs");
+        assert_eq!(apply(&["s/a/x/"], "a/a"), "x/a");
+        assert_eq!(apply(&["s/a/x/g"], "a/a"), "x/x");
+        assert_eq!(apply(&["s/A/x/gi"], "a/a"), "x/x");
+
+        // Groups, `&`, escaped delimiters and literal `$`s.
+        assert_eq!(apply(&[r"s/(\w+)\.rs$/\1.rs.bak/"], "src/main.rs"), "src/main.rs.bak");
+        assert_eq!(apply(&["s/main/[&]/"], "main.rs"), "[main].rs");
+        assert_eq!(apply(&[r"s/\//-/g"], "a/b/c"), "a-b-c");
+        assert_eq!(apply(&[r"s|a|$1\&|"], "a"), "$1&");
+
+        // Applied in turn.
+        assert_eq!(apply(&["s/a/b/", "s/b/c/"], "a"), "c");
+
+        for expr in ["y/a/b/", "s/a/b", "s/(/b/", "s/a/b/x", "sabac"] {
+            assert!(Transform::new(&[expr.to_string()]).is_err(), "{expr}");
+        }
+    }
+}
@@ -0,0 +1,436 @@
+//! `ptar verify`: read every archive in a set to check it decodes, and check file
+//! checksums against the manifest when it has them.
+//!
+//! Progress is saved to a state file after each archive, so an interrupted verify of a
+//! large set can be resumed with `--resume`.
+//!
+//! With `--checksums <DIR>` it instead re-hashes files already extracted to `DIR`.
+//!
+//! With `--sample` it checks a random sample of files from the manifest, reading only
+//! the archives that contain them, and each only as far as its last sampled file.
+
+use anyhow::{Context, ensure};
+use crate::{
+    Result, archive_set, hasher, manifest, manifest_signature,
+    shard_encryption::{self, DecryptionKeys},
+};
+use rayon::prelude::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::SystemTime,
+};
+use valuable::Valuable;
+
+#[derive(clap::Args, Clone, Debug, Valuable)]
+pub struct Args {
+    #[arg(long)]
+    in_dir: PathBuf,
+
+    /// File to save verify progress to. Defaults to `ptar-verify-state.json` in `--in-dir`.
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Skip archives the state file records as verified, if they're unchanged since.
+    #[arg(long)]
+    resume: bool,
+
+    /// Only verify archives modified after this RFC 3339 time, e.g. the `finished_at`
+    /// time from `ptar status` of the last verified run.
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Instead of reading the archives, re-hash the files extracted to this directory
+    /// and compare them with the manifest's checksums.
+    #[arg(long, value_name = "EXTRACTED_DIR")]
+    checksums: Option<PathBuf>,
+
+    /// Check only this fraction of the files with checksums in the manifest, e.g. `1%` or
+    /// `0.01`. Doesn't update the state file, as archives aren't fully read.
+    #[arg(long, value_parser = parse_fraction)]
+    sample: Option<f64>,
+
+    /// Seed for choosing the `--sample`. The same seed chooses the same files.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Decrypt archives compressed with `--encrypt`, using the age identities in this
+    /// file.
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    identity: Option<PathBuf>,
+
+    /// Decrypt archives compressed with `--passphrase`. The passphrase is read from the
+    /// `PTAR_PASSPHRASE` environment variable if set, or else prompted for.
+    #[arg(long, visible_alias = "passphrase-prompt")]
+    passphrase: bool,
+
+    /// First check the set's `manifest.sig`, written by `compress --sign-key`, is signed
+    /// by the ed25519 public key in this file, written as 64 hex digits, and that the
+    /// manifest and archives are unchanged since.
+    #[arg(long, value_name = "PUBKEY_FILE")]
+    pubkey: Option<PathBuf>,
+}
+
+/// Verify progress, saved after each archive.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+struct State {
+    /// Keyed by archive file name.
+    archives: BTreeMap<String, VerifiedArchive>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct VerifiedArchive {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: u32,
+    /// BLAKE3 hash of the compressed archive file.
+    blake3: String,
+    verified_at: String,
+}
+
+/// Shares the `Hasher` a `TeeHashReader` updates with the thread that reads its result.
+type SharedHasher = Arc<Mutex<blake3::Hasher>>;
+
+/// Passes reads through and hashes the bytes read.
+struct TeeHashReader<R: Read> {
+    hasher: SharedHasher,
+    inner: R,
+}
+
+pub const DEFAULT_STATE_FILE_NAME: &str = "ptar-verify-state.json";
+
+pub fn main(cmd_args: Args, args: crate::Args) -> Result<()> {
+    if let Some(ref pubkey) = cmd_args.pubkey {
+        let file_count = manifest_signature::verify(&cmd_args.in_dir, pubkey)?;
+        tracing::info!(file_count, "Archive set signature is valid");
+    }
+
+    if let Some(ref extracted_dir) = cmd_args.checksums {
+        return verify_extracted(&cmd_args.in_dir, extracted_dir, args.threads());
+    }
+
+    let state_path = cmd_args.state_file.clone()
+        .unwrap_or_else(|| cmd_args.in_dir.join(DEFAULT_STATE_FILE_NAME));
+    let state = if cmd_args.resume && state_path.exists() {
+        let json = fs::read_to_string(&*state_path)?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("parsing state file '{}'", state_path.display()))?
+    } else {
+        State::default()
+    };
+    let state = Mutex::new(state);
+
+    let manifest_entries = match manifest::read(&cmd_args.in_dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            ensure!(cmd_args.sample.is_none(), "--sample requires a manifest: {err}");
+            tracing::info!(%err, "No manifest read, so not checking file checksums");
+            Vec::new()
+        },
+    };
+    // Checksums are of the plaintext, so encrypted entries can't be checked in the
+    // archives, though they're still decoded.
+    let manifest_entries = manifest_entries.into_iter()
+                                           .filter(|e| e.checksum.is_some()
+                                                       && e.wrapped_key.is_none());
+
+    // Number of sampled files in each archive, with `--sample`.
+    let mut sampled_by_archive = HashMap::<String, usize>::new();
+    let checksums = match cmd_args.sample {
+        None => manifest_entries.map(|e| (e.path, e.checksum.expect("filtered to Some")))
+                                .collect::<HashMap<PathBuf, String>>(),
+        Some(fraction) => {
+            let sampled = manifest_entries
+                .filter(|e| is_sampled(cmd_args.seed, &e.path, fraction))
+                .map(|e| {
+                    *sampled_by_archive.entry(e.archive).or_insert(0) += 1;
+                    (e.path, e.checksum.expect("filtered to Some"))
+                })
+                .collect::<HashMap<PathBuf, String>>();
+            tracing::info!(sampled_count = sampled.len(),
+                           archive_count = sampled_by_archive.len(),
+                           "Chose sample of files to verify");
+            sampled
+        },
+    };
+
+    let since = cmd_args.since.as_deref().map(parse_time).transpose()?;
+    let passphrase = cmd_args.passphrase.then(|| shard_encryption::read_passphrase(false))
+                                        .transpose()?;
+    let keys = DecryptionKeys::new(cmd_args.identity.as_deref(), passphrase)?;
+
+    let mut archive_paths = Vec::new();
+    for path in archive_set::candidate_paths(&cmd_args.in_dir)? {
+        if path == state_path {
+            continue;
+        }
+        if cmd_args.sample.is_some() && !sampled_by_archive.contains_key(&file_name(&path)) {
+            continue;
+        }
+        let meta = fs::metadata(&*path)?;
+        if let Some(since) = since {
+            if meta.modified()? <= since {
+                continue;
+            }
+        }
+        if cmd_args.resume {
+            let name = file_name(&path);
+            let (mtime, mtime_nsec) = manifest::unix_time(meta.modified()?);
+            let state = state.lock().expect("state lock");
+            if let Some(v) = state.archives.get(&name) {
+                if v.size == meta.len() && v.mtime == mtime && v.mtime_nsec == mtime_nsec {
+                    tracing::debug!(archive = name, "Skipping archive already verified");
+                    continue;
+                }
+            }
+        }
+        archive_paths.push((path, meta));
+    }
+
+    let error_count = AtomicUsize::new(0);
+    let verified_count = AtomicUsize::new(0);
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build()?
+        .install(|| {
+            archive_paths
+                .into_par_iter()
+                .with_max_len(1) // 1 item per thread
+                .for_each(|(archive_path, meta)| {
+                    let name = file_name(&archive_path);
+                    let _thread_span = tracing::debug_span!("verify thread",
+                                                            archive = name).entered();
+                    let stop_after = match cmd_args.sample {
+                        Some(_) => sampled_by_archive.get(&name).copied(),
+                        None => None,
+                    };
+                    let res = verify_archive(&archive_path, keys.as_ref(), &checksums,
+                                             stop_after);
+                    match res {
+                        Ok(None) => (),
+                        Ok(Some(None)) => {
+                            // Read only part of the archive for --sample.
+                            verified_count.fetch_add(1, Ordering::SeqCst);
+                        },
+                        Ok(Some(Some(blake3))) => {
+                            verified_count.fetch_add(1, Ordering::SeqCst);
+                            let (mtime, mtime_nsec) =
+                                manifest::unix_time(meta.modified()
+                                                        .unwrap_or(SystemTime::UNIX_EPOCH));
+                            let mut state = state.lock().expect("state lock");
+                            state.archives.insert(name, VerifiedArchive {
+                                size: meta.len(),
+                                mtime,
+                                mtime_nsec,
+                                blake3,
+                                verified_at: crate::status::format_time(SystemTime::now()),
+                            });
+                            if let Err(err) = write_state(&state_path, &state) {
+                                tracing::error!(%err, "Error writing verify state file");
+                                error_count.fetch_add(1, Ordering::SeqCst);
+                            }
+                        },
+                        Err(err) => {
+                            tracing::error!(%err, archive = %archive_path.display(),
+                                            "Archive failed verification");
+                            error_count.fetch_add(1, Ordering::SeqCst);
+                        },
+                    }
+                });
+        });
+
+    let verified_count = verified_count.load(Ordering::SeqCst);
+    let error_count = error_count.load(Ordering::SeqCst);
+    tracing::info!(verified_count, error_count, "Verify finished");
+    ensure!(error_count == 0, "Errors in verify() count={error_count}");
+
+    Ok(())
+}
+
+/// Read through one archive, checking each file's checksum if `checksums` has it.
+///
+/// With `stop_after`, stops after checking that many files and returns `Some(None)`.
+/// Otherwise returns the archive's BLAKE3 hash, or None if it's not a recognised archive.
+fn verify_archive(path: &Path, keys: Option<&DecryptionKeys>,
+                  checksums: &HashMap<PathBuf, String>, stop_after: Option<usize>)
+-> Result<Option<Option<String>>>
+{
+    let hasher = SharedHasher::default();
+    let file = TeeHashReader {
+        hasher: hasher.clone(),
+        inner: fs::File::open(path)?,
+    };
+    let Some(archive) = archive_set::open_stream(file, keys, None)? else {
+        return Ok(None);
+    };
+
+    let mut tar = tar::Archive::new(archive.reader);
+    let mut mismatches = 0_usize;
+    let mut checked = 0_usize;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let Some(expected) = checksums.get(&entry_path) else {
+            io::copy(&mut entry, &mut io::sink())?;
+            continue;
+        };
+        if !check_checksum(&entry_path, expected, &mut entry)? {
+            mismatches += 1;
+        }
+        checked += 1;
+        if stop_after == Some(checked) {
+            ensure!(mismatches == 0, "{mismatches} checksum mismatches");
+            return Ok(Some(None));
+        }
+    }
+    if let Some(stop_after) = stop_after {
+        ensure!(checked == stop_after,
+                "Found only {checked} of {stop_after} sampled files in the archive");
+    }
+    ensure!(mismatches == 0, "{mismatches} checksum mismatches");
+
+    // Read the rest of the stream, e.g. the end of archive blocks, so the whole file is
+    // hashed.
+    io::copy(&mut tar.into_inner(), &mut io::sink())?;
+    let hash = hasher.lock().expect("hasher lock").finalize().to_string();
+    Ok(Some(Some(hash)))
+}
+
+/// Re-hash the files extracted to `extracted_dir` and compare them with the
+/// checksums in the manifest in `in_dir`.
+fn verify_extracted(in_dir: &Path, extracted_dir: &Path, threads: usize) -> Result<()> {
+    let entries = manifest::read(in_dir)?
+        .into_iter()
+        .filter(|e| e.checksum.is_some())
+        .collect::<Vec<manifest::Entry>>();
+    ensure!(!entries.is_empty(), "The manifest has no checksums; compress with --checksums");
+
+    let error_count = AtomicUsize::new(0);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?
+        .install(|| {
+            entries.par_iter().for_each(|entry| {
+                let path = extracted_dir.join(&entry.path);
+                let expected = entry.checksum.as_deref().expect("filtered to Some above");
+                let res = fs::File::open(&*path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|mut file| check_checksum(&entry.path, expected, &mut file));
+                match res {
+                    Ok(true) => (),
+                    Ok(false) => { error_count.fetch_add(1, Ordering::SeqCst); },
+                    Err(err) => {
+                        tracing::error!(%err, path = %path.display(), "Error hashing file");
+                        error_count.fetch_add(1, Ordering::SeqCst);
+                    },
+                }
+            });
+        });
+
+    let error_count = error_count.load(Ordering::SeqCst);
+    tracing::info!(checked_count = entries.len(), error_count, "Verify finished");
+    ensure!(error_count == 0, "Errors in verify() count={error_count}");
+    Ok(())
+}
+
+/// Hash `data` with the algorithm `expected` names and compare the result.
+/// Logs and returns false on a mismatch.
+fn check_checksum(path: &Path, expected: &str, data: &mut impl Read) -> Result<bool> {
+    let (algorithm, expected_digest) = hasher::Algorithm::parse_checksum(expected)?;
+    let mut hasher = hasher::Hasher::new(algorithm);
+    io::copy(data, &mut hasher)?;
+    let actual_digest = hasher.finalize_reset();
+    if actual_digest != expected_digest {
+        tracing::error!(path = %path.display(), expected, actual = actual_digest,
+                        "Checksum mismatch");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn write_state(path: &Path, state: &State) -> Result<()> {
+    // Write then rename so an interruption never leaves a partial file.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(state)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("archive path has a file name")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Parse a fraction between 0 and 1, or a percentage ending in `%`.
+pub(crate) fn parse_fraction(s: &str) -> Result<f64> {
+    let fraction = match s.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>()? / 100.0,
+        None => s.parse::<f64>()?,
+    };
+    ensure!((0.0..=1.0).contains(&fraction), "Sample '{s}' is not between 0% and 100%");
+    Ok(fraction)
+}
+
+/// Choose whether `path` is in the sample, independent of the order files are seen in.
+pub(crate) fn is_sampled(seed: u64, path: &Path, fraction: f64) -> bool {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&seed.to_le_bytes());
+    hasher.update(path.as_os_str().as_encoded_bytes());
+    let hash = hasher.finalize();
+    let x = u64::from_le_bytes(hash.as_bytes()[0..8].try_into().expect("8 bytes"));
+    (x as f64) < fraction * (u64::MAX as f64)
+}
+
+fn parse_time(s: &str) -> Result<SystemTime> {
+    let t = time::OffsetDateTime::parse(s, &time::format_description::well_known::Rfc3339)
+        .with_context(|| format!("parsing time '{s}'"))?;
+    Ok(t.into())
+}
+
+impl<R: Read> Read for TeeHashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.lock().expect("hasher lock").update(&buf[..count]);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractions() {
+        assert_eq!(parse_fraction("1%").unwrap(), 0.01);
+        assert_eq!(parse_fraction("0.25").unwrap(), 0.25);
+        assert!(parse_fraction("101%").is_err());
+        assert!(parse_fraction("x").is_err());
+    }
+
+    #[test]
+    fn sampling_is_seeded_and_proportional() {
+        let paths = (0..10_000).map(|i| PathBuf::from(format!("dir/{i}")))
+                               .collect::<Vec<_>>();
+        let sample = |seed, fraction| paths.iter()
+                                           .filter(|p| is_sampled(seed, p, fraction))
+                                           .collect::<Vec<_>>();
+
+        assert!(sample(1, 0.0).is_empty());
+        assert_eq!(sample(1, 1.0).len(), paths.len());
+
+        let a = sample(1, 0.1);
+        assert!((800..1200).contains(&a.len()), "len = {}", a.len());
+        assert_eq!(a, sample(1, 0.1));
+        assert_ne!(a, sample(2, 0.1));
+    }
+}
@@ -0,0 +1,128 @@
+//! Extended attributes, e.g. `user.*`, `security.selinux` and `security.capability`,
+//! stored in pax records as GNU tar and libarchive do.
+//!
+//! Values are written raw in `SCHILY.xattr.<name>` records, except those containing a
+//! newline, e.g. some file capability masks: the `tar` crate splits pax records at
+//! newlines, so those are written as libarchive's
+//! `LIBARCHIVE.xattr.<URL encoded name>=<base64 value>` instead, which GNU tar
+//! ignores. Both are restored by [`apply`], rather than the `tar` crate's
+//! `set_unpack_xattrs`, which only reads the former.
+//!
+//! ACLs, which Linux also keeps in extended attributes, are left to [`acls`].
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use crate::{Result, acls};
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+use xattr::FileExt;
+
+const SCHILY_PREFIX: &str = "SCHILY.xattr.";
+const LIBARCHIVE_PREFIX: &str = "LIBARCHIVE.xattr.";
+
+/// Read the extended attributes of `file` as pax records. Empty if the filesystem
+/// doesn't support them.
+pub fn records(file: &File) -> Result<Vec<(String, Vec<u8>)>> {
+    let names = match file.list_xattr() {
+        Ok(names) => names,
+        Err(err) if is_unsupported(&err) => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut records = Vec::new();
+    for name in names {
+        if acls::is_acl_xattr(name.as_bytes()) {
+            continue;
+        }
+        // Removed since listed.
+        let Some(value) = file.get_xattr(&name)? else {
+            continue;
+        };
+        match name.to_str() {
+            Some(name) if !value.contains(&b'\n') => {
+                records.push((format!("{SCHILY_PREFIX}{name}"), value));
+            },
+            _ => {
+                let name = url_encode(name.as_bytes());
+                records.push((format!("{LIBARCHIVE_PREFIX}{name}"),
+                              BASE64.encode(value).into_bytes()));
+            },
+        }
+    }
+    records.sort();
+    Ok(records)
+}
+
+/// Set the extended attributes in `entry`'s pax records on the file extracted from it
+/// at `path`.
+pub fn apply<R: Read>(entry: &mut tar::Entry<R>, path: &Path) -> Result<()> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(());
+    };
+    // Records the `tar` crate can't parse are skipped, as it does when extracting.
+    for extension in extensions.filter_map(|e| e.ok()) {
+        let key = extension.key_bytes();
+        if let Some(name) = key.strip_prefix(SCHILY_PREFIX.as_bytes())
+                               .filter(|name| acls::is_acl_xattr(name)) {
+            tracing::debug!(path = %path.display(), name = %String::from_utf8_lossy(name),
+                            "Skipping ACL extended attribute, which --acls restores");
+        } else if let Some(name) = key.strip_prefix(SCHILY_PREFIX.as_bytes()) {
+            xattr::set(path, OsStr::from_bytes(name), extension.value_bytes())?;
+        } else if let Some(name) = key.strip_prefix(LIBARCHIVE_PREFIX.as_bytes()) {
+            let Some(name) = url_decode(name) else {
+                tracing::warn!(path = %path.display(), "Skipping malformed xattr record");
+                continue;
+            };
+            let value = BASE64.decode(extension.value_bytes())?;
+            xattr::set(path, OsStr::from_bytes(&name), &value)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_unsupported(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::Unsupported || err.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// Percent-encode bytes other than ASCII letters, digits and `.-_~`, as libarchive does.
+fn url_encode(name: &[u8]) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for &b in name {
+        if b.is_ascii_alphanumeric() || b".-_~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+fn url_decode(name: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'%' {
+            let hex = [*bytes.next()?, *bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_encoding() {
+        let name = b"user.a b%\xff";
+        assert_eq!(url_encode(name), "user.a%20b%25%FF");
+        assert_eq!(url_decode(url_encode(name).as_bytes()).unwrap(), name);
+        assert!(url_decode(b"user.%2").is_none());
+    }
+}